@@ -0,0 +1,78 @@
+; Copies a table of numbers from a .data block into memory, sorts it there with bubble sort
+; and prints it before and after.
+
+.data
+TABLE 0x100         ; Address of the first number in memory
+COUNT 8
+V0 29
+V1 4
+V2 1000
+V3 17
+V4 4
+V5 0
+V6 512
+V7 73
+.end
+
+.ascii NEWLINE "\n"
+
+mov ax, V0
+store ax, TABLE
+mov ax, V1
+store ax, (TABLE + 1)
+mov ax, V2
+store ax, (TABLE + 2)
+mov ax, V3
+store ax, (TABLE + 3)
+mov ax, V4
+store ax, (TABLE + 4)
+mov ax, V5
+store ax, (TABLE + 5)
+mov ax, V6
+store ax, (TABLE + 6)
+mov ax, V7
+store ax, (TABLE + 7)
+call .print_table
+
+; Each pass moves the largest number left into its place at the end, so every pass looks at
+; one number less. dx is the number of pairs the pass compares.
+mov dx, #(COUNT - 1)
+pass:
+mov ax, TABLE       ; address of the left number of the pair
+clr ex              ; pairs compared so far
+pair:
+movr fx, ax
+inc fx              ; address of the right number
+loadr bx, ax
+loadr cx, fx
+cmp bx, cx
+jg exchange
+jmp next_pair
+exchange:
+storer cx, ax
+storer bx, fx
+next_pair:
+inc ax
+inc ex
+cmp ex, dx
+jl pair
+dec dx
+cmp dx, 0
+jg pass
+
+call .print_table
+halt
+
+; Prints the COUNT numbers from TABLE on one line
+.print_table
+mov ax, TABLE
+mov cx, COUNT
+each:
+loadinc bx, ax
+printw bx, 5
+dec cx
+cmp cx, 0
+jg each
+prints NEWLINE
+ret
+.end
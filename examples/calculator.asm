@@ -0,0 +1,100 @@
+; A calculator reading an operation and two numbers per calculation, each on a line of its
+; own: 1 adds, 2 subtracts, 3 multiplies, 4 divides and 0 quits.
+; Run it with --input-errors flag, so a line that is not a number sets the input error flag
+; instead of stopping the program. Overflow, a negative result and division by zero are caught
+; before they go wrong, with the overflow flag and CMP. The end of input also reads as a line
+; that is not a number, so three of those in a row end the calculator.
+
+.define INPUT_ERROR 8   ; GETF bits
+.define OVERFLOW 16
+.define MAX_BAD_LINES 3
+
+.ascii NEWLINE "\n"
+.ascii NOT_A_NUMBER "not a number\n"
+.ascii UNKNOWN "unknown operation\n"
+.ascii NEGATIVE "negative result\n"
+.ascii TOO_LARGE "result too large\n"
+.ascii DIVIDE_BY_ZERO "division by zero\n"
+.ascii GIVING_UP "too many bad lines, giving up\n"
+.ascii BYE "bye\n"
+
+calculation:
+call .read
+cmp fx, 0
+jne bad_input
+mov ex, 0               ; bad lines in a row
+movr cx, dx             ; the operation
+cmp cx, 0
+je quit
+call .read
+cmp fx, 0
+jne bad_input
+movr ax, dx
+call .read
+cmp fx, 0
+jne bad_input
+movr bx, dx
+cmp cx, 1
+je addition
+cmp cx, 2
+je subtraction
+cmp cx, 3
+je multiplication
+cmp cx, 4
+je division
+prints UNKNOWN
+jmp calculation
+
+addition:
+add ax, bx
+jmp check_overflow
+subtraction:
+cmp ax, bx
+jl negative
+sub ax, bx
+jmp result
+multiplication:
+mul ax, bx
+check_overflow:
+getf fx
+and fx, OVERFLOW
+cmp fx, 0
+jne too_large
+jmp result
+division:
+cmp bx, 0
+je divide_by_zero
+div ax, bx
+
+result:
+printw ax, 1
+prints NEWLINE
+jmp calculation
+
+bad_input:
+prints NOT_A_NUMBER
+add ex, 1
+cmp ex, MAX_BAD_LINES
+jl calculation
+prints GIVING_UP
+halt
+negative:
+prints NEGATIVE
+jmp calculation
+too_large:
+prints TOO_LARGE
+jmp calculation
+divide_by_zero:
+prints DIVIDE_BY_ZERO
+jmp calculation
+quit:
+prints BYE
+halt
+
+; Reads a line into dx, fx is INPUT_ERROR when it was not a number and 0 otherwise
+.read
+in dx
+getf fx
+and fx, INPUT_ERROR
+ret
+.end
@@ -0,0 +1,41 @@
+// Running a program that was assembled ahead of time. A crate embedding a program would
+// assemble it in its build.rs:
+//
+//   let source = std::fs::read_to_string("program.asm").unwrap();
+//   let bytes = alice::assemble(&source).expect("program.asm does not assemble");
+//   let out = std::env::var("OUT_DIR").unwrap();
+//   std::fs::write(format!("{}/program.bin", out), bytes).unwrap();
+//   println!("cargo:rerun-if-changed=program.asm");
+//
+// and then only needs load and run_with at runtime:
+//
+//   static PROGRAM: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/program.bin"));
+//
+// This example assembles in main instead, so it runs without a build script.
+
+use std::io;
+
+const SOURCE: &str = "
+in ax ; read a number
+mov bx, ax
+mul ax, bx
+print ax
+";
+
+fn main() {
+    let bytes = match alice::assemble(SOURCE) {
+        Ok(bytes) => bytes,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            return;
+        }
+    };
+    let program = alice::load(&bytes).expect("assemble produced an invalid binary");
+    let input = io::Cursor::new("12\n");
+    match program.run_with(input, io::stdout()) {
+        Ok(cpu) => println!("bx was {}", cpu.register(1)),
+        Err(error) => eprintln!("{}", error),
+    }
+}
@@ -0,0 +1,51 @@
+; Prints the Fibonacci numbers fib(0) to fib(LAST), computed by a recursive function.
+; dx counts how deep the recursion is, and .fib gives up with a message instead of running
+; into max_call_depth once it is deeper than MAX_DEPTH, which fib(DEEP) is.
+
+.define LAST 15
+.define MAX_DEPTH 20
+.define DEEP 25
+
+.ascii NEWLINE "\n"
+.ascii TOO_DEEP "fib needs more than 20 levels of recursion here, giving up\n"
+
+clr dx              ; recursion depth
+clr ax
+table:
+call .fib
+printw bx, 5
+inc ax
+cmp ax, #(LAST + 1)
+jl table
+prints NEWLINE
+
+mov ax, DEEP        ; too deep for the guard
+call .fib
+print bx            ; never reached
+
+; bx = fib(ax), ax is left as it was
+.fib
+inc dx
+cmp dx, MAX_DEPTH
+jg too_deep
+cmp ax, 2
+jl small
+push ax
+dec ax
+call .fib           ; bx = fib(n - 1)
+push bx
+dec ax
+call .fib           ; bx = fib(n - 2)
+pop cx
+add bx, cx
+pop ax
+dec dx
+ret
+small:
+movr bx, ax         ; fib(0) = 0 and fib(1) = 1
+dec dx
+ret
+too_deep:
+prints TOO_DEEP
+halt
+.end
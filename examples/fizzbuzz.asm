@@ -0,0 +1,39 @@
+; FizzBuzz from 1 to LAST: Fizz for multiples of 3, Buzz for multiples of 5, FizzBuzz for
+; multiples of both and the number itself otherwise.
+
+.define LAST 20
+
+.ascii FIZZ "Fizz\n"
+.ascii BUZZ "Buzz\n"
+.ascii FIZZBUZZ "FizzBuzz\n"
+.ascii NEWLINE "\n"
+
+mov ax, 1
+number:
+movr bx, ax
+mod bx, #15
+cmp bx, 0
+je fizzbuzz
+movr bx, ax
+mod bx, #3
+cmp bx, 0
+je fizz
+movr bx, ax
+mod bx, #5
+cmp bx, 0
+je buzz
+printw ax, 1        ; a width of 1 prints the number without padding
+prints NEWLINE
+jmp next
+fizzbuzz:
+prints FIZZBUZZ
+jmp next
+fizz:
+prints FIZZ
+jmp next
+buzz:
+prints BUZZ
+next:
+inc ax
+cmp ax, #(LAST + 1)
+jl number
@@ -0,0 +1,23 @@
+; Prints the multiplication table from 1 x 1 to SIZE x SIZE with two nested loops, the outer
+; one over rows in ax and the inner one over columns in bx. Each product is padded to WIDTH.
+
+.define SIZE 9
+.define WIDTH 4
+
+.ascii NEWLINE "\n"
+
+mov ax, 1
+row:
+mov bx, 1
+column:
+movr cx, ax
+mul cx, bx
+printw cx, WIDTH
+add bx, 1
+cmp bx, (SIZE + 1)
+jl column
+prints NEWLINE
+add ax, 1
+cmp ax, (SIZE + 1)
+jl row
+halt
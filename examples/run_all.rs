@@ -0,0 +1,147 @@
+// Runs every program in this directory through the library with canned input and checks what
+// it printed, the way a crate embedding alice would test its own programs:
+//
+//   cargo run --example run_all
+//
+// cargo test runs the same cases as well.
+//
+// Each program is parsed with parse_with_config and run on a Cpu reading from a Cursor and
+// writing into a SharedBuffer. The calculator reads lines that are not numbers, so input errors
+// are flagged for it to check instead of stopping the run.
+
+use alice::{
+    parse_with_config, run_verified, Config, Cpu, InputErrorPolicy, SharedBuffer, VerifyError,
+};
+use std::io;
+use std::process;
+
+struct Case {
+    file: &'static str,
+    input: &'static str,
+    expected: &'static str,
+}
+
+const CASES: &[Case] = &[
+    Case {
+        file: "fibonacci.asm",
+        input: "",
+        expected: concat!(
+            "    0    1    1    2    3    5    8   13   21   34   55   89  144  233  377  610\n",
+            "fib needs more than 20 levels of recursion here, giving up\n",
+        ),
+    },
+    Case {
+        file: "bubble_sort.asm",
+        input: "",
+        expected: concat!(
+            "   29    4 1000   17    4    0  512   73\n",
+            "    0    4    4   17   29   73  512 1000\n",
+        ),
+    },
+    Case {
+        file: "fizzbuzz.asm",
+        input: "",
+        expected: concat!(
+            "1\n2\nFizz\n4\nBuzz\nFizz\n7\n8\nFizz\nBuzz\n",
+            "11\nFizz\n13\n14\nFizzBuzz\n16\n17\nFizz\n19\nBuzz\n",
+        ),
+    },
+    Case {
+        file: "calculator.asm",
+        input: "1\n2\n3\n2\n10\n4\n3\n300\n300\n4\n7\n0\n4\n100\n7\nx\n9\n1\n2\n2\n3\n4\n0\n",
+        expected: concat!(
+            "5\n6\nresult too large\ndivision by zero\n14\nnot a number\n",
+            "unknown operation\nnegative result\nbye\n",
+        ),
+    },
+    Case {
+        file: "calculator.asm",
+        input: "1\n2\n",
+        expected: "not a number\nnot a number\nnot a number\ntoo many bad lines, giving up\n",
+    },
+    Case {
+        file: "multiplication_table.asm",
+        input: "",
+        expected: concat!(
+            "   1   2   3   4   5   6   7   8   9\n",
+            "   2   4   6   8  10  12  14  16  18\n",
+            "   3   6   9  12  15  18  21  24  27\n",
+            "   4   8  12  16  20  24  28  32  36\n",
+            "   5  10  15  20  25  30  35  40  45\n",
+            "   6  12  18  24  30  36  42  48  54\n",
+            "   7  14  21  28  35  42  49  56  63\n",
+            "   8  16  24  32  40  48  56  64  72\n",
+            "   9  18  27  36  45  54  63  72  81\n",
+        ),
+    },
+];
+
+// What the program printed, or why it could not be run. verified checks every step against the
+// oracle as well, with run_verified.
+fn run(case: &Case, verified: bool) -> Result<String, String> {
+    let path = format!("{}/examples/{}", env!("CARGO_MANIFEST_DIR"), case.file);
+    let source = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+    let config = Config::default();
+    let program = parse_with_config(source, &config).map_err(|e| e.to_string())?;
+
+    let output = SharedBuffer::default();
+    let mut cpu = Cpu::new(config);
+    cpu.input_errors = InputErrorPolicy::Flag;
+    cpu.max_instructions = 1_000_000;
+    cpu.set_input(Box::new(io::Cursor::new(case.input)));
+    cpu.set_output(Box::new(output.clone()));
+    cpu.load_program(&program);
+    if verified {
+        match run_verified(&mut cpu) {
+            Err(VerifyError::Diverged(divergence)) => return Err(divergence.to_string()),
+            result => result.map_err(|e| e.to_string())?,
+        }
+    } else {
+        cpu.run().map_err(|e| e.to_string())?;
+    }
+
+    Ok(output.text())
+}
+
+fn main() {
+    let mut failed = 0;
+    for case in CASES {
+        match run(case, false) {
+            Ok(printed) if printed == case.expected => println!("PASS {}", case.file),
+            Ok(printed) => {
+                failed += 1;
+                println!(
+                    "FAIL {}, expected:\n{}printed:\n{}",
+                    case.file, case.expected, printed
+                );
+            }
+            Err(error) => {
+                failed += 1;
+                println!("FAIL {}, {}", case.file, error);
+            }
+        }
+    }
+    println!("{} passed, {} failed", CASES.len() - failed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+#[test]
+fn every_example_prints_what_it_expects() {
+    for case in CASES {
+        assert_eq!(
+            run(case, false).as_deref(),
+            Ok(case.expected),
+            "{}",
+            case.file
+        );
+        // A divergence between the executor and the oracle fails the case as well
+        assert_eq!(
+            run(case, true).as_deref(),
+            Ok(case.expected),
+            "{}",
+            case.file
+        );
+    }
+}
@@ -0,0 +1,577 @@
+use crate::*;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+// Static checks over a parsed program, nothing here executes it.
+
+/// Names of the functions an instruction list CALLs directly.
+pub fn called_functions(body: &[Instruction]) -> Vec<&str> {
+    body.iter()
+        .filter_map(Instruction::called_function)
+        .collect()
+}
+
+/// Functions that can never run because no code reachable from the global code CALLs them,
+/// as (name, definition line) sorted by line.
+pub fn unreachable_functions(program: &Program) -> Vec<(&str, usize)> {
+    let mut reached: HashSet<&str> = HashSet::new();
+    let mut pending = called_functions(&program.instructions);
+    while let Some(name) = pending.pop() {
+        if !reached.insert(name) {
+            continue;
+        }
+        if let Some(function) = program.functions.get(name) {
+            pending.extend(called_functions(&function.body));
+        }
+    }
+
+    let mut unreachable: Vec<(&str, usize)> = program
+        .functions
+        .iter()
+        .filter(|(name, _)| !reached.contains(name.as_str()))
+        .map(|(name, function)| (name.as_str(), function.line))
+        .collect();
+    unreachable.sort_by_key(|&(name, line)| (line, name));
+    unreachable
+}
+
+// Name used for the global code in the call graph, it is always node 0
+pub const ENTRY_NODE: &str = "<global>";
+
+// Which code CALLs which function. Node 0 is the global code, the rest are the functions in
+// definition order. Edges map (caller, callee) to the number of CALL sites.
+pub struct CallGraph {
+    pub nodes: Vec<String>,
+    pub edges: BTreeMap<(usize, usize), usize>,
+}
+
+impl CallGraph {
+    pub fn new(program: &Program) -> CallGraph {
+        let mut functions: Vec<(&String, &Function)> = program.functions.iter().collect();
+        functions.sort_by_key(|&(name, function)| (function.line, name));
+
+        let mut nodes = vec![ENTRY_NODE.to_string()];
+        nodes.extend(functions.iter().map(|(name, _)| name.to_string()));
+        let bodies = std::iter::once(&program.instructions)
+            .chain(functions.iter().map(|(_, function)| &function.body));
+
+        let mut edges = BTreeMap::new();
+        for (caller, body) in bodies.enumerate() {
+            for name in called_functions(body) {
+                // Calls to undefined functions have no node to point at
+                if let Some(callee) = nodes[1..].iter().position(|node| node == name) {
+                    *edges.entry((caller, callee + 1)).or_insert(0) += 1;
+                }
+            }
+        }
+        CallGraph { nodes, edges }
+    }
+
+    fn successors(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        self.edges
+            .keys()
+            .filter(move |&&(caller, _)| caller == node)
+            .map(|&(_, callee)| callee)
+    }
+
+    /// Nodes reachable from the global code, including the global code itself.
+    pub fn reachable(&self) -> HashSet<usize> {
+        let mut reached = HashSet::new();
+        let mut pending = vec![0];
+        while let Some(node) = pending.pop() {
+            if reached.insert(node) {
+                pending.extend(self.successors(node));
+            }
+        }
+        reached
+    }
+
+    /// Strongly connected components, using Tarjan's algorithm.
+    pub fn strongly_connected_components(&self) -> Vec<Vec<usize>> {
+        struct Tarjan<'a> {
+            graph: &'a CallGraph,
+            index: Vec<Option<usize>>,
+            lowlink: Vec<usize>,
+            on_stack: Vec<bool>,
+            stack: Vec<usize>,
+            next_index: usize,
+            components: Vec<Vec<usize>>,
+        }
+
+        impl Tarjan<'_> {
+            fn visit(&mut self, node: usize) {
+                self.index[node] = Some(self.next_index);
+                self.lowlink[node] = self.next_index;
+                self.next_index += 1;
+                self.stack.push(node);
+                self.on_stack[node] = true;
+
+                let successors: Vec<usize> = self.graph.successors(node).collect();
+                for next in successors {
+                    match self.index[next] {
+                        None => {
+                            self.visit(next);
+                            self.lowlink[node] = self.lowlink[node].min(self.lowlink[next]);
+                        }
+                        Some(index) if self.on_stack[next] => {
+                            self.lowlink[node] = self.lowlink[node].min(index);
+                        }
+                        Some(_) => {}
+                    }
+                }
+
+                if Some(self.lowlink[node]) == self.index[node] {
+                    let mut component = Vec::new();
+                    while let Some(member) = self.stack.pop() {
+                        self.on_stack[member] = false;
+                        component.push(member);
+                        if member == node {
+                            break;
+                        }
+                    }
+                    component.sort();
+                    self.components.push(component);
+                }
+            }
+        }
+
+        let count = self.nodes.len();
+        let mut tarjan = Tarjan {
+            graph: self,
+            index: vec![None; count],
+            lowlink: vec![0; count],
+            on_stack: vec![false; count],
+            stack: Vec::new(),
+            next_index: 0,
+            components: Vec::new(),
+        };
+        for node in 0..count {
+            if tarjan.index[node].is_none() {
+                tarjan.visit(node);
+            }
+        }
+        tarjan.components
+    }
+
+    /// One cycle per recursive component, starting and ending at its first member,
+    /// e.g. [.a, .b, .a]. A function calling itself gives [.a, .a].
+    pub fn recursion_cycles(&self) -> Vec<Vec<usize>> {
+        let mut cycles: Vec<Vec<usize>> = self
+            .strongly_connected_components()
+            .into_iter()
+            .filter(|component| {
+                component.len() > 1 || self.edges.contains_key(&(component[0], component[0]))
+            })
+            .map(|component| self.cycle_through(&component))
+            .collect();
+        cycles.sort();
+        cycles
+    }
+
+    // Shortest path from the first member of a component back to itself, staying inside it
+    fn cycle_through(&self, component: &[usize]) -> Vec<usize> {
+        let start = component[0];
+        let mut previous: BTreeMap<usize, usize> = BTreeMap::new();
+        let mut queue = VecDeque::from([start]);
+        while let Some(node) = queue.pop_front() {
+            for next in self.successors(node) {
+                if next == start {
+                    // Walk back from the node that closes the cycle to the start
+                    let mut cycle = vec![node];
+                    let mut current = node;
+                    while current != start {
+                        current = previous[&current];
+                        cycle.push(current);
+                    }
+                    cycle.reverse();
+                    cycle.push(start);
+                    return cycle;
+                }
+                if component.contains(&next) && !previous.contains_key(&next) {
+                    previous.insert(next, node);
+                    queue.push_back(next);
+                }
+            }
+        }
+        vec![start, start]
+    }
+
+    pub fn format_cycle(&self, cycle: &[usize]) -> String {
+        cycle
+            .iter()
+            .map(|&node| self.nodes[node].as_str())
+            .collect::<Vec<_>>()
+            .join(" -> ")
+    }
+
+    /// Graphviz rendering: the global code is highlighted, recursion is drawn in red and
+    /// functions that can never run are greyed out.
+    pub fn to_dot(&self) -> String {
+        let reachable = self.reachable();
+        // Recursive nodes mapped to the cycle they are part of
+        let recursive: HashMap<usize, usize> = self
+            .recursion_cycles()
+            .into_iter()
+            .enumerate()
+            .flat_map(|(cycle, nodes)| nodes.into_iter().map(move |node| (node, cycle)))
+            .collect();
+
+        let mut out = String::from("digraph callgraph {\n");
+        for (node, name) in self.nodes.iter().enumerate() {
+            let style = if node == 0 {
+                "shape=box, style=filled, fillcolor=lightblue"
+            } else if !reachable.contains(&node) {
+                "color=grey, fontcolor=grey"
+            } else if recursive.contains_key(&node) {
+                "color=red, fontcolor=red"
+            } else {
+                "color=black"
+            };
+            out.push_str(&format!("    \"{}\" [{}];\n", name, style));
+        }
+        for (&(caller, callee), count) in &self.edges {
+            let same_cycle =
+                recursive.contains_key(&caller) && recursive.get(&caller) == recursive.get(&callee);
+            let color = if same_cycle {
+                ", color=red"
+            } else if !reachable.contains(&caller) {
+                ", color=grey"
+            } else {
+                ""
+            };
+            out.push_str(&format!(
+                "    \"{}\" -> \"{}\" [label=\"{}\"{}];\n",
+                self.nodes[caller], self.nodes[callee], count, color
+            ));
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+// Worst case stack use found by stack_usage
+#[derive(Debug, Clone, PartialEq)]
+pub enum StackBound {
+    Bounded {
+        call_depth: usize,  // Deepest nesting of CALLs
+        stack_depth: usize, // Most values on the stack at once, return addresses included
+    },
+    Unbounded {
+        cycle: String, // A recursion reachable from the global code, `.a -> .b -> .a`
+    },
+}
+
+impl CallGraph {
+    /// Worst case call depth and stack depth of running the program from its global code.
+    /// Bodies are read top to bottom without following jumps, so a PUSH in a loop counts once
+    /// and the stack depth is an estimate. Any reachable recursion makes both unbounded.
+    pub fn stack_usage(&self, program: &Program) -> StackBound {
+        let reachable = self.reachable();
+        if let Some(cycle) = self
+            .recursion_cycles()
+            .into_iter()
+            .find(|cycle| reachable.contains(&cycle[0]))
+        {
+            return StackBound::Unbounded {
+                cycle: self.format_cycle(&cycle),
+            };
+        }
+        let bodies: Vec<&[Instruction]> = std::iter::once(&program.instructions[..])
+            .chain(
+                self.nodes[1..]
+                    .iter()
+                    .map(|name| &program.functions[name].body[..]),
+            )
+            .collect();
+        // Without recursion the nodes can be done callees first, each one only once
+        let mut usage: HashMap<usize, (usize, usize)> = HashMap::new();
+        fn visit(
+            graph: &CallGraph,
+            node: usize,
+            bodies: &[&[Instruction]],
+            usage: &mut HashMap<usize, (usize, usize)>,
+        ) -> (usize, usize) {
+            if let Some(&known) = usage.get(&node) {
+                return known;
+            }
+            let (mut calls, mut deepest, mut depth) = (0, 0, 0usize);
+            for instruction in bodies[node] {
+                match instruction {
+                    Instruction::PUSH(_) => depth += 1,
+                    Instruction::POP(_) => depth = depth.saturating_sub(1),
+                    _ => {
+                        // Calls to undefined functions have no node and no cost
+                        let Some(callee) = instruction
+                            .called_function()
+                            .and_then(|name| graph.nodes.iter().position(|node| node == name))
+                        else {
+                            continue;
+                        };
+                        let (callee_calls, callee_depth) = visit(graph, callee, bodies, usage);
+                        calls = calls.max(callee_calls + 1);
+                        // The return address sits below everything the callee pushes
+                        deepest = deepest.max(depth + 1 + callee_depth);
+                    }
+                }
+                deepest = deepest.max(depth);
+            }
+            usage.insert(node, (calls, deepest));
+            (calls, deepest)
+        }
+        let (call_depth, stack_depth) = visit(self, 0, &bodies, &mut usage);
+        StackBound::Bounded {
+            call_depth,
+            stack_depth,
+        }
+    }
+}
+
+// A loop found by estimate_loops, the instructions from start up to the backward jump at end
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoopEstimate {
+    pub function: Option<String>, // None for global code
+    pub start: u16,
+    pub end: u16,
+    pub iterations: Option<u32>, // Times the body runs, None when no bound could be proven
+}
+
+/// Every backward jump of the program with a static estimate of how often its loop runs,
+/// global code first and then the functions by definition line. Only simple counting loops
+/// get a count: a MOV of an immediate into a counter right before the loop, one INC or DEC of
+/// it in the body and a CMP of it against an immediate right before the jump. Anything else,
+/// including loops that never end or fail on the way, is None.
+pub fn estimate_loops(program: &Program, signed: bool) -> Vec<LoopEstimate> {
+    let mut functions: Vec<(&String, &Function)> = program.functions.iter().collect();
+    functions.sort_by_key(|&(name, function)| (function.line, name));
+    let scopes = std::iter::once((None, &program.instructions[..])).chain(
+        functions
+            .into_iter()
+            .map(|(name, function)| (Some(name.clone()), &function.body[..])),
+    );
+    let mut loops = Vec::new();
+    for (function, body) in scopes {
+        for (end, instruction) in body.iter().enumerate() {
+            let target = match *instruction {
+                Instruction::JMP(target)
+                | Instruction::JE(target)
+                | Instruction::JNE(target)
+                | Instruction::JG(target)
+                | Instruction::JL(target) => target as usize,
+                _ => continue,
+            };
+            if target > end {
+                continue;
+            }
+            loops.push(LoopEstimate {
+                function: function.clone(),
+                start: target as u16,
+                end: end as u16,
+                iterations: count_iterations(body, target, end, signed),
+            });
+        }
+    }
+    loops
+}
+
+// Whether an instruction may change a register, erring on the side of yes
+fn writes_register(instruction: &Instruction, reg: u16) -> bool {
+    match *instruction {
+        Instruction::PRINT(_)
+        | Instruction::PRINTX(_)
+        | Instruction::PRINTB(_)
+        | Instruction::PRINTC(_)
+        | Instruction::PRINTS(_)
+        | Instruction::PRINTW(..)
+        | Instruction::PUSH(_)
+        | Instruction::STORE(..)
+        | Instruction::STORER(..)
+        | Instruction::CMP(..)
+        | Instruction::MEMCLR(..)
+        | Instruction::NOP => false,
+        Instruction::ADD(dst, _)
+        | Instruction::SUB(dst, _)
+        | Instruction::MUL(dst, _)
+        | Instruction::DIV(dst, _)
+        | Instruction::MOD(dst, _)
+        | Instruction::POW(dst, _)
+        | Instruction::ABSDIFF(dst, _)
+        | Instruction::AND(dst, _)
+        | Instruction::OR(dst, _)
+        | Instruction::XOR(dst, _)
+        | Instruction::SHL(dst, _)
+        | Instruction::SHR(dst, _)
+        | Instruction::RCL(dst, _)
+        | Instruction::RCR(dst, _)
+        | Instruction::MOVR(dst, _)
+        | Instruction::LOADR(dst, _)
+        | Instruction::POPCNTR(dst, ..) => dst == reg,
+        _ => instruction.registers().contains(&reg),
+    }
+}
+
+// How often the body from start to the jump at end runs, by following the counter
+fn count_iterations(body: &[Instruction], start: usize, end: usize, signed: bool) -> Option<u32> {
+    // The jump is whatever precedes it last, so the CMP has to come right before it
+    let (counter, limit) = match body[..end].last() {
+        Some(&Instruction::CMP(reg, Operand::Immediate(limit))) if end > start => (reg, limit),
+        _ => return None,
+    };
+    let mut value = match start.checked_sub(1).map(|index| &body[index]) {
+        Some(&Instruction::MOV(reg, value)) if reg == counter => value,
+        _ => return None,
+    };
+    // Any other way into the loop could start it with another count
+    let enters = body.iter().enumerate().any(|(index, instruction)| {
+        let target = match *instruction {
+            Instruction::JMP(target)
+            | Instruction::JE(target)
+            | Instruction::JNE(target)
+            | Instruction::JG(target)
+            | Instruction::JL(target) => target as usize,
+            _ => return false,
+        };
+        index != end && (start..=end).contains(&target)
+    });
+    let inner = &body[start..end - 1];
+    let leaves = inner.iter().any(|instruction| {
+        matches!(
+            instruction,
+            Instruction::JMP(_)
+                | Instruction::JE(_)
+                | Instruction::JNE(_)
+                | Instruction::JG(_)
+                | Instruction::JL(_)
+                | Instruction::RET
+                | Instruction::HALT
+                | Instruction::CUSTOM(..)
+        ) || instruction.called_function().is_some()
+    });
+    if enters || leaves {
+        return None;
+    }
+    let mut steps = inner
+        .iter()
+        .filter(|instruction| writes_register(instruction, counter));
+    let increment = match (steps.next(), steps.next()) {
+        (Some(&Instruction::INC(_)), None) => true,
+        (Some(&Instruction::DEC(_)), None) => false,
+        _ => return None,
+    };
+
+    // 65536 values of the counter, running more often than that is going around in circles
+    for iterations in 1..=65537u32 {
+        value = match (increment, signed) {
+            (true, _) => value.wrapping_add(1),
+            (false, true) => value.wrapping_sub(1),
+            (false, false) => value.checked_sub(1)?, // DEC below zero stops the program
+        };
+        let ordering = match signed {
+            true => (value as i16).cmp(&(limit as i16)),
+            false => value.cmp(&limit),
+        };
+        let taken = match body[end] {
+            Instruction::JMP(_) => true,
+            Instruction::JE(_) => ordering.is_eq(),
+            Instruction::JNE(_) => ordering.is_ne(),
+            Instruction::JG(_) => ordering.is_gt(),
+            _ => ordering.is_lt(),
+        };
+        if !taken {
+            return Some(iterations);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Program {
+        let config = Config {
+            debug: false,
+            ..Config::default()
+        };
+        parse_with_config(source.to_string(), &config).unwrap()
+    }
+
+    // .ping and .pong call each other, .again calls itself and nothing calls .lonely
+    const RECURSION: &str = "call .ping\ncall .again\nhalt\n.ping\ncall .pong\nret\n.end\n\
+                             .pong\ncall .ping\nret\n.end\n.again\ncall .again\nret\n.end\n\
+                             .lonely\nret\n.end\n";
+
+    // The global code calls .outer, which calls .inner, and .unused only calls itself
+    const NESTED: &str = "mov a, 1\ncall .outer\nhalt\n.outer\npush a\ncall .inner\npop a\nret\n\
+                          .end\n.inner\npush a\npush b\npop b\npop a\nret\n.end\n\
+                          .unused\ncall .unused\nret\n.end\n";
+
+    #[test]
+    fn a_function_nothing_calls_is_unreachable() {
+        assert_eq!(unreachable_functions(&parse(RECURSION)), [(".lonely", 16)]);
+        // Calling itself does not make a function reachable
+        assert_eq!(unreachable_functions(&parse(NESTED)), [(".unused", 17)]);
+    }
+
+    #[test]
+    fn direct_and_mutual_recursion_are_found() {
+        let graph = CallGraph::new(&parse(RECURSION));
+        let cycles: Vec<String> = graph
+            .recursion_cycles()
+            .iter()
+            .map(|cycle| graph.format_cycle(cycle))
+            .collect();
+        assert_eq!(cycles, [".ping -> .pong -> .ping", ".again -> .again"]);
+        assert!(!graph.reachable().contains(&4));
+    }
+
+    #[test]
+    fn stack_use_is_estimated_through_the_calls() {
+        let program = parse(NESTED);
+        let bound = CallGraph::new(&program).stack_usage(&program);
+        // Two return addresses, the PUSH in .outer and both in .inner
+        assert_eq!(
+            bound,
+            StackBound::Bounded {
+                call_depth: 2,
+                stack_depth: 5
+            }
+        );
+
+        let mut cpu = Cpu::new(Config {
+            debug: false,
+            ..Config::default()
+        });
+        cpu.set_output(Box::new(std::io::sink()));
+        cpu.load_program(&program);
+        cpu.run().unwrap();
+        assert_eq!((cpu.deepest_call, cpu.deepest_stack), (2, 5));
+    }
+
+    #[test]
+    fn reachable_recursion_makes_the_stack_unbounded() {
+        let program = parse(RECURSION);
+        assert_eq!(
+            CallGraph::new(&program).stack_usage(&program),
+            StackBound::Unbounded {
+                cycle: ".ping -> .pong -> .ping".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn a_counting_loop_gets_its_count_and_a_data_dependent_one_does_not() {
+        let counted = parse("mov c, 10\ntop: dec c\ncmp c, 0\njne top\nhalt\n");
+        let loops = estimate_loops(&counted, true);
+        assert_eq!(
+            loops,
+            [LoopEstimate {
+                function: None,
+                start: 1,
+                end: 3,
+                iterations: Some(10)
+            }]
+        );
+
+        let read = parse("in c\ntop: dec c\ncmp c, 0\njne top\nhalt\n");
+        assert_eq!(estimate_loops(&read, true)[0].iterations, None);
+    }
+}
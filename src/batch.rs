@@ -0,0 +1,114 @@
+use crate::*;
+use std::fs;
+
+// Runs several programs one after another, for checking a set of submissions or examples in one
+// go. Every program gets a Cpu of its own, so nothing one of them does carries over to the next.
+
+// How one program of a batch ended
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchResult {
+    pub file: String,
+    pub error: Option<String>, // Why the program could not be read, parsed or run
+}
+
+// An assembly file with its includes or an assembled binary, ready to run
+fn load_program_file(file: &String, config: &Config) -> Result<Program, String> {
+    let bytes = fs::read(file).map_err(|e| format!("Could not read '{}': {}.", file, e))?;
+    if is_binary(&bytes) {
+        return decode_program(&bytes).map_err(|e| e.to_string());
+    }
+    let (source, map) =
+        read_with_includes(file, SourceEncoding::Utf8).map_err(|e| e.to_string())?;
+    let mut program =
+        parse_with_includes(source.clone(), &map, config).map_err(|e| e.to_string())?;
+    program.positions = map.positions(&source);
+    Ok(program)
+}
+
+/// Runs the files in order, with prepare setting up each Cpu before its program is loaded.
+/// All of them run unless stop_on_first_error in config is set, then the results end with
+/// the first program that failed.
+pub fn run_batch(
+    files: &[String],
+    config: &Config,
+    mut prepare: impl FnMut(&mut Cpu),
+) -> Vec<BatchResult> {
+    let mut results = Vec::new();
+    for file in files {
+        let outcome = load_program_file(file, config).and_then(|program| {
+            let mut cpu = Cpu::new(config.clone());
+            prepare(&mut cpu);
+            cpu.load_program(&program);
+            cpu.run().map(|_| ()).map_err(|e| e.to_string())
+        });
+        let failed = outcome.is_err();
+        results.push(BatchResult {
+            file: file.clone(),
+            error: outcome.err(),
+        });
+        if failed && config.stop_on_first_error {
+            break;
+        }
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A program that runs, one that divides by zero and another one that runs
+    fn write_programs(test: &str) -> Vec<String> {
+        let directory = std::env::temp_dir().join(format!("alice-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        [
+            ("first.asm", "mov a, 1\nhalt\n"),
+            ("broken.asm", "mov a, 1\nmov b, 0\ndiv a, b\nhalt\n"),
+            ("last.asm", "mov a, 2\nhalt\n"),
+        ]
+        .iter()
+        .map(|(name, source)| {
+            let path = directory.join(name);
+            fs::write(&path, source).unwrap();
+            path.to_string_lossy().into_owned()
+        })
+        .collect()
+    }
+
+    fn failed(results: &[BatchResult]) -> Vec<bool> {
+        results
+            .iter()
+            .map(|result| result.error.is_some())
+            .collect()
+    }
+
+    #[test]
+    fn a_batch_runs_every_program_by_default() {
+        let files = write_programs("batch-all");
+        let config = Config {
+            debug: false,
+            ..Config::default()
+        };
+        let results = run_batch(&files, &config, |cpu| {
+            cpu.set_output(Box::new(std::io::sink()))
+        });
+        assert_eq!(failed(&results), [false, true, false]);
+        assert!(results[1].error.as_ref().unwrap().contains("[E112]"));
+    }
+
+    #[test]
+    fn stop_on_first_error_ends_the_batch_at_the_failing_program() {
+        let files = write_programs("batch-stop");
+        let config = Config {
+            debug: false,
+            stop_on_first_error: true,
+            ..Config::default()
+        };
+        let results = run_batch(&files, &config, |cpu| {
+            cpu.set_output(Box::new(std::io::sink()))
+        });
+        assert_eq!(failed(&results), [false, true]);
+        assert_eq!(results[1].file, files[1]);
+    }
+}
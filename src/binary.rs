@@ -0,0 +1,814 @@
+use crate::*;
+use std::collections::HashMap;
+use std::fmt;
+
+// Assembled programs are stored as (all numbers big-endian):
+//
+//   magic "ALIC" | version u16 | function count u16
+//   global code: instruction count u16 | instructions
+//   each function, in definition order:
+//     name length u16 | name (UTF-8) | definition line u32 | instruction count u16 | instructions
+//   labels of the global code: label count u16, then per label
+//     name length u16 | name (UTF-8) | instruction index u16
+//   data: cell count u16 | cells u16, loaded into memory from address 0
+//   optional debug section, left out of stripped binaries:
+//     magic "ADBG" | file count u16 | per file: name length u16 | name (UTF-8)
+//     then per instruction of the global code and of each function, in the order above:
+//     file index u16 | line u32, line 0 for an instruction without a position
+//   optional source section, kept by stripped binaries too:
+//     magic "ASRC" | fnv1a hash u64 of the source after includes are spliced in
+//
+// Every instruction is an opcode byte followed by two u16 operands, unused operands are 0.
+// The top bit of the opcode marks a second operand that is an immediate instead of a register.
+// CALL stores the index of the function in the table above instead of its name.
+// POPCNTR packs its first register and register count into the second operand, high byte first.
+
+pub const MAGIC: &[u8; 4] = b"ALIC";
+pub const VERSION: u16 = 5;
+pub const DEBUG_MAGIC: &[u8; 4] = b"ADBG";
+pub const SOURCE_MAGIC: &[u8; 4] = b"ASRC";
+
+#[derive(Debug, PartialEq)]
+pub enum EncodeError {
+    Unsupported(Instruction), // Registered CUSTOM instructions only exist in the running process
+    TooLarge(String),         // More functions or instructions than the format can count
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::Unsupported(instruction) => {
+                write!(f, "{:?} cannot be stored in a binary.", instruction)
+            }
+            EncodeError::TooLarge(what) => write!(f, "{} is too large for a binary.", what),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    UnknownOpcode { opcode: u8, offset: usize },
+    UnknownFunction { index: u16, offset: usize },
+    InvalidName { offset: usize },
+    UnknownFile { index: u16, offset: usize },
+    TrailingData { offset: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::BadMagic => write!(f, "Not a binary produced by this assembler."),
+            DecodeError::UnsupportedVersion(version) => write!(
+                f,
+                "Binary format version {} is not supported, expected version {}.",
+                version, VERSION
+            ),
+            DecodeError::Truncated => write!(f, "Binary is truncated."),
+            DecodeError::UnknownOpcode { opcode, offset } => {
+                write!(f, "Unknown opcode {:#x} at byte {}.", opcode, offset)
+            }
+            DecodeError::UnknownFunction { index, offset } => write!(
+                f,
+                "CALL to function {} at byte {} is not in the function table.",
+                index, offset
+            ),
+            DecodeError::InvalidName { offset } => {
+                write!(f, "Name at byte {} is not valid UTF-8.", offset)
+            }
+            DecodeError::UnknownFile { index, offset } => write!(
+                f,
+                "Debug info at byte {} names file {}, which is not in the file table.",
+                offset, index
+            ),
+            DecodeError::TrailingData { offset } => {
+                write!(f, "Unexpected data after the program at byte {}.", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Parses source with the default configuration and encodes it, for assembling a program ahead
+/// of time, in a build script for example. The bytes are what load turns back into the program.
+pub fn assemble(source: &str) -> Result<Vec<u8>, Vec<ParseError>> {
+    let program = parse_with_config(source.to_string(), &Config::default()).map_err(|e| vec![e])?;
+    encode_program(&program).map_err(|e| vec![ParseError::Unencodable(e)])
+}
+
+/// Decodes a program produced by assemble or encode_program, the same as decode_program.
+pub fn load(bytes: &[u8]) -> Result<Program, DecodeError> {
+    decode_program(bytes)
+}
+
+/// Whether some bytes look like an assembled program rather than assembly source.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// Functions sorted by definition line, which is the order of the function table
+fn function_table(program: &Program) -> Vec<(&String, &Function)> {
+    let mut functions: Vec<(&String, &Function)> = program.functions.iter().collect();
+    functions.sort_by_key(|&(name, function)| (function.line, name));
+    functions
+}
+
+// Set in the opcode byte when the second operand is an immediate rather than a register
+const IMMEDIATE_SOURCE: u8 = 0x80;
+
+fn with_operand(opcode: u8, dst: u16, src: Operand) -> (u8, u16, u16) {
+    match src {
+        Operand::Register(index) => (opcode, dst, index),
+        Operand::Immediate(value) => (opcode | IMMEDIATE_SOURCE, dst, value),
+    }
+}
+
+fn encode_instruction(
+    instruction: &Instruction,
+    function_index: &HashMap<&str, u16>,
+) -> Result<(u8, u16, u16), EncodeError> {
+    let encoded = match *instruction {
+        Instruction::ADD(dst, src) => with_operand(ADD_OPCODE, dst, src),
+        Instruction::MOV(dst, value) => (MOV_OPCODE, dst, value),
+        Instruction::MUL(dst, src) => with_operand(MUL_OPCODE, dst, src),
+        Instruction::SUB(dst, src) => with_operand(SUB_OPCODE, dst, src),
+        Instruction::SWAP(dst, src) => (SWAP_OPCODE, dst, src),
+        Instruction::DIV(dst, src) => with_operand(DIV_OPCODE, dst, src),
+        Instruction::MOD(dst, src) => with_operand(MOD_OPCODE, dst, src),
+        Instruction::CLR(src) => (CLR_OPCODE, src, 0),
+        Instruction::INC(src) => (INC_OPCODE, src, 0),
+        Instruction::DEC(src) => (DEC_OPCODE, src, 0),
+        Instruction::PRINT(src) => (PRINT_OPCODE, src, 0),
+        Instruction::PRINTX(src) => (PRINTX_OPCODE, src, 0),
+        Instruction::PRINTB(src) => (PRINTB_OPCODE, src, 0),
+        Instruction::PRINTC(src) => (PRINTC_OPCODE, src, 0),
+        Instruction::PRINTS(address) => (PRINTS_OPCODE, address, 0),
+        Instruction::PRINTW(reg, width) => (PRINTW_OPCODE, reg, width),
+        Instruction::POPCNTR(dest, start, count) => {
+            (POPCNTR_OPCODE, dest, (start & 0xff) << 8 | (count & 0xff))
+        }
+        Instruction::POW(dst, value) => with_operand(POW_OPCODE, dst, value),
+        Instruction::MOVR(dst, src) => (MOVR_OPCODE, dst, src),
+        Instruction::CMP(dst, src) => with_operand(CMP_OPCODE, dst, src),
+        Instruction::JMP(target) => (JMP_OPCODE, target, 0),
+        Instruction::JE(target) => (JE_OPCODE, target, 0),
+        Instruction::JNE(target) => (JNE_OPCODE, target, 0),
+        Instruction::JG(target) => (JG_OPCODE, target, 0),
+        Instruction::JL(target) => (JL_OPCODE, target, 0),
+        Instruction::CALL(ref name)
+        | Instruction::CALLE(ref name)
+        | Instruction::CALLNE(ref name) => {
+            let opcode = match instruction {
+                Instruction::CALLE(_) => CALLE_OPCODE,
+                Instruction::CALLNE(_) => CALLNE_OPCODE,
+                _ => CALL_OPCODE,
+            };
+            match function_index.get(name.as_str()) {
+                Some(&index) => (opcode, index, 0),
+                // The parser rejects these, so this only happens for hand-built programs
+                None => return Err(EncodeError::Unsupported(instruction.clone())),
+            }
+        }
+        Instruction::RET => (RET_OPCODE, 0, 0),
+        Instruction::CUSTOM(..) => return Err(EncodeError::Unsupported(instruction.clone())),
+        Instruction::LOAD(reg, address) => (LOAD_OPCODE, reg, address),
+        Instruction::STORE(reg, address) => (STORE_OPCODE, reg, address),
+        Instruction::LOADR(reg, address_reg) => (LOADR_OPCODE, reg, address_reg),
+        Instruction::STORER(reg, address_reg) => (STORER_OPCODE, reg, address_reg),
+        Instruction::LOADINC(reg, address_reg) => (LOADINC_OPCODE, reg, address_reg),
+        Instruction::PUSH(reg) => (PUSH_OPCODE, reg, 0),
+        Instruction::POP(reg) => (POP_OPCODE, reg, 0),
+        Instruction::IN(reg) => (IN_OPCODE, reg, 0),
+        Instruction::KEYIN(reg) => (KEYIN_OPCODE, reg, 0),
+        Instruction::GETF(reg) => (GETF_OPCODE, reg, 0),
+        Instruction::SETF(reg) => (SETF_OPCODE, reg, 0),
+        Instruction::FLAGSWAP(reg) => (FLAGSWAP_OPCODE, reg, 0),
+        Instruction::ABSDIFF(dst, src) => with_operand(ABSDIFF_OPCODE, dst, src),
+        Instruction::MEMCLR(start, count) => (MEMCLR_OPCODE, start, count),
+        Instruction::ISPOW2(src) => (ISPOW2_OPCODE, src, 0),
+        Instruction::NEXTPOW2(src) => (NEXTPOW2_OPCODE, src, 0),
+        Instruction::AND(dst, src) => with_operand(AND_OPCODE, dst, src),
+        Instruction::OR(dst, src) => with_operand(OR_OPCODE, dst, src),
+        Instruction::XOR(dst, src) => with_operand(XOR_OPCODE, dst, src),
+        Instruction::NOT(src) => (NOT_OPCODE, src, 0),
+        Instruction::SHL(dst, src) => with_operand(SHL_OPCODE, dst, src),
+        Instruction::SHR(dst, src) => with_operand(SHR_OPCODE, dst, src),
+        Instruction::RCL(dst, src) => with_operand(RCL_OPCODE, dst, src),
+        Instruction::RCR(dst, src) => with_operand(RCR_OPCODE, dst, src),
+        Instruction::HALT => (HALT_OPCODE, 0, 0),
+        Instruction::NOP => (NOP_OPCODE, 0, 0),
+    };
+    Ok(encoded)
+}
+
+fn decode_instruction(
+    opcode: u8,
+    a: u16,
+    b: u16,
+    function_names: &[String],
+) -> Option<Instruction> {
+    let source = if opcode & IMMEDIATE_SOURCE != 0 {
+        Operand::Immediate(b)
+    } else {
+        Operand::Register(b)
+    };
+    let base = opcode & !IMMEDIATE_SOURCE;
+    let takes_operand = matches!(
+        base,
+        ADD_OPCODE
+            | MUL_OPCODE
+            | SUB_OPCODE
+            | DIV_OPCODE
+            | MOD_OPCODE
+            | POW_OPCODE
+            | CMP_OPCODE
+            | ABSDIFF_OPCODE
+            | AND_OPCODE
+            | OR_OPCODE
+            | XOR_OPCODE
+            | SHL_OPCODE
+            | SHR_OPCODE
+            | RCL_OPCODE
+            | RCR_OPCODE
+    );
+    if opcode & IMMEDIATE_SOURCE != 0 && !takes_operand {
+        return None;
+    }
+    let instruction = match base {
+        ADD_OPCODE => Instruction::ADD(a, source),
+        MOV_OPCODE => Instruction::MOV(a, b),
+        MUL_OPCODE => Instruction::MUL(a, source),
+        SUB_OPCODE => Instruction::SUB(a, source),
+        SWAP_OPCODE => Instruction::SWAP(a, b),
+        DIV_OPCODE => Instruction::DIV(a, source),
+        MOD_OPCODE => Instruction::MOD(a, source),
+        CLR_OPCODE => Instruction::CLR(a),
+        INC_OPCODE => Instruction::INC(a),
+        DEC_OPCODE => Instruction::DEC(a),
+        PRINT_OPCODE => Instruction::PRINT(a),
+        PRINTX_OPCODE => Instruction::PRINTX(a),
+        PRINTB_OPCODE => Instruction::PRINTB(a),
+        PRINTC_OPCODE => Instruction::PRINTC(a),
+        PRINTS_OPCODE => Instruction::PRINTS(a),
+        PRINTW_OPCODE => Instruction::PRINTW(a, b),
+        POPCNTR_OPCODE => Instruction::POPCNTR(a, b >> 8, b & 0xff),
+        POW_OPCODE => Instruction::POW(a, source),
+        MOVR_OPCODE => Instruction::MOVR(a, b),
+        CMP_OPCODE => Instruction::CMP(a, source),
+        JMP_OPCODE => Instruction::JMP(a),
+        JE_OPCODE => Instruction::JE(a),
+        JNE_OPCODE => Instruction::JNE(a),
+        JG_OPCODE => Instruction::JG(a),
+        JL_OPCODE => Instruction::JL(a),
+        CALL_OPCODE => Instruction::CALL(function_names.get(a as usize)?.clone()),
+        CALLE_OPCODE => Instruction::CALLE(function_names.get(a as usize)?.clone()),
+        CALLNE_OPCODE => Instruction::CALLNE(function_names.get(a as usize)?.clone()),
+        RET_OPCODE => Instruction::RET,
+        LOAD_OPCODE => Instruction::LOAD(a, b),
+        STORE_OPCODE => Instruction::STORE(a, b),
+        LOADR_OPCODE => Instruction::LOADR(a, b),
+        STORER_OPCODE => Instruction::STORER(a, b),
+        LOADINC_OPCODE => Instruction::LOADINC(a, b),
+        PUSH_OPCODE => Instruction::PUSH(a),
+        POP_OPCODE => Instruction::POP(a),
+        IN_OPCODE => Instruction::IN(a),
+        KEYIN_OPCODE => Instruction::KEYIN(a),
+        GETF_OPCODE => Instruction::GETF(a),
+        SETF_OPCODE => Instruction::SETF(a),
+        FLAGSWAP_OPCODE => Instruction::FLAGSWAP(a),
+        ABSDIFF_OPCODE => Instruction::ABSDIFF(a, source),
+        MEMCLR_OPCODE => Instruction::MEMCLR(a, b),
+        ISPOW2_OPCODE => Instruction::ISPOW2(a),
+        NEXTPOW2_OPCODE => Instruction::NEXTPOW2(a),
+        AND_OPCODE => Instruction::AND(a, source),
+        OR_OPCODE => Instruction::OR(a, source),
+        XOR_OPCODE => Instruction::XOR(a, source),
+        NOT_OPCODE => Instruction::NOT(a),
+        SHL_OPCODE => Instruction::SHL(a, source),
+        SHR_OPCODE => Instruction::SHR(a, source),
+        RCL_OPCODE => Instruction::RCL(a, source),
+        RCR_OPCODE => Instruction::RCR(a, source),
+        HALT_OPCODE => Instruction::HALT,
+        NOP_OPCODE => Instruction::NOP,
+        _ => return None,
+    };
+    Some(instruction)
+}
+
+fn encode_body(
+    bytes: &mut Vec<u8>,
+    body: &[Instruction],
+    function_index: &HashMap<&str, u16>,
+) -> Result<(), EncodeError> {
+    let count = u16::try_from(body.len())
+        .map_err(|_| EncodeError::TooLarge(format!("{} instructions", body.len())))?;
+    bytes.extend_from_slice(&count.to_be_bytes());
+    for instruction in body {
+        let (opcode, a, b) = encode_instruction(instruction, function_index)?;
+        bytes.push(opcode);
+        bytes.extend_from_slice(&a.to_be_bytes());
+        bytes.extend_from_slice(&b.to_be_bytes());
+    }
+    Ok(())
+}
+
+/// Encodes a parsed program, including its functions, into the binary format.
+pub fn encode_program(program: &Program) -> Result<Vec<u8>, EncodeError> {
+    let functions = function_table(program);
+    let count = u16::try_from(functions.len())
+        .map_err(|_| EncodeError::TooLarge(format!("{} functions", functions.len())))?;
+    let function_index: HashMap<&str, u16> = functions
+        .iter()
+        .enumerate()
+        .map(|(index, (name, _))| (name.as_str(), index as u16))
+        .collect();
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_be_bytes());
+    bytes.extend_from_slice(&count.to_be_bytes());
+    encode_body(&mut bytes, &program.instructions, &function_index)?;
+    for (name, function) in functions {
+        encode_name(&mut bytes, name)?;
+        bytes.extend_from_slice(&(function.line as u32).to_be_bytes());
+        encode_body(&mut bytes, &function.body, &function_index)?;
+    }
+
+    let mut labels: Vec<(&String, &u16)> = program.labels.iter().collect();
+    labels.sort_by_key(|&(name, &index)| (index, name));
+    let label_count = u16::try_from(labels.len())
+        .map_err(|_| EncodeError::TooLarge(format!("{} labels", labels.len())))?;
+    bytes.extend_from_slice(&label_count.to_be_bytes());
+    for (name, index) in labels {
+        encode_name(&mut bytes, name)?;
+        bytes.extend_from_slice(&index.to_be_bytes());
+    }
+
+    let data_count = u16::try_from(program.data.len())
+        .map_err(|_| EncodeError::TooLarge(format!("{} data cells", program.data.len())))?;
+    bytes.extend_from_slice(&data_count.to_be_bytes());
+    for cell in &program.data {
+        bytes.extend_from_slice(&cell.to_be_bytes());
+    }
+    if !program.positions.is_empty() {
+        encode_positions(&mut bytes, program)?;
+    }
+    if let Some(hash) = program.source_hash {
+        bytes.extend_from_slice(SOURCE_MAGIC);
+        bytes.extend_from_slice(&hash.to_be_bytes());
+    }
+    Ok(bytes)
+}
+
+/// encode_program without the debug section, for a smaller binary whose runtime errors and
+/// traces only know instruction indices.
+pub fn encode_stripped(program: &Program) -> Result<Vec<u8>, EncodeError> {
+    encode_program(&Program {
+        positions: HashMap::new(),
+        ..program.clone()
+    })
+}
+
+// Scopes in the order of the binary, the global code first
+fn scopes(program: &Program) -> Vec<(Option<String>, usize)> {
+    let mut scopes = vec![(None, program.instructions.len())];
+    for (name, function) in function_table(program) {
+        scopes.push((Some(name.clone()), function.body.len()));
+    }
+    scopes
+}
+
+fn encode_positions(bytes: &mut Vec<u8>, program: &Program) -> Result<(), EncodeError> {
+    let mut files: Vec<&str> = program
+        .positions
+        .values()
+        .map(|(file, _)| file.as_str())
+        .collect();
+    files.sort();
+    files.dedup();
+    let file_count = u16::try_from(files.len())
+        .map_err(|_| EncodeError::TooLarge(format!("{} source files", files.len())))?;
+    bytes.extend_from_slice(DEBUG_MAGIC);
+    bytes.extend_from_slice(&file_count.to_be_bytes());
+    for file in &files {
+        encode_name(bytes, file)?;
+    }
+    for (scope, count) in scopes(program) {
+        for index in 0..count {
+            let (file, line) = match program.positions.get(&(scope.clone(), index as u16)) {
+                Some((file, line)) => (files.binary_search(&file.as_str()).unwrap_or(0), *line),
+                None => (0, 0),
+            };
+            let line =
+                u32::try_from(line).map_err(|_| EncodeError::TooLarge(format!("line {}", line)))?;
+            bytes.extend_from_slice(&(file as u16).to_be_bytes());
+            bytes.extend_from_slice(&line.to_be_bytes());
+        }
+    }
+    Ok(())
+}
+
+fn encode_name(bytes: &mut Vec<u8>, name: &str) -> Result<(), EncodeError> {
+    let name_length =
+        u16::try_from(name.len()).map_err(|_| EncodeError::TooLarge(format!("name {}", name)))?;
+    bytes.extend_from_slice(&name_length.to_be_bytes());
+    bytes.extend_from_slice(name.as_bytes());
+    Ok(())
+}
+
+// Reads the binary front to back, every read fails with Truncated past the end
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8], DecodeError> {
+        let end = self
+            .offset
+            .checked_add(count)
+            .ok_or(DecodeError::Truncated)?;
+        let taken = self
+            .bytes
+            .get(self.offset..end)
+            .ok_or(DecodeError::Truncated)?;
+        self.offset = end;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_be_bytes(bytes.try_into().expect("took 8 bytes")))
+    }
+
+    fn name(&mut self) -> Result<String, DecodeError> {
+        let length = self.u16()? as usize;
+        let offset = self.offset;
+        String::from_utf8(self.take(length)?.to_vec())
+            .map_err(|_| DecodeError::InvalidName { offset })
+    }
+
+    // Instructions are decoded after the whole function table is read, so CALLs can be checked
+    fn raw_body(&mut self) -> Result<Vec<(usize, u8, u16, u16)>, DecodeError> {
+        let count = self.u16()?;
+        (0..count)
+            .map(|_| Ok((self.offset, self.u8()?, self.u16()?, self.u16()?)))
+            .collect()
+    }
+}
+
+fn decode_body(
+    raw: Vec<(usize, u8, u16, u16)>,
+    function_names: &[String],
+) -> Result<Vec<Instruction>, DecodeError> {
+    raw.into_iter()
+        .map(|(offset, opcode, a, b)| {
+            let call = [CALL_OPCODE, CALLE_OPCODE, CALLNE_OPCODE].contains(&opcode);
+            decode_instruction(opcode, a, b, function_names).ok_or(if call {
+                DecodeError::UnknownFunction { index: a, offset }
+            } else {
+                DecodeError::UnknownOpcode { opcode, offset }
+            })
+        })
+        .collect()
+}
+
+/// Decodes a binary produced by encode_program back into the program it was made from.
+pub fn decode_program(bytes: &[u8]) -> Result<Program, DecodeError> {
+    let mut reader = Reader { bytes, offset: 0 };
+    if reader
+        .take(MAGIC.len())
+        .map_err(|_| DecodeError::BadMagic)?
+        != MAGIC
+    {
+        return Err(DecodeError::BadMagic);
+    }
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let function_count = reader.u16()?;
+    let global = reader.raw_body()?;
+
+    let mut functions = Vec::new();
+    for _ in 0..function_count {
+        let name = reader.name()?;
+        let line = reader.u32()? as usize;
+        functions.push((name, line, reader.raw_body()?));
+    }
+    let mut labels = HashMap::new();
+    for _ in 0..reader.u16()? {
+        let name = reader.name()?;
+        labels.insert(name, reader.u16()?);
+    }
+    let data = (0..reader.u16()?)
+        .map(|_| reader.u16())
+        .collect::<Result<Vec<u16>, DecodeError>>()?;
+    let debug = bytes[reader.offset..].starts_with(DEBUG_MAGIC);
+    let files = match debug {
+        true => {
+            reader.take(DEBUG_MAGIC.len())?;
+            (0..reader.u16()?)
+                .map(|_| reader.name())
+                .collect::<Result<Vec<String>, DecodeError>>()?
+        }
+        false => Vec::new(),
+    };
+
+    let function_names: Vec<String> = functions.iter().map(|(name, _, _)| name.clone()).collect();
+    let mut program = Program {
+        instructions: decode_body(global, &function_names)?,
+        functions: HashMap::new(),
+        labels,
+        data,
+        annotations: HashMap::new(),
+        positions: HashMap::new(),
+        source_hash: None,
+    };
+    for (name, line, raw) in functions {
+        let body = decode_body(raw, &function_names)?;
+        program.functions.insert(name, Function { body, line });
+    }
+    if debug {
+        for (scope, count) in scopes(&program) {
+            for index in 0..count {
+                let offset = reader.offset;
+                let (file, line) = (reader.u16()?, reader.u32()? as usize);
+                if line == 0 {
+                    continue;
+                }
+                let name = files.get(file as usize).ok_or(DecodeError::UnknownFile {
+                    index: file,
+                    offset,
+                })?;
+                program
+                    .positions
+                    .insert((scope.clone(), index as u16), (name.clone(), line));
+            }
+        }
+    }
+    if bytes[reader.offset..].starts_with(SOURCE_MAGIC) {
+        reader.take(SOURCE_MAGIC.len())?;
+        program.source_hash = Some(reader.u64()?);
+    }
+    if reader.offset != bytes.len() {
+        return Err(DecodeError::TrailingData {
+            offset: reader.offset,
+        });
+    }
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Operand::{Immediate, Register};
+
+    // Uses every opcode, both kinds of source operand and the largest operand values
+    fn every_instruction() -> Vec<Instruction> {
+        vec![
+            Instruction::HALT,
+            Instruction::ADD(0, Register(63)),
+            Instruction::ADD(1, Immediate(u16::MAX)),
+            Instruction::MOV(2, 0xbeef),
+            Instruction::MUL(3, Immediate(7)),
+            Instruction::SUB(4, Register(5)),
+            Instruction::SWAP(5, 6),
+            Instruction::DIV(6, Immediate(2)),
+            Instruction::MOD(7, Register(8)),
+            Instruction::CLR(8),
+            Instruction::INC(9),
+            Instruction::DEC(10),
+            Instruction::PRINT(11),
+            Instruction::PRINTX(12),
+            Instruction::PRINTB(13),
+            Instruction::PRINTC(14),
+            Instruction::PRINTS(0x100),
+            Instruction::PRINTW(15, 8),
+            Instruction::POW(16, Immediate(3)),
+            Instruction::MOVR(17, 18),
+            Instruction::CMP(18, Immediate(0)),
+            Instruction::JMP(0),
+            Instruction::JE(1),
+            Instruction::JNE(2),
+            Instruction::JG(3),
+            Instruction::JL(4),
+            Instruction::CALL(".double".to_string()),
+            Instruction::CALLE(".double".to_string()),
+            Instruction::CALLNE(".twice".to_string()),
+            Instruction::RET,
+            Instruction::LOAD(19, 0x200),
+            Instruction::STORE(20, 0x201),
+            Instruction::LOADR(21, 22),
+            Instruction::STORER(22, 23),
+            Instruction::LOADINC(23, 24),
+            Instruction::PUSH(24),
+            Instruction::POP(25),
+            Instruction::IN(26),
+            Instruction::KEYIN(27),
+            Instruction::GETF(28),
+            Instruction::SETF(29),
+            Instruction::FLAGSWAP(30),
+            Instruction::ABSDIFF(31, Register(32)),
+            Instruction::MEMCLR(0x300, 16),
+            Instruction::ISPOW2(33),
+            Instruction::NEXTPOW2(34),
+            Instruction::AND(35, Immediate(0xff)),
+            Instruction::OR(36, Register(37)),
+            Instruction::XOR(37, Immediate(1)),
+            Instruction::NOT(38),
+            Instruction::POPCNTR(39, 40, 3),
+            Instruction::SHL(40, Immediate(15)),
+            Instruction::SHR(41, Register(42)),
+            Instruction::RCL(42, Immediate(1)),
+            Instruction::RCR(43, Register(44)),
+            Instruction::NOP,
+        ]
+    }
+
+    fn program() -> Program {
+        let function = |body| Function { body, line: 9 };
+        Program {
+            instructions: every_instruction(),
+            functions: HashMap::from([
+                (
+                    ".double".to_string(),
+                    function(vec![Instruction::ADD(0, Register(0)), Instruction::RET]),
+                ),
+                (".twice".to_string(), function(vec![Instruction::RET])),
+            ]),
+            labels: HashMap::from([("start".to_string(), 0), ("end".to_string(), 3)]),
+            data: vec![b'H' as u16, b'i' as u16, 0],
+            annotations: HashMap::new(),
+            positions: HashMap::from([
+                ((None, 0), ("main.asm".to_string(), 1)),
+                ((None, 1), ("lib.asm".to_string(), 40)),
+                (
+                    (Some(".double".to_string()), 1),
+                    ("main.asm".to_string(), 11),
+                ),
+            ]),
+            source_hash: Some(0x0123_4567_89ab_cdef),
+        }
+    }
+
+    #[test]
+    fn every_instruction_survives_a_round_trip() {
+        let function_index = HashMap::from([(".double", 0), (".twice", 1)]);
+        let opcodes: Vec<u8> = every_instruction()
+            .iter()
+            .map(|instruction| encode_instruction(instruction, &function_index).unwrap().0)
+            .map(|opcode| opcode & !IMMEDIATE_SOURCE)
+            .collect();
+        for info in INSTRUCTION_SET {
+            assert!(opcodes.contains(&info.opcode), "{}", info.mnemonic);
+        }
+        let program = program();
+        let bytes = encode_program(&program).unwrap();
+        assert!(is_binary(&bytes));
+        assert_eq!(decode_program(&bytes), Ok(program));
+    }
+
+    #[test]
+    fn stripped_binaries_keep_everything_but_positions() {
+        let program = program();
+        let stripped = encode_stripped(&program).unwrap();
+        assert!(stripped.len() < encode_program(&program).unwrap().len());
+        assert_eq!(
+            decode_program(&stripped),
+            Ok(Program {
+                positions: HashMap::new(),
+                ..program
+            })
+        );
+    }
+
+    #[test]
+    fn assembled_source_loads_as_the_parsed_program() {
+        let source = ".ascii HI \"Hi\\n\"\nstart: call .double\nprints HI\nhalt\n\
+                      .double\nadd ax, ax\nret\n.end\n";
+        let parsed = parse_with_config(source.to_string(), &Config::default()).unwrap();
+        let loaded = load(&assemble(source).unwrap()).unwrap();
+        assert_eq!(loaded.instructions, parsed.instructions);
+        assert_eq!(loaded.functions, parsed.functions);
+        assert_eq!(loaded.labels, parsed.labels);
+        assert_eq!(loaded.data, parsed.data);
+        assert_eq!(loaded.source_hash, Some(fnv1a(source.as_bytes())));
+    }
+
+    #[test]
+    fn custom_instructions_cannot_be_encoded() {
+        let custom = Instruction::CUSTOM("TRIPLE".to_string(), vec![0]);
+        let program = Program {
+            instructions: vec![custom.clone()],
+            ..Program::default()
+        };
+        assert_eq!(
+            encode_program(&program),
+            Err(EncodeError::Unsupported(custom))
+        );
+    }
+
+    #[test]
+    fn damaged_binaries_are_rejected() {
+        let bytes = encode_program(&program()).unwrap();
+        assert_eq!(decode_program(b"ELF\x7f"), Err(DecodeError::BadMagic));
+        assert_eq!(decode_program(&bytes[..2]), Err(DecodeError::BadMagic));
+
+        let mut old = bytes.clone();
+        old[4..6].copy_from_slice(&4u16.to_be_bytes());
+        assert_eq!(
+            decode_program(&old),
+            Err(DecodeError::UnsupportedVersion(4))
+        );
+
+        assert_eq!(
+            decode_program(&bytes[..bytes.len() - 1]),
+            Err(DecodeError::Truncated)
+        );
+
+        let mut unknown = bytes.clone();
+        unknown[10] = 0x7f; // Opcode of the first instruction, after the header and its count
+        assert_eq!(
+            decode_program(&unknown),
+            Err(DecodeError::UnknownOpcode {
+                opcode: 0x7f,
+                offset: 10
+            })
+        );
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            decode_program(&trailing),
+            Err(DecodeError::TrailingData {
+                offset: bytes.len()
+            })
+        );
+    }
+
+    // Where a program stops with a runtime error, as (function, index) and its source position
+    fn failure(program: &Program) -> (Option<String>, u16, Option<(String, usize)>) {
+        let mut cpu = Cpu::new(Config {
+            debug: false,
+            ..Config::default()
+        });
+        cpu.set_output(Box::new(std::io::sink()));
+        cpu.load_program(program);
+        let error = cpu.run().unwrap_err();
+        let position = cpu
+            .positions
+            .get(&(error.function.clone(), error.index))
+            .cloned();
+        (error.function, error.index, position)
+    }
+
+    #[test]
+    fn a_runtime_error_in_a_binary_has_the_line_of_the_source() {
+        let directory =
+            std::env::temp_dir().join(format!("alice-binary-lines-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("div.asm");
+        std::fs::write(
+            &path,
+            "mov a, 6\ncall .divide\nhalt\n\n.divide\nmov b, 0\ndiv a, b\nret\n.end\n",
+        )
+        .unwrap();
+        let path = path.to_string_lossy().into_owned();
+        let (source, map) = read_with_includes(&path, SourceEncoding::Utf8).unwrap();
+        let config = Config {
+            debug: false,
+            ..Config::default()
+        };
+        let mut program = parse_with_includes(source.clone(), &map, &config).unwrap();
+        program.positions = map.positions(&source);
+        let _ = std::fs::remove_dir_all(&directory);
+
+        let from_source = failure(&program);
+        let divide = Some(".divide".to_string());
+        assert_eq!(from_source, (divide.clone(), 1, Some((path, 7))));
+        let binary = decode_program(&encode_program(&program).unwrap()).unwrap();
+        assert_eq!(failure(&binary), from_source);
+        // A stripped binary only knows the index
+        let stripped = decode_program(&encode_stripped(&program).unwrap()).unwrap();
+        assert_eq!(failure(&stripped), (divide, 1, None));
+    }
+}
@@ -1,16 +1,150 @@
-use serde::Deserialize;
-
-#[derive(Debug, Deserialize)]
-pub struct Config {
-    pub debug: bool,
-    pub verbose_debug: bool,
-}
-
-impl Default for Config {
-    fn default() -> Self {
-        Config {
-            debug: true,
-            verbose_debug: false,
-        }
-    }
-}
+use crate::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub debug: bool,
+    pub verbose_debug: bool,
+    #[serde(default)]
+    pub verbosity: u8,
+    #[serde(default)]
+    pub warn_unreachable: bool, // Warn about functions no reachable code ever CALLs
+    #[serde(default = "default_max_call_depth")]
+    pub max_call_depth: usize, // Nested CALLs allowed before the program is stopped
+    #[serde(default = "default_max_stack_depth")]
+    pub max_stack_depth: usize, // Values allowed on the stack, CALL return addresses included
+    #[serde(default = "default_memory_size")]
+    pub memory_size: usize, // Cells of data memory, addresses are 16 bits so at most 65536
+    #[serde(default)]
+    pub memory_image: Option<String>, // File loaded into memory before the program runs
+    #[serde(default)]
+    pub memory_image_offset: usize, // Address the first cell of memory_image goes to
+    #[serde(default)]
+    pub trace_filter: Vec<String>, // Mnemonics the instruction trace is limited to, empty traces all
+    #[serde(default)]
+    pub pedantic: bool, // Run the lints in lint.rs
+    #[serde(default)]
+    pub strict: bool, // Lint warnings are errors
+    #[serde(default)]
+    pub legacy_operands: bool, // Operands of HALT, NOP and RET are dropped and bare PRINT prints a without a warning, as before
+    #[serde(default = "default_history_size")]
+    pub history_size: usize, // Instructions listed when a runtime error stops the program, 0 for none
+    #[serde(default)]
+    pub show_final_state: bool, // Print the registers that are not 0 once the program halts normally
+    #[serde(default)]
+    pub group_binary: bool, // PRINTB separates the bits in groups of four, 0b0000_0000_0010_1010
+    #[serde(default = "default_signed_mode")]
+    pub signed_mode: bool, // Registers hold signed 16 bit values, SUB and DEC may go below zero
+    #[serde(default)]
+    pub zero_register: bool, // Register 0 always reads as 0 and writes to it are dropped, like MIPS $zero
+    #[serde(default = "default_max_instructions")]
+    pub max_instructions: u64, // Instructions a run may execute before it is stopped, 0 for no limit
+    #[serde(default)]
+    pub end_of_code: EndOfCode, // What happens when a function or the global code runs out of instructions
+    #[serde(default)]
+    pub operand_defaults: HashMap<String, u16>, // Source immediate per mnemonic when it is left out
+    #[serde(default)]
+    pub stop_on_first_error: bool, // A batch run stops at the first program that fails instead of running them all
+}
+
+// What running past the last instruction of a function or of the global code does
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EndOfCode {
+    #[default]
+    Implicit, // A function returns as if it ended with RET, global code halts as if it ended with HALT
+    Error, // Both stop the program with an error, every path has to end in RET or HALT
+}
+
+fn default_memory_size() -> usize {
+    65536
+}
+
+fn default_max_call_depth() -> usize {
+    1024
+}
+
+fn default_max_stack_depth() -> usize {
+    4096
+}
+
+fn default_history_size() -> usize {
+    8
+}
+
+fn default_signed_mode() -> bool {
+    true
+}
+
+fn default_max_instructions() -> u64 {
+    1_000_000
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            debug: true,
+            verbose_debug: false,
+            verbosity: 0,
+            warn_unreachable: false,
+            max_call_depth: default_max_call_depth(),
+            max_stack_depth: default_max_stack_depth(),
+            memory_size: default_memory_size(),
+            memory_image: None,
+            memory_image_offset: 0,
+            trace_filter: Vec::new(),
+            pedantic: false,
+            strict: false,
+            legacy_operands: false,
+            history_size: default_history_size(),
+            show_final_state: false,
+            group_binary: false,
+            signed_mode: default_signed_mode(),
+            zero_register: false,
+            max_instructions: default_max_instructions(),
+            end_of_code: EndOfCode::default(),
+            operand_defaults: HashMap::new(),
+            stop_on_first_error: false,
+        }
+    }
+}
+
+impl Config {
+    // 0 prints nothing extra, 1 is debug, 3 also dumps the parser's internals.
+    // verbose_debug is kept as a shorthand for level 3.
+    pub fn verbosity(&self) -> u8 {
+        let implied = if self.verbose_debug {
+            3
+        } else if self.debug {
+            1
+        } else {
+            0
+        };
+        self.verbosity.max(implied)
+    }
+
+    // Whether an executed instruction shows up in the trace, matched case-insensitively
+    pub fn traces(&self, instruction: &Instruction) -> bool {
+        passes_trace_filter(&self.trace_filter, instruction)
+    }
+}
+
+// Reads config.toml from the working directory, falling back to the defaults
+pub fn declare_config() -> Config {
+    let config_content = match fs::read_to_string("config.toml") {
+        Ok(content) => content,
+        Err(_) => {
+            return Config::default();
+        }
+    };
+
+    match toml::de::from_str::<Config>(&config_content) {
+        Ok(config) => config,
+        Err(_) => {
+            println!("config.toml parsing failed. defaulting.");
+            Config::default() // return default config if parsing fails
+        }
+    }
+}
@@ -1,205 +1,1655 @@
 use crate::*;
-use std::fs::File;
-use std::io::{self, Read, Write};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::rc::Rc;
+
+// Registers a program can use, r0 to r63. The first 26 can also be named a to z.
+pub const REGISTER_COUNT: usize = 64;
+
+// Times IN asks again for a number under the retry policy
+pub const INPUT_RETRIES: usize = 3;
+
+// Result of the last CMP, consumed by the conditional jumps.
+// Only CMP writes these, so they hold until the next CMP.
+// input_error is only written by IN, it is set when the last IN read something that was not a number.
+// overflow is written by the arithmetic instructions, it is set when the last one wrapped around.
+// carry is written by RCL and RCR, it holds the bit the last rotation moved out of the register.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Flags {
+    pub zero: bool,
+    pub less: bool,
+    pub greater: bool,
+    pub input_error: bool,
+    pub overflow: bool,
+    pub carry: bool,
+}
+
+thread_local! {
+    // The instruction a Cpu on this thread started last, see executing_instruction
+    static EXECUTING: RefCell<Option<(Option<String>, u16)>> = const { RefCell::new(None) };
+}
+
+// Function and index of the instruction a Cpu on this thread started last, None before any did.
+// Lets a panic hook tell where the emulator was, it cannot reach the Cpu that panicked.
+pub fn executing_instruction() -> Option<(Option<String>, u16)> {
+    EXECUTING.with(|executing| executing.borrow().clone())
+}
+
+// Bit layout used by GETF, SETF and FLAGSWAP
+pub const ZERO_FLAG_BIT: u16 = 1 << 0;
+pub const LESS_FLAG_BIT: u16 = 1 << 1;
+pub const GREATER_FLAG_BIT: u16 = 1 << 2;
+pub const INPUT_ERROR_FLAG_BIT: u16 = 1 << 3;
+pub const OVERFLOW_FLAG_BIT: u16 = 1 << 4;
+pub const CARRY_FLAG_BIT: u16 = 1 << 5;
+
+impl Flags {
+    pub fn to_bits(self) -> u16 {
+        [
+            (self.zero, ZERO_FLAG_BIT),
+            (self.less, LESS_FLAG_BIT),
+            (self.greater, GREATER_FLAG_BIT),
+            (self.input_error, INPUT_ERROR_FLAG_BIT),
+            (self.overflow, OVERFLOW_FLAG_BIT),
+            (self.carry, CARRY_FLAG_BIT),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |bits, (_, bit)| bits | bit)
+    }
+
+    pub fn from_bits(bits: u16) -> Flags {
+        Flags {
+            zero: bits & ZERO_FLAG_BIT != 0,
+            less: bits & LESS_FLAG_BIT != 0,
+            greater: bits & GREATER_FLAG_BIT != 0,
+            input_error: bits & INPUT_ERROR_FLAG_BIT != 0,
+            overflow: bits & OVERFLOW_FLAG_BIT != 0,
+            carry: bits & CARRY_FLAG_BIT != 0,
+        }
+    }
+}
+
+// What IN does when it reads something that is not a number
+#[derive(Debug, Default, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum InputErrorPolicy {
+    #[default]
+    Abort, // Stop the program
+    Retry, // Ask again, only when reading from a terminal. Otherwise the same as abort
+    Flag,  // Store 0 and set the input_error flag
+}
+
+// Where to resume after a RET: the caller's function (None for global code) and program counter.
+// The return address is also pushed on the data stack, sp is the stack pointer just after that
+// push, so a function that leaves values on the stack (or pops too many) is caught at RET.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub function: Option<String>,
+    pub return_pc: u16,
+    pub sp: usize,
+}
+
+// What a single step did, see Cpu::step
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepOutcome {
+    Continue, // Moved on to the next instruction
+    Jumped,   // Control moved somewhere else, a taken jump, CALL or RET
+    Halted,   // The program is no longer running
+}
+
+// Why run_until_breakpoint returned
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunOutcome {
+    Breakpoint, // The next instruction has a breakpoint, it has not run yet
+    Halted,     // The program is no longer running
+}
+
+// An instruction index in a function, None for global code
+pub type Breakpoint = (Option<String>, u16);
 
 // CPU struct
-pub struct CPU {
-    pub registers: [u16; 16],
-    pub memory: [u16; MEMORY_SIZE],
-    pub pc: u16, // Change to u16
+pub struct Cpu {
+    pub registers: [u16; REGISTER_COUNT],
+    pub program: Vec<Instruction>,
+    pub memory: Vec<u16>, // Data memory used by LOAD, STORE and the stack
+    pub sp: usize,        // Stack pointer, the stack grows down from the end of memory
+    pub functions: HashMap<String, Vec<Instruction>>,
+    pub labels: HashMap<String, u16>, // Labels of the global code
+    pub annotations: HashMap<(Option<String>, u16), String>, // See Program::annotations
+    pub positions: HashMap<(Option<String>, u16), (String, usize)>, // See Program::positions
+    pub current_function: Option<String>, // Function being executed, None for global code
+    pub call_stack: Vec<Frame>,
+    pub max_call_depth: usize, // Deepest the call stack may grow before CALL fails
+    pub max_stack_depth: usize, // Most values PUSH and CALL may put on the stack
+    pub pc: u16,
     pub running: bool,
-    pub zflag: bool,
+    pub flags: Flags,
+    pub input_errors: InputErrorPolicy,
+    pub signed: bool, // Registers hold two's complement values, see signed_mode in config.rs
+    pub zero_register: bool, // Register 0 is hardwired to 0, see zero_register in config.rs
+    pub history: VecDeque<(Option<String>, u16, Instruction)>, // Last instructions run, oldest first
+    pub history_size: usize,
+    pub show_final_state: bool, // Print final_state_table once the program halts normally
+    pub group_binary: bool,     // PRINTB groups the bits by four, see group_binary in config.rs
+    pub output: Box<dyn Write>, // Where PRINT writes, stdout unless set_output changed it
+    pub input: Option<Box<dyn BufRead>>, // Where IN reads from, stdin when None
+    pub keyboard: Option<Box<dyn Keyboard>>, // Where KEYIN reads keys from, always 0 when None
+    pub max_instructions: u64, // Instructions a run may execute before it is stopped, 0 for no limit
+    pub instructions_executed: u64,
+    pub tracers: Vec<Box<dyn Tracer>>, // Told about every executed instruction, see trace.rs
+    pub breakpoints: HashSet<Breakpoint>, // Where run_until_breakpoint stops
+    pub deepest_call: usize, // High-water mark of the call stack since the program was loaded
+    pub deepest_stack: usize, // High-water mark of the data stack, return addresses included
+    #[cfg(feature = "fault-injection")]
+    pub faults: Vec<Fault>, // Injected faults waiting for an instruction to apply to
+    config: Config,
 }
 
-impl CPU {
-    pub fn new() -> CPU {
-        CPU {
-            registers: [0; 16],
-            memory: [0; MEMORY_SIZE],
+impl Cpu {
+    pub fn new(config: Config) -> Cpu {
+        Cpu {
+            registers: [0; REGISTER_COUNT],
+            program: Vec::new(),
+            memory: vec![0; config.memory_size],
+            sp: config.memory_size,
+            functions: HashMap::new(),
+            labels: HashMap::new(),
+            annotations: HashMap::new(),
+            positions: HashMap::new(),
+            current_function: None,
+            call_stack: Vec::new(),
+            max_call_depth: config.max_call_depth,
+            max_stack_depth: config.max_stack_depth,
             pc: 0,
             running: false,
-            zflag: false,
+            flags: Flags::default(),
+            input_errors: InputErrorPolicy::default(),
+            signed: config.signed_mode,
+            zero_register: config.zero_register,
+            history: VecDeque::new(),
+            history_size: config.history_size,
+            show_final_state: config.show_final_state,
+            group_binary: config.group_binary,
+            output: Box::new(io::stdout()),
+            input: None,
+            keyboard: None,
+            max_instructions: config.max_instructions,
+            instructions_executed: 0,
+            tracers: Vec::new(),
+            breakpoints: HashSet::new(),
+            deepest_call: 0,
+            deepest_stack: 0,
+            #[cfg(feature = "fault-injection")]
+            faults: Vec::new(),
+            config,
         }
     }
 
-    pub fn load_program(&mut self, program: &[Instruction]) {
-        for (i, instruction) in program.iter().enumerate() {
-            if i < MEMORY_SIZE {
-                self.memory[i] = self.encode_instruction(instruction);
-            } else {
-                eprintln!(
-                    "{}",
-                    "Warning: Program exceeds memory size.".color(Colors::RedFg)
-                );
-                break;
-            }
+    // Sends PRINT output somewhere other than stdout, a file, a pipe or io::sink() to drop it
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    // Makes IN read lines from somewhere other than stdin, it never prompts then
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+        self.input = Some(input);
+    }
+
+    pub fn set_keyboard(&mut self, keyboard: Box<dyn Keyboard>) {
+        self.keyboard = Some(keyboard);
+    }
+
+    pub fn add_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracers.push(tracer);
+    }
+
+    pub fn load_program(&mut self, program: &Program) {
+        if program.instructions.len() > PROGRAM_SIZE {
+            eprintln!(
+                "{}",
+                "Warning: Program exceeds the maximum program size.".color(Colors::RedFg)
+            );
         }
-        let config = declare_config();
-        if config.verbose_debug {
-            println!("{:?}", self.memory);
+        self.program = program
+            .instructions
+            .iter()
+            .take(PROGRAM_SIZE)
+            .cloned()
+            .collect();
+        self.functions = program
+            .functions
+            .iter()
+            .map(|(name, function)| (name.clone(), function.body.clone()))
+            .collect();
+        self.labels = program.labels.clone();
+        self.annotations = program.annotations.clone();
+        self.positions = program.positions.clone();
+        let data = program.data.len().min(self.memory.len());
+        self.memory[..data].copy_from_slice(&program.data[..data]);
+        self.current_function = None;
+        self.call_stack.clear();
+        self.sp = self.memory.len();
+        self.pc = 0;
+        self.instructions_executed = 0;
+        self.deepest_call = 0;
+        self.deepest_stack = 0;
+        self.clear_zero_register();
+        self.running = true;
+        if self.config.verbose_debug {
+            println!("{:?}", self.program);
         }
     }
 
-    pub fn encode_instruction(&self, instruction: &Instruction) -> u16 {
-        match instruction {
-            Instruction::ADD(dst, src) => {
-                (ADD_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)
-            }
-            Instruction::MOV(dst, value) => {
-                (MOV_OPCODE << 12) | ((*dst) << 8 & 0xF00) | (*value & 0xFF)
-            }
-            Instruction::MUL(dst, src) => {
-                (MUL_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)
-            }
-            Instruction::SUB(dst, src) => {
-                (SUB_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)
-            }
-            Instruction::SWAP(dst, src) => {
-                (SWAP_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)
-            }
-            Instruction::DIV(dst, src) => {
-                (DIV_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)
+    pub fn fetch_instruction(&mut self) -> Option<Instruction> {
+        let config = &self.config;
+        let instruction = self.peek_instruction()?.clone();
+        self.pc += 1;
+
+        if config.verbose_debug && config.traces(&instruction) {
+            println!("Program Counter: {:?}", self.pc);
+            println!("Instruction: {:?}", instruction);
+        }
+        Some(instruction)
+    }
+
+    pub fn get_register(&self, index: u16) -> Option<u16> {
+        if index < self.registers.len() as u16 {
+            Some(self.registers[index as usize])
+        } else {
+            None
+        }
+    }
+
+    // Fills every register, and memory too if fuzz_memory is set, with random values from seed.
+    // A program that reads a register before writing it then misbehaves instead of seeing 0.
+    // Run it before load_program, which only writes the cells the program's data fills.
+    pub fn fuzz(&mut self, seed: u64, fuzz_memory: bool) {
+        let mut rng = Rng::new(seed);
+        for register in self.registers.iter_mut() {
+            *register = rng.next_u16();
+        }
+        self.clear_zero_register();
+        if fuzz_memory {
+            for cell in self.memory.iter_mut() {
+                *cell = rng.next_u16();
             }
-            Instruction::CLR(src) => (CLR_OPCODE << 12) | ((*src) << 4 & 0x0F0),
-            Instruction::INC(src) => (INC_OPCODE << 12) | ((*src) << 4 & 0x0F0),
-            Instruction::DEC(src) => (DEC_OPCODE << 12) | ((*src) << 4 & 0x0F0),
-            Instruction::PRINT(src) => (PRINT_OPCODE << 12) | ((*src) << 4 & 0x0F0),
-            Instruction::POW(dst, value) => {
-                (POW_OPCODE << 12) | ((*dst) << 8 & 0xF00) | (*value & 0xFF)
+        }
+    }
+
+    // Value of a register, panics if index is not below REGISTER_COUNT
+    pub fn register(&self, index: u16) -> u16 {
+        self.registers[index as usize]
+    }
+
+    pub fn set_register(&mut self, index: u16, value: u16) {
+        self.registers[index as usize] = value;
+        self.clear_zero_register();
+    }
+
+    // Undoes a write to register 0 when it is hardwired to 0. Instructions write it like any
+    // other register and this runs after each of them, so every read still sees 0.
+    fn clear_zero_register(&mut self) {
+        if self.zero_register {
+            self.registers[0] = 0;
+        }
+    }
+
+    // Value an operand stands for, immediates are used as they are
+    pub fn operand_value(&self, operand: Operand) -> u16 {
+        match operand {
+            Operand::Register(index) => self.registers[index as usize],
+            Operand::Immediate(value) => value,
+        }
+    }
+
+    pub fn print_register(&mut self, index: u16, format: PrintFormat) {
+        let line = match self.get_register(index) {
+            Some(value) => format!(
+                "{}: {}",
+                register_name(index as usize),
+                format_value(value, format, self.signed)
+            ),
+            None => format!("Register index {} is out of bounds.", index),
+        };
+        let _ = writeln!(self.output, "{}", line);
+    }
+
+    // Lists the last instructions that ran before a runtime error, the last one is the one that failed
+    pub fn print_history(&self) {
+        if self.history.is_empty() {
+            return;
+        }
+        eprintln!("{}", "Last instructions executed:".color(Colors::YellowFg));
+        for line in self.history_lines() {
+            eprintln!("{}", line);
+        }
+    }
+
+    // One line per instruction in history, with the file and line it came from when known
+    pub fn history_lines(&self) -> Vec<String> {
+        self.history
+            .iter()
+            .map(|(function, index, instruction)| {
+                let mut line = format!(
+                    "  [{} {}] {}",
+                    function.as_deref().unwrap_or("global"),
+                    index,
+                    instruction
+                );
+                if let Some((file, number)) = self.positions.get(&(function.clone(), *index)) {
+                    line.push_str(&format!(" at {}:{}", file, number));
+                }
+                line
+            })
+            .collect()
+    }
+
+    // The registers that are not 0 as a table, for show_final_state. color is off when the table
+    // goes somewhere other than a terminal or NO_COLOR is set.
+    pub fn final_state_table(&self, color: bool) -> String {
+        let header = "Final state:";
+        let mut table = match color {
+            true => header.color(Colors::YellowFg).to_string(),
+            false => header.to_string(),
+        };
+        let used: Vec<(usize, u16)> = (0..REGISTER_COUNT)
+            .map(|index| (index, self.registers[index]))
+            .filter(|&(_, value)| value != 0)
+            .collect();
+        if used.is_empty() {
+            table.push_str("\n  every register is 0");
+        }
+        for (index, value) in used {
+            let decimal = match self.signed {
+                true => (value as i16).to_string(),
+                false => value.to_string(),
+            };
+            let name = format!("{:<4}", register_name(index));
+            let name = match color {
+                true => name.color(Colors::CyanFg).to_string(),
+                false => name,
+            };
+            table.push_str(&format!("\n  {} {:>6}  {:#06x}", name, decimal, value));
+        }
+        table
+    }
+
+    pub fn call_function(&mut self, name: &str) -> Result<(), RuntimeErrorKind> {
+        if !self.functions.contains_key(name) {
+            return Err(RuntimeErrorKind::UndefinedFunction {
+                name: name.to_string(),
+            });
+        }
+        if self.call_stack.len() >= self.max_call_depth {
+            return Err(RuntimeErrorKind::CallDepthExceeded {
+                name: name.to_string(),
+                max_depth: self.max_call_depth,
+            });
+        }
+        let caller = self.current_function.clone();
+        self.enter_function(name, caller, self.pc)
+    }
+
+    fn enter_function(
+        &mut self,
+        name: &str,
+        caller: Option<String>,
+        return_pc: u16,
+    ) -> Result<(), RuntimeErrorKind> {
+        self.push(return_pc)?;
+        self.call_stack.push(Frame {
+            function: caller,
+            return_pc,
+            sp: self.sp,
+        });
+        self.deepest_call = self.deepest_call.max(self.call_stack.len());
+        self.current_function = Some(name.to_string());
+        self.pc = 0;
+        Ok(())
+    }
+
+    // Copies a memory image into memory starting at offset, two bytes per cell, big-endian like
+    // the binary format. The whole image has to fit.
+    pub fn load_memory_image(&mut self, bytes: &[u8], offset: usize) -> Result<(), String> {
+        if !bytes.len().is_multiple_of(2) {
+            return Err(format!(
+                "A memory image holds 2 bytes per cell, this one has {} bytes.",
+                bytes.len()
+            ));
+        }
+        let cells = bytes.len() / 2;
+        if offset
+            .checked_add(cells)
+            .is_none_or(|end| end > self.memory.len())
+        {
+            return Err(format!(
+                "A memory image of {} cells at address {} does not fit in the {} memory cells.",
+                cells,
+                offset,
+                self.memory.len()
+            ));
+        }
+        for (cell, pair) in self.memory[offset..].iter_mut().zip(bytes.chunks_exact(2)) {
+            *cell = u16::from_be_bytes([pair[0], pair[1]]);
+        }
+        Ok(())
+    }
+
+    // Whether a function is running or a frame on the call stack returns into it
+    pub fn function_in_use(&self, name: &str) -> bool {
+        self.current_function.as_deref() == Some(name)
+            || self
+                .call_stack
+                .iter()
+                .any(|frame| frame.function.as_deref() == Some(name))
+    }
+
+    // Swaps in the body program gives function name, for editing a function while the program
+    // runs. CALLs from then on run the new body. Refused while the function is in use, since
+    // the old body's instruction indices would no longer mean anything.
+    pub fn replace_function(&mut self, name: &str, program: &Program) -> Result<(), String> {
+        if !self.functions.contains_key(name) {
+            return Err(format!("No function called {}.", name));
+        }
+        let Some(function) = program.functions.get(name) else {
+            return Err(format!("The new source does not define {}.", name));
+        };
+        if self.function_in_use(name) {
+            return Err(format!(
+                "{} is running or on the call stack, it can be replaced once it has returned.",
+                name
+            ));
+        }
+        self.functions
+            .insert(name.to_string(), function.body.clone());
+        let scope = Some(name.to_string());
+        self.annotations
+            .retain(|(function, _), _| *function != scope);
+        for ((function, index), text) in &program.annotations {
+            if *function == scope {
+                self.annotations
+                    .insert((scope.clone(), *index), text.clone());
             }
-            Instruction::MOVR(dst, src) => {
-                (MOVR_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)
+        }
+        self.positions.retain(|(function, _), _| *function != scope);
+        for ((function, index), position) in &program.positions {
+            if *function == scope {
+                self.positions
+                    .insert((scope.clone(), *index), position.clone());
             }
-            Instruction::CMP(dst, src) => {
-                (CMP_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)
+        }
+        Ok(())
+    }
+
+    // Starts the program at a function or a label of the global code instead of its first
+    // instruction. `main` and `.main` both name the function, functions win over labels.
+    pub fn set_entry(&mut self, entry: &str) -> Result<(), String> {
+        let function = format!(".{}", entry.trim_start_matches('.'));
+        if self.functions.contains_key(&function) {
+            // Returning from the entry function ends the program instead of falling into global code
+            self.call_stack.clear();
+            return self
+                .enter_function(&function, None, self.program.len() as u16)
+                .map_err(|e| e.to_string());
+        }
+        match self.labels.get(entry) {
+            Some(&index) if !entry.starts_with('.') => {
+                self.call_stack.clear();
+                self.current_function = None;
+                self.pc = index;
+                Ok(())
             }
-            Instruction::JMP(src) => (JMP_OPCODE << 12) | ((*src) << 8 & 0xF00),
-            Instruction::HALT => HALT_OPCODE << 12,
-            Instruction::NOP => NOP_OPCODE << 12,
+            _ => Err(format!(
+                "Entry point {} is neither a function nor a label.",
+                entry
+            )),
         }
     }
 
-    pub fn fetch_instruction(&mut self) -> Option<u16> {
-        if self.pc < MEMORY_SIZE as u16 {
-            let config = declare_config();
-            let instruction = self.memory[self.pc as usize];
-            self.pc += 1;
+    pub fn return_from_function(&mut self) -> Result<(), RuntimeErrorKind> {
+        let Some(frame) = self.call_stack.pop() else {
+            return Err(RuntimeErrorKind::RetWithoutCall);
+        };
+        if self.sp != frame.sp {
+            let function = self.current_function.clone().unwrap_or_default();
+            let error = if self.sp < frame.sp {
+                RuntimeErrorKind::StackLeftOnReturn {
+                    function,
+                    count: frame.sp - self.sp,
+                }
+            } else {
+                RuntimeErrorKind::StackPoppedOnReturn {
+                    function,
+                    count: self.sp - frame.sp,
+                }
+            };
+            return Err(error);
+        }
+        self.pop()?; // The return address pushed by CALL
+        self.current_function = frame.function;
+        self.pc = frame.return_pc;
+        Ok(())
+    }
 
-            if config.verbose_debug {
-                println!("Program Counter: {:?}", self.pc);
-                println!("Instruction: {:?}", instruction);
-            }
-            Some(instruction)
-        } else {
-            None
+    // Index into memory for an access, stopping the program if it is out of bounds
+    fn memory_index(&mut self, mnemonic: &str, address: u16) -> Result<usize, RuntimeErrorKind> {
+        let index = address as usize;
+        #[cfg(feature = "fault-injection")]
+        let index = match self.take_fault(Fault::MemoryOutOfBounds) {
+            true => self.memory.len(),
+            false => index,
+        };
+        if index >= self.memory.len() {
+            return Err(RuntimeErrorKind::MemoryOutOfBounds {
+                instruction: mnemonic.to_string(),
+                address,
+                size: self.memory.len(),
+            });
         }
+        Ok(index)
     }
 
-    pub fn get_register(&self, index: u16) -> Option<u16> {
-        if index < self.registers.len() as u16 {
-            Some(self.registers[index as usize])
-        } else {
-            None
+    // The SRC of DIV and MOD, 0 when a divide by zero fault was injected
+    fn divisor(&mut self, src: Operand) -> u16 {
+        #[cfg(feature = "fault-injection")]
+        if self.take_fault(Fault::DivideByZero) {
+            return 0;
+        }
+        self.operand_value(src)
+    }
+
+    // Values on the stack, including the return addresses of CALL
+    pub fn stack_depth(&self) -> usize {
+        self.memory.len() - self.sp
+    }
+
+    pub fn push(&mut self, value: u16) -> Result<(), RuntimeErrorKind> {
+        if self.sp == 0 {
+            return Err(RuntimeErrorKind::MemoryFull);
         }
+        if self.stack_depth() >= self.max_stack_depth {
+            return Err(RuntimeErrorKind::StackOverflow {
+                max_depth: self.max_stack_depth,
+            });
+        }
+        self.sp -= 1;
+        self.memory[self.sp] = value;
+        self.deepest_stack = self.deepest_stack.max(self.stack_depth());
+        Ok(())
     }
 
-    pub fn print_register(&self, index: u16) {
-        match self.get_register(index) {
-            Some(value) => println!("{}x: {}", integer_to_letter(index as usize), value),
-            None => println!("Register index {} is out of bounds.", index),
+    pub fn pop(&mut self) -> Result<u16, RuntimeErrorKind> {
+        if self.sp >= self.memory.len() {
+            return Err(RuntimeErrorKind::StackUnderflow);
         }
+        let value = self.memory[self.sp];
+        self.sp += 1;
+        Ok(value)
+    }
+
+    // Runs an arithmetic instruction on a register, wrapping around on overflow and recording it
+    // in the overflow flag. In signed mode both values are read as two's complement.
+    fn arithmetic(
+        &mut self,
+        reg: u16,
+        value: u16,
+        unsigned: fn(u16, u16) -> (u16, bool),
+        signed: fn(i16, i16) -> (i16, bool),
+    ) {
+        let lhs = self.registers[reg as usize];
+        let (result, overflow) = if self.signed {
+            let (result, overflow) = signed(lhs as i16, value as i16);
+            (result as u16, overflow)
+        } else {
+            unsigned(lhs, value)
+        };
+        self.registers[reg as usize] = result;
+        self.flags.overflow = overflow;
     }
 
-    pub fn execute_instruction(&mut self, instruction: u16) {
-        let opcode = instruction >> 12;
-        let reg1 = (instruction >> 8) & 0xF; // Change to u16
-        let reg2 = (instruction >> 4) & 0xF; // Change to u16
-        let value = instruction & 0xFF;
+    // Reads a number from the input for IN, handling bad input according to the input error policy
+    pub fn read_input(&mut self) -> Result<u16, RuntimeErrorKind> {
+        let stdin = io::stdin();
+        let interactive = self.input.is_none() && stdin.is_terminal();
+        let attempts = match self.input_errors {
+            InputErrorPolicy::Retry if interactive => INPUT_RETRIES + 1,
+            _ => 1,
+        };
 
-        match opcode {
-            ADD_OPCODE => self.registers[reg1 as usize] += self.registers[reg2 as usize],
-            MOV_OPCODE => self.registers[reg1 as usize] = value,
-            MUL_OPCODE => self.registers[reg1 as usize] *= self.registers[reg2 as usize],
-            SUB_OPCODE => {
-                if self.registers[reg1 as usize] >= self.registers[reg2 as usize] {
-                    self.registers[reg1 as usize] -= self.registers[reg2 as usize];
+        let mut line = String::new();
+        for attempt in 0..attempts {
+            if interactive {
+                print!(
+                    "{}",
+                    if attempt == 0 {
+                        "? "
+                    } else {
+                        "Not a number, try again: "
+                    }
+                );
+                let _ = io::stdout().flush();
+            }
+            line.clear();
+            // A failed read or the end of input counts as bad input
+            let read = match &mut self.input {
+                Some(input) => input.read_line(&mut line),
+                None => stdin.lock().read_line(&mut line),
+            }
+            .unwrap_or(0);
+            let value = if self.signed {
+                line.trim().parse::<i16>().map(|value| value as u16)
+            } else {
+                line.trim().parse::<u16>()
+            };
+            if let (true, Ok(value)) = (read > 0, value) {
+                self.flags.input_error = false;
+                return Ok(value);
+            }
+        }
+
+        if self.input_errors == InputErrorPolicy::Flag {
+            self.flags.input_error = true;
+            return Ok(0);
+        }
+        Err(RuntimeErrorKind::InvalidInput {
+            input: line.trim().to_string(),
+        })
+    }
+
+    // Every operand is read before any destination is written, so the same register can be
+    // named twice. oracle::predict holds the executor to that.
+    pub fn execute_instruction(
+        &mut self,
+        instruction: &Instruction,
+    ) -> Result<(), RuntimeErrorKind> {
+        if let Some((limit, src)) = limited_operand(instruction) {
+            let value = self.operand_value(src);
+            if !limit.range.contains(&value) {
+                return Err(RuntimeErrorKind::OperandOutOfLimit {
+                    limit: limit.clone(),
+                    value,
+                });
+            }
+        }
+        match *instruction {
+            Instruction::ADD(reg1, src) => {
+                let value = self.operand_value(src);
+                self.arithmetic(reg1, value, u16::overflowing_add, i16::overflowing_add)
+            }
+            Instruction::MOV(reg1, value) => self.registers[reg1 as usize] = value,
+            Instruction::MUL(reg1, src) => {
+                let value = self.operand_value(src);
+                self.arithmetic(reg1, value, u16::overflowing_mul, i16::overflowing_mul)
+            }
+            Instruction::SUB(reg1, src) => {
+                let value = self.operand_value(src);
+                if self.signed || self.registers[reg1 as usize] >= value {
+                    self.arithmetic(reg1, value, u16::overflowing_sub, i16::overflowing_sub)
+                } else {
+                    return Err(RuntimeErrorKind::Underflow {
+                        instruction: "SUB".to_string(),
+                    });
+                }
+            }
+            Instruction::SWAP(reg1, reg2) => self.registers.swap(reg1 as usize, reg2 as usize),
+            Instruction::DIV(reg1, src) => {
+                let value = self.divisor(src);
+                if value == 0 {
+                    return Err(RuntimeErrorKind::DivideByZero {
+                        instruction: "DIV".to_string(),
+                    });
+                }
+                // Only the signed -32768 / -1 can overflow
+                self.arithmetic(reg1, value, u16::overflowing_div, i16::overflowing_div)
+            }
+            // The remainder has the sign of the register, as in Rust
+            Instruction::MOD(reg1, src) => {
+                let value = self.divisor(src);
+                if value == 0 {
+                    return Err(RuntimeErrorKind::DivideByZero {
+                        instruction: "MOD".to_string(),
+                    });
+                }
+                self.arithmetic(reg1, value, u16::overflowing_rem, i16::overflowing_rem)
+            }
+            Instruction::CLR(reg) => self.registers[reg as usize] = 0,
+            Instruction::INC(reg) => {
+                self.arithmetic(reg, 1, u16::overflowing_add, i16::overflowing_add)
+            }
+            Instruction::DEC(reg) => {
+                if self.signed || self.registers[reg as usize] >= 1 {
+                    self.arithmetic(reg, 1, u16::overflowing_sub, i16::overflowing_sub)
                 } else {
-                    neg_num_err("SUB");
+                    return Err(RuntimeErrorKind::Underflow {
+                        instruction: "DEC".to_string(),
+                    });
                 }
             }
-            SWAP_OPCODE => self.registers.swap(reg1 as usize, reg2 as usize),
-            DIV_OPCODE => {
-                if self.registers[reg2 as usize] != 0 {
-                    self.registers[reg1 as usize] /= self.registers[reg2 as usize];
+            Instruction::PRINT(reg) => self.print_register(reg, PrintFormat::Decimal),
+            Instruction::PRINTX(reg) => self.print_register(reg, PrintFormat::Hex),
+            Instruction::PRINTB(reg) => {
+                let format = match self.group_binary {
+                    true => PrintFormat::GroupedBinary,
+                    false => PrintFormat::Binary,
+                };
+                self.print_register(reg, format)
+            }
+            Instruction::PRINTC(reg) => self.print_register(reg, PrintFormat::Char),
+            // Cells outside ASCII print as U+FFFD, nothing is printed without a NUL
+            Instruction::PRINTS(address) => {
+                let start = self.memory_index("PRINTS", address)?;
+                let Some(length) = self.memory[start..].iter().position(|&cell| cell == 0) else {
+                    return Err(RuntimeErrorKind::UnterminatedString { address });
+                };
+                let text: String = self.memory[start..start + length]
+                    .iter()
+                    .map(|&cell| match u8::try_from(cell) {
+                        Ok(byte) if byte.is_ascii() => byte as char,
+                        _ => char::REPLACEMENT_CHARACTER,
+                    })
+                    .collect();
+                let _ = write!(self.output, "{}", text);
+            }
+            // Signed in signed_mode like PRINT, a value wider than the field is not cut off
+            Instruction::PRINTW(reg, width) => {
+                let value = format_value(
+                    self.registers[reg as usize],
+                    PrintFormat::Decimal,
+                    self.signed,
+                );
+                let _ = write!(self.output, "{:>1$}", value, width as usize);
+            }
+            // The exponent is always read unsigned
+            Instruction::POW(reg1, src) => {
+                let value = self.operand_value(src);
+                self.arithmetic(
+                    reg1,
+                    value,
+                    |base, exp| base.overflowing_pow(exp.into()),
+                    |base, exp| base.overflowing_pow(exp as u16 as u32),
+                )
+            }
+            Instruction::MOVR(reg1, reg2) => {
+                self.registers[reg1 as usize] = self.registers[reg2 as usize]
+            }
+            Instruction::CMP(reg1, src) => {
+                let (lhs, rhs) = (self.registers[reg1 as usize], self.operand_value(src));
+                let ordering = if self.signed {
+                    (lhs as i16).cmp(&(rhs as i16))
                 } else {
-                    self.running = false;
-                    err_print("Dividing by zero is not allowed.".to_string());
+                    lhs.cmp(&rhs)
+                };
+                self.flags.zero = ordering.is_eq();
+                self.flags.less = ordering.is_lt();
+                self.flags.greater = ordering.is_gt();
+            }
+            // Jump targets are instruction indices, labels are resolved to them by the parser
+            Instruction::JMP(target) => self.pc = target,
+            Instruction::JE(target) => {
+                if self.flags.zero {
+                    self.pc = target;
+                }
+            }
+            Instruction::JNE(target) => {
+                if !self.flags.zero {
+                    self.pc = target;
+                }
+            }
+            Instruction::JG(target) => {
+                if self.flags.greater {
+                    self.pc = target;
+                }
+            }
+            Instruction::JL(target) => {
+                if self.flags.less {
+                    self.pc = target;
+                }
+            }
+            Instruction::CALL(ref name) => self.call_function(name)?,
+            Instruction::CALLE(ref name) => {
+                if self.flags.zero {
+                    self.call_function(name)?;
+                }
+            }
+            Instruction::CALLNE(ref name) => {
+                if !self.flags.zero {
+                    self.call_function(name)?;
                 }
             }
-            CLR_OPCODE => self.registers[reg2 as usize] = 0,
-            INC_OPCODE => self.registers[reg2 as usize] += 1,
-            DEC_OPCODE => {
-                if self.registers[reg2 as usize] >= 1 {
-                    self.registers[reg2 as usize] -= 1;
+            Instruction::RET => self.return_from_function()?,
+            Instruction::LOAD(reg, address) => {
+                let index = self.memory_index("LOAD", address)?;
+                self.registers[reg as usize] = self.memory[index];
+            }
+            Instruction::STORE(reg, address) => {
+                let index = self.memory_index("STORE", address)?;
+                self.memory[index] = self.registers[reg as usize];
+            }
+            Instruction::LOADR(reg, address_reg) => {
+                let index = self.memory_index("LOADR", self.registers[address_reg as usize])?;
+                self.registers[reg as usize] = self.memory[index];
+            }
+            Instruction::STORER(reg, address_reg) => {
+                let index = self.memory_index("STORER", self.registers[address_reg as usize])?;
+                self.memory[index] = self.registers[reg as usize];
+            }
+            // The pointer is advanced after the load, if it is also the destination the loaded
+            // value wins
+            Instruction::LOADINC(reg, address_reg) => {
+                let address = self.registers[address_reg as usize];
+                let index = self.memory_index("LOADINC", address)?;
+                self.registers[address_reg as usize] = address.wrapping_add(1);
+                self.registers[reg as usize] = self.memory[index];
+            }
+            Instruction::PUSH(reg) => self.push(self.registers[reg as usize])?,
+            Instruction::POP(reg) => self.registers[reg as usize] = self.pop()?,
+            Instruction::IN(reg) => self.registers[reg as usize] = self.read_input()?,
+            // Never waits for a key
+            Instruction::KEYIN(reg) => {
+                self.registers[reg as usize] = match &mut self.keyboard {
+                    Some(keyboard) => keyboard.next_key(),
+                    None => 0,
+                }
+            }
+            Instruction::GETF(reg) => self.registers[reg as usize] = self.flags.to_bits(),
+            Instruction::SETF(reg) => self.flags = Flags::from_bits(self.registers[reg as usize]),
+            Instruction::FLAGSWAP(reg) => {
+                let bits = self.flags.to_bits();
+                self.flags = Flags::from_bits(self.registers[reg as usize]);
+                self.registers[reg as usize] = bits;
+            }
+            // Larger minus smaller, so unlike SUB this can never go negative
+            Instruction::ABSDIFF(reg1, src) => {
+                let (lhs, rhs) = (self.registers[reg1 as usize], self.operand_value(src));
+                self.registers[reg1 as usize] = if self.signed {
+                    (lhs as i16).abs_diff(rhs as i16)
                 } else {
-                    neg_num_err("DEC");
+                    lhs.abs_diff(rhs)
                 }
             }
-            PRINT_OPCODE => self.print_register(reg2),
-            POW_OPCODE => {
-                self.registers[reg1 as usize] =
-                    u16::pow(self.registers[reg1 as usize], value.into())
+            Instruction::MEMCLR(start, count) => {
+                let start = start as usize;
+                let end = start + count as usize;
+                if end > self.memory.len() {
+                    return Err(RuntimeErrorKind::RangeOutOfBounds {
+                        instruction: "MEMCLR".to_string(),
+                        start,
+                        count: count as usize,
+                        size: self.memory.len(),
+                    });
+                }
+                self.memory[start..end].fill(0);
+            }
+            // 1 for a power of two, otherwise 0, the zero flag is set on a power of two so JE can
+            // branch on it. 0 is not a power of two. Values are always read unsigned.
+            Instruction::ISPOW2(reg) => {
+                let value = self.registers[reg as usize];
+                let is_power = value != 0 && value & (value - 1) == 0;
+                self.registers[reg as usize] = is_power as u16;
+                self.flags.zero = is_power;
+            }
+            // Rounds up to a power of two, 0 becomes 1. Above 32768 there is none that fits,
+            // the result wraps to 0 and sets the overflow flag.
+            Instruction::NEXTPOW2(reg) => {
+                let value = self.registers[reg as usize];
+                let result = value.checked_next_power_of_two();
+                self.registers[reg as usize] = result.unwrap_or(0);
+                self.flags.overflow = result.is_none();
             }
-            MOVR_OPCODE => self.registers[reg1 as usize] = self.registers[reg2 as usize],
-            CMP_OPCODE => {
-                self.zflag = self.registers[reg1 as usize] == self.registers[reg2 as usize];
+            Instruction::AND(reg, src) => self.registers[reg as usize] &= self.operand_value(src),
+            Instruction::OR(reg, src) => self.registers[reg as usize] |= self.operand_value(src),
+            Instruction::XOR(reg, src) => self.registers[reg as usize] ^= self.operand_value(src),
+            Instruction::NOT(reg) => self.registers[reg as usize] = !self.registers[reg as usize],
+            // At most 64 registers of 16 bits, the sum always fits
+            Instruction::POPCNTR(dest, start, count) => {
+                let range = start as usize..start as usize + count as usize;
+                self.registers[dest as usize] = self.registers[range]
+                    .iter()
+                    .map(|value| value.count_ones() as u16)
+                    .sum();
             }
-            JMP_OPCODE => {
-                // Here, we interpret `value` as the new program counter (PC) address
-                let jump_address = value; // Ensure this is cast correctly
-                self.pc = jump_address; // Update the program counter to the jump address
+            // The amounts of shifts and rotations are within their INSTRUCTION_SET limits by now
+            Instruction::SHL(reg, src) => {
+                self.registers[reg as usize] <<= self.operand_value(src);
+            }
+            Instruction::SHR(reg, src) => {
+                self.registers[reg as usize] >>= self.operand_value(src);
+            }
+            // Rotations through carry work on 17 bits, the register and the carry flag above it
+            Instruction::RCL(reg, src) => {
+                let amount = self.operand_value(src);
+                for _ in 0..amount {
+                    let value = self.registers[reg as usize];
+                    self.registers[reg as usize] = value << 1 | self.flags.carry as u16;
+                    self.flags.carry = value & 0x8000 != 0;
+                }
             }
-            NOP_OPCODE => {}
-            _ => self.running = false,
+            Instruction::RCR(reg, src) => {
+                let amount = self.operand_value(src);
+                for _ in 0..amount {
+                    let value = self.registers[reg as usize];
+                    self.registers[reg as usize] = value >> 1 | (self.flags.carry as u16) << 15;
+                    self.flags.carry = value & 1 != 0;
+                }
+            }
+            Instruction::CUSTOM(ref mnemonic, ref operands) => {
+                if !execute_custom(self, mnemonic, operands) {
+                    return Err(RuntimeErrorKind::UnknownInstruction {
+                        mnemonic: mnemonic.clone(),
+                    });
+                }
+            }
+            Instruction::NOP => {}
+            Instruction::HALT => self.running = false,
         }
+        Ok(())
     }
 
-    pub fn run(&mut self) {
-        self.running = true;
-        while self.running {
-            if let Some(instruction) = self.fetch_instruction() {
-                self.execute_instruction(instruction);
-            } else {
-                self.running = false;
+    // Location of the next instruction, the function it is in (None for global code) and its index
+    pub fn location(&self) -> (Option<&str>, u16) {
+        (self.current_function.as_deref(), self.pc)
+    }
+
+    // Instruction the next step will execute, None if the current body has run out
+    pub fn peek_instruction(&self) -> Option<&Instruction> {
+        let body = match &self.current_function {
+            Some(name) => self.functions.get(name)?,
+            None => &self.program,
+        };
+        body.get(self.pc as usize)
+    }
+
+    // Executes a single instruction. An error stops the program, history then lists what led to it.
+    pub fn step(&mut self) -> Result<StepOutcome, RuntimeError> {
+        if !self.running {
+            return Ok(StepOutcome::Halted);
+        }
+        let instruction = self.peek_instruction().cloned();
+        if self.history_size > 0 {
+            if let Some(instruction) = &instruction {
+                if self.history.len() == self.history_size {
+                    self.history.pop_front();
+                }
+                self.history.push_back((
+                    self.current_function.clone(),
+                    self.pc,
+                    instruction.clone(),
+                ));
+            }
+        }
+        let (function, pc) = (self.current_function.clone(), self.pc);
+        EXECUTING.with(|executing| match &mut *executing.borrow_mut() {
+            Some((current, index)) if *current == function => *index = pc,
+            other => *other = Some((function.clone(), pc)),
+        });
+        // Only tracing needs the registers from before the instruction
+        let before = (!self.tracers.is_empty()).then_some(self.registers);
+        if let Err(reason) = self.advance() {
+            self.running = false;
+            return Err(RuntimeError {
+                function,
+                index: pc,
+                instruction: instruction.map(Box::new),
+                reason,
+            });
+        }
+        if let (Some(before), Some(instruction)) = (before, instruction) {
+            let event = TraceEvent {
+                function: function.clone(),
+                index: pc,
+                annotation: self.annotations.get(&(function.clone(), pc)).cloned(),
+                position: self.positions.get(&(function.clone(), pc)).cloned(),
+                instruction,
+                changes: register_changes(&before, &self.registers),
+            };
+            for tracer in self.tracers.iter_mut() {
+                tracer.trace(&event);
             }
         }
+        Ok(if !self.running {
+            StepOutcome::Halted
+        } else if self.current_function == function && self.pc == pc + 1 {
+            StepOutcome::Continue
+        } else {
+            StepOutcome::Jumped
+        })
     }
 
-    pub fn emit_binary(&self, filename: &str) -> io::Result<()> {
-        let mut file = File::create(filename)?;
-        for &instruction in &self.memory {
-            if instruction != 0 {
-                file.write_all(&instruction.to_be_bytes())?;
+    // Fetches and executes the next instruction for step
+    fn advance(&mut self) -> Result<(), RuntimeErrorKind> {
+        if self.max_instructions > 0 && self.instructions_executed >= self.max_instructions {
+            return Err(RuntimeErrorKind::InstructionLimitExceeded {
+                limit: self.max_instructions,
+            });
+        }
+        if let Some(instruction) = self.fetch_instruction() {
+            // Registers are checked up front, a binary can name any register
+            if let Some(&register) = instruction
+                .registers()
+                .iter()
+                .find(|&&register| register as usize >= REGISTER_COUNT)
+            {
+                return Err(RuntimeErrorKind::InvalidRegister { register });
             }
+            self.instructions_executed += 1;
+            let result = self.execute_instruction(&instruction);
+            self.clear_zero_register();
+            result
+        } else if self.config.end_of_code == EndOfCode::Error {
+            Err(RuntimeErrorKind::RanOffEnd {
+                function: self.current_function.clone(),
+            })
+        } else if self.current_function.is_some() {
+            // Running past the end of a function returns to the caller as if it had a RET
+            self.return_from_function()
+        } else {
+            self.running = false;
+            Ok(())
         }
+    }
+
+    // Stops run_until_breakpoint before instruction index of the global code
+    pub fn set_breakpoint(&mut self, index: u16) {
+        self.breakpoints.insert((None, index));
+    }
+
+    // Stops run_until_breakpoint before instruction index of a function, named with its dot
+    pub fn set_function_breakpoint(&mut self, function: &str, index: u16) {
+        self.breakpoints.insert((Some(function.to_string()), index));
+    }
+
+    // Removes a breakpoint, returns whether there was one
+    pub fn clear_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        self.breakpoints.remove(breakpoint)
+    }
+
+    // Runs at least one instruction, then stops before the next one with a breakpoint. Starting on
+    // a breakpoint therefore moves past it instead of stopping straight away.
+    pub fn run_until_breakpoint(&mut self) -> Result<RunOutcome, RuntimeError> {
+        loop {
+            if self.step()? == StepOutcome::Halted {
+                return Ok(RunOutcome::Halted);
+            }
+            let (function, index) = self.location();
+            if self
+                .breakpoints
+                .contains(&(function.map(str::to_string), index))
+            {
+                return Ok(RunOutcome::Breakpoint);
+            }
+        }
+    }
+
+    // Runs until the program halts or fails
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        self.running = true;
+        while self.step()? != StepOutcome::Halted {}
         Ok(())
     }
+}
 
-    pub fn load_binary(&mut self, filename: &str) -> io::Result<()> {
-        let mut file = File::open(filename)?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
+impl Program {
+    /// Runs the program with the default configuration, IN reading lines from input and PRINT
+    /// writing to output. Returns the Cpu as the program left it.
+    pub fn run_with(
+        &self,
+        input: impl BufRead + 'static,
+        output: impl Write + 'static,
+    ) -> Result<Cpu, RuntimeError> {
+        let mut cpu = Cpu::new(Config::default());
+        cpu.set_input(Box::new(input));
+        cpu.set_output(Box::new(output));
+        cpu.load_program(self);
+        cpu.run()?;
+        Ok(cpu)
+    }
+}
 
-        for (i, chunk) in buffer.chunks_exact(2).enumerate() {
-            if i < MEMORY_SIZE {
-                let instruction = u16::from_be_bytes([chunk[0], chunk[1]]);
-                self.memory[i] = instruction;
-            } else {
-                eprintln!("Warning: Binary exceeds memory size.");
+// What is left of the machine once a program has run, returned by run_program
+#[derive(Debug, Clone)]
+pub struct MachineState {
+    pub registers: [u16; REGISTER_COUNT],
+    pub stack: Vec<u16>, // Values still on the stack, the top of the stack last
+    pub flags: Flags,
+    pub output: String, // Everything PRINT wrote
+}
+
+impl MachineState {
+    // Value of a register by its letter, panics for anything but a to z
+    pub fn reg(&self, letter: char) -> u16 {
+        let index = letter_to_integer(letter).expect("registers are named a to z");
+        self.registers[index as usize]
+    }
+}
+
+// Collects PRINT output in memory, the Cpu owns one handle and whoever set it up keeps the other
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    // Everything written so far, invalid UTF-8 replaced
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Assembles and runs source with the default configuration and returns the final state.
+// Nothing is read from or written to files or stdout, errors are returned instead of exiting.
+pub fn run_program(source: &str) -> Result<MachineState, RunError> {
+    let config = Config::default();
+    let program = parse_with_config(source.to_string(), &config)?;
+    let output = SharedBuffer::default();
+    let mut cpu = Cpu::new(config);
+    cpu.set_output(Box::new(output.clone()));
+    cpu.load_program(&program);
+    cpu.run()?;
+
+    let mut stack = cpu.memory[cpu.sp..].to_vec();
+    stack.reverse();
+    let output = output.text();
+    Ok(MachineState {
+        registers: cpu.registers,
+        stack,
+        flags: cpu.flags,
+        output,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            debug: false,
+            ..Config::default()
+        }
+    }
+
+    fn program(source: &str) -> Program {
+        parse_with_config(source.to_string(), &config()).unwrap()
+    }
+
+    // A Cpu with nothing loaded yet, and what it prints
+    fn cpu() -> (Cpu, SharedBuffer) {
+        let output = SharedBuffer::default();
+        let mut cpu = Cpu::new(config());
+        cpu.set_output(Box::new(output.clone()));
+        (cpu, output)
+    }
+
+    #[test]
+    fn strings_survive_fuzzing_memory_before_load() {
+        let program = program(".ascii HELLO \"hello\\n\"\nPRINTS HELLO\nHALT\n");
+        let (mut cpu, output) = cpu();
+        cpu.fuzz(7, true);
+        cpu.load_program(&program);
+        cpu.run().unwrap();
+        assert_eq!(output.text(), "hello\n");
+    }
+
+    #[test]
+    fn memory_image_survives_fuzzing_memory_before_load() {
+        let program = program("HALT\n");
+        let (mut cpu, _) = cpu();
+        cpu.fuzz(7, true);
+        cpu.load_program(&program);
+        cpu.load_memory_image(&[0, 1, 0x12, 0x34], 10).unwrap();
+        assert_eq!(cpu.memory[10..12], [1, 0x1234]);
+    }
+
+    #[test]
+    fn memory_image_must_fit() {
+        let (mut cpu, _) = cpu();
+        let end = cpu.memory.len();
+        assert!(cpu.load_memory_image(&[0, 1], end - 1).is_ok());
+        assert_eq!(
+            cpu.load_memory_image(&[0, 1, 0, 2], end - 1),
+            Err(format!(
+                "A memory image of 2 cells at address {} does not fit in the {} memory cells.",
+                end - 1,
+                end
+            ))
+        );
+        assert!(cpu.load_memory_image(&[0, 1], usize::MAX).is_err());
+    }
+
+    #[test]
+    fn divide_by_zero_lists_the_instructions_before_it() {
+        let program = program("MOV a, 6\nMOV b, 0\nDIV a, b\nHALT\n");
+        let (mut cpu, _) = cpu();
+        cpu.load_program(&program);
+        cpu.positions.insert((None, 2), ("div.asm".to_string(), 3));
+        let error = cpu.run().unwrap_err();
+        assert!(error.to_string().contains("DIV by zero"));
+        assert_eq!(
+            cpu.history_lines(),
+            [
+                "  [global 0] MOV ax, #6",
+                "  [global 1] MOV bx, #0",
+                "  [global 2] DIV ax, bx at div.asm:3",
+            ]
+        );
+    }
+
+    #[test]
+    fn registers_are_signed_by_default() {
+        let program = program("MOV a, 5\nSUB a, 8\nPRINT a\nMOV b, -4\nPRINT b\nHALT\n");
+        let (mut cpu, output) = cpu();
+        cpu.load_program(&program);
+        cpu.run().unwrap();
+        assert_eq!(cpu.register(0), (-3i16) as u16);
+        assert_eq!(output.text(), "ax: -3\nbx: -4\n");
+    }
+
+    #[test]
+    fn going_below_zero_stops_the_program_with_signed_mode_off() {
+        let program = program("MOV a, 5\nSUB a, 8\nHALT\n");
+        let mut cpu = Cpu::new(Config {
+            signed_mode: false,
+            ..config()
+        });
+        cpu.set_output(Box::new(io::sink()));
+        cpu.load_program(&program);
+        assert_eq!(cpu.run().unwrap_err().reason.code(), "E111");
+    }
+
+    #[test]
+    fn printb_groups_bits_by_four_with_group_binary() {
+        let program = program("MOV a, 0xAC5\nPRINTB a\nHALT\n");
+        for (group_binary, printed_value) in [
+            (false, "ax: 0b0000101011000101\n"),
+            (true, "ax: 0b0000_1010_1100_0101\n"),
+        ] {
+            let output = SharedBuffer::default();
+            let mut cpu = Cpu::new(Config {
+                group_binary,
+                ..config()
+            });
+            cpu.set_output(Box::new(output.clone()));
+            cpu.load_program(&program);
+            cpu.run().unwrap();
+            assert_eq!(output.text(), printed_value);
+        }
+    }
+
+    // Runs a program to its end on a Cpu of its own, for tests that look at more than registers
+    fn ran(source: &str) -> (Cpu, SharedBuffer) {
+        let (mut cpu, output) = cpu();
+        cpu.load_program(&program(source));
+        cpu.run().unwrap();
+        (cpu, output)
+    }
+
+    fn state(source: &str) -> MachineState {
+        run_program(source).unwrap()
+    }
+
+    #[test]
+    fn a_program_built_from_instructions_runs_without_parsing() {
+        let program = Program {
+            instructions: vec![
+                Instruction::MOV(0, 6),
+                Instruction::MUL(0, Operand::Immediate(7)),
+                Instruction::MOVR(1, 0),
+                Instruction::HALT,
+            ],
+            ..Program::default()
+        };
+        let cpu = program.run_with(io::empty(), io::sink()).unwrap();
+        assert_eq!((cpu.register(0), cpu.register(1)), (42, 42));
+    }
+
+    #[test]
+    fn run_program_returns_registers_stack_and_output() {
+        let state = state("mov a, 7\npush a\nprint a\nhalt\n");
+        assert_eq!(state.reg('a'), 7);
+        assert_eq!(state.stack, [7]);
+        assert_eq!(state.output, "ax: 7\n");
+    }
+
+    #[test]
+    fn calls_come_back_to_the_caller() {
+        let source = "mov a, 3\ncall .double\ncall .double\nhalt\n.double\nmul a, 2\nret\n.end\n";
+        assert_eq!(state(source).reg('a'), 12);
+    }
+
+    #[test]
+    fn a_function_without_ret_returns_at_its_end() {
+        let source = "call .bump\ncall .bump\nhalt\n.bump\ninc a\n.end\n";
+        assert_eq!(state(source).reg('a'), 2);
+    }
+
+    #[test]
+    fn calle_and_callne_follow_the_zero_flag() {
+        let source = "mov a, 1\ncmp a, 1\ncalle .mark\ncallne .mark\ncmp a, 2\ncallne .mark\n\
+                      halt\n.mark\ninc b\nret\n.end\n";
+        assert_eq!(state(source).reg('b'), 2);
+    }
+
+    #[test]
+    fn an_entry_function_runs_instead_of_the_global_code() {
+        let (mut cpu, _) = cpu();
+        cpu.load_program(&program("mov a, 1\nhalt\n.main\nmov b, 5\nret\n.end\n"));
+        cpu.set_entry("main").unwrap();
+        cpu.run().unwrap();
+        assert_eq!((cpu.register(0), cpu.register(1)), (0, 5));
+        assert!(cpu.set_entry("nowhere").is_err());
+    }
+
+    #[test]
+    fn stored_values_load_back_and_the_stack_is_last_in_first_out() {
+        let source = "mov a, 42\nstore a, 100\nload b, 100\nmov c, 1\nmov d, 2\npush c\npush d\n\
+                      pop e\npop f\nhalt\n";
+        let state = state(source);
+        assert_eq!(state.reg('b'), 42);
+        assert_eq!((state.reg('e'), state.reg('f')), (2, 1));
+        assert!(state.stack.is_empty());
+    }
+
+    #[test]
+    fn loadinc_walks_an_array() {
+        let source = "mov a, 7\nstore a, 10\nmov a, 8\nstore a, 11\nmov a, 9\nstore a, 12\n\
+                      mov p, 10\nloadinc b, p\nloadinc c, p\nloadinc d, p\nhalt\n";
+        let state = state(source);
+        assert_eq!((state.reg('b'), state.reg('c'), state.reg('d')), (7, 8, 9));
+        assert_eq!(state.reg('p'), 13);
+    }
+
+    #[test]
+    fn memclr_zeroes_only_its_range() {
+        let (cpu, _) = ran(
+            "mov a, 5\nstore a, 9\nstore a, 10\nstore a, 12\nstore a, 13\n\
+                            memclr 10, 3\nhalt\n",
+        );
+        assert_eq!(cpu.memory[9..14], [5, 0, 0, 0, 5]);
+    }
+
+    #[test]
+    fn a_memory_image_is_read_by_load() {
+        let (mut cpu, _) = cpu();
+        cpu.load_program(&program("load a, 0x100\nload b, 0x101\nhalt\n"));
+        cpu.load_memory_image(&[0x12, 0x34, 0, 9], 0x100).unwrap();
+        cpu.run().unwrap();
+        assert_eq!((cpu.register(0), cpu.register(1)), (0x1234, 9));
+    }
+
+    #[test]
+    fn absdiff_does_not_depend_on_the_order() {
+        let state = state("mov a, 3\nabsdiff a, 10\nmov b, 10\nabsdiff b, 3\nhalt\n");
+        assert_eq!((state.reg('a'), state.reg('b')), (7, 7));
+    }
+
+    #[test]
+    fn dec_at_zero_goes_negative_only_in_signed_mode() {
+        assert_eq!(state("dec a\nhalt\n").reg('a'), (-1i16) as u16);
+        let (mut cpu, _) = cpu();
+        cpu.signed = false;
+        cpu.load_program(&program("dec a\nhalt\n"));
+        assert_eq!(cpu.run().unwrap_err().reason.code(), "E111");
+    }
+
+    #[test]
+    fn mul_overflow_depends_on_the_mode() {
+        // 40000 fits in 16 bits unsigned, not signed
+        for (signed, value, overflow) in [(false, 40000, false), (true, 40000, true)] {
+            let (mut cpu, _) = cpu();
+            cpu.signed = signed;
+            cpu.load_program(&program("mov a, 200\nmul a, 200\nhalt\n"));
+            cpu.run().unwrap();
+            assert_eq!((cpu.register(0), cpu.flags.overflow), (value, overflow));
+        }
+        let (cpu, _) = ran("mov a, 300\nmul a, 300\nhalt\n");
+        assert_eq!((cpu.register(0), cpu.flags.overflow), (24464, true));
+    }
+
+    #[test]
+    fn mod_keeps_the_remainder_and_both_divisions_reject_zero() {
+        assert_eq!(state("mov a, 7\nmod a, 3\nhalt\n").reg('a'), 1);
+        for source in [
+            "mov a, 7\nmov b, 0\ndiv a, b\n",
+            "mov a, 7\nmov b, 0\nmod a, b\n",
+        ] {
+            let error = run_program(source).unwrap_err();
+            assert!(error.to_string().contains("by zero"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn a_runtime_error_names_the_instruction_that_failed() {
+        let (mut cpu, _) = cpu();
+        cpu.load_program(&program("mov a, 1\nmov b, 0\nnop\ndiv a, b\nhalt\n"));
+        let error = cpu.run().unwrap_err();
+        assert_eq!((error.function, error.index), (None, 3));
+        assert_eq!(error.reason.code(), "E112");
+    }
+
+    #[test]
+    fn a_loop_that_never_ends_hits_the_instruction_limit() {
+        let (mut cpu, _) = cpu();
+        cpu.max_instructions = 1000;
+        cpu.load_program(&program("top: jmp top\n"));
+        let error = cpu.run().unwrap_err();
+        assert_eq!(error.reason.code(), "E116");
+        assert_eq!(cpu.instructions_executed, 1000);
+    }
+
+    #[test]
+    fn every_executed_instruction_is_counted() {
+        // 1 MOV, 3 times DEC and JNE... the loop body, then HALT
+        let (cpu, _) = ran("mov a, 3\ntop: dec a\ncmp a, 0\njne top\nhalt\n");
+        assert_eq!(cpu.instructions_executed, 1 + 3 * 3 + 1);
+    }
+
+    #[test]
+    fn bitwise_instructions_mask_toggle_and_shift() {
+        let state = state(
+            "mov a, 0xABCD\nand a, 0x00FF\nmov b, 0b1010\nxor b, 0b0110\nmov c, 0x1234\n\
+             shl c, 4\nshr c, 4\nmov d, 99\nxor d, d\nmov e, 0\nnot e\nhalt\n",
+        );
+        assert_eq!(state.reg('a'), 0x00CD);
+        assert_eq!(state.reg('b'), 0b1100);
+        assert_eq!(state.reg('c'), 0x0234);
+        assert_eq!(state.reg('d'), 0);
+        assert_eq!(state.reg('e'), 0xFFFF);
+    }
+
+    #[test]
+    fn ispow2_and_nextpow2() {
+        let state =
+            state("mov a, 64\nispow2 a\nmov b, 12\nispow2 b\nmov c, 100\nnextpow2 c\nhalt\n");
+        assert_eq!(
+            (state.reg('a'), state.reg('b'), state.reg('c')),
+            (1, 0, 128)
+        );
+        let (cpu, _) = ran("mov a, 40000\nnextpow2 a\nhalt\n");
+        assert_eq!((cpu.register(0), cpu.flags.overflow), (0, true));
+    }
+
+    #[test]
+    fn popcntr_counts_bits_across_registers() {
+        let state = state("mov b, 0b1011\nmov c, 0xFF\nmov d, 1\npopcntr a, b, 3\nhalt\n");
+        assert_eq!(state.reg('a'), 3 + 8 + 1);
+    }
+
+    #[test]
+    fn rcl_shifts_a_32_bit_value_held_in_two_registers() {
+        // 0x8001_4000 shifted left by one is 0x0002_8000 with the top bit in the carry
+        let (cpu, _) =
+            ran("mov l, 0x4000\nmov h, 0x8001\nmov f, 0\nsetf f\nrcl l, 1\nrcl h, 1\nhalt\n");
+        assert_eq!((cpu.register(7), cpu.register(11)), (0x0002, 0x8000));
+        assert!(cpu.flags.carry);
+    }
+
+    #[test]
+    fn flagswap_round_trips_the_flags() {
+        let (cpu, _) = ran("mov a, 1\ncmp a, 2\ngetf b\nflagswap c\nflagswap c\ngetf d\nhalt\n");
+        assert_eq!(cpu.register(1), cpu.register(3));
+        assert!(cpu.flags.less);
+    }
+
+    #[test]
+    fn operands_are_read_before_any_register_is_written() {
+        let state = state(
+            "mov a, 1\nmov b, 2\nswap a, b\nmov c, 3\nmovr c, c\nmov d, 20\nstorer d, d\n\
+             loadr e, d\nhalt\n",
+        );
+        assert_eq!((state.reg('a'), state.reg('b')), (2, 1));
+        assert_eq!(state.reg('c'), 3);
+        assert_eq!(state.reg('e'), 20);
+    }
+
+    #[test]
+    fn register_0_can_be_hardwired_to_0() {
+        let (mut cpu, _) = cpu();
+        cpu.zero_register = true;
+        cpu.load_program(&program("mov a, 5\nmovr b, a\ninc a\nhalt\n"));
+        cpu.run().unwrap();
+        assert_eq!((cpu.register(0), cpu.register(1)), (0, 0));
+    }
+
+    #[test]
+    fn strings_and_fields_print_exactly() {
+        let (_, output) = ran(".ascii HI \"Hi\\n\"\nprints HI\nmov a, 5\nprintw a, 4\nhalt\n");
+        assert_eq!(output.text(), "Hi\n   5");
+    }
+
+    #[test]
+    fn keyin_reads_scripted_keys_until_none_is_left() {
+        let (mut cpu, output) = cpu();
+        cpu.set_keyboard(Box::new(ScriptedKeys::new("hjk")));
+        cpu.load_program(&program(
+            "top: keyin a\ncmp a, 0\nje done\nprintc a\njmp top\ndone: halt\n",
+        ));
+        cpu.run().unwrap();
+        assert_eq!(output.text(), "ax: 'h'\nax: 'j'\nax: 'k'\n");
+    }
+
+    #[test]
+    fn bad_input_stops_the_program_or_sets_the_flag() {
+        let source = "in a\ngetf b\nin c\nhalt\n";
+        let (mut aborting, _) = cpu();
+        aborting.set_input(Box::new(io::Cursor::new("x\n")));
+        aborting.load_program(&program(source));
+        assert_eq!(aborting.run().unwrap_err().reason.code(), "E113");
+
+        let (mut flagging, _) = cpu();
+        flagging.input_errors = InputErrorPolicy::Flag;
+        flagging.set_input(Box::new(io::Cursor::new("x\n12\n")));
+        flagging.load_program(&program(source));
+        flagging.run().unwrap();
+        assert_eq!(flagging.register(0), 0);
+        assert!(Flags::from_bits(flagging.register(1)).input_error);
+        assert_eq!(flagging.register(2), 12);
+        assert!(!flagging.flags.input_error);
+    }
+
+    #[test]
+    fn the_final_state_lists_the_registers_that_are_not_0() {
+        let (cpu, _) = ran("mov a, 42\nmov c, -1\nhalt\n");
+        assert_eq!(
+            cpu.final_state_table(false),
+            "Final state:\n  ax       42  0x002a\n  cx       -1  0xffff"
+        );
+    }
+
+    #[test]
+    fn shift_amounts_are_range_checked_when_parsed_and_when_run() {
+        let parsed = parse_with_config("mov a, 1\nshl a, 40\n".to_string(), &config()).unwrap_err();
+        assert_eq!(parsed.code(), "E005");
+
+        let (mut cpu, _) = cpu();
+        cpu.load_program(&program("mov a, 1\nmov b, 40\nshl a, b\nhalt\n"));
+        let ran = cpu.run().unwrap_err();
+        assert_eq!(ran.reason.code(), "E118");
+        let message = "SHL amount must be 0..=15, got 40";
+        assert!(parsed
+            .to_string()
+            .starts_with(&format!("{} on line 2.", message)));
+        assert!(ran.reason.to_string().starts_with(&format!("{}.", message)));
+    }
+
+    #[test]
+    fn stepping_follows_jumps_calls_and_returns() {
+        let (mut cpu, _) = cpu();
+        cpu.load_program(&program(
+            "mov a, 1\njmp over\nmov a, 9\nover: call .bump\nhalt\n.bump\ninc a\nret\n.end\n",
+        ));
+        let mut steps = Vec::new();
+        loop {
+            let (function, index) = cpu.location();
+            let location = (function.map(str::to_string), index);
+            let outcome = cpu.step().unwrap();
+            steps.push((location, outcome, cpu.register(0)));
+            if outcome == StepOutcome::Halted {
                 break;
             }
         }
-        self.pc = 0;
-        Ok(())
+        let bump = Some(".bump".to_string());
+        assert_eq!(
+            steps,
+            [
+                ((None, 0), StepOutcome::Continue, 1),
+                ((None, 1), StepOutcome::Jumped, 1),
+                ((None, 3), StepOutcome::Jumped, 1),
+                ((bump.clone(), 0), StepOutcome::Continue, 2),
+                ((bump, 1), StepOutcome::Jumped, 2),
+                ((None, 4), StepOutcome::Halted, 2),
+            ]
+        );
+        // Once halted nothing runs any more
+        assert_eq!(cpu.step().unwrap(), StepOutcome::Halted);
+        assert_eq!(cpu.instructions_executed, 6);
+    }
+
+    #[test]
+    fn run_until_breakpoint_stops_before_each_breakpoint() {
+        let (mut cpu, _) = cpu();
+        cpu.load_program(&program(
+            "mov a, 1\ncall .bump\ncall .bump\nhalt\n.bump\ninc a\nret\n.end\n",
+        ));
+        cpu.set_function_breakpoint(".bump", 1);
+        cpu.set_breakpoint(3);
+        let mut stops = Vec::new();
+        while cpu.run_until_breakpoint().unwrap() == RunOutcome::Breakpoint {
+            let (function, index) = cpu.location();
+            stops.push((function.map(str::to_string), index, cpu.register(0)));
+        }
+        let bump = Some(".bump".to_string());
+        assert_eq!(stops, [(bump.clone(), 1, 2), (bump, 1, 3), (None, 3, 3)]);
+        assert!(cpu.clear_breakpoint(&(None, 3)));
+        assert!(!cpu.clear_breakpoint(&(None, 3)));
+    }
+
+    #[test]
+    fn fuzzing_shows_a_register_that_was_never_set() {
+        // Counts to 3 in b, but only works because b starts at 0
+        let source = "mov a, 3\ntop: inc b\ndec a\ncmp a, 0\njne top\nprint b\nhalt\n";
+        let output_with = |seed: Option<u64>| {
+            let (mut cpu, output) = cpu();
+            if let Some(seed) = seed {
+                cpu.fuzz(seed, false);
+            }
+            cpu.load_program(&program(source));
+            cpu.run().unwrap();
+            output.text()
+        };
+        assert_eq!(output_with(None), "bx: 3\n");
+        let fuzzed = output_with(Some(7));
+        assert_ne!(fuzzed, "bx: 3\n");
+        // The same seed fails the same way
+        assert_eq!(output_with(Some(7)), fuzzed);
     }
 }
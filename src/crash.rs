@@ -0,0 +1,258 @@
+use alice::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::panic::{self, PanicHookInfo};
+use std::process;
+
+// Turns a panic of alice itself into a report to file as an issue instead of a bare backtrace.
+// main records what it is busy with as it goes, the hook adds where the Cpu was from
+// executing_instruction. Only the binary installs the hook, the library leaves panics alone.
+
+const ISSUES: &str = "https://github.com/BlueGummi/alice/issues";
+
+// Exit code after the report, the same as an unhandled panic
+const PANIC_EXIT_CODE: i32 = 101;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Phase {
+    #[default]
+    Starting,
+    Parsing,
+    Loaded, // Parsed, not running yet
+    Running,
+}
+
+impl Phase {
+    fn describe(self) -> &'static str {
+        match self {
+            Phase::Starting => "starting up",
+            Phase::Parsing => "reading and parsing the program",
+            Phase::Loaded => "after parsing, before running",
+            Phase::Running => "running the program",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Context {
+    phase: Phase,
+    source: Option<(String, u64)>, // File and hash of its contents
+    positions: HashMap<(Option<String>, u16), (String, usize)>, // Of the program running
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+pub fn set_phase(phase: Phase) {
+    CONTEXT.with(|context| context.borrow_mut().phase = phase);
+}
+
+// Records the file being loaded, with a hash of its bytes to tell versions of it apart
+pub fn set_source(path: &str, bytes: &[u8]) {
+    let source = (path.to_string(), fnv1a(bytes));
+    CONTEXT.with(|context| context.borrow_mut().source = Some(source));
+}
+
+// Records the program about to run, so the report can give the line of its instruction
+pub fn set_program(program: &Program) {
+    CONTEXT.with(|context| context.borrow_mut().positions = program.positions.clone());
+}
+
+// The lines of the report, before the border goes around them
+fn report(info: &PanicHookInfo) -> Vec<String> {
+    let message = match info.payload().downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match info.payload().downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "no message".to_string(),
+        },
+    };
+    let mut lines = vec![
+        "Internal emulator bug: alice panicked, this is not a mistake in your program.".to_string(),
+        String::new(),
+        format!("Version:     alice {}", env!("CARGO_PKG_VERSION")),
+        format!("Command:     {}", env::args().collect::<Vec<_>>().join(" ")),
+    ];
+    // A panic while main was updating the context leaves it borrowed, report without it then
+    let context = CONTEXT.with(|context| {
+        context.try_borrow().ok().map(|context| {
+            let mut lines = Vec::new();
+            if let Some((file, hash)) = &context.source {
+                lines.push(format!("Source:      {} (FNV-1a {:016x})", file, hash));
+            }
+            lines.push(format!("Phase:       {}", context.phase.describe()));
+            if let (Phase::Running, Some((function, index))) =
+                (context.phase, executing_instruction())
+            {
+                let mut at = format!(
+                    "Instruction: {} {}",
+                    function.as_deref().unwrap_or("global code"),
+                    index
+                );
+                if let Some((file, line)) = context.positions.get(&(function, index)) {
+                    at.push_str(&format!(", {} line {}", file, line));
+                }
+                lines.push(at);
+            }
+            lines
+        })
+    });
+    lines.extend(context.unwrap_or_default());
+    let location = match info.location() {
+        Some(location) => format!(" at {}:{}", location.file(), location.line()),
+        None => String::new(),
+    };
+    lines.push(format!("Panic:       {}{}", message, location));
+    lines.push(String::new());
+    lines.push(format!("Please report it at {} with this report", ISSUES));
+    lines.push(
+        "and the program attached, cut down to the smallest one that still panics.".to_string(),
+    );
+    lines
+}
+
+// Replaces the default panic output with the report and exits with PANIC_EXIT_CODE
+pub fn install_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        let lines = report(info);
+        let width = lines
+            .iter()
+            .map(|line| line.chars().count())
+            .max()
+            .unwrap_or(0);
+        let border = format!("+{}+", "-".repeat(width + 2));
+        eprintln!("{}", border);
+        for line in lines {
+            eprintln!("| {:<1$} |", line, width);
+        }
+        eprintln!("{}", border);
+        process::exit(PANIC_EXIT_CODE);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct Explode;
+
+    impl InstructionDef for Explode {
+        fn mnemonic(&self) -> &str {
+            "EXPLODE"
+        }
+        fn arity(&self) -> usize {
+            0
+        }
+        fn execute(&self, _: &mut Cpu, _: &[u16]) {
+            panic!("EXPLODE went off");
+        }
+    }
+
+    // The hook is global, one test at a time swaps it
+    static HOOK: Mutex<()> = Mutex::new(());
+
+    // The report of a panic in f, panics of other threads go to the default hook meanwhile
+    fn reported(f: impl FnOnce() + panic::UnwindSafe) -> Vec<String> {
+        let _swapping = HOOK.lock().unwrap_or_else(|e| e.into_inner());
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let caught = Arc::clone(&lines);
+        let thread = std::thread::current().id();
+        let default = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if std::thread::current().id() == thread {
+                *caught.lock().unwrap() = report(info);
+            } else {
+                default(info);
+            }
+        }));
+        assert!(panic::catch_unwind(f).is_err());
+        let _ = panic::take_hook();
+        let lines = lines.lock().unwrap().clone();
+        lines
+    }
+
+    #[test]
+    fn a_report_gives_the_phase_source_and_instruction_of_the_panic() {
+        register_instruction(Box::new(Explode));
+        let source = "mov a, 1\nexplode\nhalt\n";
+        let config = Config {
+            debug: false,
+            ..Config::default()
+        };
+        let mut program = parse_with_config(source.to_string(), &config).unwrap();
+        program
+            .positions
+            .insert((None, 1), ("boom.asm".to_string(), 2));
+        set_source("boom.asm", source.as_bytes());
+        set_program(&program);
+        set_phase(Phase::Running);
+
+        let lines = reported(move || {
+            let mut cpu = Cpu::new(config);
+            cpu.load_program(&program);
+            let _ = cpu.run();
+        });
+        let field = |name: &str| {
+            lines
+                .iter()
+                .find(|line| line.starts_with(name))
+                .cloned()
+                .unwrap_or_default()
+        };
+        assert!(lines[0].starts_with("Internal emulator bug"));
+        assert_eq!(
+            field("Source:"),
+            format!(
+                "Source:      boom.asm (FNV-1a {:016x})",
+                fnv1a(source.as_bytes())
+            )
+        );
+        assert_eq!(field("Phase:"), "Phase:       running the program");
+        assert_eq!(
+            field("Instruction:"),
+            "Instruction: global code 1, boom.asm line 2"
+        );
+        assert!(field("Panic:").starts_with("Panic:       EXPLODE went off at "));
+        assert!(lines
+            .last()
+            .unwrap()
+            .starts_with("and the program attached"));
+    }
+
+    #[test]
+    fn a_panicking_program_exits_with_the_panic_exit_code() {
+        const CHILD: &str = "ALICE_PANIC_HOOK_CHILD";
+        // The child is this test run again by itself, it installs the hook and panics for real
+        if env::var_os(CHILD).is_some() {
+            install_panic_hook();
+            register_instruction(Box::new(Explode));
+            let _ = run_program("explode\nhalt\n");
+            unreachable!("EXPLODE did not go off");
+        }
+        let output = process::Command::new(env::current_exe().unwrap())
+            .args([
+                "crash::tests::a_panicking_program_exits_with_the_panic_exit_code",
+                "--exact",
+                "--nocapture",
+            ])
+            .env(CHILD, "1")
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(PANIC_EXIT_CODE), "{}", stderr);
+        assert!(stderr.contains("| Internal emulator bug: alice panicked"));
+        assert!(stderr.contains("| Panic:       EXPLODE went off at "));
+    }
+
+    #[test]
+    fn a_report_outside_a_run_has_no_instruction() {
+        set_phase(Phase::Parsing);
+        let lines = reported(|| panic!("while parsing"));
+        assert!(lines.contains(&"Phase:       reading and parsing the program".to_string()));
+        assert!(!lines.iter().any(|line| line.starts_with("Instruction:")));
+        assert!(!lines.iter().any(|line| line.starts_with("Source:")));
+    }
+}
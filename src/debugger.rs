@@ -0,0 +1,829 @@
+use crate::{color_stdout, report_runtime_error};
+use alice::*;
+use colorized::*;
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::process::Command;
+
+// Interactive prompt started by --debug, stepping the CPU one instruction at a time.
+// Breakpoints are instruction indices, in the global code or in a function (`break .name N`),
+// or the Nth line a search matches (`break /pattern/ N`).
+
+const HELP: &str = "Commands:
+  step, s              execute one instruction and show it
+  continue, c          run until a breakpoint or the end of the program
+  break [.name] N, b   break before instruction N of the global code or of a function
+  break /PATTERN/ N    break at the Nth line matching PATTERN, as numbered by search
+  delete [.name] N     remove a breakpoint
+  search PATTERN, /    list the source lines matching PATTERN, e.g. /innerloop
+  info symbols [PAT]   list the labels, functions and constants, or those matching PAT
+  regs                 dump all registers
+  print REG, p         print one register, e.g. print a, print r3
+  mem ADDR [COUNT]     print COUNT memory cells starting at ADDR
+  set TARGET = VALUE   change a register, memory cell or flag, e.g. set r3 = 0xFF,
+                       set [0x100] = -5, set flags.carry = 1
+  edit .name           replace the body of a function, in $EDITOR or typed up to .end
+  help, h              show this message
+  quit, q              stop the program
+Patterns match anywhere in a line or name and ignore case, add -c to match case.
+In a pattern * stands for any characters and ? for one.";
+
+// Lines listed before the output waits for Enter, when stdin is a terminal and LINES is not set
+const DEFAULT_PAGE_LINES: usize = 24;
+
+thread_local! {
+    // Files of the program being debugged with their contents, from retain_source
+    static SOURCE: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+// Keeps the source files a program was parsed from for search and info symbols
+pub fn retain_source(files: Vec<(String, String)>) {
+    SOURCE.with(|source| *source.borrow_mut() = files);
+}
+
+fn print_location(cpu: &Cpu, label: &str) {
+    let (function, index) = cpu.location();
+    let scope = function.unwrap_or("global");
+    let position = match cpu.positions.get(&(function.map(str::to_string), index)) {
+        Some((file, line)) => format!(" {}:{}", file, line),
+        None => String::new(),
+    };
+    match cpu.peek_instruction() {
+        Some(instruction) => println!(
+            "{} {} {}{}",
+            label.color(Colors::YellowFg),
+            format!("[{} {}]", scope, index).color(Colors::CyanFg),
+            highlight_line(
+                &lex(&instruction.to_string()).concat(),
+                Highlight::colored(color_stdout())
+            ),
+            position.color(Colors::BrightBlackFg)
+        ),
+        None => println!(
+            "{} {}",
+            label.color(Colors::YellowFg),
+            format!("[{} {}] end of {}", scope, index, scope).color(Colors::CyanFg)
+        ),
+    }
+}
+
+// Parses the operands of break and delete, `N`, `.name N` or `/pattern/ N`
+fn parse_breakpoint(cpu: &Cpu, args: &[&str]) -> Result<Breakpoint, String> {
+    if args.first().is_some_and(|first| first.starts_with('/')) {
+        return breakpoint_at_match(cpu, &args.join(" "));
+    }
+    let (function, index) = match args {
+        [index] => (None, index),
+        [name, index] => {
+            let name = format!(".{}", name.trim_start_matches('.'));
+            if !cpu.functions.contains_key(&name) {
+                return Err(format!("No function called {}.", name));
+            }
+            (Some(name), index)
+        }
+        _ => return Err("Usage: break [.name] N".to_string()),
+    };
+    let index: u16 = index
+        .parse()
+        .map_err(|_| format!("\"{}\" is not an instruction index.", index))?;
+    let length = match &function {
+        Some(name) => cpu.functions[name].len(),
+        None => cpu.program.len(),
+    };
+    if index as usize >= length {
+        return Err(format!(
+            "Instruction {} does not exist, {} has {} instructions.",
+            index,
+            function.as_deref().unwrap_or("the global code"),
+            length
+        ));
+    }
+    Ok((function, index))
+}
+
+// A search pattern, typed after search or between the slashes of /pattern/
+struct Pattern {
+    typed: String, // As typed, without -c
+    text: Vec<char>,
+    match_case: bool,
+}
+
+impl Pattern {
+    // The words of a pattern, `-c` among them making it match case
+    fn new(words: &str) -> Pattern {
+        let match_case = words.split_whitespace().any(|word| word == "-c");
+        let text = words
+            .split_whitespace()
+            .filter(|&word| word != "-c")
+            .collect::<Vec<_>>()
+            .join(" ");
+        let folded = match match_case {
+            true => text.clone(),
+            false => text.to_lowercase(),
+        };
+        Pattern {
+            typed: text,
+            text: folded.chars().collect(),
+            match_case,
+        }
+    }
+
+    // Whether the pattern is found anywhere in text
+    fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = match self.match_case {
+            true => text.chars().collect(),
+            false => text.to_lowercase().chars().collect(),
+        };
+        (0..=text.len()).any(|start| glob_prefix(&self.text, &text[start..]))
+    }
+}
+
+// Whether pattern matches the start of text, `*` standing for any characters and `?` for one
+fn glob_prefix(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => true,
+        Some(('*', rest)) => (0..=text.len()).any(|skip| glob_prefix(rest, &text[skip..])),
+        Some((&c, rest)) => text
+            .split_first()
+            .is_some_and(|(&t, text)| (c == '?' || c == t) && glob_prefix(rest, text)),
+    }
+}
+
+// Every retained source line the pattern matches as file, line number and text
+fn search_source(pattern: &Pattern) -> Vec<(String, usize, String)> {
+    SOURCE.with(|source| {
+        let mut found = Vec::new();
+        for (file, contents) in source.borrow().iter() {
+            for (index, line) in contents.lines().enumerate() {
+                if pattern.matches(line) {
+                    found.push((file.clone(), index + 1, line.to_string()));
+                }
+            }
+        }
+        found
+    })
+}
+
+fn no_source() -> String {
+    "The source is not available, the program was loaded from a binary.".to_string()
+}
+
+// Prints lines, waiting for Enter after each screenful when someone is reading at a terminal.
+// Stops early if q is typed.
+fn paginate(output: &[String], lines: &mut impl Iterator<Item = io::Result<String>>) {
+    let interactive = io::stdin().is_terminal() && io::stdout().is_terminal();
+    let page = env::var("LINES")
+        .ok()
+        .and_then(|lines| lines.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_PAGE_LINES)
+        .saturating_sub(1)
+        .max(1);
+    for (index, line) in output.iter().enumerate() {
+        if interactive && index > 0 && index % page == 0 {
+            print!(
+                "{}",
+                "-- more, Enter to go on, q to stop --".color(Colors::BrightBlackFg)
+            );
+            let _ = io::stdout().flush();
+            match lines.next() {
+                Some(Ok(answer)) if answer.trim() != "q" => {}
+                _ => return,
+            }
+        }
+        println!("{}", line);
+    }
+}
+
+// `search PATTERN` and `/PATTERN`, lists the matching lines numbered for break /PATTERN/ N
+fn search(words: &str, lines: &mut impl Iterator<Item = io::Result<String>>) -> Result<(), String> {
+    let pattern = Pattern::new(words);
+    if pattern.text.is_empty() {
+        return Err("Usage: search PATTERN or /PATTERN".to_string());
+    }
+    if SOURCE.with(|source| source.borrow().is_empty()) {
+        return Err(no_source());
+    }
+    let found = search_source(&pattern);
+    if found.is_empty() {
+        return Err(format!("No line matches \"{}\".", pattern.typed));
+    }
+    let output: Vec<String> = found
+        .iter()
+        .enumerate()
+        .map(|(index, (file, line, text))| {
+            format!(
+                "{:>4} {} {}",
+                index + 1,
+                format!("{}:{}", file, line).color(Colors::BrightBlackFg),
+                highlight_source_line(text, Highlight::colored(color_stdout()))
+            )
+        })
+        .collect();
+    paginate(&output, lines);
+    Ok(())
+}
+
+// `break /PATTERN/ N`, the first instruction at or after the Nth line the pattern matches.
+// N can be left out when only one line matches.
+fn breakpoint_at_match(cpu: &Cpu, args: &str) -> Result<Breakpoint, String> {
+    let usage = || "Usage: break /PATTERN/ N".to_string();
+    let (words, rest) = args[1..].split_once('/').ok_or_else(usage)?;
+    let rest: Vec<&str> = rest.split_whitespace().collect();
+    let mut pattern = Pattern::new(words);
+    if rest.contains(&"-c") {
+        pattern = Pattern::new(&format!("{} -c", words));
+    }
+    let rest: Vec<&str> = rest.into_iter().filter(|&word| word != "-c").collect();
+    if SOURCE.with(|source| source.borrow().is_empty()) {
+        return Err(no_source());
+    }
+    let found = search_source(&pattern);
+    let number = match (rest.as_slice(), found.len()) {
+        (_, 0) => return Err(format!("No line matches \"{}\".", pattern.typed)),
+        ([], 1) => 1,
+        ([], count) => {
+            return Err(format!(
+                "{} lines match \"{}\", add which one, as numbered by search.",
+                count, pattern.typed
+            ))
+        }
+        ([number], _) => number
+            .parse::<usize>()
+            .map_err(|_| format!("\"{}\" is not a match number.", number))?,
+        _ => return Err(usage()),
+    };
+    let (file, line, _) = found.get(number.wrapping_sub(1)).ok_or_else(|| {
+        format!(
+            "There is no match {}, {} lines match \"{}\".",
+            number,
+            found.len(),
+            pattern.typed
+        )
+    })?;
+    cpu.positions
+        .iter()
+        .filter(|(_, (position_file, position_line))| {
+            position_file == file && position_line >= line
+        })
+        // Ties on one line go to global code first, then functions by name, then the lowest index
+        .min_by_key(|&(breakpoint, (_, position_line))| (*position_line, breakpoint))
+        .map(|(breakpoint, _)| breakpoint.clone())
+        .ok_or_else(|| format!("There is no instruction at or after {}:{}.", file, line))
+}
+
+// `info symbols [PATTERN]`, the labels, functions and constants of the retained source
+fn info(args: &[&str], lines: &mut impl Iterator<Item = io::Result<String>>) -> Result<(), String> {
+    let Some((&"symbols", words)) = args.split_first() else {
+        return Err("Usage: info symbols [PATTERN]".to_string());
+    };
+    let pattern = Pattern::new(&words.join(" "));
+    let files = SOURCE.with(|source| source.borrow().clone());
+    if files.is_empty() {
+        return Err(no_source());
+    }
+    let mut output = Vec::new();
+    for (file, contents) in &files {
+        for symbol in symbol_definitions(contents) {
+            if !pattern.matches(&symbol.name) {
+                continue;
+            }
+            let kind = match symbol.kind {
+                SymbolKind::Label => "label",
+                SymbolKind::Constant => "constant",
+                SymbolKind::Function => "function",
+            };
+            let scope = match &symbol.scope {
+                Some(function) => format!(" in {}", function),
+                None => String::new(),
+            };
+            output.push(format!(
+                "{:<9}{}{} {}",
+                kind,
+                symbol.name.color(Colors::CyanFg),
+                scope,
+                format!("{}:{}", file, symbol.line).color(Colors::BrightBlackFg)
+            ));
+        }
+    }
+    if output.is_empty() {
+        return Err(format!("No symbol matches \"{}\".", pattern.typed));
+    }
+    paginate(&output, lines);
+    Ok(())
+}
+
+fn warn(message: String) {
+    println!(
+        "{}",
+        format!("Warning: {}", message).color(Colors::YellowFg)
+    );
+}
+
+// A function as edit shows it, jump targets are instruction indices since labels are resolved
+fn function_source(cpu: &Cpu, name: &str) -> String {
+    let mut source = format!("{}\n", name);
+    for instruction in &cpu.functions[name] {
+        source.push_str(&format!("  {}\n", instruction));
+    }
+    source.push_str(".end\n");
+    source
+}
+
+// The new source of a function, from $EDITOR when it is set and stdin is a terminal, otherwise
+// typed as lines up to .end
+fn read_new_source(
+    name: &str,
+    current: &str,
+    lines: &mut impl Iterator<Item = io::Result<String>>,
+) -> Result<String, String> {
+    if let Some(editor) = env::var_os("EDITOR").filter(|_| io::stdin().is_terminal()) {
+        let path = env::temp_dir().join(format!("alice-{}{}.asm", std::process::id(), name));
+        fs::write(&path, current).map_err(|e| format!("Could not write {:?}: {}.", path, e))?;
+        let status = Command::new(&editor).arg(&path).status();
+        let source = fs::read_to_string(&path);
+        let _ = fs::remove_file(&path);
+        return match status {
+            Ok(status) if status.success() => source.map_err(|e| e.to_string()),
+            Ok(status) => Err(format!("The editor exited with {}.", status)),
+            Err(e) => Err(format!("Could not start {:?}: {}.", editor, e)),
+        };
+    }
+    print!("{}", current);
+    println!("Type the new body of {}, then .end:", name);
+    let mut source = format!("{}\n", name);
+    loop {
+        match lines.next() {
+            Some(Ok(line)) if line.trim() == ".end" => break,
+            Some(Ok(line)) => source.push_str(&format!("{}\n", line)),
+            _ => return Err("The input ended before .end.".to_string()),
+        }
+    }
+    source.push_str(".end\n");
+    Ok(source)
+}
+
+// Parses an edited function on its own. The other functions are declared empty after it so
+// CALLs to them still parse, constants of the original file are not known.
+fn parse_function(cpu: &Cpu, name: &str, source: &str) -> Result<Program, String> {
+    let mut source = source.to_string();
+    for other in cpu.functions.keys().filter(|other| *other != name) {
+        source.push_str(&format!("\n{}\n.end", other));
+    }
+    let program = parse_with_config(source, &declare_config()).map_err(|e| e.to_string())?;
+    if !program.instructions.is_empty() {
+        return Err(format!(
+            "Only {} can be edited, the new source has global code.",
+            name
+        ));
+    }
+    Ok(program)
+}
+
+// `edit .name`, the function keeps its old body if anything goes wrong
+fn edit_function(
+    cpu: &mut Cpu,
+    args: &[&str],
+    lines: &mut impl Iterator<Item = io::Result<String>>,
+) -> Result<(), String> {
+    let [name] = args else {
+        return Err("Usage: edit .name".to_string());
+    };
+    let name = format!(".{}", name.trim_start_matches('.'));
+    if !cpu.functions.contains_key(&name) {
+        return Err(format!("No function called {}.", name));
+    }
+    // replace_function checks this too, but then the edit would be thrown away
+    if cpu.function_in_use(&name) {
+        return Err(format!(
+            "{} is running or on the call stack, it can be edited once it has returned.",
+            name
+        ));
+    }
+    let source = read_new_source(&name, &function_source(cpu, &name), lines)?;
+    let program =
+        parse_function(cpu, &name, &source).map_err(|e| format!("{} {} is unchanged.", e, name))?;
+    cpu.replace_function(&name, &program)?;
+    println!(
+        "Replaced {}, {} instructions.",
+        name,
+        cpu.functions[&name].len()
+    );
+    Ok(())
+}
+
+// The assembler's message about a token of a command, which has no line number of its own
+fn command_error(error: ParseError) -> String {
+    error.to_string().replacen(" on line 0", "", 1)
+}
+
+// `set TARGET = VALUE`, the target a register, `[ADDR]` or `flags.NAME`, the value a number
+// as the assembler reads it
+fn set(cpu: &mut Cpu, args: &[&str]) -> Result<(), String> {
+    let usage = || "Usage: set REG|[ADDR]|flags.NAME = VALUE".to_string();
+    let line = args.join(" ");
+    let (target, value) = line.split_once('=').ok_or_else(usage)?;
+    let (target, value) = (target.trim(), value.trim());
+    if target.is_empty() || value.is_empty() || value.contains(char::is_whitespace) {
+        return Err(usage());
+    }
+    // parse_value reads a register as its index, a register is no value to set
+    let value = match parse_operand(value, 0) {
+        Ok(Operand::Register(_)) => Err(format!("\"{}\" is not a number.", value)),
+        _ => parse_value(value, 0).map_err(command_error),
+    }?;
+
+    if let Some(address) = target.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+        let address = parse_value(address.trim(), 0).map_err(command_error)? as usize;
+        if address >= cpu.memory.len() {
+            return Err(format!(
+                "Address {} is outside the {} memory cells.",
+                address,
+                cpu.memory.len()
+            ));
+        }
+        cpu.memory[address] = value;
+        println!("[{}]: {}", address, value.to_string().color(Colors::CyanFg));
+    } else if let Some(name) = target.strip_prefix("flags.") {
+        let flag = match name {
+            "zero" => &mut cpu.flags.zero,
+            "less" => &mut cpu.flags.less,
+            "greater" => &mut cpu.flags.greater,
+            "input_error" => &mut cpu.flags.input_error,
+            "overflow" => &mut cpu.flags.overflow,
+            "carry" => &mut cpu.flags.carry,
+            _ => {
+                return Err(format!(
+                    "No flag called {}, the flags are zero, less, greater, input_error, \
+                     overflow and carry.",
+                    name
+                ))
+            }
+        };
+        *flag = match value {
+            0 => false,
+            1 => true,
+            _ => return Err(format!("A flag is 0 or 1, not {}.", value)),
+        };
+        println!(
+            "flags.{}: {}",
+            name,
+            value.to_string().color(Colors::CyanFg)
+        );
+    } else {
+        let index = parse_register(target, 0).map_err(command_error)?;
+        cpu.set_register(index, value);
+        cpu.print_register(index, PrintFormat::Decimal);
+    }
+    Ok(())
+}
+
+// Executes one instruction, returns whether the program is still running afterwards
+fn step(cpu: &mut Cpu) -> bool {
+    match cpu.step() {
+        Ok(outcome) => outcome != StepOutcome::Halted,
+        Err(e) => {
+            report_runtime_error(cpu, &e);
+            false
+        }
+    }
+}
+
+// Runs to the next breakpoint, returns whether the program is still running afterwards
+fn continue_running(cpu: &mut Cpu) -> bool {
+    match cpu.run_until_breakpoint() {
+        Ok(RunOutcome::Breakpoint) => {
+            print_location(cpu, "breakpoint:");
+            true
+        }
+        Ok(RunOutcome::Halted) => false,
+        Err(e) => {
+            report_runtime_error(cpu, &e);
+            false
+        }
+    }
+}
+
+// Runs the loaded program under the prompt until it ends or the user quits
+pub fn debug(cpu: &mut Cpu) {
+    let stdin = io::stdin();
+    run_commands(cpu, &mut stdin.lock().lines());
+}
+
+// Reads commands from lines until the program ends, the user quits or the lines run out
+fn run_commands(cpu: &mut Cpu, lines: &mut impl Iterator<Item = io::Result<String>>) {
+    cpu.running = true;
+
+    println!("Type \"help\" for a list of commands.");
+    print_location(cpu, "next:");
+    while cpu.running {
+        print!("(debug) ");
+        let _ = io::stdout().flush();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => return, // stdin closed
+        };
+        if let Some(pattern) = line.trim_start().strip_prefix('/') {
+            let pattern = pattern.trim_end().strip_suffix('/').unwrap_or(pattern);
+            if let Err(e) = search(pattern, lines) {
+                warn(e);
+            }
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = words.split_first() else {
+            continue;
+        };
+
+        match command {
+            "step" | "s" => {
+                print_location(cpu, "step:");
+                if !step(cpu) {
+                    println!("Program ended.");
+                }
+            }
+            "continue" | "c" => {
+                if !continue_running(cpu) {
+                    println!("Program ended.");
+                }
+            }
+            "break" | "b" => match parse_breakpoint(cpu, args) {
+                Ok(breakpoint) => {
+                    cpu.breakpoints.insert(breakpoint);
+                }
+                Err(e) => warn(e),
+            },
+            "delete" | "d" => match parse_breakpoint(cpu, args) {
+                Ok(breakpoint) => {
+                    if !cpu.clear_breakpoint(&breakpoint) {
+                        warn("There is no breakpoint there.".to_string());
+                    }
+                }
+                Err(e) => warn(e),
+            },
+            "regs" => {
+                for (i, &value) in cpu.registers.iter().enumerate() {
+                    println!("R{}: {}", i, value.to_string().color(Colors::CyanFg));
+                }
+            }
+            "print" | "p" => match args {
+                [register] => match parse_register(register, 0) {
+                    Ok(index) => cpu.print_register(index, PrintFormat::Decimal),
+                    Err(_) => warn(format!("\"{}\" is not a register.", register)),
+                },
+                _ => warn("Usage: print REG".to_string()),
+            },
+            "mem" => {
+                let address = args.first().and_then(|a| a.parse::<usize>().ok());
+                let count = args
+                    .get(1)
+                    .and_then(|c| c.parse::<usize>().ok())
+                    .unwrap_or(1);
+                match address {
+                    Some(address) if address < cpu.memory.len() => {
+                        let end = (address + count).min(cpu.memory.len());
+                        for (offset, value) in cpu.memory[address..end].iter().enumerate() {
+                            println!(
+                                "[{}]: {}",
+                                address + offset,
+                                value.to_string().color(Colors::CyanFg)
+                            );
+                        }
+                    }
+                    Some(address) => warn(format!(
+                        "Address {} is outside the {} memory cells.",
+                        address,
+                        cpu.memory.len()
+                    )),
+                    None => warn("Usage: mem ADDR [COUNT]".to_string()),
+                }
+            }
+            "search" => {
+                if let Err(e) = search(&args.join(" "), lines) {
+                    warn(e);
+                }
+            }
+            "set" => {
+                if let Err(e) = set(cpu, args) {
+                    warn(e);
+                }
+            }
+            "info" => {
+                if let Err(e) = info(args, lines) {
+                    warn(e);
+                }
+            }
+            "edit" => {
+                if let Err(e) = edit_function(cpu, args, lines) {
+                    warn(e);
+                }
+            }
+            "help" | "h" => println!("{}", HELP),
+            "quit" | "q" => return,
+            _ => warn(format!(
+                "Unknown command \"{}\", type \"help\" for a list.",
+                command
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            debug: false,
+            ..Config::default()
+        }
+    }
+
+    // A Cpu with source from a file of its own loaded, the file retained for search
+    fn debugged(test: &str, source: &str) -> (Cpu, SharedBuffer, String) {
+        let directory = env::temp_dir().join(format!("alice-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("main.asm").to_string_lossy().into_owned();
+        fs::write(&path, source).unwrap();
+        let (spliced, map) = read_with_includes(&path, SourceEncoding::Utf8).unwrap();
+        let mut program = parse_with_includes(spliced.clone(), &map, &config()).unwrap();
+        program.positions = map.positions(&spliced);
+        let _ = fs::remove_dir_all(&directory);
+        retain_source(vec![(path.clone(), source.to_string())]);
+
+        let output = SharedBuffer::default();
+        let mut cpu = Cpu::new(config());
+        cpu.set_output(Box::new(output.clone()));
+        cpu.load_program(&program);
+        cpu.running = true;
+        (cpu, output, path)
+    }
+
+    // Lines typed at the prompt
+    fn typed(lines: &[&str]) -> impl Iterator<Item = io::Result<String>> {
+        lines
+            .iter()
+            .map(|line| Ok(line.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    const LOOPS: &str = "mov a, 2\nouter: dec a\ninnerloop: nop\ncmp a, 0\njne outer\n\
+                         call .tail\nhalt\n.tail\ninnerloop: inc b\nret\n.end\n";
+
+    #[test]
+    fn break_at_the_nth_match_of_a_search() {
+        let (cpu, _, path) = debugged("search-break", LOOPS);
+        // The two innerloop lines are 3 and 9
+        assert_eq!(breakpoint_at_match(&cpu, "/innerloop/ 1"), Ok((None, 2)));
+        assert_eq!(
+            breakpoint_at_match(&cpu, "/innerloop/ 2"),
+            Ok((Some(".tail".to_string()), 0))
+        );
+        assert_eq!(breakpoint_at_match(&cpu, "/call*tail/"), Ok((None, 5)));
+        assert_eq!(
+            breakpoint_at_match(&cpu, "/innerloop/"),
+            Err("2 lines match \"innerloop\", add which one, as numbered by search.".to_string())
+        );
+        assert_eq!(
+            breakpoint_at_match(&cpu, "/innerloop/ 3"),
+            Err("There is no match 3, 2 lines match \"innerloop\".".to_string())
+        );
+        // Matching case, INNERLOOP is nowhere
+        assert_eq!(
+            breakpoint_at_match(&cpu, "/INNERLOOP/ -c"),
+            Err("No line matches \"INNERLOOP\".".to_string())
+        );
+        assert!(path.ends_with("main.asm"));
+    }
+
+    #[test]
+    fn a_search_without_matches_says_so() {
+        let _ = debugged("search-none", LOOPS);
+        assert_eq!(
+            search("outerloop", &mut typed(&[])),
+            Err("No line matches \"outerloop\".".to_string())
+        );
+        assert_eq!(search("innerloop", &mut typed(&[])), Ok(()));
+        assert_eq!(
+            search("", &mut typed(&[])),
+            Err("Usage: search PATTERN or /PATTERN".to_string())
+        );
+    }
+
+    #[test]
+    fn a_pattern_globs_and_ignores_case_unless_asked() {
+        assert!(Pattern::new("LOAD*bx").matches("  load ax, 5\n  add bx"));
+        assert!(Pattern::new("l?ad").matches("LEAD"));
+        assert!(!Pattern::new("LOAD -c").matches("load"));
+        assert!(Pattern::new("-c Load").matches("  Load"));
+    }
+
+    // b is 1 if a is not 0 at the CMP, 2 if it is
+    const BRANCH: &str = "mov a, 1\ncmp a, 0\nje zero\nmov b, 1\nhalt\nzero: mov b, 2\nhalt\n";
+
+    fn scripted(commands: &[&str]) -> Cpu {
+        let (mut cpu, _, _) = debugged("set", BRANCH);
+        run_commands(&mut cpu, &mut typed(commands));
+        cpu
+    }
+
+    #[test]
+    fn setting_a_register_before_a_branch_takes_the_other_way() {
+        assert_eq!(scripted(&["continue"]).register(1), 1);
+        let patched = scripted(&["break 1", "continue", "set a = 0", "continue"]);
+        assert_eq!(patched.register(1), 2);
+        let flagged = scripted(&["break 2", "continue", "set flags.zero = 1", "continue"]);
+        assert_eq!(flagged.register(1), 2);
+    }
+
+    #[test]
+    fn set_takes_hex_and_negative_values_and_memory_cells() {
+        let (mut cpu, _, _) = debugged("set-values", BRANCH);
+        set(&mut cpu, &["r3", "=", "0xFF"]).unwrap();
+        set(&mut cpu, &["[0x10]", "=", "-5"]).unwrap();
+        set(&mut cpu, &["flags.carry=1"]).unwrap();
+        assert_eq!(cpu.register(3), 0xFF);
+        assert_eq!(cpu.memory[0x10], (-5i16) as u16);
+        assert!(cpu.flags.carry);
+    }
+
+    #[test]
+    fn set_rejects_bad_targets_and_values_as_the_assembler_does() {
+        let (mut cpu, _, _) = debugged("set-errors", BRANCH);
+        cpu.memory.truncate(256);
+        let cases = [
+            ("zz = 1", "\"zz\" is not a register. [E004]".to_string()),
+            (
+                "a = 70000",
+                "Operand \"70000\" does not fit in 16 bits. [E003]".to_string(),
+            ),
+            ("a = 1x", "Invalid operand \"1x\". [E002]".to_string()),
+            ("a = b", "\"b\" is not a number.".to_string()),
+            (
+                "[70000] = 1",
+                "Operand \"70000\" does not fit in 16 bits. [E003]".to_string(),
+            ),
+            (
+                "[0x100] = 1",
+                "Address 256 is outside the 256 memory cells.".to_string(),
+            ),
+            ("flags.carry = 2", "A flag is 0 or 1, not 2.".to_string()),
+            (
+                "flags.sign = 1",
+                "No flag called sign, the flags are zero, less, greater, input_error, overflow \
+                 and carry."
+                    .to_string(),
+            ),
+            (
+                "a 5",
+                "Usage: set REG|[ADDR]|flags.NAME = VALUE".to_string(),
+            ),
+        ];
+        for (command, message) in cases {
+            let args: Vec<&str> = command.split_whitespace().collect();
+            assert_eq!(set(&mut cpu, &args), Err(message), "{}", command);
+        }
+        assert!(cpu.registers.iter().all(|&value| value == 0));
+    }
+
+    const TWICE: &str = "call .show\ncall .show\nhalt\n.show\nprint a\nret\n.end\n";
+
+    #[test]
+    fn an_edited_function_runs_from_its_next_call() {
+        let (mut cpu, output, _) = debugged("edit", TWICE);
+        cpu.set_breakpoint(1);
+        assert_eq!(cpu.run_until_breakpoint().unwrap(), RunOutcome::Breakpoint);
+        edit_function(
+            &mut cpu,
+            &[".show"],
+            &mut typed(&["mov a, 7", "print a", ".end"]),
+        )
+        .unwrap();
+        assert_eq!(cpu.run_until_breakpoint().unwrap(), RunOutcome::Halted);
+        assert_eq!(output.text(), "ax: 0\nax: 7\n");
+    }
+
+    #[test]
+    fn a_function_on_the_call_stack_cannot_be_edited() {
+        let (mut cpu, _, _) = debugged("edit-paused", TWICE);
+        cpu.set_function_breakpoint(".show", 1);
+        assert_eq!(cpu.run_until_breakpoint().unwrap(), RunOutcome::Breakpoint);
+        assert_eq!(
+            edit_function(&mut cpu, &["show"], &mut typed(&["nop", ".end"])),
+            Err(
+                ".show is running or on the call stack, it can be edited once it has returned."
+                    .to_string()
+            )
+        );
+        assert_eq!(cpu.functions[".show"].len(), 2);
+        // A body that does not parse leaves the old one as well
+        cpu.set_breakpoint(1);
+        cpu.run_until_breakpoint().unwrap();
+        let error =
+            edit_function(&mut cpu, &[".show"], &mut typed(&["frob a", ".end"])).unwrap_err();
+        assert!(error.ends_with(".show is unchanged."), "{}", error);
+        assert_eq!(cpu.functions[".show"].len(), 2);
+    }
+}
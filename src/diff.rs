@@ -0,0 +1,264 @@
+use crate::*;
+use std::collections::{BTreeSet, HashMap};
+
+// Instruction level diff of two programs. Both sides are compared after parsing, so comments,
+// formatting and label names make no difference as long as every jump still goes to the same place.
+
+// An instruction of one of the programs, or the `.name` line starting a function
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffEntry {
+    pub function: Option<String>, // None for global code
+    pub index: usize,             // Position in the function or the global code
+    pub line: Option<usize>,      // Source line, None when diffing a binary
+    pub text: String,             // Disassembly, what the two sides are compared by
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffOp {
+    Removed(DiffEntry),
+    Added(DiffEntry),
+    Changed(DiffEntry, DiffEntry), // old, new
+}
+
+// Functions of a program in definition order
+fn sorted_functions(program: &Program) -> Vec<(&String, &Function)> {
+    let mut functions: Vec<(&String, &Function)> = program.functions.iter().collect();
+    functions.sort_by_key(|&(name, function)| (function.line, name));
+    functions
+}
+
+fn body_entries(
+    function: Option<&String>,
+    body: &[Instruction],
+    lines: &HashMap<Option<String>, Vec<usize>>,
+) -> Vec<DiffEntry> {
+    let scope_lines = lines.get(&function.cloned());
+    body.iter()
+        .enumerate()
+        .map(|(index, instruction)| DiffEntry {
+            function: function.cloned(),
+            index,
+            line: scope_lines.and_then(|lines| lines.get(index).copied()),
+            text: instruction.to_string(),
+        })
+        .collect()
+}
+
+// The body of a function with its `.name` line in front, so a renamed function shows up
+fn function_entries(
+    name: &String,
+    function: &Function,
+    lines: &HashMap<Option<String>, Vec<usize>>,
+) -> Vec<DiffEntry> {
+    let header = DiffEntry {
+        function: Some(name.clone()),
+        index: 0,
+        line: Some(function.line).filter(|&line| line > 0),
+        text: name.clone(),
+    };
+    std::iter::once(header)
+        .chain(body_entries(Some(name), &function.body, lines))
+        .collect()
+}
+
+/// Aligns two instruction sequences on their longest common subsequence. Removed and added
+/// instructions between the same two matches are paired up as changes.
+pub fn align(old: &[DiffEntry], new: &[DiffEntry]) -> Vec<DiffOp> {
+    // common[i][j] is the longest common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i].text == new[j].text {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut ops = Vec::new();
+    let (mut removed, mut added) = (Vec::new(), Vec::new());
+    let flush =
+        |ops: &mut Vec<DiffOp>, removed: &mut Vec<DiffEntry>, added: &mut Vec<DiffEntry>| {
+            let paired = removed.len().min(added.len());
+            let rest_removed = removed.split_off(paired);
+            let rest_added = added.split_off(paired);
+            ops.extend(
+                removed
+                    .drain(..)
+                    .zip(added.drain(..))
+                    .map(|(old, new)| DiffOp::Changed(old, new)),
+            );
+            ops.extend(rest_removed.into_iter().map(DiffOp::Removed));
+            ops.extend(rest_added.into_iter().map(DiffOp::Added));
+        };
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i].text == new[j].text {
+            flush(&mut ops, &mut removed, &mut added);
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+            removed.push(old[i].clone());
+            i += 1;
+        } else {
+            added.push(new[j].clone());
+            j += 1;
+        }
+    }
+    flush(&mut ops, &mut removed, &mut added);
+    ops
+}
+
+/// Differences between two programs. The lines come from instruction_lines of each source,
+/// empty maps leave the entries without line numbers. With by_function each function is only
+/// aligned with the function of the same name, otherwise everything is one sequence.
+pub fn diff_programs(
+    old: &Program,
+    old_lines: &HashMap<Option<String>, Vec<usize>>,
+    new: &Program,
+    new_lines: &HashMap<Option<String>, Vec<usize>>,
+    by_function: bool,
+) -> Vec<DiffOp> {
+    let entries = |program: &Program, lines| {
+        let mut entries = body_entries(None, &program.instructions, lines);
+        for (name, function) in sorted_functions(program) {
+            entries.extend(function_entries(name, function, lines));
+        }
+        entries
+    };
+    if !by_function {
+        return align(&entries(old, old_lines), &entries(new, new_lines));
+    }
+
+    let mut ops = align(
+        &body_entries(None, &old.instructions, old_lines),
+        &body_entries(None, &new.instructions, new_lines),
+    );
+    // Functions of the old program first, in its order, then the ones only the new one has
+    let mut names: Vec<&String> = sorted_functions(old)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    let known: BTreeSet<&String> = names.iter().copied().collect();
+    names.extend(
+        sorted_functions(new)
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| !known.contains(name)),
+    );
+    for name in names {
+        let side = |program: &Program, lines| {
+            program
+                .functions
+                .get(name)
+                .map(|function| function_entries(name, function, lines))
+                .unwrap_or_default()
+        };
+        ops.extend(align(&side(old, old_lines), &side(new, new_lines)));
+    }
+    ops
+}
+
+// Where an entry is, `line 4` or `.f 2` for a binary without source lines
+fn location(entry: &DiffEntry) -> String {
+    match entry.line {
+        Some(line) => format!("line {}", line),
+        None => format!(
+            "{} {}",
+            entry.function.as_deref().unwrap_or("global"),
+            entry.index
+        ),
+    }
+}
+
+/// One line per difference, `-` removed, `+` added and `~` changed.
+pub fn format_diff(ops: &[DiffOp]) -> String {
+    let mut out = String::new();
+    for op in ops {
+        let line = match op {
+            DiffOp::Removed(old) => format!("- old {}: {}", location(old), old.text)
+                .color(Colors::RedFg)
+                .to_string(),
+            DiffOp::Added(new) => format!("+ new {}: {}", location(new), new.text)
+                .color(Colors::GreenFg)
+                .to_string(),
+            DiffOp::Changed(old, new) => format!(
+                "~ old {}, new {}: {} -> {}",
+                location(old),
+                location(new),
+                old.text,
+                new.text
+            )
+            .color(Colors::YellowFg)
+            .to_string(),
+        };
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(old: &str, new: &str, by_function: bool) -> Vec<DiffOp> {
+        let config = Config {
+            debug: false,
+            ..Config::default()
+        };
+        let parse = |source: &str| parse_with_config(source.to_string(), &config).unwrap();
+        diff_programs(
+            &parse(old),
+            &instruction_lines(old),
+            &parse(new),
+            &instruction_lines(new),
+            by_function,
+        )
+    }
+
+    // What each difference is, where it is on both sides and the instruction text
+    fn summary(ops: &[DiffOp]) -> Vec<(char, Option<usize>, Option<usize>, String)> {
+        ops.iter()
+            .map(|op| match op {
+                DiffOp::Removed(old) => ('-', old.line, None, old.text.clone()),
+                DiffOp::Added(new) => ('+', None, new.line, new.text.clone()),
+                DiffOp::Changed(old, new) => ('~', old.line, new.line, new.text.clone()),
+            })
+            .collect()
+    }
+
+    const PROGRAM: &str = "mov a, 5\nloop: dec a\ncmp a, 0\njne loop\ncall .show\nhalt\n\
+                           .show\nprint a\nret\n.end\n";
+
+    #[test]
+    fn formatting_comments_and_label_names_are_not_differences() {
+        let reformatted = "; counts down\nMOV ax, 5\n\nagain:\n  DEC ax ; step\n  CMP ax, 0\n  \
+                           JNE again\nCALL .show\nHALT\n.show\nPRINT ax\nRET\n.end\n";
+        assert!(diff(PROGRAM, reformatted, false).is_empty());
+        assert!(diff(PROGRAM, reformatted, true).is_empty());
+    }
+
+    #[test]
+    fn an_operand_change_is_one_change_at_both_lines() {
+        let changed = PROGRAM.replace("mov a, 5", "\nmov a, 6");
+        assert_eq!(
+            summary(&diff(PROGRAM, &changed, false)),
+            [('~', Some(1), Some(2), Instruction::MOV(0, 6).to_string())]
+        );
+    }
+
+    #[test]
+    fn an_added_function_is_added_with_its_name() {
+        let added = format!("{}.extra\ninc a\nret\n.end\n", PROGRAM);
+        assert_eq!(
+            summary(&diff(PROGRAM, &added, true)),
+            [
+                ('+', None, Some(11), ".extra".to_string()),
+                ('+', None, Some(12), Instruction::INC(0).to_string()),
+                ('+', None, Some(13), Instruction::RET.to_string()),
+            ]
+        );
+    }
+}
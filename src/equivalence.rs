@@ -0,0 +1,119 @@
+use crate::*;
+
+// Differential testing of two programs, for example a submission against a reference solution.
+// Both run from the same starting registers, what they leave behind in the observed registers
+// and everything they PRINT has to match.
+
+// What one program left behind after a run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Observation {
+    pub registers: Vec<u16>, // The observed registers, in the order they were asked for
+    pub output: String,      // Everything PRINT wrote
+    pub error: Option<RuntimeErrorKind>, // Why the program stopped, if it failed
+}
+
+// The first starting point the two programs disagree on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub case: usize, // Index into the cases
+    pub left: Observation,
+    pub right: Observation,
+}
+
+// Runs program from the registers given as (register, value) pairs, the rest start at 0
+pub fn observe(
+    program: &Program,
+    config: &Config,
+    registers: &[(u16, u16)],
+    observed: &[u16],
+) -> Observation {
+    let output = SharedBuffer::default();
+    let mut cpu = Cpu::new(config.clone());
+    cpu.set_output(Box::new(output.clone()));
+    cpu.load_program(program);
+    for &(register, value) in registers {
+        cpu.set_register(register, value);
+    }
+    let error = cpu.run().err().map(|error| error.reason);
+    Observation {
+        registers: observed
+            .iter()
+            .map(|&register| cpu.register(register))
+            .collect(),
+        output: output.text(),
+        error,
+    }
+}
+
+/// Runs both programs on every case and returns the first one where they differ, None if they
+/// agree on all of them. Failing counts as an outcome, two programs that stop with the same error
+/// are equivalent on that case. max_instructions in config keeps a program that never halts from
+/// hanging the comparison.
+pub fn check_equivalence(
+    left: &Program,
+    right: &Program,
+    config: &Config,
+    cases: &[Vec<(u16, u16)>],
+    observed: &[u16],
+) -> Option<Mismatch> {
+    for (case, registers) in cases.iter().enumerate() {
+        let left = observe(left, config, registers, observed);
+        let right = observe(right, config, registers, observed);
+        if left != right {
+            return Some(Mismatch { case, left, right });
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            debug: false,
+            ..Config::default()
+        }
+    }
+
+    fn parse(source: &str) -> Program {
+        parse_with_config(source.to_string(), &config()).unwrap()
+    }
+
+    // a * b into c, starting from a and b
+    const CASES: [[(u16, u16); 2]; 3] = [[(0, 3), (1, 4)], [(0, 0), (1, 9)], [(0, 7), (1, 1)]];
+
+    fn cases() -> Vec<Vec<(u16, u16)>> {
+        CASES.iter().map(|case| case.to_vec()).collect()
+    }
+
+    #[test]
+    fn two_ways_of_multiplying_are_equivalent() {
+        let multiply = parse("movr c, a\nmul c, b\nprint c\nhalt\n");
+        let add_up = parse(
+            "mov c, 0\ncmp b, 0\nje done\nagain: add c, a\ndec b\ncmp b, 0\njne again\n\
+             done: print c\nhalt\n",
+        );
+        assert_eq!(
+            check_equivalence(&multiply, &add_up, &config(), &cases(), &[2]),
+            None
+        );
+    }
+
+    #[test]
+    fn a_wrong_answer_is_reported_with_its_case() {
+        let multiply = parse("movr c, a\nmul c, b\nprint c\nhalt\n");
+        // Off by one, it adds a once too often
+        let buggy = parse(
+            "mov c, 0\nagain: add c, a\ncmp b, 0\nje done\ndec b\njmp again\ndone: print c\nhalt\n",
+        );
+        let mismatch = check_equivalence(&multiply, &buggy, &config(), &cases(), &[2]).unwrap();
+        assert_eq!(mismatch.case, 0);
+        assert_eq!(
+            (mismatch.left.registers, mismatch.right.registers),
+            (vec![12], vec![15])
+        );
+        assert_eq!(mismatch.left.output, "cx: 12\n");
+    }
+}
@@ -0,0 +1,713 @@
+use crate::*;
+use std::fmt;
+use std::io;
+
+// The escapes strings and characters accept, for the error messages
+const ESCAPES: &str = "\\n, \\t, \\r, \\0, \\\\, \\', \\\" and \\xHH";
+
+// Errors produced while reading and parsing assembly. Line numbers start at 1.
+#[derive(Debug)]
+pub enum ParseError {
+    UnknownInstruction {
+        line: usize,
+        token: String,
+    },
+    InvalidOperand {
+        line: usize,
+        token: String,
+    },
+    OperandOutOfRange {
+        line: usize,
+        token: String,
+    },
+    InvalidRegister {
+        line: usize,
+        token: String,
+    },
+    OperandOutOfLimit {
+        line: usize,
+        limit: OperandLimit,
+        value: u16,
+    },
+    MissingOperand {
+        line: usize,
+        instruction: String,
+    },
+    WrongOperandCount {
+        line: usize,
+        instruction: String,
+        expected: usize,
+        found: usize,
+    },
+    UnmatchedEnd {
+        line: usize,
+    },
+    NestedFunction {
+        line: usize,
+    },
+    UnterminatedFunction {
+        line: usize,
+        name: String,
+    },
+    UnterminatedData {
+        line: usize,
+    },
+    InvalidString {
+        line: usize,
+        string: String,
+    },
+    InvalidCharacter {
+        line: usize,
+        token: String,
+    },
+    RegisterRangeOutOfBounds {
+        line: usize,
+        start: u16,
+        count: u16,
+    },
+    DuplicateLabel {
+        line: usize,
+        label: String,
+    },
+    UndefinedLabel {
+        line: usize,
+        label: String,
+    },
+    UndefinedFunction {
+        line: usize,
+        name: String,
+    },
+    DuplicateConstant {
+        line: usize,
+        name: String,
+        first_line: usize,
+    },
+    InvalidConstantName {
+        line: usize,
+        name: String,
+    },
+    ReservedName {
+        line: usize,
+        kind: String,     // label, function or constant
+        name: String,     // As written, functions with their dot
+        reserved: String, // What else it names, an instruction or a register
+    },
+    UndefinedConstant {
+        line: usize,
+        name: String,
+    },
+    InvalidExpression {
+        line: usize,
+        expression: String,
+    },
+    ConstantDivideByZero {
+        line: usize,
+    },
+    DivisionByZero {
+        line: usize,
+        instruction: String,
+    },
+    IoError {
+        path: String,
+        error: io::Error,
+    },
+    Unencodable(EncodeError), // Parsed, but cannot be stored in a binary, see assemble
+    InvalidUtf8 {
+        path: String,
+        line: usize,
+        column: usize,
+        byte: u8,
+    },
+    IncludeNotFound {
+        line: usize,
+        path: String,
+        error: io::Error,
+    },
+    IncludeCycle {
+        chain: Vec<String>, // Files from the one that starts the cycle back to it
+    },
+    NotExported {
+        line: usize,
+        name: String,
+        file: String, // The file that defines it without exporting it
+    },
+    DuplicateExport {
+        line: usize,
+        name: String,
+        file: String, // The file that exports it first
+    },
+    UndefinedExport {
+        line: usize,
+        name: String,
+    },
+    // Data placed by a .data block on a cell an earlier line already placed data on
+    DataOverlap {
+        line: usize,
+        address: usize,
+        first_line: usize,
+    },
+    // An error in a program made of several files, its line is a line of file
+    Located {
+        file: String,
+        error: Box<ParseError>,
+    },
+}
+
+impl ParseError {
+    // The line an error is on, None for errors about a whole file or already located in one
+    pub fn line_mut(&mut self) -> Option<&mut usize> {
+        match self {
+            ParseError::UnknownInstruction { line, .. }
+            | ParseError::InvalidOperand { line, .. }
+            | ParseError::OperandOutOfRange { line, .. }
+            | ParseError::InvalidRegister { line, .. }
+            | ParseError::MissingOperand { line, .. }
+            | ParseError::WrongOperandCount { line, .. }
+            | ParseError::UnmatchedEnd { line }
+            | ParseError::NestedFunction { line }
+            | ParseError::UnterminatedFunction { line, .. }
+            | ParseError::UnterminatedData { line }
+            | ParseError::InvalidString { line, .. }
+            | ParseError::InvalidCharacter { line, .. }
+            | ParseError::OperandOutOfLimit { line, .. }
+            | ParseError::RegisterRangeOutOfBounds { line, .. }
+            | ParseError::DuplicateLabel { line, .. }
+            | ParseError::UndefinedLabel { line, .. }
+            | ParseError::UndefinedFunction { line, .. }
+            | ParseError::DuplicateConstant { line, .. }
+            | ParseError::InvalidConstantName { line, .. }
+            | ParseError::ReservedName { line, .. }
+            | ParseError::UndefinedConstant { line, .. }
+            | ParseError::InvalidExpression { line, .. }
+            | ParseError::ConstantDivideByZero { line }
+            | ParseError::DivisionByZero { line, .. }
+            | ParseError::IncludeNotFound { line, .. }
+            | ParseError::NotExported { line, .. }
+            | ParseError::DuplicateExport { line, .. }
+            | ParseError::DataOverlap { line, .. }
+            | ParseError::UndefinedExport { line, .. } => Some(line),
+            ParseError::IoError { .. }
+            | ParseError::InvalidUtf8 { .. }
+            | ParseError::IncludeCycle { .. }
+            | ParseError::Unencodable(_)
+            | ParseError::Located { .. } => None,
+        }
+    }
+}
+
+impl ParseError {
+    /// Stable code of the error, shown after the message and explained by `--explain CODE`.
+    /// An error located in an included file has the code of the error itself.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseError::UnknownInstruction { .. } => "E001",
+            ParseError::InvalidOperand { .. } => "E002",
+            ParseError::OperandOutOfRange { .. } => "E003",
+            ParseError::InvalidRegister { .. } => "E004",
+            ParseError::OperandOutOfLimit { .. } => "E005",
+            ParseError::MissingOperand { .. } => "E006",
+            ParseError::WrongOperandCount { .. } => "E007",
+            ParseError::UnmatchedEnd { .. } => "E008",
+            ParseError::NestedFunction { .. } => "E009",
+            ParseError::UnterminatedFunction { .. } => "E010",
+            ParseError::UnterminatedData { .. } => "E011",
+            ParseError::InvalidString { .. } => "E012",
+            ParseError::InvalidCharacter { .. } => "E013",
+            ParseError::RegisterRangeOutOfBounds { .. } => "E014",
+            ParseError::DuplicateLabel { .. } => "E015",
+            ParseError::UndefinedLabel { .. } => "E016",
+            ParseError::UndefinedFunction { .. } => "E017",
+            ParseError::DuplicateConstant { .. } => "E018",
+            ParseError::InvalidConstantName { .. } => "E019",
+            ParseError::ReservedName { .. } => "E020",
+            ParseError::UndefinedConstant { .. } => "E021",
+            ParseError::InvalidExpression { .. } => "E022",
+            ParseError::ConstantDivideByZero { .. } => "E023",
+            ParseError::DivisionByZero { .. } => "E024",
+            ParseError::IoError { .. } => "E025",
+            ParseError::Unencodable(_) => "E026",
+            ParseError::InvalidUtf8 { .. } => "E027",
+            ParseError::IncludeNotFound { .. } => "E028",
+            ParseError::IncludeCycle { .. } => "E029",
+            ParseError::NotExported { .. } => "E030",
+            ParseError::DuplicateExport { .. } => "E031",
+            ParseError::UndefinedExport { .. } => "E032",
+            ParseError::DataOverlap { .. } => "E033",
+            ParseError::Located { error, .. } => error.code(),
+        }
+    }
+
+    // The message without the code
+    fn fmt_message(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnknownInstruction { line, token } => {
+                write!(f, "Unknown instruction \"{}\" on line {}.", token, line)
+            }
+            ParseError::InvalidOperand { line, token } => {
+                write!(f, "Invalid operand \"{}\" on line {}.", token, line)
+            }
+            ParseError::OperandOutOfRange { line, token } => write!(
+                f,
+                "Operand \"{}\" does not fit in 16 bits on line {}.",
+                token, line
+            ),
+            ParseError::InvalidRegister { line, token } => {
+                write!(f, "\"{}\" is not a register on line {}.", token, line)
+            }
+            ParseError::MissingOperand { line, instruction } => {
+                write!(f, "{} is missing an operand on line {}.", instruction, line)
+            }
+            ParseError::WrongOperandCount {
+                line,
+                instruction,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} takes {} operands but {} were given on line {}.",
+                instruction, expected, found, line
+            ),
+            ParseError::UnmatchedEnd { line } => {
+                write!(f, ".end without a corresponding function on line {}.", line)
+            }
+            ParseError::NestedFunction { line } => write!(
+                f,
+                "Nested function definitions are not allowed on line {}.",
+                line
+            ),
+            ParseError::UnterminatedFunction { line, name } => {
+                write!(f, "Function {} defined on line {} has no .end.", name, line)
+            }
+            ParseError::UnterminatedData { line } => {
+                write!(f, "The .data block on line {} has no .end.", line)
+            }
+            ParseError::RegisterRangeOutOfBounds { line, start, count } => write!(
+                f,
+                "{} registers starting at {} run past r{} on line {}.",
+                count,
+                register_name(*start as usize),
+                REGISTER_COUNT - 1,
+                line
+            ),
+            ParseError::InvalidString { line, string } => write!(
+                f,
+                "Invalid string {} on line {}, strings are ASCII in double quotes with the escapes {}.",
+                string, line, ESCAPES
+            ),
+            ParseError::InvalidCharacter { line, token } => write!(
+                f,
+                "Invalid character {} on line {}, characters are one ASCII character or one of the escapes {} in single quotes.",
+                token, line, ESCAPES
+            ),
+            ParseError::OperandOutOfLimit { line, limit, value } => {
+                write!(f, "{} on line {}.", limit.message(*value), line)
+            }
+            ParseError::DuplicateLabel { line, label } => {
+                write!(f, "Duplicate label \"{}\" on line {}.", label, line)
+            }
+            ParseError::UndefinedLabel { line, label } => {
+                write!(f, "Undefined label \"{}\" on line {}.", label, line)
+            }
+            ParseError::UndefinedFunction { line, name } => {
+                write!(f, "CALL to undefined function {} on line {}.", name, line)
+            }
+            ParseError::DuplicateConstant {
+                line,
+                name,
+                first_line,
+            } => write!(
+                f,
+                "Constant {} on line {} is already defined on line {}.",
+                name, line, first_line
+            ),
+            ParseError::ReservedName {
+                line,
+                kind,
+                name,
+                reserved,
+            } => write!(
+                f,
+                "The {} \"{}\" on line {} is also {}, rename it, for example to \"{}_\".",
+                kind, name, line, reserved, name
+            ),
+            ParseError::InvalidConstantName { line, name } => {
+                write!(
+                    f,
+                    "\"{}\" cannot be a constant name on line {}.",
+                    name, line
+                )
+            }
+            ParseError::UndefinedConstant { line, name } => {
+                write!(f, "Undefined constant \"{}\" on line {}.", name, line)
+            }
+            ParseError::InvalidExpression { line, expression } => {
+                write!(f, "Invalid expression \"{}\" on line {}.", expression, line)
+            }
+            ParseError::ConstantDivideByZero { line } => write!(
+                f,
+                "Division by zero in a constant expression on line {}.",
+                line
+            ),
+            ParseError::DivisionByZero { line, instruction } => {
+                write!(f, "{} by zero on line {}.", instruction, line)
+            }
+            ParseError::IoError { path, error } => {
+                write!(f, "Could not access file '{}': {}.", path, error)
+            }
+            ParseError::IncludeNotFound { line, path, error } => {
+                write!(f, "Could not include '{}' on line {}: {}.", path, line, error)
+            }
+            ParseError::IncludeCycle { chain } => {
+                write!(f, "Circular include: {}.", chain.join(" -> "))
+            }
+            ParseError::NotExported { line, name, file } => write!(
+                f,
+                "{} on line {} exists but is not exported from {}, add `.export {}` there to use it from another file.",
+                name, line, file, name
+            ),
+            ParseError::DuplicateExport { line, name, file } => write!(
+                f,
+                "{} on line {} is already exported from {}, only one file can export a name.",
+                name, line, file
+            ),
+            ParseError::UndefinedExport { line, name } => write!(
+                f,
+                "Cannot export {} on line {}, this file does not define it.",
+                name, line
+            ),
+            ParseError::DataOverlap {
+                line,
+                address,
+                first_line,
+            } => write!(
+                f,
+                "Data on line {} overlaps the data placed on line {} at address {}.",
+                line, first_line, address
+            ),
+            ParseError::Located { file, error } => write!(f, "{}: {}", file, error),
+            ParseError::Unencodable(error) => write!(f, "{}", error),
+            ParseError::InvalidUtf8 {
+                path,
+                line,
+                column,
+                byte,
+            } => write!(
+                f,
+                "'{}' is not valid UTF-8, byte 0x{:02x} on line {} column {}. Use --encoding latin1 for a Latin-1 file or --lossy-utf8 to replace invalid bytes.",
+                path, byte, line, column
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_message(f)?;
+        match self {
+            // The error inside already ends with its code
+            ParseError::Located { .. } => Ok(()),
+            _ => write!(f, " [{}]", self.code()),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::IoError { error, .. } | ParseError::IncludeNotFound { error, .. } => {
+                Some(error)
+            }
+            ParseError::Located { error, .. } => Some(error.as_ref()),
+            ParseError::Unencodable(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+// A file that could not be written, see write_atomic
+#[derive(Debug)]
+pub struct WriteError {
+    pub path: String,
+    pub error: io::Error,
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Could not write file '{}': {}.", self.path, self.error)
+    }
+}
+
+impl std::error::Error for WriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+// Why run_program could not produce a final machine state
+#[derive(Debug)]
+pub enum RunError {
+    Parse(ParseError),
+    Runtime(RuntimeError),
+}
+
+impl From<ParseError> for RunError {
+    fn from(error: ParseError) -> RunError {
+        RunError::Parse(error)
+    }
+}
+
+impl From<RuntimeError> for RunError {
+    fn from(error: RuntimeError) -> RunError {
+        RunError::Runtime(error)
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::Parse(error) => error.fmt(f),
+            RunError::Runtime(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RunError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RunError::Parse(error) => Some(error),
+            RunError::Runtime(error) => Some(error),
+        }
+    }
+}
+
+// Why a running program was stopped
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeErrorKind {
+    UndefinedFunction {
+        name: String,
+    },
+    CallDepthExceeded {
+        name: String,
+        max_depth: usize,
+    },
+    StackLeftOnReturn {
+        function: String,
+        count: usize,
+    },
+    StackPoppedOnReturn {
+        function: String,
+        count: usize,
+    },
+    RetWithoutCall,
+    MemoryOutOfBounds {
+        instruction: String,
+        address: u16,
+        size: usize,
+    },
+    RangeOutOfBounds {
+        instruction: String,
+        start: usize,
+        count: usize,
+        size: usize,
+    },
+    MemoryFull,
+    StackOverflow {
+        max_depth: usize,
+    },
+    StackUnderflow,
+    Underflow {
+        instruction: String,
+    },
+    DivideByZero {
+        instruction: String,
+    },
+    InvalidInput {
+        input: String,
+    },
+    UnknownInstruction {
+        mnemonic: String,
+    },
+    InvalidRegister {
+        register: u16,
+    },
+    InstructionLimitExceeded {
+        limit: u64,
+    },
+    UnterminatedString {
+        address: u16,
+    },
+    OperandOutOfLimit {
+        limit: OperandLimit,
+        value: u16,
+    },
+    RanOffEnd {
+        function: Option<String>, // None for global code
+    },
+}
+
+impl RuntimeErrorKind {
+    /// Stable code of the error, shown after the message and explained by `--explain CODE`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            RuntimeErrorKind::UndefinedFunction { .. } => "E101",
+            RuntimeErrorKind::CallDepthExceeded { .. } => "E102",
+            RuntimeErrorKind::StackLeftOnReturn { .. } => "E103",
+            RuntimeErrorKind::StackPoppedOnReturn { .. } => "E104",
+            RuntimeErrorKind::RetWithoutCall => "E105",
+            RuntimeErrorKind::MemoryOutOfBounds { .. } => "E106",
+            RuntimeErrorKind::RangeOutOfBounds { .. } => "E107",
+            RuntimeErrorKind::MemoryFull => "E108",
+            RuntimeErrorKind::StackOverflow { .. } => "E109",
+            RuntimeErrorKind::StackUnderflow => "E110",
+            RuntimeErrorKind::Underflow { .. } => "E111",
+            RuntimeErrorKind::DivideByZero { .. } => "E112",
+            RuntimeErrorKind::InvalidInput { .. } => "E113",
+            RuntimeErrorKind::UnknownInstruction { .. } => "E114",
+            RuntimeErrorKind::InvalidRegister { .. } => "E115",
+            RuntimeErrorKind::InstructionLimitExceeded { .. } => "E116",
+            RuntimeErrorKind::UnterminatedString { .. } => "E117",
+            RuntimeErrorKind::OperandOutOfLimit { .. } => "E118",
+            RuntimeErrorKind::RanOffEnd { .. } => "E119",
+        }
+    }
+
+    // The message without the code
+    fn fmt_message(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeErrorKind::UndefinedFunction { name } => {
+                write!(f, "CALL to undefined function {}.", name)
+            }
+            RuntimeErrorKind::CallDepthExceeded { name, max_depth } => write!(
+                f,
+                "CALL to {} exceeds the maximum call depth of {}.",
+                name, max_depth
+            ),
+            RuntimeErrorKind::StackLeftOnReturn { function, count } => write!(
+                f,
+                "{} returned with {} values it pushed still on the stack.",
+                function, count
+            ),
+            RuntimeErrorKind::StackPoppedOnReturn { function, count } => write!(
+                f,
+                "{} popped {} values more than it pushed before returning.",
+                function, count
+            ),
+            RuntimeErrorKind::RetWithoutCall => write!(f, "RET without a matching CALL."),
+            RuntimeErrorKind::MemoryOutOfBounds {
+                instruction,
+                address,
+                size,
+            } => write!(
+                f,
+                "{} of address {} is out of bounds, memory has {} cells.",
+                instruction, address, size
+            ),
+            RuntimeErrorKind::RangeOutOfBounds {
+                instruction,
+                start,
+                count,
+                size,
+            } => write!(
+                f,
+                "{} of {} cells at address {} is out of bounds, memory has {} cells.",
+                instruction, count, start, size
+            ),
+            RuntimeErrorKind::MemoryFull => write!(f, "PUSH overflows the stack, memory is full."),
+            RuntimeErrorKind::StackOverflow { max_depth } => write!(
+                f,
+                "Stack overflow, the stack is limited to {} values.",
+                max_depth
+            ),
+            RuntimeErrorKind::StackUnderflow => write!(f, "POP from an empty stack."),
+            RuntimeErrorKind::Underflow { instruction } => {
+                write!(f, "{} will result in a negative number.", instruction)
+            }
+            RuntimeErrorKind::DivideByZero { instruction } => {
+                write!(f, "{} by zero is not allowed.", instruction)
+            }
+            RuntimeErrorKind::InvalidInput { input } => {
+                write!(f, "IN expected a number but read \"{}\".", input)
+            }
+            RuntimeErrorKind::UnknownInstruction { mnemonic } => {
+                write!(f, "{} is not a registered instruction.", mnemonic)
+            }
+            RuntimeErrorKind::InvalidRegister { register } => write!(
+                f,
+                "Register {} does not exist, there are {} registers.",
+                register, REGISTER_COUNT
+            ),
+            RuntimeErrorKind::InstructionLimitExceeded { limit } => write!(
+                f,
+                "The program ran for more than {} instructions, see max_instructions.",
+                limit
+            ),
+            RuntimeErrorKind::UnterminatedString { address } => write!(
+                f,
+                "The string at address {} runs to the end of memory without a NUL.",
+                address
+            ),
+            RuntimeErrorKind::OperandOutOfLimit { limit, value } => {
+                write!(f, "{}.", limit.message(*value))
+            }
+            RuntimeErrorKind::RanOffEnd {
+                function: Some(function),
+            } => {
+                write!(
+                    f,
+                    "{} ran past its last instruction without a RET.",
+                    function
+                )
+            }
+            RuntimeErrorKind::RanOffEnd { function: None } => {
+                write!(
+                    f,
+                    "The program ran past its last instruction without a HALT."
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_message(f)?;
+        write!(f, " [{}]", self.code())
+    }
+}
+
+// A runtime error and where it happened, the instruction is None when the program failed
+// running off the end of a function or the global code
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub function: Option<String>, // None for global code
+    pub index: u16,
+    pub instruction: Option<Box<Instruction>>, // Boxed to keep the error small
+    pub reason: RuntimeErrorKind,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let function = self.function.as_deref().unwrap_or("global");
+        match &self.instruction {
+            Some(instruction) => write!(
+                f,
+                "[{} {}] {}: {}",
+                function, self.index, instruction, self.reason
+            ),
+            None => write!(
+                f,
+                "[{} {}] end of {}: {}",
+                function,
+                self.index,
+                if self.function.is_some() {
+                    "function"
+                } else {
+                    "program"
+                },
+                self.reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
@@ -0,0 +1,387 @@
+// Longer explanations of the error and lint codes, printed by `--explain CODE`. Every code that
+// ParseError::code, RuntimeErrorKind::code or a Lint can return has an entry here, with an
+// example of the mistake and of the fix where one fits on a few lines.
+
+pub struct Explanation {
+    pub code: &'static str,
+    pub title: &'static str,
+    pub text: &'static str,
+    pub broken: &'static str, // Empty when the error is not about the source
+    pub fixed: &'static str,
+}
+
+const fn explanation(
+    code: &'static str,
+    title: &'static str,
+    text: &'static str,
+    broken: &'static str,
+    fixed: &'static str,
+) -> Explanation {
+    Explanation {
+        code,
+        title,
+        text,
+        broken,
+        fixed,
+    }
+}
+
+#[rustfmt::skip]
+pub const EXPLANATIONS: &[Explanation] = &[
+    explanation("E001", "Unknown instruction",
+        "The first word of a line is neither an instruction, a directive starting with `.` nor a label ending in `:`. Usually a typo, or a colon missing after a label.",
+        "mov ax, 5\nprnt ax",
+        "mov ax, 5\nprint ax"),
+    explanation("E002", "Invalid operand",
+        "An operand is not something the instruction can use: not a register, number, label, constant or expression. A jump target must be a label or an instruction index, and a memory address a number.",
+        "load ax, bx",
+        "loadr ax, bx"),
+    explanation("E003", "Operand does not fit in 16 bits",
+        "Registers and memory cells hold 16 bits, so numbers go from 0 to 65535, or from -32768 to 32767 when written with a minus sign.",
+        "mov ax, 70000",
+        "mov ax, 65535"),
+    explanation("E004", "Not a register",
+        "The operand has to be a register: a letter (`a` or `ax`), `rN` or a register number below the number of registers.",
+        "movr ax, zz",
+        "movr ax, bx"),
+    explanation("E005", "Operand outside the range of the instruction",
+        "Some operands have a narrower range than 16 bits, a shift amount for example only goes up to 15. An immediate outside the range is caught by the assembler, a register outside it when the instruction runs (E118).",
+        "shl ax, 40",
+        "shl ax, 15"),
+    explanation("E006", "Missing operand",
+        "The instruction needs more operands than the line gives it.",
+        "add ax",
+        "add ax, #1"),
+    explanation("E007", "Wrong number of operands",
+        "The line has a different number of operands than the instruction takes. MOV needs both a destination and a source, unless operand_defaults in config.toml gives it a default source, and `.include` takes exactly one path.",
+        "mov ax",
+        "mov ax, 5"),
+    explanation("E008", ".end without a function",
+        "`.end` closes a function or a `.data` block, but no function or block was open.",
+        "mov ax, 1\n.end",
+        "mov ax, 1\nhalt"),
+    explanation("E009", "Nested function",
+        "A function starts inside another one. Functions cannot contain functions, each one ends with `.end` before the next starts.",
+        ".outer\n.inner\nret\n.end",
+        ".outer\nret\n.end\n.inner\nret\n.end"),
+    explanation("E010", "Function without .end",
+        "The file ends while a function is still open.",
+        ".double\nadd ax, ax\nret",
+        ".double\nadd ax, ax\nret\n.end"),
+    explanation("E011", ".data block without .end",
+        "The file ends while a `.data` block is still open.",
+        ".data\ncount 10",
+        ".data\ncount 10\n.end"),
+    explanation("E012", "Invalid string",
+        "Strings of `.ascii` are ASCII text in double quotes. Only the escapes \\n, \\t, \\r, \\0, \\\\, \\', \\\" and \\xHH are known.",
+        ".ascii GREETING \"Hi\\q\"",
+        ".ascii GREETING \"Hi\\n\""),
+    explanation("E013", "Invalid character",
+        "A character literal is one ASCII character or one escape in single quotes, it stands for the number of that character.",
+        "mov ax, 'AB'",
+        "mov ax, 'A'"),
+    explanation("E014", "Register range out of bounds",
+        "The registers counted by POPCNTR, starting at the second register, run past the last register.",
+        "popcntr ax, r60, 8",
+        "popcntr ax, r56, 8"),
+    explanation("E015", "Duplicate label",
+        "A label is defined twice in the same scope, so jumps to it would be ambiguous. Labels in different functions do not clash.",
+        "start:\nmov ax, 1\nstart:\nhalt",
+        "start:\nmov ax, 1\nend:\nhalt"),
+    explanation("E016", "Undefined label",
+        "A jump names a label that is not defined in the same function, or in the global code for a jump in the global code.",
+        "jmp done\nhalt",
+        "jmp done\ndone:\nhalt"),
+    explanation("E017", "CALL to an undefined function",
+        "A CALL, CALLE or CALLNE names a function that no `.name` line defines.",
+        "call .square\nhalt",
+        "call .square\nhalt\n.square\nmul ax, ax\nret\n.end"),
+    explanation("E018", "Duplicate constant",
+        "A constant is defined a second time, with `.define`, in a `.data` block or by `.ascii`.",
+        ".define SIZE 4\n.define SIZE 8",
+        ".define SIZE 4\n.define DOUBLE_SIZE 8"),
+    explanation("E019", "Invalid constant name",
+        "Constant names start with a letter or `_` and hold letters, digits and `_`. They cannot look like a number or name a register.",
+        ".define 2X 4",
+        ".define TWICE 4"),
+    explanation("E020", "Reserved name",
+        "Labels, functions and constants cannot be named like an instruction or a register, in any case. `--fix-reserved-names FILE` renames them by adding an underscore.",
+        ".mov\nret\n.end",
+        ".mov_\nret\n.end"),
+    explanation("E021", "Undefined constant",
+        "A `#NAME` operand or a name in an expression was never defined as a constant.",
+        "mov ax, #SIZE",
+        ".define SIZE 4\nmov ax, #SIZE"),
+    explanation("E022", "Invalid expression",
+        "A constant expression in parentheses could not be read. Expressions use numbers, constants, `+`, `-`, `*`, `/` and parentheses.",
+        "mov ax, (4 +)",
+        "mov ax, (4 + 1)"),
+    explanation("E023", "Division by zero in a constant expression",
+        "A constant expression divides by zero, which has no value.",
+        ".define ZERO 0\nmov ax, (8 / ZERO)",
+        ".define TWO 2\nmov ax, (8 / TWO)"),
+    explanation("E024", "Division by a zero immediate",
+        "DIV or MOD by the immediate 0 can only ever fail when it runs, so the assembler rejects it.",
+        "div ax, #0",
+        "div ax, #2"),
+    explanation("E025", "File not accessible",
+        "The source file could not be read, because it does not exist or cannot be opened. The message includes the reason from the operating system.",
+        "", ""),
+    explanation("E026", "Cannot be stored in a binary",
+        "The program parsed, but has something the binary format cannot hold, such as an instruction registered by a plugin or more instructions than a count of 16 bits. Run the source directly instead.",
+        "", ""),
+    explanation("E027", "Invalid UTF-8",
+        "The source file is not UTF-8. `--encoding latin1` reads a Latin-1 file, `--lossy-utf8` replaces the invalid bytes.",
+        "", ""),
+    explanation("E028", "Included file not found",
+        "The file named by `.include` could not be read. The path is relative to the file with the `.include` line.",
+        ".include \"math.asm\"",
+        ".include \"lib/math.asm\""),
+    explanation("E029", "Circular include",
+        "A file ends up including itself, through the chain of includes in the message. Move what both files need into a third file they both include.",
+        "; a.asm\n.include \"b.asm\"\n; b.asm\n.include \"a.asm\"",
+        "; a.asm\n.include \"common.asm\"\n; b.asm\n.include \"common.asm\""),
+    explanation("E030", "Name not exported",
+        "A label, constant or function is used in one file but defined in another that keeps it to itself. Names are private to their file unless it has `.export NAME`, functions are exported with their dot.",
+        "; lib.asm\n.square\nmul ax, ax\nret\n.end\n; main.asm\n.include \"lib.asm\"\ncall .square",
+        "; lib.asm\n.export .square\n.square\nmul ax, ax\nret\n.end\n; main.asm\n.include \"lib.asm\"\ncall .square"),
+    explanation("E031", "Name exported twice",
+        "Two files export the same name, so a third could not tell which one it uses. Export it from one of them, the other keeps its own private copy.",
+        "; a.asm\n.export LIMIT\n.define LIMIT 10\n; b.asm\n.export LIMIT\n.define LIMIT 20",
+        "; a.asm\n.export LIMIT\n.define LIMIT 10\n; b.asm\n.define LIMIT 20"),
+    explanation("E032", "Export of an undefined name",
+        "`.export NAME` names nothing this file defines: no label, constant or function of that name, or a function written without its dot.",
+        ".export square\n.square\nmul ax, ax\nret\n.end",
+        ".export .square\n.square\nmul ax, ax\nret\n.end"),
+    explanation("E033", "Data placed twice",
+        "Two lines of `.data` blocks place data on the same memory cell, usually because a `.org` moved back into data placed before it. The message gives both lines and the first address they share. Move one of them with `.org`, or leave room with `.space`.",
+        ".data\n.org 0x10\nbuffer: .space 8\n.org 0x14\n.ascii NAME \"ok\"\n.end\nhalt",
+        ".data\n.org 0x10\nbuffer: .space 8\n.org 0x18\n.ascii NAME \"ok\"\n.end\nhalt"),
+    explanation("E101", "CALL to an undefined function",
+        "A CALL ran for a function that does not exist. The assembler catches this for source files (E017), so it comes from a program built by hand or a function removed in the debugger.",
+        "", ""),
+    explanation("E102", "Call depth exceeded",
+        "More CALLs are nested than max_call_depth in config.toml allows, usually a recursion without an end.",
+        "call .count\nhalt\n.count\ninc ax\ncall .count\nret\n.end",
+        "call .count\nhalt\n.count\ninc ax\ncmp ax, #10\nje done\ncall .count\ndone:\nret\n.end"),
+    explanation("E103", "Values left on the stack on return",
+        "A function returned with values it PUSHed still on the stack, so the caller would find them there. Every PUSH in a function needs a POP before its RET.",
+        "call .save\nhalt\n.save\npush ax\nret\n.end",
+        "call .save\nhalt\n.save\npush ax\npop ax\nret\n.end"),
+    explanation("E104", "More values popped than pushed on return",
+        "A function POPped values that its caller pushed. A function may only POP what it PUSHed itself.",
+        "push ax\ncall .take\nhalt\n.take\npop ax\nret\n.end",
+        "push ax\ncall .take\npop ax\nhalt\n.take\nret\n.end"),
+    explanation("E105", "RET without CALL",
+        "RET ran in the global code, where there is no CALL to return to. The global code ends with HALT.",
+        "mov ax, 1\nret",
+        "mov ax, 1\nhalt"),
+    explanation("E106", "Memory address out of bounds",
+        "A LOAD, STORE or PRINTS used an address past the end of memory. Memory has memory_size cells, set in config.toml, so below the default of 65536 the highest addresses do not exist. Raise memory_size or use a lower address.",
+        "", ""),
+    explanation("E107", "Memory range out of bounds",
+        "MEMCLR was asked to clear cells past the end of memory.",
+        "memclr 65530, 10",
+        "memclr 65520, 10"),
+    explanation("E108", "Memory full",
+        "PUSH has no free cell left, the stack grew down into the start of memory.",
+        "", ""),
+    explanation("E109", "Stack overflow",
+        "The stack holds more values than max_stack_depth in config.toml allows, often a PUSH in a loop without a matching POP.",
+        "mov cx, 5000\nloop:\npush cx\ndec cx\ncmp cx, #0\njne loop",
+        "mov cx, 5000\nloop:\npush cx\npop cx\ndec cx\ncmp cx, #0\njne loop"),
+    explanation("E110", "Stack underflow",
+        "POP ran with nothing on the stack.",
+        "pop ax",
+        "push bx\npop ax"),
+    explanation("E111", "Below zero",
+        "SUB or DEC would go below zero with `signed_mode = false` in config.toml. Compare first, or remove that line for negative numbers.",
+        "mov ax, 0\ndec ax",
+        "mov ax, 0\ncmp ax, #0\nje done\ndec ax\ndone:"),
+    explanation("E112", "Division by zero",
+        "DIV or MOD by a register that holds 0. Check the divisor before dividing.",
+        "mov bx, 0\ndiv ax, bx",
+        "mov bx, 0\ncmp bx, #0\nje skip\ndiv ax, bx\nskip:"),
+    explanation("E113", "Invalid input",
+        "IN read something that is not a number. --input-errors chooses whether IN stops the program, retries or reads 0 instead.",
+        "", ""),
+    explanation("E114", "Unknown custom instruction",
+        "A CUSTOM instruction ran whose mnemonic no plugin registered in this process.",
+        "", ""),
+    explanation("E115", "Register does not exist",
+        "An instruction names a register index past the last register. The assembler checks registers, so this comes from a program built by hand or a corrupt binary.",
+        "", ""),
+    explanation("E116", "Instruction limit exceeded",
+        "The program ran more instructions than max_instructions in config.toml or --max-instructions allow, usually a loop that never ends. A limit of 0 turns the check off.",
+        "loop:\njmp loop",
+        "mov cx, 5\nloop:\ndec cx\ncmp cx, #0\njne loop"),
+    explanation("E117", "Unterminated string",
+        "PRINTS found no 0 between the address and the end of memory. Strings placed by `.ascii` end with a 0, one written by STORE needs one as well.",
+        "", ""),
+    explanation("E118", "Register operand outside the range of the instruction",
+        "A register used as an operand with a narrower range than 16 bits, such as a shift amount, held a value outside that range when the instruction ran. Immediates are checked by the assembler (E005).",
+        "mov cx, 20\nshl ax, cx",
+        "mov cx, 15\nshl ax, cx"),
+    explanation("E119", "Ran past the end",
+        "With end_of_code = \"error\" in config.toml, running past the last instruction of the program without a HALT, or of a function without a RET, is an error.",
+        "mov ax, 1\nprint ax",
+        "mov ax, 1\nprint ax\nhalt"),
+    explanation("L001", "Same register twice",
+        "DIV, MOD, SUB, CMP or XOR uses one register for both operands, so the result is always the same and a different register was probably meant. `; lint-allow: self-operand` excuses a line.",
+        "sub ax, ax",
+        "sub ax, bx"),
+    explanation("L002", "Unbalanced stack",
+        "A function has a different number of PUSH and POP instructions, so RET fails unless the jumps in it make up for that. `; lint-allow: unbalanced-stack` on the `.name` line excuses a function.",
+        ".save\npush ax\nret\n.end",
+        ".save\npush ax\npop ax\nret\n.end"),
+    explanation("L003", "Operand that is ignored",
+        "HALT, NOP and RET take no operands, anything after them is dropped by the assembler. HALT does not set an exit code, so `halt 1` stops the program like `halt`. `legacy_operands = true` in config.toml turns this warning off, `; lint-allow: ignored-operand` excuses a line.",
+        "halt 1",
+        "halt"),
+    explanation("L004", "PRINT without a register",
+        "PRINT with no operand prints register a, which is easy to do by accident when another register was meant. Write the register out. `legacy_operands = true` in config.toml turns this warning off, `; lint-allow: bare-print` excuses a line.",
+        "print",
+        "print a"),
+];
+
+/// The explanation of a code such as E014, in any case.
+pub fn explanation_of(code: &str) -> Option<&'static Explanation> {
+    EXPLANATIONS
+        .iter()
+        .find(|explanation| explanation.code.eq_ignore_ascii_case(code))
+}
+
+/// The page `--explain` prints for an explanation.
+pub fn format_explanation(explanation: &Explanation) -> String {
+    let mut out = format!(
+        "{}: {}\n\n{}\n",
+        explanation.code, explanation.title, explanation.text
+    );
+    if !explanation.broken.is_empty() {
+        out.push_str(&format!(
+            "\nFor example:\n\n{}\n",
+            indent(explanation.broken)
+        ));
+        out.push_str(&format!(
+            "\ncan be fixed as:\n\n{}\n",
+            indent(explanation.fixed)
+        ));
+    }
+    out
+}
+
+fn indent(code: &str) -> String {
+    code.lines()
+        .map(|line| format!("    {}", line))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use std::collections::BTreeSet;
+    use std::fs;
+
+    // Every "E123" or "L123" string literal in the source files of the crate
+    fn codes_in_source() -> BTreeSet<String> {
+        let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/src");
+        let mut codes = BTreeSet::new();
+        for entry in fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            if path.file_name().unwrap() == "explain.rs" {
+                continue;
+            }
+            let source = fs::read_to_string(&path).unwrap();
+            for window in source.as_bytes().windows(6) {
+                if let [b'"', b'E' | b'L', digits @ .., b'"'] = window {
+                    if digits.iter().all(u8::is_ascii_digit) {
+                        codes.insert(String::from_utf8_lossy(&window[1..5]).into_owned());
+                    }
+                }
+            }
+        }
+        codes
+    }
+
+    #[test]
+    fn every_code_has_an_explanation_and_every_explanation_a_code() {
+        let explained: BTreeSet<String> = EXPLANATIONS
+            .iter()
+            .map(|explanation| explanation.code.to_string())
+            .collect();
+        assert_eq!(
+            explained.len(),
+            EXPLANATIONS.len(),
+            "a code is explained twice"
+        );
+        assert_eq!(codes_in_source(), explained);
+    }
+
+    #[test]
+    fn codes_are_found_in_any_case() {
+        assert_eq!(explanation_of("e016").unwrap().title, "Undefined label");
+        assert!(explanation_of("E999").is_none());
+    }
+
+    #[test]
+    fn an_explanation_page_shows_both_examples() {
+        assert_eq!(
+            format_explanation(explanation_of("E006").unwrap()),
+            "E006: Missing operand\n\n\
+             The instruction needs more operands than the line gives it.\n\n\
+             For example:\n\n    add ax\n\n\
+             can be fixed as:\n\n    add ax, #1\n"
+        );
+    }
+
+    // The code the example of an explanation fails with, None if it parses, runs and lints clean
+    fn failure(example: &str, config: &Config) -> Option<String> {
+        let program = match parse_with_config(example.to_string(), config) {
+            Ok(program) => program,
+            Err(error) => return Some(error.code().to_string()),
+        };
+        if let Some(lint) = lints_for(example, config).first() {
+            return Some(lint.code.to_string());
+        }
+        let mut cpu = Cpu::new(config.clone());
+        cpu.set_input(Box::new(std::io::Cursor::new("")));
+        cpu.set_output(Box::new(std::io::sink()));
+        cpu.load_program(&program);
+        cpu.run().err().map(|error| error.reason.code().to_string())
+    }
+
+    #[test]
+    fn broken_examples_fail_with_their_code_and_fixed_ones_do_not() {
+        // The examples of include and export errors are several files written one after another
+        let multi_file = ["E028", "E029", "E030", "E031"];
+        for explanation in EXPLANATIONS {
+            if explanation.broken.is_empty() || multi_file.contains(&explanation.code) {
+                continue;
+            }
+            let config = Config {
+                debug: false,
+                pedantic: explanation.code.starts_with('L'),
+                signed_mode: explanation.code != "E111",
+                end_of_code: match explanation.code {
+                    "E119" => EndOfCode::Error,
+                    _ => EndOfCode::Implicit,
+                },
+                ..Config::default()
+            };
+            assert_eq!(
+                failure(explanation.broken, &config).as_deref(),
+                Some(explanation.code),
+                "{}",
+                explanation.broken
+            );
+            assert_eq!(
+                failure(explanation.fixed, &config),
+                None,
+                "{}",
+                explanation.fixed
+            );
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use crate::*;
+
+// Constant expressions the assembler evaluates, as in `.define AREA (WIDTH*HEIGHT)` or `#(BASE+4)`.
+// Numbers and characters are written as anywhere else in the assembly, names refer to earlier
+// .defines.
+// +, -, * and / work as usual, * and / bind tighter, division rounds towards zero.
+
+#[derive(Debug, PartialEq)]
+pub enum ExpressionError {
+    Syntax,
+    Undefined(String),
+    DivideByZero,
+    Overflow,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(char),
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, ExpressionError> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if "+-*/()".contains(c) {
+            tokens.push(Token::Symbol(c));
+            chars.next();
+        } else if c == '\'' {
+            // Up to the closing quote, char_literal checks what is in between
+            chars.next();
+            let mut end = start + 1;
+            let mut escaped = false;
+            for (i, c) in chars.by_ref() {
+                end = i + c.len_utf8();
+                if c == '\'' && !escaped {
+                    break;
+                }
+                escaped = !escaped && c == '\\';
+            }
+            let value = char_literal(&expression[start..end]).ok_or(ExpressionError::Syntax)?;
+            tokens.push(Token::Number(value.into()));
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            let word = &expression[start..end];
+            tokens.push(match number_literal(word) {
+                Some((digits, radix)) => Token::Number(
+                    i64::from_str_radix(digits, radix).map_err(|_| ExpressionError::Syntax)?,
+                ),
+                None => Token::Name(word.to_string()),
+            });
+        } else {
+            return Err(ExpressionError::Syntax);
+        }
+    }
+    Ok(tokens)
+}
+
+struct Evaluator<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    lookup: &'a dyn Fn(&str) -> Option<i64>,
+}
+
+impl Evaluator<'_> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek_symbol(&self) -> Option<char> {
+        match self.tokens.get(self.position) {
+            Some(Token::Symbol(c)) => Some(*c),
+            _ => None,
+        }
+    }
+
+    // sum = product (('+' | '-') product)*
+    fn sum(&mut self) -> Result<i64, ExpressionError> {
+        let mut value = self.product()?;
+        while let Some(op @ ('+' | '-')) = self.peek_symbol() {
+            self.position += 1;
+            let rhs = self.product()?;
+            value = if op == '+' {
+                value.checked_add(rhs)
+            } else {
+                value.checked_sub(rhs)
+            }
+            .ok_or(ExpressionError::Overflow)?;
+        }
+        Ok(value)
+    }
+
+    // product = factor (('*' | '/') factor)*
+    fn product(&mut self) -> Result<i64, ExpressionError> {
+        let mut value = self.factor()?;
+        while let Some(op @ ('*' | '/')) = self.peek_symbol() {
+            self.position += 1;
+            let rhs = self.factor()?;
+            value = if op == '*' {
+                value.checked_mul(rhs).ok_or(ExpressionError::Overflow)?
+            } else if rhs == 0 {
+                return Err(ExpressionError::DivideByZero);
+            } else {
+                value / rhs
+            };
+        }
+        Ok(value)
+    }
+
+    // factor = '-' factor | '(' sum ')' | number | name
+    fn factor(&mut self) -> Result<i64, ExpressionError> {
+        match self.next() {
+            Some(Token::Symbol('-')) => Ok(-self.factor()?),
+            Some(Token::Symbol('(')) => {
+                let value = self.sum()?;
+                match self.next() {
+                    Some(Token::Symbol(')')) => Ok(value),
+                    _ => Err(ExpressionError::Syntax),
+                }
+            }
+            Some(Token::Number(value)) => Ok(value),
+            Some(Token::Name(name)) => (self.lookup)(&name).ok_or(ExpressionError::Undefined(name)),
+            _ => Err(ExpressionError::Syntax),
+        }
+    }
+}
+
+/// Evaluates a constant expression, lookup gives the value of a name or None if it is undefined.
+pub fn evaluate(
+    expression: &str,
+    lookup: &dyn Fn(&str) -> Option<i64>,
+) -> Result<i64, ExpressionError> {
+    let mut evaluator = Evaluator {
+        tokens: tokenize(expression)?,
+        position: 0,
+        lookup,
+    };
+    let value = evaluator.sum()?;
+    if evaluator.position != evaluator.tokens.len() {
+        return Err(ExpressionError::Syntax);
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(expression: &str) -> Result<i64, ExpressionError> {
+        evaluate(expression, &|name| match name {
+            "WIDTH" => Some(8),
+            "HEIGHT" => Some(3),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn multiplication_binds_tighter_than_addition() {
+        assert_eq!(value("2 + 3 * 4"), Ok(14));
+        assert_eq!(value("10 - 4 - 3"), Ok(3));
+        assert_eq!(value("WIDTH*HEIGHT+1"), Ok(25));
+        assert_eq!(value("-7 / 2"), Ok(-3));
+    }
+
+    #[test]
+    fn parentheses_nest() {
+        assert_eq!(value("(2 + 3) * 4"), Ok(20));
+        assert_eq!(value("((1 + (2 * (3 + 4))) - 5)"), Ok(10));
+        assert_eq!(value("(1 + 2"), Err(ExpressionError::Syntax));
+        assert_eq!(value("1 + 2)"), Err(ExpressionError::Syntax));
+    }
+
+    #[test]
+    fn bad_expressions_say_what_is_wrong() {
+        assert_eq!(value("8 / (WIDTH - 8)"), Err(ExpressionError::DivideByZero));
+        assert_eq!(
+            value("DEPTH + 1"),
+            Err(ExpressionError::Undefined("DEPTH".to_string()))
+        );
+        assert_eq!(value("4 +"), Err(ExpressionError::Syntax));
+    }
+}
@@ -0,0 +1,88 @@
+use crate::*;
+
+// Faults injected into a running Cpu, to test how a host handles the errors a program can stop
+// with. Only compiled with the fault-injection feature, so a normal build cannot inject any.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    DivideByZero,      // The next DIV or MOD divides by zero
+    MemoryOutOfBounds, // The next LOAD, STORE, LOADR, STORER, LOADINC or PRINTS is out of bounds
+}
+
+impl Cpu {
+    // Makes the next instruction the fault applies to fail, each injected fault fires once
+    pub fn inject_fault(&mut self, fault: Fault) {
+        self.faults.push(fault);
+    }
+
+    // Gives a register picked from seed a different value, also picked from seed. Returns the
+    // register, the same seed always corrupts the same one the same way.
+    pub fn corrupt_register(&mut self, seed: u64) -> u16 {
+        let mut rng = Rng::new(seed);
+        let index = rng.next_u16() as usize % REGISTER_COUNT;
+        // Flipping at least one bit so the value always changes
+        self.registers[index] ^= rng.next_u16().max(1);
+        index as u16
+    }
+
+    // Whether an injected fault of this kind is waiting, it is used up if so
+    pub(crate) fn take_fault(&mut self, fault: Fault) -> bool {
+        match self.faults.iter().position(|&pending| pending == fault) {
+            Some(position) => {
+                self.faults.remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            debug: false,
+            ..Config::default()
+        }
+    }
+
+    fn cpu(source: &str) -> (Cpu, Program) {
+        let program = parse_with_config(source.to_string(), &config()).unwrap();
+        let mut cpu = Cpu::new(config());
+        cpu.set_output(Box::new(std::io::sink()));
+        cpu.load_program(&program);
+        (cpu, program)
+    }
+
+    #[test]
+    fn an_injected_divide_by_zero_stops_the_next_div() {
+        let (mut cpu, _) = cpu("mov a, 8\nmov b, 2\ndiv a, b\ndiv a, b\nhalt\n");
+        cpu.inject_fault(Fault::DivideByZero);
+        let error = cpu.run().unwrap_err();
+        assert_eq!((error.index, error.reason.code()), (2, "E112"));
+    }
+
+    #[test]
+    fn each_fault_fires_once() {
+        let (mut cpu, program) = cpu("mov a, 1\nstore a, 5\nload b, 5\nhalt\n");
+        cpu.inject_fault(Fault::MemoryOutOfBounds);
+        let error = cpu.run().unwrap_err();
+        assert_eq!((error.index, error.reason.code()), (1, "E106"));
+        // Run again from the start, the fault is used up
+        cpu.load_program(&program);
+        cpu.run().unwrap();
+        assert_eq!(cpu.register(1), 1);
+    }
+
+    #[test]
+    fn the_same_seed_corrupts_the_same_register() {
+        let (mut first, _) = cpu("halt\n");
+        let (mut second, _) = cpu("halt\n");
+        let register = first.corrupt_register(42);
+        assert_eq!(second.corrupt_register(42), register);
+        assert_ne!(first.register(register), 0);
+        assert_eq!(first.register(register), second.register(register));
+    }
+}
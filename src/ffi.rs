@@ -0,0 +1,337 @@
+use crate::*;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+// A C interface to the assembler and emulator, for driving it from other languages (Python's
+// ctypes for example). It is only compiled with the ffi feature, and a shared library comes
+// from building the lib target as a cdylib:
+//
+//   cargo rustc --lib --release --features ffi --crate-type cdylib
+//
+// The matching declarations for C:
+//
+//   typedef struct CpuemuProgram CpuemuProgram;
+//   typedef struct CpuemuResult CpuemuResult;
+//
+//   int cpuemu_parse(const char *src, CpuemuProgram **out, char **err_json);
+//   int cpuemu_run(const CpuemuProgram *program, CpuemuResult **out);
+//   int cpuemu_result_register(const CpuemuResult *result, size_t index, uint16_t *value);
+//   const char *cpuemu_result_output(const CpuemuResult *result, size_t *len);
+//   const char *cpuemu_result_error(const CpuemuResult *result);
+//   void cpuemu_program_free(CpuemuProgram *program);
+//   void cpuemu_result_free(CpuemuResult *result);
+//   void cpuemu_string_free(char *string);
+//
+// The contract:
+// - Functions returning int return one of the CPUEMU_ codes below, 0 is success.
+// - There is no global state. Any number of programs and results can exist at once, and a
+//   program can be run any number of times, each run on a new machine with the default
+//   configuration. IN is given no input, it fails like at the end of a file.
+// - Programs, results and error strings handed out through an out pointer belong to the caller,
+//   who frees them with the matching free function. Freeing NULL does nothing. Strings returned
+//   by the result accessors belong to the result and stay valid until it is freed.
+// - A panic inside the emulator is caught before it reaches the caller and returned as
+//   CPUEMU_PANIC, the out pointers are then left NULL.
+
+pub const CPUEMU_OK: c_int = 0;
+pub const CPUEMU_PARSE_ERROR: c_int = 1;
+pub const CPUEMU_RUNTIME_ERROR: c_int = 2; // There is still a result, see cpuemu_result_error
+pub const CPUEMU_INVALID_ARGUMENT: c_int = 3; // NULL, source that is not UTF-8 or no such register
+pub const CPUEMU_PANIC: c_int = 4;
+
+// A parsed program, opaque to C
+pub struct CpuemuProgram(Program);
+
+// What a run left behind, opaque to C
+pub struct CpuemuResult {
+    registers: [u16; REGISTER_COUNT],
+    output: CString, // What PRINT wrote
+    error: Option<CString>,
+}
+
+// Runs f, a panic becomes CPUEMU_PANIC instead of unwinding into C
+fn guarded(f: impl FnOnce() -> c_int) -> c_int {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(CPUEMU_PANIC)
+}
+
+// A C string of text, NULs in it would end the string early so they are dropped
+fn c_string(text: &str) -> CString {
+    CString::new(text.replace('\0', "")).unwrap_or_default()
+}
+
+fn parse_error_json(mut error: ParseError) -> String {
+    let line = match error.line_mut() {
+        Some(line) => line.to_string(),
+        None => "null".to_string(),
+    };
+    format!(
+        "{{\"line\":{},\"code\":{},\"message\":{}}}",
+        line,
+        json_string(error.code()),
+        json_string(&error.to_string())
+    )
+}
+
+/// Parses src, a NUL terminated UTF-8 string of assembly, with the default configuration.
+/// On success *out is the program and *err_json NULL, on CPUEMU_PARSE_ERROR *err_json is
+/// `{"line":3,"code":"E001","message":"..."}`, with a null line for errors not on a line. err_json may be
+/// NULL when the error is not wanted.
+///
+/// # Safety
+///
+/// src must be NULL or a valid NUL terminated string, out and err_json NULL or valid to write.
+#[no_mangle]
+pub unsafe extern "C" fn cpuemu_parse(
+    src: *const c_char,
+    out: *mut *mut CpuemuProgram,
+    err_json: *mut *mut c_char,
+) -> c_int {
+    if src.is_null() || out.is_null() {
+        return CPUEMU_INVALID_ARGUMENT;
+    }
+    *out = ptr::null_mut();
+    if !err_json.is_null() {
+        *err_json = ptr::null_mut();
+    }
+    guarded(|| {
+        let Ok(source) = CStr::from_ptr(src).to_str() else {
+            return CPUEMU_INVALID_ARGUMENT;
+        };
+        match parse_with_config(source.to_string(), &Config::default()) {
+            Ok(program) => {
+                *out = Box::into_raw(Box::new(CpuemuProgram(program)));
+                CPUEMU_OK
+            }
+            Err(error) => {
+                if !err_json.is_null() {
+                    *err_json = c_string(&parse_error_json(error)).into_raw();
+                }
+                CPUEMU_PARSE_ERROR
+            }
+        }
+    })
+}
+
+/// Runs a program until it halts. *out is the result both on CPUEMU_OK and on
+/// CPUEMU_RUNTIME_ERROR, where it holds the registers as the failing instruction left them.
+///
+/// # Safety
+///
+/// program must be NULL or from cpuemu_parse and not yet freed, out NULL or valid to write.
+#[no_mangle]
+pub unsafe extern "C" fn cpuemu_run(
+    program: *const CpuemuProgram,
+    out: *mut *mut CpuemuResult,
+) -> c_int {
+    if program.is_null() || out.is_null() {
+        return CPUEMU_INVALID_ARGUMENT;
+    }
+    *out = ptr::null_mut();
+    guarded(|| {
+        let output = SharedBuffer::default();
+        let mut cpu = Cpu::new(Config::default());
+        cpu.set_input(Box::new(io::empty()));
+        cpu.set_output(Box::new(output.clone()));
+        cpu.load_program(&(*program).0);
+        let error = cpu.run().err();
+        let code = match error {
+            Some(_) => CPUEMU_RUNTIME_ERROR,
+            None => CPUEMU_OK,
+        };
+        *out = Box::into_raw(Box::new(CpuemuResult {
+            registers: cpu.registers,
+            output: c_string(&output.text()),
+            error: error.map(|error| c_string(&error.to_string())),
+        }));
+        code
+    })
+}
+
+/// Writes the final value of register index, 0 for ax, to *value.
+///
+/// # Safety
+///
+/// result must be NULL or from cpuemu_run and not yet freed, value NULL or valid to write.
+#[no_mangle]
+pub unsafe extern "C" fn cpuemu_result_register(
+    result: *const CpuemuResult,
+    index: usize,
+    value: *mut u16,
+) -> c_int {
+    if result.is_null() || value.is_null() {
+        return CPUEMU_INVALID_ARGUMENT;
+    }
+    match (*result).registers.get(index) {
+        Some(&register) => {
+            *value = register;
+            CPUEMU_OK
+        }
+        None => CPUEMU_INVALID_ARGUMENT,
+    }
+}
+
+/// Everything the program printed, as UTF-8 with invalid bytes replaced. *len gets its length
+/// in bytes when len is not NULL.
+///
+/// # Safety
+///
+/// result must be NULL or from cpuemu_run and not yet freed, len NULL or valid to write.
+#[no_mangle]
+pub unsafe extern "C" fn cpuemu_result_output(
+    result: *const CpuemuResult,
+    len: *mut usize,
+) -> *const c_char {
+    if result.is_null() {
+        return ptr::null();
+    }
+    if !len.is_null() {
+        *len = (*result).output.as_bytes().len();
+    }
+    (*result).output.as_ptr()
+}
+
+/// The runtime error a run stopped with, NULL if it halted normally.
+///
+/// # Safety
+///
+/// result must be NULL or from cpuemu_run and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn cpuemu_result_error(result: *const CpuemuResult) -> *const c_char {
+    match result.as_ref().and_then(|result| result.error.as_ref()) {
+        Some(error) => error.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// # Safety
+///
+/// program must be NULL or from cpuemu_parse, and is not used again.
+#[no_mangle]
+pub unsafe extern "C" fn cpuemu_program_free(program: *mut CpuemuProgram) {
+    if !program.is_null() {
+        drop(Box::from_raw(program));
+    }
+}
+
+/// # Safety
+///
+/// result must be NULL or from cpuemu_run, and is not used again.
+#[no_mangle]
+pub unsafe extern "C" fn cpuemu_result_free(result: *mut CpuemuResult) {
+    if !result.is_null() {
+        drop(Box::from_raw(result));
+    }
+}
+
+/// Frees an error string from cpuemu_parse.
+///
+/// # Safety
+///
+/// string must be NULL or from cpuemu_parse, and is not used again.
+#[no_mangle]
+pub unsafe extern "C" fn cpuemu_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parses and runs source through the C interface, as (parse code, run code, result)
+    unsafe fn run_source(source: &str) -> (c_int, c_int, *mut CpuemuResult) {
+        let source = CString::new(source).unwrap();
+        let mut program = ptr::null_mut();
+        let parsed = cpuemu_parse(source.as_ptr(), &mut program, ptr::null_mut());
+        let mut result = ptr::null_mut();
+        let ran = match parsed {
+            CPUEMU_OK => cpuemu_run(program, &mut result),
+            _ => -1,
+        };
+        cpuemu_program_free(program);
+        (parsed, ran, result)
+    }
+
+    #[test]
+    fn a_program_runs_and_its_registers_and_output_can_be_read() {
+        unsafe {
+            let (parsed, ran, result) = run_source("mov b, 42\nprint b\nhalt\n");
+            assert_eq!((parsed, ran), (CPUEMU_OK, CPUEMU_OK));
+            let mut value = 0;
+            assert_eq!(cpuemu_result_register(result, 1, &mut value), CPUEMU_OK);
+            assert_eq!(value, 42);
+            assert_eq!(
+                cpuemu_result_register(result, REGISTER_COUNT, &mut value),
+                CPUEMU_INVALID_ARGUMENT
+            );
+            let mut len = 0;
+            let output = CStr::from_ptr(cpuemu_result_output(result, &mut len));
+            assert_eq!((output.to_str(), len), (Ok("bx: 42\n"), 7));
+            assert!(cpuemu_result_error(result).is_null());
+            cpuemu_result_free(result);
+        }
+    }
+
+    #[test]
+    fn errors_come_back_as_codes_json_and_messages() {
+        unsafe {
+            let source = CString::new("nop\nfrob a\n").unwrap();
+            let (mut program, mut json) = (ptr::null_mut(), ptr::null_mut());
+            assert_eq!(
+                cpuemu_parse(source.as_ptr(), &mut program, &mut json),
+                CPUEMU_PARSE_ERROR
+            );
+            assert!(program.is_null());
+            let error = CStr::from_ptr(json).to_str().unwrap().to_string();
+            assert!(
+                error.starts_with("{\"line\":2,\"code\":\"E001\",\"message\":"),
+                "{}",
+                error
+            );
+            cpuemu_string_free(json);
+
+            let (_, ran, result) = run_source("mov a, 1\nmov b, 0\ndiv a, b\n");
+            assert_eq!(ran, CPUEMU_RUNTIME_ERROR);
+            let error = CStr::from_ptr(cpuemu_result_error(result));
+            assert!(error.to_str().unwrap().contains("[E112]"));
+            cpuemu_result_free(result);
+
+            let mut out = ptr::null_mut();
+            assert_eq!(
+                cpuemu_parse(ptr::null(), &mut out, ptr::null_mut()),
+                CPUEMU_INVALID_ARGUMENT
+            );
+            assert_eq!(
+                cpuemu_run(ptr::null(), &mut ptr::null_mut()),
+                CPUEMU_INVALID_ARGUMENT
+            );
+        }
+    }
+
+    struct Explode;
+
+    impl InstructionDef for Explode {
+        fn mnemonic(&self) -> &str {
+            "EXPLODE"
+        }
+        fn arity(&self) -> usize {
+            0
+        }
+        fn execute(&self, _: &mut Cpu, _: &[u16]) {
+            panic!("EXPLODE went off");
+        }
+    }
+
+    #[test]
+    fn a_panic_is_caught_before_it_reaches_the_caller() {
+        register_instruction(Box::new(Explode));
+        unsafe {
+            let (parsed, ran, result) = run_source("mov a, 1\nexplode\nhalt\n");
+            assert_eq!((parsed, ran), (CPUEMU_OK, CPUEMU_PANIC));
+            assert!(result.is_null());
+        }
+    }
+}
@@ -1,27 +1,11 @@
 use crate::*;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
 use std::process;
 
 // these are helper functions, not missiong critical, they help.
 
-pub fn neg_num_err(instruction: &str) {
-    eprintln!(
-        "{}{}{}",
-        "ERROR, ".color(Colors::RedFg),
-        instruction.color(Colors::YellowFg),
-        " WILL RESULT IN NEGATIVE NUMBER.\nTERMINATING.".color(Colors::RedFg)
-    );
-    process::exit(0);
-}
-
-pub fn err_print(error: String) {
-    eprintln!(
-        "{}{}",
-        "ERROR, ".color(Colors::RedFg),
-        error.color(Colors::RedFg)
-    );
-    process::exit(0);
-}
-
 pub fn letter_to_integer(letter: char) -> Option<u8> {
     if letter.is_ascii_lowercase() {
         Some(letter as u8 - b'a')
@@ -32,31 +16,104 @@ pub fn letter_to_integer(letter: char) -> Option<u8> {
     }
 }
 
-pub fn integer_to_letter(n: usize) -> char {
+// 64 bit FNV-1a, only to recognize the same bytes again
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Letter of one of the first 26 registers, None for the rest
+pub fn integer_to_letter(n: usize) -> Option<char> {
     if n < 26 {
-        (n as u8 + b'a') as char
+        Some((n as u8 + b'a') as char)
     } else {
-        err_print("value passed to integer_to_letter was too large.".to_string());
-        process::exit(0);
+        None
     }
 }
 
-pub fn has_b_with_num(s: &str) -> bool {
-    let bytes = s.as_bytes();
-    let mut found_b = false;
-    for &byte in bytes {
-        if found_b {
-            if byte.is_ascii_digit() {
-                return true;
-            } else {
-                found_b = false; // reset if not a digit
-            }
+// How PRINT and its variants show a value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrintFormat {
+    Decimal, // Signed in signed mode
+    Hex,
+    Binary,
+    GroupedBinary, // Binary with a _ between groups of four bits
+    Char,
+}
+
+// Formats a register value for printing. Characters outside printable ASCII are escaped,
+// e.g. 10 is '\n' and 200 is '\xc8'. Hex and binary always show the raw 16 bits.
+pub fn format_value(value: u16, format: PrintFormat, signed: bool) -> String {
+    match format {
+        PrintFormat::Decimal if signed => (value as i16).to_string(),
+        PrintFormat::Decimal => value.to_string(),
+        PrintFormat::Hex => format!("0x{:04x}", value),
+        PrintFormat::Binary => format!("0b{:016b}", value),
+        PrintFormat::GroupedBinary => {
+            let bits = format!("{:016b}", value);
+            let groups: Vec<&str> = (0..4).map(|i| &bits[i * 4..i * 4 + 4]).collect();
+            format!("0b{}", groups.join("_"))
         }
-        if byte == b'b' || byte == b'B' {
-            found_b = true;
+        PrintFormat::Char => match u8::try_from(value) {
+            Ok(byte) => format!("'{}'", byte.escape_ascii()),
+            Err(_) => format!("'\\u{{{:x}}}'", value),
+        },
+    }
+}
+
+// Small seeded random number generator (splitmix64), the same seed always gives the same numbers
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_u16(&mut self) -> u16 {
+        (self.next_u64() >> 48) as u16
+    }
+}
+
+// Writes a file so that it either holds all of contents or is left as it was. The contents go to
+// a temporary file next to it first, which is synced and then renamed over the target, so an
+// interrupted write never leaves a truncated file behind.
+pub fn write_atomic(path: &str, contents: &[u8]) -> Result<(), WriteError> {
+    let target = Path::new(path);
+    let file_name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let temp = target.with_file_name(format!(".{}.{}.tmp", file_name, process::id()));
+    let written = fs::File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&temp, target));
+    written.map_err(|error| {
+        let _ = fs::remove_file(&temp);
+        WriteError {
+            path: path.to_string(),
+            error,
         }
+    })
+}
+
+// Name a register is printed with, its letter form where it has one
+pub fn register_name(n: usize) -> String {
+    match integer_to_letter(n) {
+        Some(letter) => format!("{}x", letter),
+        None => format!("r{}", n),
     }
-    false
 }
 
 #[allow(dead_code)]
@@ -82,3 +139,41 @@ pub fn debug_print(instruc: &str, src: &String, dest: &String, f_contents: &str)
 pub fn print_type<T>(_: &T) {
     println!("{:?}", std::any::type_name::<T>());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directory(test: &str) -> std::path::PathBuf {
+        let directory = std::env::temp_dir().join(format!("alice-{}-{}", test, process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn an_atomic_write_replaces_the_whole_file() {
+        let directory = directory("atomic-write");
+        let path = directory.join("out.bin");
+        fs::write(&path, b"an older and longer file").unwrap();
+        write_atomic(&path.to_string_lossy(), &[0, 1, 2, 0xff]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [0, 1, 2, 0xff]);
+        // Only the target is left, no temporary file
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn a_failed_atomic_write_leaves_nothing_behind() {
+        let directory = directory("atomic-fail");
+        // Renaming a file over a directory that is not empty fails
+        let target = directory.join("taken");
+        fs::create_dir(&target).unwrap();
+        fs::write(target.join("kept"), b"kept").unwrap();
+        let error = write_atomic(&target.to_string_lossy(), b"new").unwrap_err();
+        assert_eq!(error.path, target.to_string_lossy());
+        assert_eq!(fs::read(target.join("kept")).unwrap(), b"kept");
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
@@ -1,40 +1,421 @@
-pub const MEMORY_SIZE: usize = 255;
+use crate::*;
+use std::collections::HashMap;
+use std::fmt;
+
+// Most instructions a program can hold, data lives in the separate CPU memory
+pub const PROGRAM_SIZE: usize = 255;
 
 // Opcode constants
-pub const ADD_OPCODE: u16 = 0x1;
-pub const MOV_OPCODE: u16 = 0x2;
-pub const MUL_OPCODE: u16 = 0x3;
-pub const SUB_OPCODE: u16 = 0x4;
-pub const SWAP_OPCODE: u16 = 0x5;
-pub const DIV_OPCODE: u16 = 0x6;
-pub const CLR_OPCODE: u16 = 0x7;
-pub const INC_OPCODE: u16 = 0x8;
-pub const DEC_OPCODE: u16 = 0x9;
-pub const PRINT_OPCODE: u16 = 0xa;
-pub const POW_OPCODE: u16 = 0xb;
-pub const MOVR_OPCODE: u16 = 0xc;
-pub const CMP_OPCODE: u16 = 0xd;
-pub const JMP_OPCODE: u16 = 0xe;
-pub const NOP_OPCODE: u16 = 0xf;
-pub const HALT_OPCODE: u16 = 0x0;
+pub const ADD_OPCODE: u8 = 0x1;
+pub const MOV_OPCODE: u8 = 0x2;
+pub const MUL_OPCODE: u8 = 0x3;
+pub const SUB_OPCODE: u8 = 0x4;
+pub const SWAP_OPCODE: u8 = 0x5;
+pub const DIV_OPCODE: u8 = 0x6;
+pub const CLR_OPCODE: u8 = 0x7;
+pub const INC_OPCODE: u8 = 0x8;
+pub const DEC_OPCODE: u8 = 0x9;
+pub const PRINT_OPCODE: u8 = 0xa;
+pub const POW_OPCODE: u8 = 0xb;
+pub const MOVR_OPCODE: u8 = 0xc;
+pub const CMP_OPCODE: u8 = 0xd;
+pub const JMP_OPCODE: u8 = 0xe;
+pub const NOP_OPCODE: u8 = 0xf;
+pub const JE_OPCODE: u8 = 0x10;
+pub const JNE_OPCODE: u8 = 0x11;
+pub const JG_OPCODE: u8 = 0x12;
+pub const JL_OPCODE: u8 = 0x13;
+pub const RET_OPCODE: u8 = 0x14;
+pub const LOAD_OPCODE: u8 = 0x15;
+pub const STORE_OPCODE: u8 = 0x16;
+pub const LOADR_OPCODE: u8 = 0x17;
+pub const STORER_OPCODE: u8 = 0x18;
+pub const PUSH_OPCODE: u8 = 0x19;
+pub const POP_OPCODE: u8 = 0x1a;
+pub const IN_OPCODE: u8 = 0x1b;
+pub const GETF_OPCODE: u8 = 0x1c;
+pub const SETF_OPCODE: u8 = 0x1d;
+pub const ABSDIFF_OPCODE: u8 = 0x1e;
+pub const CALL_OPCODE: u8 = 0x1f;
+pub const MEMCLR_OPCODE: u8 = 0x20;
+pub const PRINTX_OPCODE: u8 = 0x21;
+pub const PRINTB_OPCODE: u8 = 0x22;
+pub const PRINTC_OPCODE: u8 = 0x23;
+pub const ISPOW2_OPCODE: u8 = 0x24;
+pub const NEXTPOW2_OPCODE: u8 = 0x25;
+pub const AND_OPCODE: u8 = 0x26;
+pub const OR_OPCODE: u8 = 0x27;
+pub const XOR_OPCODE: u8 = 0x28;
+pub const NOT_OPCODE: u8 = 0x29;
+pub const SHL_OPCODE: u8 = 0x2a;
+pub const SHR_OPCODE: u8 = 0x2b;
+pub const MOD_OPCODE: u8 = 0x2c;
+pub const LOADINC_OPCODE: u8 = 0x2d;
+pub const PRINTS_OPCODE: u8 = 0x2e;
+pub const POPCNTR_OPCODE: u8 = 0x2f;
+pub const RCL_OPCODE: u8 = 0x30;
+pub const RCR_OPCODE: u8 = 0x31;
+pub const FLAGSWAP_OPCODE: u8 = 0x32;
+pub const KEYIN_OPCODE: u8 = 0x33;
+pub const CALLE_OPCODE: u8 = 0x34;
+pub const CALLNE_OPCODE: u8 = 0x35;
+pub const PRINTW_OPCODE: u8 = 0x36;
+pub const HALT_OPCODE: u8 = 0x0;
+
+// Source operand of the arithmetic instructions and CMP.
+// `#5` or a bare number is an immediate, a register name such as `b` or `r1` is a register.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Register(u16),
+    Immediate(u16),
+}
 
 // Add instructions here
-#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
-    ADD(u16, u16),
+    ADD(u16, Operand),
     MOV(u16, u16),
-    MUL(u16, u16),
-    SUB(u16, u16),
+    MUL(u16, Operand),
+    SUB(u16, Operand),
     SWAP(u16, u16),
-    DIV(u16, u16),
+    DIV(u16, Operand),
+    MOD(u16, Operand),
     CLR(u16),
     INC(u16),
     DEC(u16),
     PRINT(u16),
-    POW(u16, u16),
+    PRINTX(u16),      // PRINT in hexadecimal
+    PRINTB(u16),      // PRINT in binary
+    PRINTC(u16),      // PRINT as an ASCII character
+    PRINTS(u16),      // address of a NUL terminated string
+    PRINTW(u16, u16), // register, width of the field it is right-justified in
+    POW(u16, Operand),
     MOVR(u16, u16),
-    CMP(u16, u16),
+    CMP(u16, Operand),
     JMP(u16),
+    JE(u16),
+    JNE(u16),
+    JG(u16),
+    JL(u16),
+    CALL(String),
+    CALLE(String),  // CALL if the zero flag is set
+    CALLNE(String), // CALL if the zero flag is clear
+    RET,
+    LOAD(u16, u16),    // register, address
+    STORE(u16, u16),   // register, address
+    LOADR(u16, u16),   // register, register holding the address
+    STORER(u16, u16),  // register, register holding the address
+    LOADINC(u16, u16), // register, register holding the address, which is incremented
+    PUSH(u16),
+    POP(u16),
+    IN(u16),
+    KEYIN(u16), // next key of the keyboard, 0 when none is waiting
+    GETF(u16),
+    SETF(u16),
+    FLAGSWAP(u16),
+    ABSDIFF(u16, Operand),
+    MEMCLR(u16, u16), // start address, number of cells
+    ISPOW2(u16),
+    NEXTPOW2(u16),
+    AND(u16, Operand),
+    OR(u16, Operand),
+    XOR(u16, Operand),
+    NOT(u16),
+    POPCNTR(u16, u16, u16), // destination, first register, number of registers
+    SHL(u16, Operand),      // register, shift amount
+    SHR(u16, Operand),      // register, shift amount
+    RCL(u16, Operand),      // register, rotate amount
+    RCR(u16, Operand),      // register, rotate amount
+    CUSTOM(String, Vec<u16>), // Instruction registered through plugins.rs
     NOP,
     HALT,
 }
+
+impl Instruction {
+    // Mnemonic as written in the assembly, in uppercase
+    pub fn to_mnemonic(&self) -> &str {
+        match self {
+            Instruction::ADD(..) => "ADD",
+            Instruction::MOV(..) => "MOV",
+            Instruction::MUL(..) => "MUL",
+            Instruction::SUB(..) => "SUB",
+            Instruction::SWAP(..) => "SWAP",
+            Instruction::DIV(..) => "DIV",
+            Instruction::MOD(..) => "MOD",
+            Instruction::CLR(_) => "CLR",
+            Instruction::INC(_) => "INC",
+            Instruction::DEC(_) => "DEC",
+            Instruction::PRINT(_) => "PRINT",
+            Instruction::PRINTX(_) => "PRINTX",
+            Instruction::PRINTB(_) => "PRINTB",
+            Instruction::PRINTC(_) => "PRINTC",
+            Instruction::PRINTS(_) => "PRINTS",
+            Instruction::PRINTW(..) => "PRINTW",
+            Instruction::POW(..) => "POW",
+            // MOVR is written as MOV with a register source
+            Instruction::MOVR(..) => "MOV",
+            Instruction::CMP(..) => "CMP",
+            Instruction::JMP(_) => "JMP",
+            Instruction::JE(_) => "JE",
+            Instruction::JNE(_) => "JNE",
+            Instruction::JG(_) => "JG",
+            Instruction::JL(_) => "JL",
+            Instruction::CALL(_) => "CALL",
+            Instruction::CALLE(_) => "CALLE",
+            Instruction::CALLNE(_) => "CALLNE",
+            Instruction::RET => "RET",
+            Instruction::LOAD(..) => "LOAD",
+            Instruction::STORE(..) => "STORE",
+            Instruction::LOADR(..) => "LOADR",
+            Instruction::STORER(..) => "STORER",
+            Instruction::LOADINC(..) => "LOADINC",
+            Instruction::PUSH(_) => "PUSH",
+            Instruction::POP(_) => "POP",
+            Instruction::IN(_) => "IN",
+            Instruction::KEYIN(_) => "KEYIN",
+            Instruction::GETF(_) => "GETF",
+            Instruction::SETF(_) => "SETF",
+            Instruction::FLAGSWAP(_) => "FLAGSWAP",
+            Instruction::ABSDIFF(..) => "ABSDIFF",
+            Instruction::MEMCLR(..) => "MEMCLR",
+            Instruction::ISPOW2(_) => "ISPOW2",
+            Instruction::NEXTPOW2(_) => "NEXTPOW2",
+            Instruction::AND(..) => "AND",
+            Instruction::OR(..) => "OR",
+            Instruction::XOR(..) => "XOR",
+            Instruction::NOT(_) => "NOT",
+            Instruction::POPCNTR(..) => "POPCNTR",
+            Instruction::SHL(..) => "SHL",
+            Instruction::SHR(..) => "SHR",
+            Instruction::RCL(..) => "RCL",
+            Instruction::RCR(..) => "RCR",
+            Instruction::CUSTOM(mnemonic, _) => mnemonic,
+            Instruction::NOP => "NOP",
+            Instruction::HALT => "HALT",
+        }
+    }
+
+    // The function a CALL, CALLE or CALLNE may run
+    pub fn called_function(&self) -> Option<&str> {
+        match self {
+            Instruction::CALL(name) | Instruction::CALLE(name) | Instruction::CALLNE(name) => {
+                Some(name)
+            }
+            _ => None,
+        }
+    }
+
+    // The SRC of an instruction that takes REG, SRC
+    pub fn source_operand(&self) -> Option<Operand> {
+        match *self {
+            Instruction::ADD(_, src)
+            | Instruction::MUL(_, src)
+            | Instruction::SUB(_, src)
+            | Instruction::DIV(_, src)
+            | Instruction::MOD(_, src)
+            | Instruction::POW(_, src)
+            | Instruction::CMP(_, src)
+            | Instruction::ABSDIFF(_, src)
+            | Instruction::AND(_, src)
+            | Instruction::OR(_, src)
+            | Instruction::XOR(_, src)
+            | Instruction::SHL(_, src)
+            | Instruction::SHR(_, src)
+            | Instruction::RCL(_, src)
+            | Instruction::RCR(_, src) => Some(src),
+            _ => None,
+        }
+    }
+
+    // Registers the instruction names, operand registers included
+    pub fn registers(&self) -> Vec<u16> {
+        match *self {
+            Instruction::ADD(reg, src)
+            | Instruction::MUL(reg, src)
+            | Instruction::SUB(reg, src)
+            | Instruction::DIV(reg, src)
+            | Instruction::MOD(reg, src)
+            | Instruction::POW(reg, src)
+            | Instruction::CMP(reg, src)
+            | Instruction::ABSDIFF(reg, src)
+            | Instruction::AND(reg, src)
+            | Instruction::OR(reg, src)
+            | Instruction::XOR(reg, src)
+            | Instruction::SHL(reg, src)
+            | Instruction::SHR(reg, src)
+            | Instruction::RCL(reg, src)
+            | Instruction::RCR(reg, src) => match src {
+                Operand::Register(src_reg) => vec![reg, src_reg],
+                Operand::Immediate(_) => vec![reg],
+            },
+            Instruction::SWAP(reg1, reg2)
+            | Instruction::MOVR(reg1, reg2)
+            | Instruction::LOADR(reg1, reg2)
+            | Instruction::STORER(reg1, reg2)
+            | Instruction::LOADINC(reg1, reg2) => vec![reg1, reg2],
+            Instruction::MOV(reg, _)
+            | Instruction::LOAD(reg, _)
+            | Instruction::STORE(reg, _)
+            | Instruction::CLR(reg)
+            | Instruction::INC(reg)
+            | Instruction::DEC(reg)
+            | Instruction::PRINT(reg)
+            | Instruction::PRINTX(reg)
+            | Instruction::PRINTB(reg)
+            | Instruction::PRINTC(reg)
+            | Instruction::PUSH(reg)
+            | Instruction::POP(reg)
+            | Instruction::IN(reg)
+            | Instruction::KEYIN(reg)
+            | Instruction::GETF(reg)
+            | Instruction::SETF(reg)
+            | Instruction::FLAGSWAP(reg)
+            | Instruction::ISPOW2(reg)
+            | Instruction::NEXTPOW2(reg)
+            | Instruction::NOT(reg)
+            | Instruction::PRINTW(reg, _) => vec![reg],
+            Instruction::POPCNTR(dest, start, count) => std::iter::once(dest)
+                .chain(start..start.saturating_add(count))
+                .collect(),
+            // Custom operands are plain numbers, what they mean is up to the plugin
+            Instruction::JMP(_)
+            | Instruction::JE(_)
+            | Instruction::JNE(_)
+            | Instruction::JG(_)
+            | Instruction::JL(_)
+            | Instruction::CALL(_)
+            | Instruction::CALLE(_)
+            | Instruction::CALLNE(_)
+            | Instruction::RET
+            | Instruction::MEMCLR(..)
+            | Instruction::PRINTS(_)
+            | Instruction::CUSTOM(..)
+            | Instruction::NOP
+            | Instruction::HALT => Vec::new(),
+        }
+    }
+}
+
+// Shows an instruction as assembly the parser would accept, `ADD ax, #5` or `MOV ax, bx`.
+// Jump targets show as instruction indices, since labels are already resolved.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let register = |index: u16| register_name(index as usize);
+        let operand = |operand: Operand| match operand {
+            Operand::Register(index) => register(index),
+            Operand::Immediate(value) => format!("#{}", value),
+        };
+        let mnemonic = self.to_mnemonic();
+        match *self {
+            Instruction::ADD(reg, src)
+            | Instruction::MUL(reg, src)
+            | Instruction::SUB(reg, src)
+            | Instruction::DIV(reg, src)
+            | Instruction::MOD(reg, src)
+            | Instruction::POW(reg, src)
+            | Instruction::CMP(reg, src)
+            | Instruction::ABSDIFF(reg, src)
+            | Instruction::AND(reg, src)
+            | Instruction::OR(reg, src)
+            | Instruction::XOR(reg, src)
+            | Instruction::SHL(reg, src)
+            | Instruction::SHR(reg, src)
+            | Instruction::RCL(reg, src)
+            | Instruction::RCR(reg, src) => {
+                write!(f, "{} {}, {}", mnemonic, register(reg), operand(src))
+            }
+            Instruction::MOV(reg, value) => write!(f, "MOV {}, #{}", register(reg), value),
+            Instruction::MOVR(reg1, reg2)
+            | Instruction::SWAP(reg1, reg2)
+            | Instruction::LOADR(reg1, reg2)
+            | Instruction::STORER(reg1, reg2)
+            | Instruction::LOADINC(reg1, reg2) => {
+                write!(f, "{} {}, {}", mnemonic, register(reg1), register(reg2))
+            }
+            Instruction::LOAD(reg, address) | Instruction::STORE(reg, address) => {
+                write!(f, "{} {}, {}", mnemonic, register(reg), address)
+            }
+            Instruction::CLR(reg)
+            | Instruction::INC(reg)
+            | Instruction::DEC(reg)
+            | Instruction::PRINT(reg)
+            | Instruction::PRINTX(reg)
+            | Instruction::PRINTB(reg)
+            | Instruction::PRINTC(reg)
+            | Instruction::PUSH(reg)
+            | Instruction::POP(reg)
+            | Instruction::IN(reg)
+            | Instruction::KEYIN(reg)
+            | Instruction::GETF(reg)
+            | Instruction::SETF(reg)
+            | Instruction::FLAGSWAP(reg)
+            | Instruction::ISPOW2(reg)
+            | Instruction::NEXTPOW2(reg)
+            | Instruction::NOT(reg) => write!(f, "{} {}", mnemonic, register(reg)),
+            Instruction::JMP(target)
+            | Instruction::JE(target)
+            | Instruction::JNE(target)
+            | Instruction::JG(target)
+            | Instruction::JL(target) => write!(f, "{} {}", mnemonic, target),
+            Instruction::CALL(ref name)
+            | Instruction::CALLE(ref name)
+            | Instruction::CALLNE(ref name) => write!(f, "{} {}", mnemonic, name),
+            Instruction::MEMCLR(start, count) => write!(f, "MEMCLR {}, {}", start, count),
+            Instruction::POPCNTR(dest, start, count) => {
+                write!(
+                    f,
+                    "POPCNTR {}, {}, {}",
+                    register(dest),
+                    register(start),
+                    count
+                )
+            }
+            Instruction::PRINTS(address) => write!(f, "PRINTS {}", address),
+            Instruction::PRINTW(reg, width) => write!(f, "PRINTW {}, {}", register(reg), width),
+            Instruction::CUSTOM(ref name, ref operands) => {
+                let operands: Vec<String> = operands.iter().map(u16::to_string).collect();
+                write!(f, "{}", name)?;
+                if !operands.is_empty() {
+                    write!(f, " {}", operands.join(", "))?;
+                }
+                Ok(())
+            }
+            Instruction::RET | Instruction::NOP | Instruction::HALT => write!(f, "{}", mnemonic),
+        }
+    }
+}
+
+// A function defined with a `.name` ... `.end` block
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub body: Vec<Instruction>,
+    pub line: usize, // Line of the `.name` definition
+}
+
+// Everything parse_file produces: the global code and the functions it can CALL
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    pub functions: HashMap<String, Function>,
+    pub labels: HashMap<String, u16>, // Labels of the global code, for picking an entry point
+    pub data: Vec<u16>,               // Memory contents from address 0 on, placed there by .ascii
+    // Text of the `;@` comments by function and instruction index, binaries leave them out
+    pub annotations: HashMap<(Option<String>, u16), String>,
+    // File and line of each instruction by function and instruction index, from the source or
+    // the debug section of a binary, empty for stripped binaries and programs built by hand
+    pub positions: HashMap<(Option<String>, u16), (String, usize)>,
+    // fnv1a of the source after its includes were spliced in, None for programs built by hand.
+    // Binaries keep it, so they can be checked against the source they were assembled from.
+    pub source_hash: Option<u64>,
+}
+
+// A program with only global code, for building one by hand instead of parsing it
+impl From<Vec<Instruction>> for Program {
+    fn from(instructions: Vec<Instruction>) -> Program {
+        Program {
+            instructions,
+            ..Program::default()
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use crate::*;
+use std::ops::RangeInclusive;
+use std::sync::OnceLock;
+
+// Reference of the instruction set, the source of the Markdown written by --doc.
+// Every instruction the parser knows has an entry with a description and an example, the
+// example is run when the reference is generated and its output is included.
+
+pub struct InstructionInfo {
+    pub mnemonic: &'static str,
+    pub opcode: u8,
+    pub group: &'static str,
+    pub operands: &'static str, // REG register, SRC register or immediate, IMM immediate only
+    pub flags: &'static str,    // Flags the instruction writes
+    pub description: &'static str,
+    pub example: &'static str,
+    pub runs: bool, // Whether the example can run unattended, IN waits for input
+    pub limit: Option<OperandLimit>, // A narrower range for SRC than its 16 bits
+}
+
+const fn info(
+    mnemonic: &'static str,
+    opcode: u8,
+    group: &'static str,
+    operands: &'static str,
+    flags: &'static str,
+    description: &'static str,
+    example: &'static str,
+) -> InstructionInfo {
+    InstructionInfo {
+        mnemonic,
+        opcode,
+        group,
+        operands,
+        flags,
+        description,
+        example,
+        runs: true,
+        limit: None,
+    }
+}
+
+impl InstructionInfo {
+    // Limits SRC to range, operand says what it is in the error message
+    const fn limited(self, operand: &'static str, range: RangeInclusive<u16>) -> InstructionInfo {
+        InstructionInfo {
+            limit: Some(OperandLimit {
+                mnemonic: self.mnemonic,
+                operand,
+                range,
+            }),
+            ..self
+        }
+    }
+}
+
+#[rustfmt::skip]
+pub const INSTRUCTION_SET: &[InstructionInfo] = &[
+    info("HALT", HALT_OPCODE, "control", "", "-",
+        "Stops the CPU, nothing after it runs.",
+        "mov ax, 1\nprint ax\nhalt\nprint ax"),
+    info("ADD", ADD_OPCODE, "arithmetic", "REG, SRC", "overflow",
+        "Adds SRC to REG, wrapping around on overflow.",
+        "mov ax, 5\nadd ax, #3\nprint ax"),
+    info("MOV", MOV_OPCODE, "data", "REG, SRC", "-",
+        "Copies SRC into REG, an immediate or another register.",
+        "mov ax, 42\nmov bx, ax\nprint bx"),
+    info("MUL", MUL_OPCODE, "arithmetic", "REG, SRC", "overflow",
+        "Multiplies REG by SRC, wrapping around on overflow.",
+        "mov ax, 6\nmul ax, #7\nprint ax"),
+    info("SUB", SUB_OPCODE, "arithmetic", "REG, SRC", "overflow",
+        "Subtracts SRC from REG. Going below zero stops the program only with signed_mode off.",
+        "mov ax, 10\nsub ax, #4\nprint ax"),
+    info("SWAP", SWAP_OPCODE, "data", "REG, REG", "-",
+        "Exchanges the values of two registers.",
+        "mov ax, 1\nmov bx, 2\nswap ax, bx\nprint ax\nprint bx"),
+    info("DIV", DIV_OPCODE, "arithmetic", "REG, SRC", "overflow",
+        "Divides REG by SRC, rounding towards zero. Dividing by zero is an error.",
+        "mov ax, 17\ndiv ax, #5\nprint ax"),
+    info("MOD", MOD_OPCODE, "arithmetic", "REG, SRC", "overflow",
+        "Divides REG by SRC and keeps the remainder. Dividing by zero is an error.",
+        "mov ax, 17\nmod ax, #5\nprint ax"),
+    info("CLR", CLR_OPCODE, "data", "REG", "-",
+        "Sets REG to 0.",
+        "mov ax, 9\nclr ax\nprint ax"),
+    info("INC", INC_OPCODE, "arithmetic", "REG", "overflow",
+        "Adds 1 to REG.",
+        "mov ax, 9\ninc ax\nprint ax"),
+    info("DEC", DEC_OPCODE, "arithmetic", "REG", "overflow",
+        "Subtracts 1 from REG. Going below zero stops the program only with signed_mode off.",
+        "mov ax, 9\ndec ax\nprint ax"),
+    info("PRINT", PRINT_OPCODE, "output", "REG", "-",
+        "Prints REG in decimal, signed unless signed_mode is off.",
+        "mov ax, 42\nprint ax"),
+    info("PRINTX", PRINTX_OPCODE, "output", "REG", "-",
+        "Prints REG in hexadecimal.",
+        "mov ax, 42\nprintx ax"),
+    info("PRINTB", PRINTB_OPCODE, "output", "REG", "-",
+        "Prints all 16 bits of REG in binary, in groups of four with group_binary.",
+        "mov ax, 42\nprintb ax"),
+    info("PRINTC", PRINTC_OPCODE, "output", "REG", "-",
+        "Prints REG as an ASCII character, escaping anything that is not printable.",
+        "mov ax, 65\nprintc ax\nmov ax, 10\nprintc ax"),
+    info("PRINTS", PRINTS_OPCODE, "output", "IMM", "-",
+        "Prints the NUL terminated string starting at an address, as placed there by .ascii.",
+        ".ascii GREETING \"Hi\\n\"\nprints GREETING"),
+    info("PRINTW", PRINTW_OPCODE, "output", "REG, IMM", "-",
+        "Prints REG in decimal right-justified in a field of IMM characters, without a newline.",
+        ".ascii NEWLINE \"\\n\"\nmov ax, 5\nprintw ax, 4\nprintw ax, 4\nprints NEWLINE"),
+    info("POW", POW_OPCODE, "arithmetic", "REG, SRC", "overflow",
+        "Raises REG to the power SRC, wrapping around on overflow.",
+        "mov ax, 2\npow ax, #10\nprint ax"),
+    info("MOVR", MOVR_OPCODE, "data", "REG, REG", "-",
+        "Copies one register into another, the same as MOV with a register source.",
+        "mov bx, 7\nmovr ax, bx\nprint ax"),
+    info("CMP", CMP_OPCODE, "control", "REG, SRC", "zero, less, greater",
+        "Compares REG with SRC for the conditional jumps, signed unless signed_mode is off.",
+        "mov ax, 3\ncmp ax, #5\ngetf bx\nprint bx"),
+    info("JMP", JMP_OPCODE, "control", "TARGET", "-",
+        "Continues at a label or instruction index.",
+        "jmp skip\nmov ax, 1\nskip: print ax"),
+    info("NOP", NOP_OPCODE, "control", "", "-",
+        "Does nothing.",
+        "nop\nmov ax, 1\nprint ax"),
+    info("JE", JE_OPCODE, "control", "TARGET", "-",
+        "Jumps if the last CMP found the values equal.",
+        "mov ax, 5\ncmp ax, #5\nje equal\nmov bx, 1\nequal: print bx"),
+    info("JNE", JNE_OPCODE, "control", "TARGET", "-",
+        "Jumps if the last CMP found the values different.",
+        "mov ax, 3\nloop: dec ax\ncmp ax, #0\njne loop\nprint ax"),
+    info("JG", JG_OPCODE, "control", "TARGET", "-",
+        "Jumps if REG was greater than SRC in the last CMP.",
+        "mov ax, 7\ncmp ax, #5\njg bigger\nmov bx, 1\nbigger: print bx"),
+    info("JL", JL_OPCODE, "control", "TARGET", "-",
+        "Jumps if REG was less than SRC in the last CMP.",
+        "mov ax, 3\ncmp ax, #5\njl smaller\nmov bx, 1\nsmaller: print bx"),
+    info("RET", RET_OPCODE, "control", "", "-",
+        "Returns from a function to the instruction after its CALL.",
+        "call .one\nprint ax\n.one\nmov ax, 1\nret\n.end"),
+    info("LOAD", LOAD_OPCODE, "memory", "REG, IMM", "-",
+        "Reads the memory cell at an address into REG.",
+        "mov ax, 9\nstore ax, 100\nload bx, 100\nprint bx"),
+    info("STORE", STORE_OPCODE, "memory", "REG, IMM", "-",
+        "Writes REG to the memory cell at an address.",
+        "mov ax, 9\nstore ax, 100\nload bx, 100\nprint bx"),
+    info("LOADR", LOADR_OPCODE, "memory", "REG, REG", "-",
+        "Reads the memory cell at the address held in the second register.",
+        "mov ax, 9\nstore ax, 100\nmov cx, 100\nloadr bx, cx\nprint bx"),
+    info("STORER", STORER_OPCODE, "memory", "REG, REG", "-",
+        "Writes the first register to the address held in the second register.",
+        "mov ax, 9\nmov cx, 100\nstorer ax, cx\nload bx, 100\nprint bx"),
+    info("PUSH", PUSH_OPCODE, "stack", "REG", "-",
+        "Pushes REG onto the stack.",
+        "mov ax, 4\npush ax\npop bx\nprint bx"),
+    info("POP", POP_OPCODE, "stack", "REG", "-",
+        "Pops the top of the stack into REG.",
+        "mov ax, 4\npush ax\npop bx\nprint bx"),
+    InstructionInfo {
+        runs: false,
+        ..info("IN", IN_OPCODE, "input", "REG", "input_error",
+            "Reads a number from stdin into REG, see --input-errors for what happens to anything else.",
+            "in ax\nprint ax")
+    },
+    info("KEYIN", KEYIN_OPCODE, "input", "REG", "-",
+        "Reads the next key pressed into REG as its ASCII code, 0 when no key is waiting. Never waits.",
+        "mov ax, 1\nkeyin ax\nprint ax"),
+    info("GETF", GETF_OPCODE, "flags", "REG", "-",
+        "Copies the flags into REG: zero 1, less 2, greater 4, input_error 8, overflow 16, carry 32.",
+        "mov ax, 5\ncmp ax, #5\ngetf bx\nprint bx"),
+    info("SETF", SETF_OPCODE, "flags", "REG", "all",
+        "Sets the flags from REG, in the same layout as GETF.",
+        "mov ax, 1\nsetf ax\nje set\nmov bx, 1\nset: print bx"),
+    info("FLAGSWAP", FLAGSWAP_OPCODE, "flags", "REG", "all",
+        "Exchanges the flags with REG, in the same layout as GETF. Bits without a flag read as 0.",
+        "mov ax, 3\ncmp ax, #5\nmov bx, 1\nflagswap bx\nprint bx\nflagswap bx\nprint bx"),
+    info("ABSDIFF", ABSDIFF_OPCODE, "arithmetic", "REG, SRC", "-",
+        "Stores the distance between REG and SRC, larger minus smaller.",
+        "mov ax, 3\nabsdiff ax, #10\nprint ax"),
+    info("CALL", CALL_OPCODE, "control", "NAME", "-",
+        "Runs a function, pushing the return address on the stack.",
+        "call .double\nprint ax\n.double\nmov ax, 21\nadd ax, ax\nret\n.end"),
+    info("CALLE", CALLE_OPCODE, "control", "NAME", "-",
+        "Runs a function like CALL if the zero flag is set, so after CMP if the values were equal.",
+        "mov ax, 5\ncmp ax, #5\ncalle .hit\ncmp ax, #6\ncalle .hit\nprint bx\n.hit\ninc bx\nret\n.end"),
+    info("CALLNE", CALLNE_OPCODE, "control", "NAME", "-",
+        "Runs a function like CALL if the zero flag is clear, so after CMP if the values differed.",
+        "mov ax, 5\ncmp ax, #5\ncallne .miss\ncmp ax, #6\ncallne .miss\nprint bx\n.miss\ninc bx\nret\n.end"),
+    info("MEMCLR", MEMCLR_OPCODE, "memory", "IMM, IMM", "-",
+        "Sets a number of memory cells starting at an address to 0.",
+        "mov ax, 9\nstore ax, 100\nmemclr 100, 4\nload bx, 100\nprint bx"),
+    info("ISPOW2", ISPOW2_OPCODE, "bitwise", "REG", "zero",
+        "Replaces REG with 1 if it is a power of two and 0 otherwise, setting zero for a power of two.",
+        "mov ax, 64\nispow2 ax\nprint ax"),
+    info("NEXTPOW2", NEXTPOW2_OPCODE, "bitwise", "REG", "overflow",
+        "Rounds REG up to a power of two, values above 32768 give 0 and set overflow.",
+        "mov ax, 100\nnextpow2 ax\nprint ax"),
+    info("AND", AND_OPCODE, "bitwise", "REG, SRC", "-",
+        "Bitwise AND of REG and SRC.",
+        "mov ax, 0b1100\nand ax, #0b1010\nprintb ax"),
+    info("OR", OR_OPCODE, "bitwise", "REG, SRC", "-",
+        "Bitwise OR of REG and SRC.",
+        "mov ax, 0b1100\nor ax, #0b1010\nprintb ax"),
+    info("XOR", XOR_OPCODE, "bitwise", "REG, SRC", "-",
+        "Bitwise XOR of REG and SRC.",
+        "mov ax, 0b1100\nxor ax, #0b1010\nprintb ax"),
+    info("NOT", NOT_OPCODE, "bitwise", "REG", "-",
+        "Inverts every bit of REG.",
+        "mov ax, 0\nnot ax\nprintx ax"),
+    info("POPCNTR", POPCNTR_OPCODE, "bitwise", "REG, REG, IMM", "-",
+        "Counts the set bits of a number of registers starting at the second REG into the first.",
+        "mov bx, 0xff\nmov cx, 1\nmov dx, 0b101\npopcntr ax, bx, 3\nprint ax"),
+    info("SHL", SHL_OPCODE, "bitwise", "REG, SRC", "-",
+        "Shifts REG left by SRC bits, from 0 to 15.",
+        "mov ax, 1\nshl ax, #4\nprint ax").limited("amount", 0..=15),
+    info("SHR", SHR_OPCODE, "bitwise", "REG, SRC", "-",
+        "Shifts REG right by SRC bits, from 0 to 15.",
+        "mov ax, 256\nshr ax, #4\nprint ax").limited("amount", 0..=15),
+    // A rotation through carry turns 17 bits, so 16 is still a rotation of its own
+    info("RCL", RCL_OPCODE, "bitwise", "REG, SRC", "carry",
+        "Rotates REG and the carry flag left by SRC bits, from 0 to 16, the top bit of REG goes into carry and carry into bit 0.",
+        "mov ax, 0x8001\nmov bx, 0\nrcl ax, #1\nrcl bx, #1\nprintx ax\nprint bx").limited("amount", 0..=16),
+    info("RCR", RCR_OPCODE, "bitwise", "REG, SRC", "carry",
+        "Rotates REG and the carry flag right by SRC bits, from 0 to 16, bit 0 of REG goes into carry and carry into the top bit.",
+        "mov bx, 1\nmov ax, 0\nrcr bx, #1\nrcr ax, #1\nprint bx\nprintx ax").limited("amount", 0..=16),
+    info("LOADINC", LOADINC_OPCODE, "memory", "REG, REG", "-",
+        "Reads the memory cell at the address held in the second register, then increments that register.",
+        "mov ax, 9\nstore ax, 100\nmov cx, 100\nloadinc bx, cx\nprint bx\nprint cx"),
+];
+
+// Runs an example and checks that it uses the instruction it documents, returns what it printed
+fn run_example(info: &InstructionInfo) -> Result<String, String> {
+    let fail = |reason: String| Err(format!("The example of {} {}", info.mnemonic, reason));
+    let program = match parse_with_config(info.example.to_string(), &Config::default()) {
+        Ok(program) => program,
+        Err(e) => return fail(format!("does not assemble: {}", e)),
+    };
+    // Debug shows the variant name, which is the mnemonic also for MOVR
+    let uses_instruction = program
+        .instructions
+        .iter()
+        .chain(program.functions.values().flat_map(|f| &f.body))
+        .any(|instruction| format!("{:?}", instruction).split('(').next() == Some(info.mnemonic));
+    if !uses_instruction {
+        return fail("does not use it".to_string());
+    }
+    if !info.runs {
+        return Ok(String::new());
+    }
+    match run_program(info.example) {
+        Ok(state) => Ok(state.output),
+        Err(e) => fail(format!("fails: {}", e)),
+    }
+}
+
+// Source operands with a narrower range than their 16 bits. The parser checks immediates
+// against these, the Cpu and the oracle check registers when the instruction runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OperandLimit {
+    pub mnemonic: &'static str,
+    pub operand: &'static str, // What the operand is, for the error message
+    pub range: RangeInclusive<u16>,
+}
+
+impl OperandLimit {
+    // "SHL amount must be 0..=15, got 40", shared by the parse and the runtime error
+    pub fn message(&self, value: u16) -> String {
+        format!(
+            "{} {} must be {}..={}, got {}",
+            self.mnemonic,
+            self.operand,
+            self.range.start(),
+            self.range.end(),
+            value
+        )
+    }
+}
+
+/// The limit on the source operand of an instruction and that operand, for the instructions
+/// whose INSTRUCTION_SET entry has one.
+pub fn limited_operand(instruction: &Instruction) -> Option<(&'static OperandLimit, Operand)> {
+    static LIMITS: OnceLock<Vec<&'static OperandLimit>> = OnceLock::new();
+    let source = instruction.source_operand()?;
+    let mnemonic = instruction.to_mnemonic();
+    LIMITS
+        .get_or_init(|| {
+            INSTRUCTION_SET
+                .iter()
+                .filter_map(|info| info.limit.as_ref())
+                .collect()
+        })
+        .iter()
+        .find(|limit| limit.mnemonic == mnemonic)
+        .map(|&limit| (limit, source))
+}
+
+/// Markdown reference of the instruction set in opcode order. Fails if an instruction has
+/// no description or example, or if an example does not run.
+pub fn generate_isa_doc() -> Result<String, String> {
+    isa_doc(INSTRUCTION_SET)
+}
+
+fn isa_doc(table: &[InstructionInfo]) -> Result<String, String> {
+    let mut instructions: Vec<&InstructionInfo> = table.iter().collect();
+    instructions.sort_by_key(|info| info.opcode);
+
+    let mut out = String::from("# ALICE instruction set\n\n");
+    out.push_str("Generated by `alice --doc`. REG is a register, SRC a register or an immediate, ");
+    out.push_str("IMM an immediate, TARGET a label or instruction index and NAME a function.\n");
+    for info in instructions {
+        if info.description.is_empty() {
+            return Err(format!("{} has no description.", info.mnemonic));
+        }
+        if info.example.is_empty() {
+            return Err(format!("{} has no example.", info.mnemonic));
+        }
+        let output = run_example(info)?;
+        out.push_str(&format!(
+            "\n## {} - OPCODE: {:#x}\n\n",
+            info.mnemonic, info.opcode
+        ));
+        out.push_str(&format!(
+            "Group: {}  \nOperands: {}  \nFlags written: {}\n\n",
+            info.group,
+            if info.operands.is_empty() {
+                "none".to_string()
+            } else {
+                format!("`{}`", info.operands)
+            },
+            info.flags
+        ));
+        out.push_str(&format!(
+            "{}\n\n```\n{}\n```\n",
+            info.description, info.example
+        ));
+        if !output.is_empty() {
+            out.push_str(&format!("\nOutput:\n\n```\n{}```\n", output));
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_reference_has_a_section_for_every_mnemonic() {
+        let doc = generate_isa_doc().unwrap();
+        for info in INSTRUCTION_SET {
+            let heading = format!("\n## {} - OPCODE: {:#x}\n", info.mnemonic, info.opcode);
+            assert!(doc.contains(&heading), "{} is missing", info.mnemonic);
+        }
+        // The outputs of the examples are in it
+        assert!(doc.contains("Output:\n\n```\nax: 8\n```"));
+    }
+
+    #[test]
+    fn the_limits_come_from_the_instruction_set_entries() {
+        let (limit, source) = limited_operand(&Instruction::RCR(0, Operand::Register(1))).unwrap();
+        assert_eq!(limit.message(17), "RCR amount must be 0..=16, got 17");
+        assert_eq!(source, Operand::Register(1));
+        assert_eq!(
+            limited_operand(&Instruction::SHL(0, Operand::Immediate(3)))
+                .map(|(l, _)| l.range.clone()),
+            Some(0..=15)
+        );
+        assert!(limited_operand(&Instruction::ADD(0, Operand::Immediate(99))).is_none());
+    }
+
+    #[test]
+    fn a_broken_example_stops_the_reference_from_being_generated() {
+        let table = |example| {
+            [
+                info(
+                    "MOV",
+                    MOV_OPCODE,
+                    "data",
+                    "REG, SRC",
+                    "-",
+                    "Copies SRC into REG.",
+                    "mov ax, 1",
+                ),
+                info(
+                    "ADD",
+                    ADD_OPCODE,
+                    "arithmetic",
+                    "REG, SRC",
+                    "overflow",
+                    "Adds.",
+                    example,
+                ),
+            ]
+        };
+        assert!(isa_doc(&table("mov ax, 5\nadd ax, #3")).is_ok());
+        assert!(isa_doc(&table("add ax, zz"))
+            .unwrap_err()
+            .starts_with("The example of ADD does not assemble"));
+        assert_eq!(
+            isa_doc(&table("mov ax, 5")).unwrap_err(),
+            "The example of ADD does not use it"
+        );
+        assert!(isa_doc(&table("mov bx, 0\nadd ax, #1\ndiv ax, bx"))
+            .unwrap_err()
+            .starts_with("The example of ADD fails"));
+        assert_eq!(isa_doc(&table("")).unwrap_err(), "ADD has no example.");
+    }
+}
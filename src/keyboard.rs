@@ -0,0 +1,32 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::Receiver;
+
+// Where KEYIN gets its keys from. A key is its ASCII code and 0 means no key is waiting, reading
+// never blocks, so a program can poll for keys in its main loop.
+pub trait Keyboard {
+    fn next_key(&mut self) -> u16;
+}
+
+// Keys given up front, such as with --keys, one per read and 0 once they are used up
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedKeys(pub VecDeque<u8>);
+
+impl ScriptedKeys {
+    pub fn new(keys: &str) -> ScriptedKeys {
+        ScriptedKeys(keys.bytes().collect())
+    }
+}
+
+impl Keyboard for ScriptedKeys {
+    fn next_key(&mut self) -> u16 {
+        self.0.pop_front().map_or(0, u16::from)
+    }
+}
+
+// Keys sent from another thread, the one reading the terminal with --interactive. A bounded
+// sync_channel keeps the queue from growing while the program is not reading.
+impl Keyboard for Receiver<u8> {
+    fn next_key(&mut self) -> u16 {
+        self.try_recv().map_or(0, u16::from)
+    }
+}
@@ -0,0 +1,65 @@
+// The assembler and emulator as a library, the alice binary is a thin command line wrapper.
+// A program can be parsed, loaded into a Cpu and run without touching stdout:
+//
+//   let program = parse_file(source)?;
+//   let mut cpu = Cpu::new(Config::default());
+//   cpu.set_output(Box::new(std::io::sink()));
+//   cpu.load_program(&program);
+//   cpu.run()?;
+//   let ax = cpu.register(0);
+//
+// step() runs one instruction at a time and reports whether it moved on, jumped or halted,
+// run_until_breakpoint() runs up to the next instruction set with set_breakpoint().
+// add_tracer() hands every executed instruction and the registers it changed to a Tracer.
+
+pub mod analysis;
+pub mod batch;
+pub mod binary;
+pub mod config;
+pub mod cpu;
+pub mod diff;
+pub mod equivalence;
+pub mod error;
+pub mod explain;
+pub mod expression;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod helpers;
+pub mod instructions;
+pub mod isa;
+pub mod keyboard;
+pub mod lint;
+pub mod oracle;
+pub mod parser;
+pub mod plugins;
+pub mod profile;
+pub mod stats;
+pub mod symbols;
+pub mod trace;
+pub use analysis::*;
+pub use batch::*;
+pub use binary::*;
+use colorized::*;
+pub use config::*;
+pub use cpu::*;
+pub use diff::*;
+pub use equivalence::*;
+pub use error::*;
+pub use explain::*;
+pub use expression::*;
+#[cfg(feature = "fault-injection")]
+pub use fault::*;
+pub use helpers::*;
+pub use instructions::*;
+pub use isa::*;
+pub use keyboard::*;
+pub use lint::*;
+pub use oracle::*;
+pub use parser::*;
+pub use plugins::*;
+pub use profile::*;
+pub use stats::*;
+pub use symbols::*;
+pub use trace::*;
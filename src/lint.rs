@@ -0,0 +1,339 @@
+use crate::*;
+
+// Pedantic checks on assembly that parses fine but is probably not what was meant.
+// They only run with `pedantic = true` in config.toml, `strict = true` turns them into errors.
+// A line is skipped when its comment contains `lint-allow: <lint name>`.
+
+#[derive(Debug, PartialEq)]
+pub struct Lint {
+    pub line: usize, // Starts at 1
+    pub name: &'static str,
+    pub code: &'static str, // For `--explain CODE`, like the codes of errors
+    pub message: String,
+}
+
+pub const SELF_OPERAND_LINT: &str = "self-operand";
+pub const UNBALANCED_STACK_LINT: &str = "unbalanced-stack";
+pub const IGNORED_OPERAND_LINT: &str = "ignored-operand";
+pub const BARE_PRINT_LINT: &str = "bare-print";
+
+// Whether the comment on a line suppresses the lint called name
+fn is_allowed(line: &str, name: &str) -> bool {
+    line_comment(line).is_some_and(|comment| {
+        comment
+            .split("lint-allow:")
+            .skip(1)
+            .any(|allowed| allowed.split_whitespace().next() == Some(name))
+    })
+}
+
+/// Flags two-operand instructions that use the same register twice, which is almost always a typo.
+pub fn self_operand_lints(source: &str) -> Vec<Lint> {
+    let mut lints = Vec::new();
+    for (index, (line, tokens)) in source.lines().zip(lex(source)).enumerate() {
+        let [instruction, dest, src] = split_labels(&tokens).1 else {
+            continue;
+        };
+        let dest = dest.trim_end_matches(',');
+        // `ax` and `r0` are the same register, immediates such as `#1` never match
+        let same_register = match (parse_register(dest, 0), parse_operand(src, 0)) {
+            (Ok(reg), Ok(Operand::Register(src_reg))) => reg == src_reg,
+            _ => false,
+        };
+        if !same_register {
+            continue;
+        }
+        let instruction = instruction.to_uppercase();
+        let message = match instruction.as_str() {
+            "DIV" => format!(
+                "DIV {0}, {1} on line {2} always yields 1, did you mean a different register?",
+                dest,
+                src,
+                index + 1
+            ),
+            "MOD" => format!(
+                "MOD {0}, {1} on line {2} always yields 0, did you mean a different register?",
+                dest,
+                src,
+                index + 1
+            ),
+            "SUB" => format!(
+                "SUB {0}, {1} on line {2} always yields 0, did you mean a different register?",
+                dest,
+                src,
+                index + 1
+            ),
+            "CMP" => format!(
+                "CMP {0}, {1} on line {2} always compares equal.",
+                dest,
+                src,
+                index + 1
+            ),
+            // Clearing with XOR is deliberate, it just has a clearer spelling
+            "XOR" => format!(
+                "XOR {0}, {1} on line {2} clears the register, use CLR {0} instead.",
+                dest,
+                src,
+                index + 1
+            ),
+            _ => continue,
+        };
+        if !is_allowed(line, SELF_OPERAND_LINT) {
+            lints.push(Lint {
+                line: index + 1,
+                name: SELF_OPERAND_LINT,
+                code: "L001",
+                message,
+            });
+        }
+    }
+    lints
+}
+
+/// Flags functions with more PUSHes than POPs or the other way round, RET fails on those unless
+/// the jumps inside make up for it. Allowed by a comment on the function's `.name` line.
+pub fn unbalanced_stack_lints(source: &str) -> Vec<Lint> {
+    let mut lints = Vec::new();
+    let mut function: Option<(usize, String, usize, usize)> = None; // line, name, pushes, pops
+    for (index, (line, tokens)) in source.lines().zip(lex(source)).enumerate() {
+        let tokens = split_labels(&tokens).1;
+        let Some(first) = tokens.first() else {
+            continue;
+        };
+        match first.to_uppercase().as_str() {
+            ".END" => {
+                if let Some((line, name, pushes, pops)) = function.take() {
+                    if pushes != pops {
+                        lints.push(Lint {
+                            line,
+                            name: UNBALANCED_STACK_LINT,
+                            code: "L002",
+                            message: format!(
+                                "Function {} on line {} has {} PUSH and {} POP instructions, the stack is not balanced when it returns.",
+                                name, line, pushes, pops
+                            ),
+                        });
+                    }
+                }
+            }
+            ".DEFINE" | ".DATA" | ".ASCII" | ".INCLUDE" | ".INCLUDE_FORCE" | ".EXPORT" => {}
+            _ if first.starts_with('.') => {
+                function = (!is_allowed(line, UNBALANCED_STACK_LINT))
+                    .then(|| (index + 1, first.clone(), 0, 0));
+            }
+            "PUSH" => {
+                if let Some(function) = function.as_mut() {
+                    function.2 += 1;
+                }
+            }
+            "POP" => {
+                if let Some(function) = function.as_mut() {
+                    function.3 += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    lints
+}
+
+/// Flags operands given to an instruction that takes none, such as `halt 5`, which the assembler
+/// drops. Unlike the pedantic lints this one runs unless `legacy_operands = true`.
+pub fn ignored_operand_lints(source: &str) -> Vec<Lint> {
+    let mut lints = Vec::new();
+    for (index, (line, tokens)) in source.lines().zip(lex(source)).enumerate() {
+        let [instruction, operands @ ..] = split_labels(&tokens).1 else {
+            continue;
+        };
+        if operands.is_empty() || is_allowed(line, IGNORED_OPERAND_LINT) {
+            continue;
+        }
+        let mnemonic = instruction.to_uppercase();
+        let takes_none = INSTRUCTION_SET
+            .iter()
+            .any(|info| info.mnemonic == mnemonic && info.operands.is_empty());
+        if takes_none {
+            lints.push(Lint {
+                line: index + 1,
+                name: IGNORED_OPERAND_LINT,
+                code: "L003",
+                message: format!(
+                    "{} on line {} takes no operands, {} is ignored.",
+                    mnemonic,
+                    index + 1,
+                    operands.join(" ")
+                ),
+            });
+        }
+    }
+    lints
+}
+
+/// Flags PRINT without a register, which prints register a. Runs unless `legacy_operands = true`,
+/// like ignored_operand_lints.
+pub fn bare_print_lints(source: &str) -> Vec<Lint> {
+    let mut lints = Vec::new();
+    for (index, (line, tokens)) in source.lines().zip(lex(source)).enumerate() {
+        let [instruction] = split_labels(&tokens).1 else {
+            continue;
+        };
+        if instruction.eq_ignore_ascii_case("PRINT") && !is_allowed(line, BARE_PRINT_LINT) {
+            lints.push(Lint {
+                line: index + 1,
+                name: BARE_PRINT_LINT,
+                code: "L004",
+                message: format!(
+                    "PRINT on line {} has no register and prints a, write PRINT a if that is meant.",
+                    index + 1
+                ),
+            });
+        }
+    }
+    lints
+}
+
+/// The lints config asks for on a source file, by line. Ignored operands and bare PRINT are
+/// checked unless `legacy_operands = true`, the rest only when pedantic.
+pub fn lints_for(source: &str, config: &Config) -> Vec<Lint> {
+    let mut lints = Vec::new();
+    if config.pedantic {
+        lints.extend(self_operand_lints(source));
+        lints.extend(unbalanced_stack_lints(source));
+    }
+    if !config.legacy_operands {
+        lints.extend(ignored_operand_lints(source));
+        lints.extend(bare_print_lints(source));
+    }
+    lints.sort_by_key(|lint| lint.line);
+    lints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(strict: bool, legacy_operands: bool) -> Config {
+        Config {
+            debug: false,
+            strict,
+            legacy_operands,
+            ..Config::default()
+        }
+    }
+
+    fn codes(source: &str, config: &Config) -> Vec<&'static str> {
+        lints_for(source, config)
+            .iter()
+            .map(|lint| lint.code)
+            .collect()
+    }
+
+    #[test]
+    fn strict_mode_flags_bare_print() {
+        let config = config(true, false);
+        let program = parse_with_config("mov a, 1\nprint\nhalt\n".to_string(), &config).unwrap();
+        assert_eq!(program.instructions[1], Instruction::PRINT(0));
+        assert_eq!(codes("mov a, 1\nprint\nhalt\n", &config), ["L004"]);
+        assert_eq!(
+            lints_for("print\n", &config)[0].message,
+            "PRINT on line 1 has no register and prints a, write PRINT a if that is meant."
+        );
+        assert!(codes("print ; lint-allow: bare-print\nprint b\n", &config).is_empty());
+    }
+
+    #[test]
+    fn strict_mode_flags_an_operand_of_halt() {
+        let config = config(true, false);
+        let program = parse_with_config("halt 5\n".to_string(), &config).unwrap();
+        assert_eq!(program.instructions, [Instruction::HALT]);
+        assert_eq!(codes("halt 5\n", &config), ["L003"]);
+    }
+
+    #[test]
+    fn legacy_mode_prints_register_a_for_bare_print_quietly() {
+        let config = config(true, true);
+        let program = parse_with_config("print\n".to_string(), &config).unwrap();
+        assert_eq!(program.instructions, [Instruction::PRINT(0)]);
+        assert!(codes("print\n", &config).is_empty());
+    }
+
+    #[test]
+    fn legacy_mode_drops_the_operand_of_halt_quietly() {
+        let config = config(true, true);
+        let program = parse_with_config("halt 5\n".to_string(), &config).unwrap();
+        assert_eq!(program.instructions, [Instruction::HALT]);
+        assert!(codes("halt 5\n", &config).is_empty());
+    }
+
+    #[test]
+    fn pedantic_lints_only_run_when_asked() {
+        let source = "sub ax, ax\n";
+        assert!(codes(source, &config(false, false)).is_empty());
+        let pedantic = Config {
+            pedantic: true,
+            ..config(false, false)
+        };
+        assert_eq!(codes(source, &pedantic), ["L001"]);
+    }
+
+    #[test]
+    fn a_function_with_more_pushes_than_pops_is_unbalanced() {
+        let source = ".keep\npush a\npush b\npop b\nret\n.end\n.even\npush a\npop a\nret\n.end\n";
+        let lints = unbalanced_stack_lints(source);
+        assert_eq!(lints.len(), 1);
+        assert_eq!((lints[0].line, lints[0].code), (1, "L002"));
+        assert_eq!(
+            lints[0].message,
+            "Function .keep on line 1 has 2 PUSH and 1 POP instructions, the stack is not balanced when it returns."
+        );
+        let allowed = source.replacen(".keep", ".keep ; lint-allow: unbalanced-stack", 1);
+        assert!(unbalanced_stack_lints(&allowed).is_empty());
+    }
+
+    #[test]
+    fn the_same_register_twice_is_flagged_where_it_is_a_likely_typo() {
+        let lines = |source| {
+            self_operand_lints(source)
+                .iter()
+                .map(|lint| lint.line)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            lines(
+                "sub ax, r0\nsub a, #0\nadd a, a\ncmp c, c\nxor b, b ; lint-allow: self-operand\n"
+            ),
+            [1, 4]
+        );
+    }
+
+    #[test]
+    fn each_self_operand_lint_says_what_the_instruction_does() {
+        let message = |source| self_operand_lints(source)[0].message.clone();
+        assert_eq!(
+            message("div bx, bx\n"),
+            "DIV bx, bx on line 1 always yields 1, did you mean a different register?"
+        );
+        assert_eq!(
+            message("mod c, r2\n"),
+            "MOD c, r2 on line 1 always yields 0, did you mean a different register?"
+        );
+        assert_eq!(
+            message("sub a, a\n"),
+            "SUB a, a on line 1 always yields 0, did you mean a different register?"
+        );
+        assert_eq!(
+            message("cmp d, d\n"),
+            "CMP d, d on line 1 always compares equal."
+        );
+        assert_eq!(
+            message("xor e, e\n"),
+            "XOR e, e on line 1 clears the register, use CLR e instead."
+        );
+        for source in [
+            "div bx, bx ; lint-allow: self-operand\n",
+            "xor e, e ; lint-allow: self-operand\n",
+        ] {
+            assert!(self_operand_lints(source).is_empty());
+        }
+    }
+}
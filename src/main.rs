@@ -1,129 +1,987 @@
+use alice::*;
 use clap::Parser;
 use colorized::*;
+use debugger::*;
+use std::env;
 use std::fs;
-mod config;
-mod cpu;
-mod helpers;
-mod instructions;
-mod parser;
-use config::*;
-use cpu::*;
-use helpers::*;
-use instructions::*;
-use parser::*;
+use std::io::{self, IsTerminal};
+use std::time::Instant;
+use terminal::*;
+mod crash;
+mod debugger;
+mod terminal;
 
 #[derive(Parser)]
 struct Args {
     /// Output file for the binary
-    #[clap(short = 'o', long)]
+    #[clap(short = 'o', long, alias = "emit-bin")]
     output: Option<String>,
 
-    /// Path to the assembly file
-    file: String,
+    /// Path to the assembly file or an assembled binary
+    #[clap(required_unless_present_any = ["doc", "explain", "batch"])]
+    file: Option<String>,
 
     /// Run the binary
     #[clap(short, long)]
     run: bool,
+
+    /// Run these programs one after another and report the ones that failed, stopping at the
+    /// first one if stop_on_first_error is set in config.toml
+    #[clap(long, value_name = "FILES", num_args = 1.., conflicts_with_all = ["file", "debug"])]
+    batch: Vec<String>,
+
+    /// What IN does with input that is not a number
+    #[clap(long, value_enum, default_value_t = InputErrorPolicy::Abort)]
+    input_errors: InputErrorPolicy,
+
+    /// Leave the debug section with source lines out of the binary written by -o or -r
+    #[clap(long)]
+    strip: bool,
+
+    /// Stop the program after this many instructions, 0 for no limit, overrides max_instructions
+    #[clap(long, value_name = "N")]
+    max_instructions: Option<u64>,
+
+    /// Print every executed instruction and the registers it changed
+    #[clap(long)]
+    trace: bool,
+
+    /// Write the trace as JSON lines to this file
+    #[clap(long, value_name = "FILE")]
+    trace_file: Option<String>,
+
+    /// Character encoding of the source file
+    #[clap(long, value_enum, default_value_t = SourceEncoding::Utf8)]
+    encoding: SourceEncoding,
+
+    /// Replace invalid UTF-8 in the source with U+FFFD instead of failing
+    #[clap(long, conflicts_with = "encoding")]
+    lossy_utf8: bool,
+
+    /// Function or label to start running at instead of the first instruction
+    #[clap(long)]
+    entry: Option<String>,
+
+    /// Step through the program in an interactive debugger
+    #[clap(long)]
+    debug: bool,
+
+    /// Start every register at a random value from this seed instead of 0
+    #[clap(long, value_name = "SEED")]
+    fuzz_regs: Option<u64>,
+
+    /// Also fill memory with random values, needs --fuzz-regs
+    #[clap(long, requires = "fuzz_regs")]
+    fuzz_mem: bool,
+
+    /// Compare the program with another version of it, instruction by instruction
+    #[clap(long, value_name = "NEW")]
+    diff: Option<String>,
+
+    /// Only compare functions with the function of the same name, needs --diff
+    #[clap(long, requires = "diff")]
+    diff_functions: bool,
+
+    /// Write a Markdown reference of the instruction set, checking every example in it
+    #[clap(long, value_name = "OUT")]
+    doc: Option<String>,
+
+    /// Explain an error or lint code such as E014, shown in brackets after its message
+    #[clap(long, value_name = "CODE")]
+    explain: Option<String>,
+
+    /// Write the call graph of the program as a DOT file
+    #[clap(long)]
+    callgraph: Option<String>,
+
+    /// Print the worst case call depth and stack depth of the program without running it
+    #[clap(long)]
+    stack_info: bool,
+
+    /// List the loops of the program with how often each one runs, as far as that is known
+    /// without running it
+    #[clap(long)]
+    loops: bool,
+
+    /// Print the deepest the call stack and the data stack got once the program ends
+    #[clap(long)]
+    stack_hwm: bool,
+
+    /// Print how many instructions were executed once the program halts
+    #[clap(long)]
+    count: bool,
+
+    /// Keys KEYIN reads one per read, after them it reads 0
+    #[clap(long, value_name = "KEYS")]
+    keys: Option<String>,
+
+    /// Read keys for KEYIN from the terminal as they are pressed
+    #[clap(long, conflicts_with_all = ["keys", "debug"])]
+    interactive: bool,
+
+    /// Check every instruction against the reference implementation in oracle.rs, slowly
+    #[clap(long, conflicts_with = "debug")]
+    verify: bool,
+
+    /// Rewrite b1010 binary literals in the source file as 0b1010, keeping the original as .bak
+    #[clap(long)]
+    fix_binary_literals: bool,
+
+    /// Rename labels, functions and constants named like an instruction or register by adding
+    /// an underscore, keeping the original as .bak
+    #[clap(long)]
+    fix_reserved_names: bool,
+
+    /// Run the program repeatedly without output and report instructions per second
+    #[clap(long, conflicts_with_all = ["debug", "verify", "interactive"])]
+    bench: bool,
+
+    /// How many times --bench runs the program
+    #[clap(long, value_name = "N", default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    bench_iters: u32,
+
+    /// Also benchmark with this enabled and print its overhead, trace is the only choice
+    #[clap(long, value_name = "FEATURE", value_parser = ["trace"], requires = "bench")]
+    bench_compare: Option<String>,
+
+    /// Register and at least three values for it, like a=10,100,1000, to rerun the program
+    /// with and guess how its instruction count grows when it hits the instruction limit
+    #[clap(long, value_name = "REG=N,N,N", value_parser = parse_scale_reg)]
+    scale_reg: Option<(u16, Vec<u16>)>,
+
+    /// Check that a binary or --callgraph file was made from the source file as it is now,
+    /// includes and all, exiting with 1 if it was not
+    #[clap(long, value_name = "ARTIFACT")]
+    check_artifact: Option<String>,
+
+    /// Source file the binary being loaded was assembled from, warns if it has changed since
+    #[clap(long, value_name = "SOURCE")]
+    source: Option<String>,
+
+    /// Only guess the growth over the --scale-reg values, without a normal run
+    #[clap(long, requires = "scale_reg", conflicts_with_all = ["debug", "bench"])]
+    profile_growth: bool,
+}
+
+// REG=N1,N2,N3 for --scale-reg
+fn parse_scale_reg(text: &str) -> Result<(u16, Vec<u16>), String> {
+    let (name, values) = text
+        .split_once('=')
+        .ok_or("expected REG=N,N,N, like a=10,100,1000")?;
+    let register = parse_register(name.trim(), 0).map_err(|e| e.to_string())?;
+    let values = values
+        .split(',')
+        .map(|value| {
+            let value = value.trim();
+            value
+                .parse()
+                .map_err(|_| format!("{} is not a register value", value))
+        })
+        .collect::<Result<Vec<u16>, String>>()?;
+    let mut distinct = values.clone();
+    distinct.sort_unstable();
+    distinct.dedup();
+    if distinct.len() < 3 {
+        return Err("needs at least three different values".to_string());
+    }
+    Ok((register, values))
+}
+
+// Warns once per file that still writes binary literals as b1010
+fn warn_legacy_literals(files: &[(String, String)]) {
+    for (file, source) in files {
+        let found = legacy_binary_literals(source);
+        let Some(&(line, _)) = found.first() else {
+            continue;
+        };
+        eprintln!(
+            "{}",
+            format!(
+                "Warning: {} writes {} binary literal(s) as b1010, the first on line {}. This form is deprecated, write 0b1010 instead or run with --fix-binary-literals to rewrite the file.",
+                file,
+                found.len(),
+                line
+            )
+            .color(Colors::YellowFg)
+        );
+    }
+}
+
+// Rewrites a source file in place with fix, the original goes to FILE.bak. Nothing is written
+// when fix leaves the source as it was.
+fn fix_in_place<T>(path: &String, fix: impl FnOnce(&str) -> (String, T)) -> Result<T, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Could not read '{}': {}.", path, e))?;
+    let source = decode_source(path, bytes, SourceEncoding::Utf8).map_err(|e| e.to_string())?;
+    let (fixed, found) = fix(&source);
+    if fixed != source {
+        write_atomic(&format!("{}.bak", path), source.as_bytes()).map_err(|e| e.to_string())?;
+        write_atomic(path, fixed.as_bytes()).map_err(|e| e.to_string())?;
+    }
+    Ok(found)
+}
+
+// First line of a call graph written by --callgraph, followed by the source hash in hex
+const SOURCE_HASH_COMMENT: &str = "// source hash ";
+
+// The hash of a source file with its includes spliced in, as the parser gives Program::source_hash
+fn hash_source(path: &String, encoding: SourceEncoding) -> Result<u64, String> {
+    read_with_includes(path, encoding)
+        .map(|(source, _)| fnv1a(source.as_bytes()))
+        .map_err(|e| e.to_string())
+}
+
+// The source hash an artifact records, in the source section of a binary or the first line of
+// a call graph. None if it has none.
+fn recorded_source_hash(path: &str) -> Result<Option<u64>, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Could not read '{}': {}.", path, e))?;
+    if is_binary(&bytes) {
+        return decode_program(&bytes)
+            .map(|program| program.source_hash)
+            .map_err(|e| e.to_string());
+    }
+    Ok(String::from_utf8_lossy(&bytes)
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix(SOURCE_HASH_COMMENT))
+        .and_then(|hash| u64::from_str_radix(hash.trim(), 16).ok()))
+}
+
+// What is wrong when an artifact was not made from the source, None if it was
+fn source_mismatch(
+    artifact: &str,
+    recorded: Option<u64>,
+    source: &str,
+    hash: u64,
+) -> Option<String> {
+    match recorded {
+        Some(recorded) if recorded == hash => None,
+        Some(recorded) => Some(format!(
+            "{} was not made from {} as it is now: it records source hash {:016x}, {} with its includes hashes to {:016x}.",
+            artifact, source, recorded, source, hash
+        )),
+        None => Some(format!(
+            "{} records no source hash, it was built by hand or by an older alice.",
+            artifact
+        )),
+    }
+}
+
+// Warns when a binary being loaded was not assembled from the --source file as it is now
+fn warn_changed_source(program: &Program, args: &Args, encoding: SourceEncoding) {
+    let (Some(source), Some(file)) = (&args.source, &args.file) else {
+        return;
+    };
+    let message = match hash_source(source, encoding) {
+        Ok(hash) => source_mismatch(file, program.source_hash, source, hash),
+        Err(e) => Some(format!(
+            "Could not check {} against {}: {}",
+            file, source, e
+        )),
+    };
+    if let Some(message) = message {
+        eprintln!(
+            "{}",
+            format!("Warning: {}", message).color(Colors::YellowFg)
+        );
+    }
 }
-// declare config in config.rs
-pub fn declare_config() -> Config {
-    let config_content = match fs::read_to_string("config.toml") {
-        Ok(content) => content,
-        Err(_) => {
-            return Config::default();
+
+// Prints the lints for the source files, exiting if strict mode made any of them errors
+fn report_lints(files: &[(String, String)]) {
+    let config = declare_config();
+    let mut lints = Vec::new();
+    for (file, source) in files {
+        let mut found = lints_for(source, &config);
+        // Once other files are included, say which file a line number is in
+        if files.len() > 1 {
+            for lint in &mut found {
+                lint.message = format!("{}: {}", file, lint.message);
+            }
+        }
+        lints.extend(found);
+    }
+    for lint in &lints {
+        if config.strict {
+            eprintln!(
+                "{}{}",
+                "ERROR, ".color(Colors::RedFg),
+                format!("{} [{}]", lint.message, lint.code).color(Colors::RedFg)
+            );
+        } else {
+            eprintln!(
+                "{}",
+                format!("Warning: {} [{}]", lint.message, lint.code).color(Colors::YellowFg)
+            );
         }
+    }
+    if config.strict && !lints.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+// Reads and parses an assembly file, or decodes it if it is an assembled binary,
+// exiting with an error message if either fails
+fn load_source(path: &String, encoding: SourceEncoding) -> Program {
+    crash::set_phase(crash::Phase::Parsing);
+    let bytes = fs::read(path);
+    if let Ok(bytes) = &bytes {
+        crash::set_source(path, bytes);
+    }
+    let loaded = match bytes {
+        Ok(bytes) if is_binary(&bytes) => decode_program(&bytes).map_err(|e| e.to_string()),
+        _ => read_with_includes(path, encoding)
+            .and_then(|(source, map)| {
+                let mut program = parse_with_includes(source.clone(), &map, &declare_config())?;
+                program.positions = map.positions(&source);
+                if declare_config().verbose_debug {
+                    for (included, file, line) in &map.skipped {
+                        println!(
+                            "Skipped including {} again on line {} of {}",
+                            included, line, file
+                        );
+                    }
+                }
+                warn_legacy_literals(&map.files);
+                report_lints(&map.files);
+                retain_source(map.files);
+                Ok(program)
+            })
+            .map_err(|e| e.to_string()),
     };
+    match loaded {
+        Ok(program) => {
+            if declare_config().warn_unreachable {
+                for (name, line) in unreachable_functions(&program) {
+                    eprintln!(
+                        "{}",
+                        format!(
+                            "Warning: function {} defined on line {} is never called.",
+                            name, line
+                        )
+                        .color(Colors::YellowFg)
+                    );
+                }
+            }
+            crash::set_phase(crash::Phase::Loaded);
+            program
+        }
+        Err(e) => {
+            eprintln!(
+                "{}{}",
+                "ERROR, ".color(Colors::RedFg),
+                e.color(Colors::RedFg)
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+// Moves the CPU to the entry point given on the command line, exiting if it does not exist
+fn select_entry(cpu: &mut Cpu, entry: &Option<String>) {
+    if let Some(entry) = entry {
+        if let Err(e) = cpu.set_entry(entry) {
+            eprintln!(
+                "{}{}",
+                "ERROR, ".color(Colors::RedFg),
+                e.color(Colors::RedFg)
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+// Whether what goes to stdout is colored: only at a terminal, and not when NO_COLOR is set
+pub fn color_stdout() -> bool {
+    io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none()
+}
 
-    match toml::de::from_str::<Config>(&config_content) {
-        Ok(config) => config,
-        Err(_) => {
-            println!("config.toml parsing failed. defaulting.");
-            Config::default() // return default config if parsing fails
+// Prints a runtime error with the instructions that led up to it and exits
+pub fn report_runtime_error(cpu: &Cpu, error: &RuntimeError) {
+    cpu.print_history();
+    eprintln!(
+        "{}{}",
+        "ERROR, ".color(Colors::RedFg),
+        error.to_string().color(Colors::RedFg)
+    );
+    match cpu.positions.get(&(error.function.clone(), error.index)) {
+        Some((file, line)) => {
+            eprintln!("{}", format!("  at {}:{}", file, line).color(Colors::RedFg))
+        }
+        None if cpu.positions.is_empty() => eprintln!(
+            "{}",
+            "  The binary has no debug info, so only the instruction index is known."
+                .color(Colors::RedFg)
+        ),
+        None => {}
+    }
+}
+
+// Loads the memory_image of config.toml into a Cpu that has its program loaded, exiting if
+// it cannot be read or does not fit
+fn load_memory_image(cpu: &mut Cpu, config: &Config) {
+    let Some(path) = &config.memory_image else {
+        return;
+    };
+    let loaded = fs::read(path)
+        .map_err(|e| format!("Could not read the memory image '{}': {}.", path, e))
+        .and_then(|bytes| cpu.load_memory_image(&bytes, config.memory_image_offset));
+    if let Err(e) = loaded {
+        eprintln!(
+            "{}{}",
+            "ERROR, ".color(Colors::RedFg),
+            e.color(Colors::RedFg)
+        );
+        std::process::exit(1);
+    }
+}
+
+// Loads program into cpu for run. --fuzz-regs fills the registers and memory first, so the
+// .ascii data of the program and the memory image still land on top of the random values.
+fn prepare(cpu: &mut Cpu, program: &Program, config: &Config, args: &Args) {
+    if let Some(seed) = args.fuzz_regs {
+        eprintln!("Fuzzing registers with seed {}", seed);
+        cpu.fuzz(seed, args.fuzz_mem);
+    }
+    cpu.load_program(program);
+    load_memory_image(cpu, config);
+    select_entry(cpu, &args.entry);
+}
+
+fn run(cpu: &mut Cpu, args: &Args, program: &Program, config: &Config) {
+    crash::set_program(program);
+    crash::set_phase(crash::Phase::Running);
+    if let Some(keys) = &args.keys {
+        cpu.set_keyboard(Box::new(ScriptedKeys::new(keys)));
+    }
+    // Restores the terminal once dropped, before anything is reported
+    let raw_mode = match args.interactive.then(start_keyboard) {
+        Some(Ok((raw_mode, keys))) => {
+            cpu.set_keyboard(Box::new(keys));
+            Some(raw_mode)
+        }
+        Some(Err(e)) => {
+            eprintln!(
+                "{}{}",
+                "ERROR, ".color(Colors::RedFg),
+                e.color(Colors::RedFg)
+            );
+            std::process::exit(1);
+        }
+        None => None,
+    };
+    let result = if args.debug {
+        debug(cpu);
+        Ok(())
+    } else if args.verify {
+        match run_verified(cpu) {
+            Ok(()) => Ok(()),
+            Err(VerifyError::Runtime(e)) => Err(e),
+            Err(VerifyError::Diverged(divergence)) => {
+                restore_terminal();
+                eprintln!("{}", divergence.to_string().color(Colors::RedFg));
+                std::process::exit(1);
+            }
+        }
+    } else {
+        cpu.run()
+    };
+    drop(raw_mode);
+    if args.stack_hwm {
+        eprintln!(
+            "Stack high-water mark: call depth {}, stack depth {}",
+            cpu.deepest_call, cpu.deepest_stack
+        );
+    }
+    if let Err(e) = result {
+        report_runtime_error(cpu, &e);
+        if matches!(e.reason, RuntimeErrorKind::InstructionLimitExceeded { .. }) {
+            profile_growth(program, config, args);
+        }
+        std::process::exit(1);
+    }
+    if args.count {
+        eprintln!("Instructions executed: {}", cpu.instructions_executed);
+    }
+    if cpu.show_final_state {
+        println!("{}", cpu.final_state_table(color_stdout()));
+    }
+}
+
+// Instructions per second of each of iterations runs of program, every run on a new Cpu with
+// no output. traced adds a JSON tracer writing nowhere, which still formats every event.
+fn bench_runs(
+    program: &Program,
+    config: &Config,
+    args: &Args,
+    traced: bool,
+) -> Result<(u64, Vec<f64>), RuntimeError> {
+    crash::set_program(program);
+    crash::set_phase(crash::Phase::Running);
+    let mut executed = 0;
+    let mut samples = Vec::new();
+    for _ in 0..args.bench_iters {
+        let mut cpu = Cpu::new(config.clone());
+        cpu.set_output(Box::new(io::sink()));
+        cpu.set_input(Box::new(io::empty()));
+        if let Some(limit) = args.max_instructions {
+            cpu.max_instructions = limit;
+        }
+        if traced {
+            cpu.add_tracer(Box::new(JsonTracer { out: io::sink() }));
+        }
+        cpu.load_program(program);
+        load_memory_image(&mut cpu, config);
+        select_entry(&mut cpu, &args.entry);
+        let start = Instant::now();
+        cpu.run()?;
+        let seconds = start.elapsed().as_secs_f64();
+        executed = cpu.instructions_executed;
+        samples.push(executed as f64 / seconds.max(f64::MIN_POSITIVE));
+    }
+    Ok((executed, samples))
+}
+
+// Reruns program on a new Cpu with no output for each --scale-reg value in the register, counting
+// the instructions of every function, and prints the curve those counts fit best
+fn profile_growth(program: &Program, config: &Config, args: &Args) {
+    let Some((register, values)) = &args.scale_reg else {
+        return;
+    };
+    let name = register_name(*register as usize);
+    crash::set_program(program);
+    crash::set_phase(crash::Phase::Running);
+    let profile = growth_profile(program, config, *register, values, |cpu| {
+        if let Some(limit) = args.max_instructions {
+            cpu.max_instructions = limit;
+        }
+        load_memory_image(cpu, config);
+        select_entry(cpu, &args.entry);
+    });
+    for (value, e) in &profile.failed {
+        eprintln!("With {} = {}, left out: {}", name, value, e);
+    }
+    println!(
+        "Growth with {}, a heuristic guess from {} runs:",
+        name,
+        values.len() - profile.failed.len()
+    );
+    for row in profile.rows {
+        let growth = match row.growth {
+            Some(growth) => growth.to_string(),
+            None => "unclear".to_string(),
+        };
+        let counts: Vec<String> = row
+            .steps
+            .iter()
+            .map(|(value, count)| format!("{}={}: {}", name, value, count))
+            .collect();
+        println!("  {:<16}{:<12}({})", row.label, growth, counts.join(", "));
+    }
+}
+
+fn bench(program: &Program, config: &Config, args: &Args) {
+    let configurations = match &args.bench_compare {
+        Some(feature) => vec![
+            (format!("Without {}: ", feature), false),
+            (format!("With {}: ", feature), true),
+        ],
+        None => vec![(String::new(), false)],
+    };
+    let mut medians = Vec::new();
+    for (label, traced) in configurations {
+        let (executed, samples) = match bench_runs(program, config, args, traced) {
+            Ok(result) => result,
+            Err(e) => {
+                eprintln!("{}", format!("ERROR, {}", e).color(Colors::RedFg));
+                std::process::exit(1);
+            }
+        };
+        let summary = summarize(&samples).expect("bench_iters is at least 1");
+        println!(
+            "{}{} runs of {} instructions, instructions/s: min {:.0}, median {:.0}, p95 {:.0}, max {:.0}, std dev {:.0}",
+            label,
+            samples.len(),
+            executed,
+            summary.min,
+            summary.median,
+            summary.p95,
+            summary.max,
+            summary.std_dev
+        );
+        medians.push(summary.median);
+    }
+    if let (Some(feature), [without, with]) = (&args.bench_compare, &medians[..]) {
+        println!(
+            "Overhead of {}: {:.1}% more time (median)",
+            feature,
+            overhead_percent(*without, *with)
+        );
+    }
+}
+
+fn write_binary(program: &Program, path: &str, strip: bool) -> bool {
+    let encoded = match strip {
+        true => encode_stripped(program),
+        false => encode_program(program),
+    };
+    let written = encoded.map_err(|e| e.to_string()).and_then(|bytes| {
+        write_atomic(path, &bytes)
+            .map(|()| bytes.len())
+            .map_err(|e| e.to_string())
+    });
+    match written {
+        Ok(size) => {
+            println!("Binary emitted to {}", path);
+            if let Ok(stripped) = encode_stripped(program).map(|bytes| bytes.len()) {
+                if size > stripped {
+                    println!(
+                        "Debug info: {} of {} bytes ({:.0}%), --strip leaves it out",
+                        size - stripped,
+                        size,
+                        (size - stripped) as f64 * 100.0 / size as f64
+                    );
+                }
+            }
+            true
+        }
+        Err(e) => {
+            eprintln!("Error writing binary file: {}", e);
+            false
         }
     }
 }
 
 fn main() {
+    crash::install_panic_hook();
     let config = declare_config();
-    let mut cpu = CPU::new();
+    let mut cpu = Cpu::new(config.clone());
 
     // Parse command-line arguments
     let args = Args::parse();
+    cpu.input_errors = args.input_errors;
+    if let Some(limit) = args.max_instructions {
+        cpu.max_instructions = limit;
+    }
+    if args.trace {
+        cpu.add_tracer(Box::new(FilteredTracer {
+            filter: config.trace_filter.clone(),
+            tracer: ConsoleTracer {
+                signed: config.signed_mode,
+                color: color_stdout(),
+            },
+        }));
+    }
+    if let Some(trace_file) = &args.trace_file {
+        match fs::File::create(trace_file) {
+            // Line by line, so the trace is complete even when an error exits the process
+            Ok(file) => cpu.add_tracer(Box::new(FilteredTracer {
+                filter: config.trace_filter.clone(),
+                tracer: JsonTracer {
+                    out: std::io::LineWriter::new(file),
+                },
+            })),
+            Err(e) => {
+                eprintln!("Error creating trace file: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Explain a code, no program is needed either
+    if let Some(code) = &args.explain {
+        match explanation_of(code) {
+            Some(explanation) => print!("{}", format_explanation(explanation)),
+            None => {
+                eprintln!(
+                    "{}{}",
+                    "ERROR, ".color(Colors::RedFg),
+                    format!("There is no error or lint with the code {}.", code)
+                        .color(Colors::RedFg)
+                );
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Generate the instruction set reference, no program is needed
+    if let Some(doc_file) = &args.doc {
+        let written = generate_isa_doc()
+            .and_then(|doc| write_atomic(doc_file, doc.as_bytes()).map_err(|e| e.to_string()));
+        match written {
+            Ok(()) => println!("Instruction set reference written to {}", doc_file),
+            Err(e) => {
+                eprintln!(
+                    "{}{}",
+                    "ERROR, ".color(Colors::RedFg),
+                    e.color(Colors::RedFg)
+                );
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    // Run a batch of programs, each of them on a Cpu of its own
+    if !args.batch.is_empty() {
+        let results = run_batch(&args.batch, &config, |cpu| {
+            cpu.input_errors = args.input_errors;
+            if let Some(limit) = args.max_instructions {
+                cpu.max_instructions = limit;
+            }
+        });
+        let failed = results
+            .iter()
+            .filter(|result| result.error.is_some())
+            .count();
+        for result in &results {
+            if let Some(e) = &result.error {
+                eprintln!(
+                    "{}{}",
+                    "ERROR, ".color(Colors::RedFg),
+                    format!("{}: {}", result.file, e).color(Colors::RedFg)
+                );
+            }
+        }
+        let skipped = args.batch.len() - results.len();
+        match skipped {
+            0 => eprintln!("{} of {} programs failed", failed, results.len()),
+            _ => eprintln!(
+                "Stopped at the first program that failed, {} of {} not run",
+                skipped,
+                args.batch.len()
+            ),
+        }
+        if failed > 0 {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let file = args.file.clone().unwrap_or_default(); // clap only leaves it out with --doc
+    let encoding = if args.lossy_utf8 {
+        SourceEncoding::LossyUtf8
+    } else {
+        args.encoding
+    };
+
+    // Compare an artifact with the source it should have been made from
+    if let Some(artifact) = &args.check_artifact {
+        let mismatch = hash_source(&file, encoding).and_then(|hash| {
+            recorded_source_hash(artifact)
+                .map(|recorded| (source_mismatch(artifact, recorded, &file, hash), hash))
+        });
+        match mismatch {
+            Ok((None, hash)) => println!(
+                "{} was made from {} (source hash {:016x}).",
+                artifact, file, hash
+            ),
+            Ok((Some(e), _)) | Err(e) => {
+                eprintln!(
+                    "{}{}",
+                    "ERROR, ".color(Colors::RedFg),
+                    e.color(Colors::RedFg)
+                );
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if args.fix_binary_literals || args.fix_reserved_names {
+        let fixed = if args.fix_binary_literals {
+            fix_in_place(&file, fix_binary_literals).map(|count| match count {
+                0 => format!("{} has no b1010 binary literals to rewrite.", file),
+                _ => format!(
+                    "Rewrote {} binary literal(s) in {} as 0b, the original is in {}.bak",
+                    count, file, file
+                ),
+            })
+        } else {
+            fix_in_place(&file, fix_reserved_names).map(|renamed| match renamed.is_empty() {
+                true => format!("{} has no names to rename.", file),
+                false => format!(
+                    "Renamed {} in {}, the original is in {}.bak",
+                    renamed.join(", "),
+                    file,
+                    file
+                ),
+            })
+        };
+        match fixed {
+            Ok(message) => println!("{}", message),
+            Err(e) => {
+                eprintln!(
+                    "{}{}",
+                    "ERROR, ".color(Colors::RedFg),
+                    e.color(Colors::RedFg)
+                );
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Compare two programs, the exit code says whether they differ
+    if let Some(new_file) = &args.diff {
+        // Binaries have no source to take line numbers from
+        let lines = |path: &String| match fs::read(path) {
+            Ok(bytes) if !is_binary(&bytes) => instruction_lines(&String::from_utf8_lossy(&bytes)),
+            _ => Default::default(),
+        };
+        let ops = diff_programs(
+            &load_source(&file, encoding),
+            &lines(&file),
+            &load_source(new_file, encoding),
+            &lines(new_file),
+            args.diff_functions,
+        );
+        if ops.is_empty() {
+            println!("The programs are identical.");
+            return;
+        }
+        print!("{}", format_diff(&ops));
+        std::process::exit(1);
+    }
+
+    // Estimate how often each loop runs without running anything
+    if args.loops {
+        let program = load_source(&file, encoding);
+        let loops = estimate_loops(&program, config.signed_mode);
+        if loops.is_empty() {
+            println!("The program has no loops.");
+        }
+        for estimate in loops {
+            let scope = estimate.function.clone();
+            let mut location = format!(
+                "[{} {}..={}]",
+                scope.as_deref().unwrap_or("global"),
+                estimate.start,
+                estimate.end
+            );
+            let lines = |index| program.positions.get(&(scope.clone(), index));
+            if let (Some((file, first)), Some((_, last))) =
+                (lines(estimate.start), lines(estimate.end))
+            {
+                location = format!("{} {}:{}-{}", location, file, first, last);
+            }
+            match estimate.iterations {
+                Some(iterations) => println!("Loop {}: runs {} time(s)", location, iterations),
+                None => println!(
+                    "Loop {}: {}",
+                    location,
+                    "unknown number of iterations".color(Colors::YellowFg)
+                ),
+            }
+        }
+        return;
+    }
+
+    // Estimate the stack use without running anything
+    if args.stack_info {
+        let program = load_source(&file, encoding);
+        match CallGraph::new(&program).stack_usage(&program) {
+            StackBound::Bounded {
+                call_depth,
+                stack_depth,
+            } => {
+                println!("Maximum call depth: {}", call_depth);
+                println!("Maximum stack depth: {} (estimate)", stack_depth);
+            }
+            StackBound::Unbounded { cycle } => {
+                println!("Maximum call depth: unbounded (recursion via {})", cycle);
+                println!("Maximum stack depth: unbounded (recursion via {})", cycle);
+            }
+        }
+        return;
+    }
+
+    if args.bench {
+        bench(&load_source(&file, encoding), &config, &args);
+        return;
+    }
+
+    // Export the call graph without running anything
+    if let Some(dot_file) = args.callgraph {
+        let program = load_source(&file, encoding);
+        let graph = CallGraph::new(&program);
+        let mut dot = graph.to_dot();
+        if let Some(hash) = program.source_hash {
+            dot = format!("{}{:016x}\n{}", SOURCE_HASH_COMMENT, hash, dot);
+        }
+        if let Err(e) = write_atomic(&dot_file, dot.as_bytes()) {
+            eprintln!("Error writing call graph: {}", e);
+            return;
+        }
+        println!("Call graph written to {}", dot_file);
+        for cycle in graph.recursion_cycles() {
+            println!("recursion cycle: {}", graph.format_cycle(&cycle));
+        }
+        return;
+    }
 
     // Check if the -o flag is used for compilation
     if let Some(output_file) = args.output {
         // Read the assembly file
-        let program = parse_file(read_file(&args.file));
+        let program = load_source(&file, encoding);
 
         if config.verbose_debug {
             println!("{:?}", program);
         }
 
-        // Load the program into the CPU
-        cpu.load_program(&program);
-
-        // Emit the binary
-        if let Err(e) = cpu.emit_binary(&output_file) {
-            eprintln!("Error writing binary file: {}", e);
-            return;
-        } else {
-            println!("Binary emitted to {}", output_file);
-        }
-
+        write_binary(&program, &output_file, args.strip);
         return; // Exit after compiling
     }
 
     // If the -r flag is used, run the specified file
     if args.run {
-        let file_to_run = &args.file; // Use the provided file argument
+        let file_to_run = &file; // Use the provided file argument
 
-        // Attempt to load the binary file
-        if let Err(e) = cpu.load_binary(file_to_run) {
-            eprintln!("Error loading binary file: {}", e);
-
-            // If loading the binary fails, assume it's an assembly file and compile it
-            let program = parse_file(read_file(&file_to_run.to_string()));
+        // Binaries run directly, anything else is assembled to <file>.bin first
+        let is_assembled = fs::read(file_to_run).is_ok_and(|bytes| is_binary(&bytes));
+        let binary_file = if is_assembled {
+            file_to_run.clone()
+        } else {
+            let program = load_source(file_to_run, encoding);
 
             if config.verbose_debug {
                 println!("{:?}", program);
             }
 
-            // Load the program into the CPU
-            cpu.load_program(&program);
-
-            // Emit default output file if not specified
             let output_file = format!("{}.bin", file_to_run);
-            if let Err(e) = cpu.emit_binary(&output_file) {
-                eprintln!("Error writing binary file: {}", e);
+            if !write_binary(&program, &output_file, args.strip) {
                 return;
-            } else {
-                println!("Binary emitted to {}", output_file);
             }
+            output_file
+        };
 
-            // Run the newly created binary
-            if let Err(e) = cpu.load_binary(&output_file) {
-                eprintln!("Error loading binary file: {}", e);
-                return;
-            }
-            cpu.run();
-            return; // Exit after running the binary
+        // Run the binary
+        let program = load_source(&binary_file, encoding);
+        warn_changed_source(&program, &args, encoding);
+        if args.profile_growth {
+            profile_growth(&program, &config, &args);
+            return;
         }
-
-        // If it successfully loads the binary, just run it
-        cpu.run();
+        prepare(&mut cpu, &program, &config, &args);
+        run(&mut cpu, &args, &program, &config);
         return; // Exit after running the binary
     }
 
     // Normal execution flow for assembly if no run flag is used
-    let program = parse_file(read_file(&args.file));
+    let program = load_source(&file, encoding);
+    warn_changed_source(&program, &args, encoding);
 
     if config.verbose_debug {
         println!("{:?}", program);
     }
 
+    if args.profile_growth {
+        profile_growth(&program, &config, &args);
+        return;
+    }
+
     // Load the program into the CPU and run it
-    cpu.load_program(&program);
-    cpu.run();
+    prepare(&mut cpu, &program, &config, &args);
+    run(&mut cpu, &args, &program, &config);
 
     // Print register values if debug is enabled
     if config.debug || config.verbose_debug {
@@ -137,7 +995,80 @@ fn main() {
         println!(
             "{}\n{}\n",
             "\nFILE CONTENTS".color(Colors::WhiteFg),
-            read_file(&args.file).color(Colors::GreenFg)
+            read_file(&file, encoding)
+                .unwrap_or_default()
+                .color(Colors::GreenFg)
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bench_runs_the_program_bench_iters_times_with_and_without_a_tracer() {
+        let config = Config {
+            debug: false,
+            ..Config::default()
+        };
+        let program = parse_with_config(
+            "mov a, 3\nloop: dec a\ncmp a, 0\njne loop\nhalt\n".to_string(),
+            &config,
+        )
+        .unwrap();
+        let args =
+            Args::try_parse_from(["alice", "loop.asm", "--bench", "--bench-iters", "2"]).unwrap();
+        for traced in [false, true] {
+            let (executed, samples) = bench_runs(&program, &config, &args, traced).unwrap();
+            // MOV, three rounds of DEC, CMP and JNE, then HALT
+            assert_eq!(executed, 11, "traced: {}", traced);
+            assert_eq!(samples.len(), 2, "traced: {}", traced);
+            assert!(samples.iter().all(|&rate| rate > 0.0), "{:?}", samples);
+        }
+    }
+
+    #[test]
+    fn an_artifact_matches_only_the_source_it_was_made_from() {
+        let directory = env::temp_dir().join(format!("alice-artifact-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = |name: &str| directory.join(name).to_string_lossy().into_owned();
+        fs::write(path("main.asm"), ".include \"lib.asm\"\nhalt\n").unwrap();
+        fs::write(path("lib.asm"), "mov a, 1\n").unwrap();
+        let hash = hash_source(&path("main.asm"), SourceEncoding::Utf8).unwrap();
+
+        let config = Config {
+            debug: false,
+            ..Config::default()
+        };
+        let (source, map) = read_with_includes(&path("main.asm"), SourceEncoding::Utf8).unwrap();
+        let program = parse_with_includes(source, &map, &config).unwrap();
+        fs::write(path("main.bin"), encode_program(&program).unwrap()).unwrap();
+        fs::write(
+            path("graph.dot"),
+            format!("{}{:016x}\ndigraph {{}}\n", SOURCE_HASH_COMMENT, hash),
+        )
+        .unwrap();
+        fs::write(path("old.dot"), "digraph {}\n").unwrap();
+        for artifact in ["main.bin", "graph.dot"] {
+            let recorded = recorded_source_hash(&path(artifact)).unwrap();
+            assert_eq!(recorded, Some(hash));
+            assert_eq!(source_mismatch(artifact, recorded, "main.asm", hash), None);
+        }
+        assert!(source_mismatch(
+            "old.dot",
+            recorded_source_hash(&path("old.dot")).unwrap(),
+            "main.asm",
+            hash
+        )
+        .unwrap()
+        .contains("records no source hash"));
+
+        // One byte of an included file is enough
+        fs::write(path("lib.asm"), "mov a, 2\n").unwrap();
+        let changed = hash_source(&path("main.asm"), SourceEncoding::Utf8).unwrap();
+        let mismatch = source_mismatch("main.bin", Some(hash), "main.asm", changed).unwrap();
+        assert!(mismatch.starts_with("main.bin was not made from main.asm as it is now"));
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
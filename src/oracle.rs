@@ -0,0 +1,618 @@
+use crate::*;
+use std::fmt;
+
+// A second implementation of the instructions, written from their documented behaviour instead
+// of sharing code with execute_instruction, to catch the two drifting apart. Each instruction is
+// a pure function of the state before it: operands are only read from state and results only
+// written to next, so an instruction can never see its own writes. The executor has to read all
+// operands before writing any destination too, a register named twice that it got wrong shows up
+// as a divergence. run_verified runs a Cpu one step at a time and checks every step against it,
+// which is slow, it is meant for testing new instructions.
+
+// Everything an instruction can change apart from where execution continues
+#[derive(Debug, Clone, PartialEq)]
+pub struct OracleState {
+    pub registers: [u16; REGISTER_COUNT],
+    pub flags: Flags,
+    pub memory: Vec<u16>,
+    pub sp: usize,
+}
+
+impl OracleState {
+    pub fn of(cpu: &Cpu) -> OracleState {
+        OracleState {
+            registers: cpu.registers,
+            flags: cpu.flags,
+            memory: cpu.memory.clone(),
+            sp: cpu.sp,
+        }
+    }
+}
+
+// What the oracle expects an instruction to do
+#[derive(Debug, Clone, PartialEq)]
+pub enum Prediction {
+    State(OracleState),
+    Error,     // The instruction must fail, which error is not compared
+    Unmodeled, // CALL, a taken CALLE or CALLNE, RET, IN, KEYIN and CUSTOM depend on more than the state
+}
+
+// The flag bits GETF documents
+fn flag_bits(flags: &Flags) -> u16 {
+    flags.zero as u16
+        | (flags.less as u16) << 1
+        | (flags.greater as u16) << 2
+        | (flags.input_error as u16) << 3
+        | (flags.overflow as u16) << 4
+        | (flags.carry as u16) << 5
+}
+
+fn flags_from_bits(bits: u16) -> Flags {
+    Flags {
+        zero: bits & 1 != 0,
+        less: bits & 2 != 0,
+        greater: bits & 4 != 0,
+        input_error: bits & 8 != 0,
+        overflow: bits & 16 != 0,
+        carry: bits & 32 != 0,
+    }
+}
+
+// Rotates the 17 bit value of carry above a register left by amount
+fn rotate_17(value: u16, carry: bool, amount: u32) -> (u16, bool) {
+    let word = (carry as u32) << 16 | value as u32;
+    let rotated = (word << amount | word >> (17 - amount)) & 0x1ffff;
+    (rotated as u16, rotated >> 16 != 0)
+}
+
+/// What an instruction does to a state. signed is signed_mode and max_stack_depth the limit
+/// PUSH checks, the only configuration the instructions read.
+pub fn predict(
+    state: &OracleState,
+    instruction: &Instruction,
+    signed: bool,
+    max_stack_depth: usize,
+) -> Prediction {
+    if instruction
+        .registers()
+        .iter()
+        .any(|&register| register as usize >= REGISTER_COUNT)
+    {
+        return Prediction::Error;
+    }
+    let mut next = state.clone();
+    // Values as numbers, two's complement in signed mode
+    let number = |value: u16| match signed {
+        true => value as i16 as i64,
+        false => value as i64,
+    };
+    let fits = |value: i64| match signed {
+        true => (-32768..=32767).contains(&value),
+        false => (0..=65535).contains(&value),
+    };
+    let value = |operand: Operand| match operand {
+        Operand::Register(register) => state.registers[register as usize],
+        Operand::Immediate(value) => value,
+    };
+    if let Some((limit, src)) = limited_operand(instruction) {
+        if !limit.range.contains(&value(src)) {
+            return Prediction::Error;
+        }
+    }
+    let size = state.memory.len();
+    // The exact result of an arithmetic instruction, stored wrapped around
+    let mut arithmetic = |reg: u16, result: i64| {
+        next.registers[reg as usize] = result as u16;
+        next.flags.overflow = !fits(result);
+    };
+    match *instruction {
+        Instruction::MOV(reg, value) => next.registers[reg as usize] = value,
+        Instruction::MOVR(reg1, reg2) => {
+            next.registers[reg1 as usize] = state.registers[reg2 as usize]
+        }
+        Instruction::SWAP(reg1, reg2) => {
+            next.registers[reg1 as usize] = state.registers[reg2 as usize];
+            next.registers[reg2 as usize] = state.registers[reg1 as usize];
+        }
+        Instruction::CLR(reg) => next.registers[reg as usize] = 0,
+        Instruction::ADD(reg, src) => arithmetic(
+            reg,
+            number(state.registers[reg as usize]) + number(value(src)),
+        ),
+        Instruction::MUL(reg, src) => arithmetic(
+            reg,
+            number(state.registers[reg as usize]) * number(value(src)),
+        ),
+        Instruction::INC(reg) => arithmetic(reg, number(state.registers[reg as usize]) + 1),
+        // Unsigned, going below zero is an error rather than an overflow
+        Instruction::SUB(reg, src) => {
+            let result = number(state.registers[reg as usize]) - number(value(src));
+            if !signed && result < 0 {
+                return Prediction::Error;
+            }
+            arithmetic(reg, result);
+        }
+        Instruction::DEC(reg) => {
+            let result = number(state.registers[reg as usize]) - 1;
+            if !signed && result < 0 {
+                return Prediction::Error;
+            }
+            arithmetic(reg, result);
+        }
+        Instruction::DIV(reg, src) => {
+            let divisor = number(value(src));
+            if divisor == 0 {
+                return Prediction::Error;
+            }
+            arithmetic(reg, number(state.registers[reg as usize]) / divisor);
+        }
+        // The result always fits, but as in Rust -32768 % -1 still counts as an overflow
+        Instruction::MOD(reg, src) => {
+            let (lhs, divisor) = (number(state.registers[reg as usize]), number(value(src)));
+            if divisor == 0 {
+                return Prediction::Error;
+            }
+            next.registers[reg as usize] = (lhs % divisor) as u16;
+            next.flags.overflow = signed && lhs == -32768 && divisor == -1;
+        }
+        // The exponent is always unsigned. The magnitude only grows once it leaves the range,
+        // so the loop can stop there.
+        Instruction::POW(reg, src) => {
+            let (base, exponent) = (number(state.registers[reg as usize]), value(src));
+            let mut exact = 1;
+            let mut overflow = false;
+            for _ in 0..exponent {
+                exact *= base;
+                if !fits(exact) {
+                    overflow = true;
+                    break;
+                }
+            }
+            next.registers[reg as usize] =
+                state.registers[reg as usize].wrapping_pow(exponent as u32);
+            next.flags.overflow = overflow;
+        }
+        Instruction::ABSDIFF(reg, src) => {
+            let difference = number(state.registers[reg as usize]) - number(value(src));
+            next.registers[reg as usize] = difference.unsigned_abs() as u16;
+        }
+        Instruction::CMP(reg, src) => {
+            let (lhs, rhs) = (number(state.registers[reg as usize]), number(value(src)));
+            next.flags.zero = lhs == rhs;
+            next.flags.less = lhs < rhs;
+            next.flags.greater = lhs > rhs;
+        }
+        Instruction::AND(reg, src) => next.registers[reg as usize] &= value(src),
+        Instruction::OR(reg, src) => next.registers[reg as usize] |= value(src),
+        Instruction::XOR(reg, src) => next.registers[reg as usize] ^= value(src),
+        Instruction::NOT(reg) => next.registers[reg as usize] = !state.registers[reg as usize],
+        // Amounts are within their INSTRUCTION_SET limits, checked above
+        Instruction::SHL(reg, src) => {
+            next.registers[reg as usize] = state.registers[reg as usize] << value(src)
+        }
+        Instruction::SHR(reg, src) => {
+            next.registers[reg as usize] = state.registers[reg as usize] >> value(src)
+        }
+        // A right rotation of 17 bits is a left rotation by 17 minus the amount
+        Instruction::RCL(reg, src) | Instruction::RCR(reg, src) => {
+            let amount = value(src) as u32;
+            let amount = match instruction {
+                Instruction::RCR(..) => (17 - amount) % 17,
+                _ => amount,
+            };
+            let (result, carry) =
+                rotate_17(state.registers[reg as usize], state.flags.carry, amount);
+            next.registers[reg as usize] = result;
+            next.flags.carry = carry;
+        }
+        Instruction::POPCNTR(dest, start, count) => {
+            next.registers[dest as usize] = (start..start + count)
+                .map(|register| state.registers[register as usize].count_ones() as u16)
+                .sum();
+        }
+        Instruction::ISPOW2(reg) => {
+            let is_power = state.registers[reg as usize].count_ones() == 1;
+            next.registers[reg as usize] = is_power as u16;
+            next.flags.zero = is_power;
+        }
+        Instruction::NEXTPOW2(reg) => {
+            let value = state.registers[reg as usize] as u32;
+            let power = (0..=16)
+                .map(|bit| 1u32 << bit)
+                .find(|&power| power >= value);
+            let power = power.unwrap_or(1 << 16);
+            next.registers[reg as usize] = power as u16;
+            next.flags.overflow = power > 0xffff;
+        }
+        Instruction::GETF(reg) => next.registers[reg as usize] = flag_bits(&state.flags),
+        Instruction::SETF(reg) => next.flags = flags_from_bits(state.registers[reg as usize]),
+        Instruction::FLAGSWAP(reg) => {
+            next.registers[reg as usize] = flag_bits(&state.flags);
+            next.flags = flags_from_bits(state.registers[reg as usize]);
+        }
+        Instruction::LOAD(reg, address) | Instruction::LOADR(reg, address) => {
+            let address = match instruction {
+                Instruction::LOADR(..) => state.registers[address as usize],
+                _ => address,
+            };
+            let Some(&cell) = state.memory.get(address as usize) else {
+                return Prediction::Error;
+            };
+            next.registers[reg as usize] = cell;
+        }
+        Instruction::STORE(reg, address) | Instruction::STORER(reg, address) => {
+            let address = match instruction {
+                Instruction::STORER(..) => state.registers[address as usize],
+                _ => address,
+            };
+            if address as usize >= size {
+                return Prediction::Error;
+            }
+            next.memory[address as usize] = state.registers[reg as usize];
+        }
+        // The loaded value is written last, so it wins when both registers are the same
+        Instruction::LOADINC(reg, pointer) => {
+            let address = state.registers[pointer as usize];
+            let Some(&cell) = state.memory.get(address as usize) else {
+                return Prediction::Error;
+            };
+            next.registers[pointer as usize] = address.wrapping_add(1);
+            next.registers[reg as usize] = cell;
+        }
+        Instruction::MEMCLR(start, count) => {
+            let end = start as usize + count as usize;
+            if end > size {
+                return Prediction::Error;
+            }
+            next.memory[start as usize..end].fill(0);
+        }
+        // The stack grows down from the end of memory and holds at most max_stack_depth values
+        Instruction::PUSH(reg) => {
+            if state.sp == 0 || size - state.sp >= max_stack_depth {
+                return Prediction::Error;
+            }
+            next.sp = state.sp - 1;
+            next.memory[next.sp] = state.registers[reg as usize];
+        }
+        Instruction::POP(reg) => {
+            if state.sp >= size {
+                return Prediction::Error;
+            }
+            next.registers[reg as usize] = state.memory[state.sp];
+            next.sp = state.sp + 1;
+        }
+        // A string must end with a 0 somewhere before the end of memory
+        Instruction::PRINTS(address) => {
+            let terminated = state
+                .memory
+                .get(address as usize..)
+                .is_some_and(|cells| cells.contains(&0));
+            if address as usize >= size || !terminated {
+                return Prediction::Error;
+            }
+        }
+        Instruction::PRINT(_)
+        | Instruction::PRINTX(_)
+        | Instruction::PRINTB(_)
+        | Instruction::PRINTC(_)
+        | Instruction::PRINTW(..)
+        | Instruction::JMP(_)
+        | Instruction::JE(_)
+        | Instruction::JNE(_)
+        | Instruction::JG(_)
+        | Instruction::JL(_)
+        | Instruction::NOP
+        | Instruction::HALT => {}
+        // Not calling changes nothing
+        Instruction::CALLE(_) if !state.flags.zero => {}
+        Instruction::CALLNE(_) if state.flags.zero => {}
+        Instruction::CALL(_)
+        | Instruction::CALLE(_)
+        | Instruction::CALLNE(_)
+        | Instruction::RET
+        | Instruction::IN(_)
+        | Instruction::KEYIN(_)
+        | Instruction::CUSTOM(..) => return Prediction::Unmodeled,
+    }
+    Prediction::State(next)
+}
+
+// Fields where the executor and the oracle ended up with different states
+fn differences(actual: &OracleState, expected: &OracleState) -> Vec<String> {
+    // field is only named for a difference, memory has a cell for every address
+    fn differ<T: PartialEq + fmt::Display>(
+        differences: &mut Vec<String>,
+        field: impl FnOnce() -> String,
+        actual: T,
+        expected: T,
+    ) {
+        if actual != expected {
+            differences.push(format!(
+                "{}: executor {}, oracle {}",
+                field(),
+                actual,
+                expected
+            ));
+        }
+    }
+    let mut differences = Vec::new();
+    for (register, (actual, expected)) in
+        actual.registers.iter().zip(&expected.registers).enumerate()
+    {
+        differ(
+            &mut differences,
+            || register_name(register),
+            actual,
+            expected,
+        );
+    }
+    let flags = |flags: &Flags| {
+        [
+            ("zero", flags.zero),
+            ("less", flags.less),
+            ("greater", flags.greater),
+            ("input_error", flags.input_error),
+            ("overflow", flags.overflow),
+            ("carry", flags.carry),
+        ]
+    };
+    for ((name, actual), (_, expected)) in
+        flags(&actual.flags).into_iter().zip(flags(&expected.flags))
+    {
+        differ(
+            &mut differences,
+            || format!("{} flag", name),
+            actual,
+            expected,
+        );
+    }
+    differ(
+        &mut differences,
+        || "sp".to_string(),
+        actual.sp,
+        expected.sp,
+    );
+    // Comparing the whole of memory at once first keeps a step cheap when no cell differs
+    if actual.memory == expected.memory {
+        return differences;
+    }
+    for (address, (actual, expected)) in actual.memory.iter().zip(&expected.memory).enumerate() {
+        differ(
+            &mut differences,
+            || format!("memory[{}]", address),
+            actual,
+            expected,
+        );
+    }
+    differences
+}
+
+// A step where the executor did something else than the oracle
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    pub step: u64, // Instructions run before it, counting from 0
+    pub function: Option<String>,
+    pub index: u16,
+    pub instruction: Instruction,
+    pub differences: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The executor and the oracle disagree at step {}, [{} {}] {}:",
+            self.step,
+            self.function.as_deref().unwrap_or("global"),
+            self.index,
+            self.instruction
+        )?;
+        for difference in &self.differences {
+            write!(f, "\n  {}", difference)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    Runtime(RuntimeError), // The program failed, and the oracle agreed it should
+    Diverged(Box<Divergence>),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::Runtime(error) => error.fmt(f),
+            VerifyError::Diverged(divergence) => divergence.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Runs a loaded program like Cpu::run, checking the state after every instruction against
+/// predict. Stops at the first step where they disagree.
+pub fn run_verified(cpu: &mut Cpu) -> Result<(), VerifyError> {
+    cpu.running = true;
+    let mut step = 0;
+    loop {
+        let (function, index) = (cpu.current_function.clone(), cpu.pc);
+        let limit_reached =
+            cpu.max_instructions > 0 && cpu.instructions_executed >= cpu.max_instructions;
+        let instruction = cpu
+            .peek_instruction()
+            .filter(|_| cpu.running && !limit_reached)
+            .cloned();
+        let mut prediction = match &instruction {
+            Some(instruction) => predict(
+                &OracleState::of(cpu),
+                instruction,
+                cpu.signed,
+                cpu.max_stack_depth,
+            ),
+            None => Prediction::Unmodeled,
+        };
+        // A hardwired register 0 is not part of the instruction semantics
+        if let (Prediction::State(state), true) = (&mut prediction, cpu.zero_register) {
+            state.registers[0] = 0;
+        }
+        let result = cpu.step();
+        let differences = match (&prediction, &result) {
+            (Prediction::State(expected), Ok(_)) => differences(&OracleState::of(cpu), expected),
+            (Prediction::State(_), Err(error)) => {
+                vec![format!(
+                    "executor failed with \"{}\", oracle expected no error",
+                    error.reason
+                )]
+            }
+            (Prediction::Error, Ok(_)) => {
+                vec!["executor ran it, oracle expected an error".to_string()]
+            }
+            _ => Vec::new(),
+        };
+        if let (false, Some(instruction)) = (differences.is_empty(), instruction) {
+            return Err(VerifyError::Diverged(Box::new(Divergence {
+                step,
+                function,
+                index,
+                instruction,
+                differences,
+            })));
+        }
+        if result.map_err(VerifyError::Runtime)? == StepOutcome::Halted {
+            return Ok(());
+        }
+        step += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Registers a to d, so instructions keep working on each other's results
+    fn register(rng: &mut Rng) -> u16 {
+        rng.next_u16() % 4
+    }
+
+    // An operand, mostly an immediate that fits a shift or rotate and does not divide by zero
+    fn operand(rng: &mut Rng) -> Operand {
+        match rng.next_u16() % 4 {
+            0 => Operand::Register(register(rng)),
+            _ => Operand::Immediate(1 + rng.next_u16() % 15),
+        }
+    }
+
+    // One of the instructions the oracle models, on addresses within the 64 cells of MEMORY
+    fn random_instruction(rng: &mut Rng) -> Instruction {
+        let (reg, other) = (register(rng), register(rng));
+        let (src, address) = (operand(rng), rng.next_u16() % 64);
+        let list = [
+            Instruction::ADD(reg, src),
+            Instruction::SUB(reg, src),
+            Instruction::MUL(reg, src),
+            Instruction::DIV(reg, src),
+            Instruction::MOD(reg, src),
+            Instruction::POW(reg, src),
+            Instruction::ABSDIFF(reg, src),
+            Instruction::AND(reg, src),
+            Instruction::OR(reg, src),
+            Instruction::XOR(reg, src),
+            Instruction::SHL(reg, src),
+            Instruction::SHR(reg, src),
+            Instruction::RCL(reg, src),
+            Instruction::RCR(reg, src),
+            Instruction::CMP(reg, src),
+            Instruction::MOV(reg, rng.next_u16()),
+            Instruction::MOVR(reg, other),
+            Instruction::SWAP(reg, other),
+            Instruction::CLR(reg),
+            Instruction::INC(reg),
+            Instruction::DEC(reg),
+            Instruction::NOT(reg),
+            Instruction::ISPOW2(reg),
+            Instruction::NEXTPOW2(reg),
+            Instruction::POPCNTR(reg, other, rng.next_u16() % 4),
+            Instruction::GETF(reg),
+            Instruction::SETF(reg),
+            Instruction::FLAGSWAP(reg),
+            Instruction::PUSH(reg),
+            Instruction::PUSH(other),
+            Instruction::POP(reg),
+            Instruction::LOAD(reg, address),
+            Instruction::STORE(reg, address),
+            Instruction::LOADR(reg, other),
+            Instruction::STORER(reg, other),
+            Instruction::LOADINC(reg, other),
+            Instruction::MEMCLR(address, rng.next_u16() % 8),
+        ];
+        list[rng.next_u16() as usize % list.len()].clone()
+    }
+
+    #[test]
+    fn random_programs_run_the_same_on_the_executor_and_the_oracle() {
+        for signed in [true, false] {
+            for seed in 0..500 {
+                let mut rng = Rng::new(seed);
+                // Something on the stack first, so not every POP fails
+                let pushes = (0..16).map(|register| Instruction::PUSH(register % 4));
+                let program = Program {
+                    instructions: pushes
+                        .chain((0..100).map(|_| random_instruction(&mut rng)))
+                        .collect(),
+                    ..Program::default()
+                };
+                let mut cpu = Cpu::new(Config {
+                    debug: false,
+                    memory_size: 64,
+                    signed_mode: signed,
+                    ..Config::default()
+                });
+                cpu.set_output(Box::new(std::io::sink()));
+                cpu.fuzz(seed, true);
+                cpu.load_program(&program);
+                // Small enough to be an address or a shift amount most of the time
+                for register in 0..4 {
+                    cpu.set_register(register, rng.next_u16() % 64);
+                }
+                // Failing is fine as long as the oracle expected it
+                if let Err(VerifyError::Diverged(divergence)) = run_verified(&mut cpu) {
+                    panic!("seed {}, signed {}: {}", seed, signed, divergence);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn the_oracle_predicts_results_and_failures() {
+        let state = OracleState::of(&Cpu::new(Config::default()));
+        let Prediction::State(next) = predict(&state, &Instruction::MOV(2, 7), true, 16) else {
+            panic!("MOV has a result");
+        };
+        assert_eq!(next.registers[2], 7);
+        assert_eq!(
+            predict(&state, &Instruction::DIV(0, Operand::Register(1)), true, 16),
+            Prediction::Error
+        );
+        assert_eq!(
+            predict(&state, &Instruction::CALL(".f".to_string()), true, 16),
+            Prediction::Unmodeled
+        );
+    }
+
+    #[test]
+    fn a_divergence_names_the_step_and_what_differs() {
+        let divergence = Divergence {
+            step: 3,
+            function: None,
+            index: 3,
+            instruction: Instruction::ADD(0, Operand::Immediate(1)),
+            differences: vec!["ax: executor 7, oracle 6".to_string()],
+        };
+        assert_eq!(
+            divergence.to_string(),
+            "The executor and the oracle disagree at step 3, [global 3] ADD ax, #1:\n  ax: executor 7, oracle 6"
+        );
+    }
+}
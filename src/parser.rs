@@ -1,67 +1,1559 @@
 use crate::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::num::IntErrorKind;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+
+// How the bytes of a source file are turned into text
+#[derive(Debug, Default, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum SourceEncoding {
+    #[default]
+    Utf8, // Invalid UTF-8 is an error pointing at the first bad byte
+    Latin1, // Every byte is the character with the same code, any file decodes
+    #[value(skip)]
+    LossyUtf8, // Invalid UTF-8 is replaced with U+FFFD after a warning, set by --lossy-utf8
+}
+
+// Line and column, both starting at 1, of a byte offset into a file
+fn byte_position(bytes: &[u8], offset: usize) -> (usize, usize) {
+    let before = &bytes[..offset];
+    let line_start = before
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    let line = before.iter().filter(|&&b| b == b'\n').count() + 1;
+    // Everything up to offset is valid UTF-8, so the column can count characters
+    let column = String::from_utf8_lossy(&before[line_start..])
+        .chars()
+        .count()
+        + 1;
+    (line, column)
+}
+
+/// Turns the bytes of a source file into text, path is only used in errors and warnings.
+pub fn decode_source(
+    path: &str,
+    bytes: Vec<u8>,
+    encoding: SourceEncoding,
+) -> Result<String, ParseError> {
+    if encoding == SourceEncoding::Latin1 {
+        return Ok(bytes.iter().map(|&byte| byte as char).collect());
+    }
+    let error = match String::from_utf8(bytes) {
+        Ok(source) => return Ok(source),
+        Err(error) => error,
+    };
+    let offset = error.utf8_error().valid_up_to();
+    let bytes = error.into_bytes();
+    let (line, column) = byte_position(&bytes, offset);
+    if encoding == SourceEncoding::LossyUtf8 {
+        eprintln!(
+            "{}",
+            format!(
+                "Warning: '{}' is not valid UTF-8 from line {} column {}, invalid bytes are replaced.",
+                path, line, column
+            )
+            .color(Colors::YellowFg)
+        );
+        return Ok(String::from_utf8_lossy(&bytes).into_owned());
+    }
+    Err(ParseError::InvalidUtf8 {
+        path: path.to_string(),
+        line,
+        column,
+        byte: bytes[offset],
+    })
+}
 
 /// Reads the contents of a file or creates it with default content.
-pub fn read_file(f_name: &String) -> String {
+pub fn read_file(f_name: &String, encoding: SourceEncoding) -> Result<String, ParseError> {
+    let io_error = |error| ParseError::IoError {
+        path: f_name.to_string(),
+        error,
+    };
     // Check if the file exists at the given path
     if Path::new(&f_name).exists() {
         // If it exists, read the contents of the file
-        fs::read_to_string(f_name).unwrap_or_else(|_| {
-            // If reading fails, print an error and exit
-            println!("Error reading file '{}'. Exiting.", f_name);
-            std::process::exit(1);
-        })
+        let bytes = fs::read(f_name).map_err(io_error)?;
+        decode_source(f_name, bytes, encoding)
     } else {
         // If the file does not exist, create it with default content
         println!("Could not find file; creating it.");
         let default_content = "MOV 1, 5\nMOV 2, 3\nADD 0, 1\nSUB 1, 2\nMUL 1, 2";
-        fs::write(f_name, default_content).unwrap_or_else(|_| {
-            // If writing fails, print an error and exit
-            println!("Could not write to file '{}'. Exiting.", f_name);
-            std::process::exit(1);
-        });
+        write_atomic(f_name, default_content.as_bytes()).map_err(|e| io_error(e.error))?;
         // Return the default content as a string
-        default_content.to_string()
+        Ok(default_content.to_string())
+    }
+}
+
+// Byte offset of the comment on a line. `;` starts a comment anywhere outside a string or
+// character, `#` only where it is not an immediate: at the start of the line or followed by
+// whitespace, so `mov a, 5 # set a` is a comment but `mov a, #5` is not.
+fn comment_start(line: &str) -> Option<usize> {
+    let mut quote = None; // The quote of the string or character being read
+    let mut escaped = false;
+    let mut chars = line.char_indices().peekable();
+    while let Some((offset, c)) = chars.next() {
+        if let Some(open) = quote {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                _ if c == open => quote = None,
+                _ => {}
+            }
+            continue;
+        }
+        let comment = match c {
+            '"' | '\'' => {
+                quote = Some(c);
+                false
+            }
+            ';' => true,
+            '#' => {
+                line[..offset].trim().is_empty()
+                    || chars.peek().is_none_or(|&(_, next)| next.is_whitespace())
+            }
+            _ => false,
+        };
+        if comment {
+            return Some(offset);
+        }
+    }
+    None
+}
+
+/// The text of a `;@ label=setup` comment, which stays with the instruction on its line and is
+/// shown in traces. None for lines without one, other comments are dropped.
+pub fn annotation(line: &str) -> Option<&str> {
+    let offset = comment_start(line)?;
+    line[offset..].strip_prefix(";@").map(str::trim)
+}
+
+/// The comment on a line without its `;` or `#`, None if the line has none.
+pub fn line_comment(line: &str) -> Option<&str> {
+    comment_start(line).map(|offset| &line[offset + 1..])
+}
+
+// Byte ranges of the tokens of one line, its comment left out. A `"..."` string or `'.'`
+// character is part of a single token, quotes, spaces and `;` included, and so is an expression
+// in parentheses like `#(W * H)`.
+fn token_spans(line: &str) -> Vec<Range<usize>> {
+    let end = comment_start(line).unwrap_or(line.len());
+    let mut spans = Vec::new();
+    let mut start = None; // Where the token being read starts
+    let mut depth = 0; // Open parentheses
+    let mut chars = line[..end].char_indices();
+    while let Some((offset, c)) = chars.next() {
+        if c.is_whitespace() && depth == 0 {
+            if let Some(start) = start.take() {
+                spans.push(start..offset);
+            }
+            continue;
+        }
+        start.get_or_insert(offset);
+        match c {
+            '"' | '\'' => {
+                // Up to the closing quote, escapes are kept as written for unescape
+                let open = c;
+                while let Some((_, c)) = chars.next() {
+                    if c == '\\' {
+                        chars.next();
+                    } else if c == open {
+                        break;
+                    }
+                }
+            }
+            '(' => depth += 1,
+            ')' => depth = (depth - 1).max(0),
+            _ => {}
+        }
     }
+    if let Some(start) = start {
+        spans.push(start..end);
+    }
+    spans
+}
+
+// Tokens of one line with its comment removed, see token_spans. Whitespace inside an
+// expression becomes a single space, strings and characters are kept as written.
+fn lex_line(line: &str) -> Vec<String> {
+    token_spans(line)
+        .into_iter()
+        .map(|span| {
+            let mut token = String::new();
+            let (mut quote, mut escaped) = (None, false);
+            for c in line[span].chars() {
+                if let Some(open) = quote {
+                    if !escaped && c == open {
+                        quote = None;
+                    }
+                    escaped = !escaped && c == '\\';
+                } else if c == '"' || c == '\'' {
+                    quote = Some(c);
+                } else if c.is_whitespace() {
+                    if !token.ends_with(' ') {
+                        token.push(' ');
+                    }
+                    continue;
+                }
+                token.push(c);
+            }
+            token
+        })
+        .collect()
+}
+
+// Whether a word is a binary literal in the older `b1010` form
+fn is_legacy_binary(word: &str) -> bool {
+    word.len() > 1
+        && word.starts_with(['b', 'B'])
+        && word[1..].chars().all(|c| c == '0' || c == '1')
+}
+
+/// The `b1010` binary literals in a source, as the line they are on and the byte offset of
+/// their `b`. Only operands are looked at, the same words where parse_number reads a number,
+/// not mnemonics, constant names, strings or comments.
+pub fn legacy_binary_literals(source: &str) -> Vec<(usize, usize)> {
+    let mut found = Vec::new();
+    let mut line_start = 0;
+    for (index, line) in source.split_inclusive('\n').enumerate() {
+        let spans = token_spans(line);
+        let labels = spans
+            .iter()
+            .take_while(|span| line[(*span).clone()].ends_with(':'))
+            .count();
+        let mut operands = spans.iter().skip(labels);
+        if let Some(first) = operands.next() {
+            // The name a constant directive defines is not an operand
+            if is_constant_directive(&line[first.clone()]) {
+                operands.next();
+            }
+        }
+        for span in operands {
+            let token = &line[span.clone()];
+            if token.starts_with('"') {
+                continue;
+            }
+            // Words as the expression tokenizer splits them, so `#(b11 + 1)` is found too
+            let mut word_start = None;
+            for (offset, c) in token.char_indices().chain([(token.len(), ' ')]) {
+                if c.is_ascii_alphanumeric() || c == '_' {
+                    word_start.get_or_insert(offset);
+                } else if let Some(start) = word_start.take() {
+                    if is_legacy_binary(&token[start..offset]) {
+                        found.push((index + 1, line_start + span.start + start));
+                    }
+                }
+            }
+        }
+        line_start += line.len();
+    }
+    found
+}
+
+/// Rewrites every `b1010` binary literal in a source to `0b1010`, leaving all other text as it
+/// is. Returns the new source and how many literals were changed.
+pub fn fix_binary_literals(source: &str) -> (String, usize) {
+    let found = legacy_binary_literals(source);
+    let mut fixed = String::with_capacity(source.len() + found.len());
+    let mut copied = 0;
+    for &(_, offset) in &found {
+        fixed.push_str(&source[copied..offset]);
+        fixed.push_str("0b");
+        copied = offset + 1;
+    }
+    fixed.push_str(&source[copied..]);
+    (fixed, found.len())
+}
+
+// `.include "file.asm"` splices another file in at that line, the path is relative to the
+// file the line is in. A file is only included once, by its canonical path, later includes
+// of it are skipped. `.include_force` splices it in again anyway.
+const INCLUDE_DIRECTIVE: &str = ".include";
+const INCLUDE_FORCE_DIRECTIVE: &str = ".include_force";
+
+// Where each line of a source with its includes spliced in came from, the file and its line
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    pub lines: Vec<(usize, usize)>, // Index into files and the line there
+    pub files: Vec<(String, String)>, // Every file read and its text, the including file first
+    pub skipped: Vec<(String, String, usize)>, // Repeated includes left out: file, includer, line
+}
+
+impl SourceMap {
+    /// File and line of every instruction of source, the spliced source this map was made for,
+    /// as Program::positions holds them.
+    pub fn positions(&self, source: &str) -> HashMap<(Option<String>, u16), (String, usize)> {
+        let mut positions = HashMap::new();
+        let tokens = lex(source);
+        let mut scopes = Scopes::new(&tokens, Some(self)).unwrap_or_default();
+        for (scope, lines) in scoped_instruction_lines(&tokens, &mut scopes) {
+            for (index, line) in lines.into_iter().enumerate() {
+                if let Some(&(file, line)) =
+                    line.checked_sub(1).and_then(|line| self.lines.get(line))
+                {
+                    let position = (self.files[file].0.clone(), line);
+                    positions.insert((scope.clone(), index as u16), position);
+                }
+            }
+        }
+        positions
+    }
+
+    /// Moves an error on a line of the spliced source to the file and line it came from.
+    /// Without includes the error is returned as it is.
+    pub fn locate(&self, mut error: ParseError) -> ParseError {
+        if self.files.len() < 2 {
+            return error;
+        }
+        let Some(line) = error.line_mut() else {
+            return error;
+        };
+        let Some(&(file, original)) = line.checked_sub(1).and_then(|index| self.lines.get(index))
+        else {
+            return error;
+        };
+        *line = original;
+        if let ParseError::DuplicateConstant { first_line, .. } = &mut error {
+            if let Some((_, original)) = first_line
+                .checked_sub(1)
+                .and_then(|index| self.lines.get(index))
+            {
+                *first_line = *original;
+            }
+        }
+        ParseError::Located {
+            file: self.files[file].0.clone(),
+            error: Box::new(error),
+        }
+    }
+}
+
+// Includes already spliced in, and the chain of files currently being read for cycle errors
+struct Includes {
+    encoding: SourceEncoding,
+    done: HashSet<PathBuf>,
+    chain: Vec<(PathBuf, String)>,
+}
+
+impl Includes {
+    // Appends the lines of source, read from path, to out with the includes in it spliced in
+    fn splice(
+        &mut self,
+        path: &str,
+        source: &str,
+        out: &mut String,
+        map: &mut SourceMap,
+    ) -> Result<(), ParseError> {
+        let key = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        self.done.insert(key.clone());
+        self.chain.push((key, path.to_string()));
+        let file = map.files.len();
+        map.files.push((path.to_string(), source.to_string()));
+        let directory = Path::new(path).parent().unwrap_or(Path::new(""));
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let tokens = lex_line(line);
+            let Some(directive) = tokens
+                .first()
+                .map(String::as_str)
+                .filter(|first| [INCLUDE_DIRECTIVE, INCLUDE_FORCE_DIRECTIVE].contains(first))
+            else {
+                out.push_str(line);
+                out.push('\n');
+                map.lines.push((file, line_number));
+                continue;
+            };
+            let located = |error| ParseError::Located {
+                file: path.to_string(),
+                error: Box::new(error),
+            };
+            let included = match tokens.as_slice() {
+                [_, name] => name
+                    .strip_prefix('"')
+                    .and_then(|name| name.strip_suffix('"'))
+                    .filter(|name| !name.is_empty())
+                    .ok_or_else(|| {
+                        located(ParseError::InvalidOperand {
+                            line: line_number,
+                            token: name.clone(),
+                        })
+                    })?,
+                _ => {
+                    return Err(located(ParseError::WrongOperandCount {
+                        line: line_number,
+                        instruction: directive.to_string(),
+                        expected: 1,
+                        found: tokens.len() - 1,
+                    }))
+                }
+            };
+            let included = directory.join(included).to_string_lossy().into_owned();
+            let bytes = fs::read(&included).map_err(|error| {
+                located(ParseError::IncludeNotFound {
+                    line: line_number,
+                    path: included.clone(),
+                    error,
+                })
+            })?;
+            let key = fs::canonicalize(&included).unwrap_or_else(|_| PathBuf::from(&included));
+            if let Some(start) = self.chain.iter().position(|(open, _)| *open == key) {
+                let mut chain: Vec<String> = self.chain[start..]
+                    .iter()
+                    .map(|(_, name)| name.clone())
+                    .collect();
+                chain.push(included);
+                return Err(ParseError::IncludeCycle { chain });
+            }
+            if self.done.contains(&key) && directive != INCLUDE_FORCE_DIRECTIVE {
+                map.skipped.push((included, path.to_string(), line_number));
+                continue;
+            }
+            let text = decode_source(&included, bytes, self.encoding)?;
+            self.splice(&included, &text, out, map)?;
+        }
+        self.chain.pop();
+        Ok(())
+    }
+}
+
+/// Reads a source file like read_file and splices in the files it includes. The map tells
+/// which file and line each line of the result came from, see SourceMap::locate.
+pub fn read_with_includes(
+    f_name: &String,
+    encoding: SourceEncoding,
+) -> Result<(String, SourceMap), ParseError> {
+    let source = read_file(f_name, encoding)?;
+    let mut includes = Includes {
+        encoding,
+        done: HashSet::new(),
+        chain: Vec::new(),
+    };
+    let (mut out, mut map) = (String::new(), SourceMap::default());
+    includes.splice(f_name, &source, &mut out, &mut map)?;
+    Ok((out, map))
 }
 
 /// Lexer to tokenize the assembly code.
-fn lex(input: &str) -> Vec<Vec<String>> {
-    input
-        .lines() // Split input into lines
+pub fn lex(input: &str) -> Vec<Vec<String>> {
+    input.lines().map(lex_line).collect()
+}
+
+/// Splits the label definitions such as `loop:` off the start of a line, returning the labels
+/// and the tokens after them, so `loop: dec a` defines the label and the instruction together.
+pub fn split_labels(tokens: &[String]) -> (Vec<&str>, &[String]) {
+    let mut labels = Vec::new();
+    let mut rest = tokens;
+    while let Some((first, after)) = rest.split_first() {
+        match first.strip_suffix(':').filter(|name| !name.is_empty()) {
+            Some(name) => labels.push(name),
+            None => break,
+        }
+        rest = after;
+    }
+    (labels, rest)
+}
+
+// What a token of a line is to the parser, for highlighting
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenClass {
+    Mnemonic,
+    Register,
+    Immediate, // Numbers, `#` operands, expressions and strings
+    Symbol,    // Labels, constants, functions and directives
+    Comment,   // The comment of a source line, see highlight_source_line
+}
+
+/// Classifies the tokens of a line the way parse_instruction reads them: the labels in front,
+/// the mnemonic or directive, then each operand by what parse_operand makes of it. Lexed lines
+/// hold no comments, highlight_source_line finds the comment of a line itself.
+pub fn classify_tokens(tokens: &[String]) -> Vec<TokenClass> {
+    let (labels, rest) = split_labels(tokens);
+    let mut classes = vec![TokenClass::Symbol; labels.len()];
+    for (i, token) in rest.iter().enumerate() {
+        classes.push(if i == 0 {
+            match token.starts_with('.') {
+                true => TokenClass::Symbol,
+                false => TokenClass::Mnemonic,
+            }
+        } else if token.starts_with(['"', '#', '(']) {
+            TokenClass::Immediate
+        } else {
+            match parse_operand(token, 0) {
+                Ok(Operand::Register(_)) => TokenClass::Register,
+                Ok(Operand::Immediate(_)) => TokenClass::Immediate,
+                Err(_) => TokenClass::Symbol,
+            }
+        });
+    }
+    classes
+}
+
+/// How highlighting shows the class of each token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Highlight {
+    Plain,
+    Color, // Terminal colors, comments dimmed
+    Tags,  // `<mnemonic>add</mnemonic>` and so on, readable where escapes are not
+}
+
+impl Highlight {
+    /// Color where colors are wanted, plain text otherwise.
+    pub fn colored(color: bool) -> Highlight {
+        match color {
+            true => Highlight::Color,
+            false => Highlight::Plain,
+        }
+    }
+}
+
+// One token in the style of its class
+fn paint(text: &str, class: TokenClass, highlight: Highlight) -> String {
+    let (color, tag) = match class {
+        TokenClass::Mnemonic => (Colors::BrightMagentaFg, "mnemonic"),
+        TokenClass::Register => (Colors::CyanFg, "register"),
+        TokenClass::Immediate => (Colors::GreenFg, "immediate"),
+        TokenClass::Symbol => (Colors::YellowFg, "symbol"),
+        TokenClass::Comment => (Colors::BrightBlackFg, "comment"),
+    };
+    match highlight {
+        Highlight::Plain => text.to_string(),
+        Highlight::Color => text.color(color),
+        Highlight::Tags => format!("<{}>{}</{}>", tag, text, tag),
+    }
+}
+
+/// The tokens of a line joined by spaces, each shown by its class from classify_tokens.
+pub fn highlight_line(tokens: &[String], highlight: Highlight) -> String {
+    let classes = classify_tokens(tokens);
+    let words: Vec<String> = tokens
+        .iter()
+        .zip(classes)
+        .map(|(token, class)| paint(token, class, highlight))
+        .collect();
+    words.join(" ")
+}
+
+/// A line of source as it was written, spacing kept, with its tokens shown as highlight_line
+/// shows them and its comment dimmed.
+pub fn highlight_source_line(line: &str, highlight: Highlight) -> String {
+    let classes = classify_tokens(&lex_line(line));
+    let mut out = String::new();
+    let mut written = 0;
+    for (span, class) in token_spans(line).into_iter().zip(classes) {
+        out.push_str(&line[written..span.start]);
+        out.push_str(&paint(&line[span.clone()], class, highlight));
+        written = span.end;
+    }
+    match comment_start(line) {
+        Some(start) => {
+            out.push_str(&line[written..start]);
+            let comment = line[start..].trim_end_matches(['\n', '\r']);
+            out.push_str(&paint(comment, TokenClass::Comment, highlight));
+            out.push_str(&line[start + comment.len()..]);
+        }
+        None => out.push_str(&line[written..]),
+    }
+    out
+}
+
+// Label positions per function scope, None being the global code
+type Labels = HashMap<Option<String>, HashMap<SymbolId, u16>>;
+
+// Makes the name after it visible to the other files of a program
+const EXPORT_DIRECTIVE: &str = ".export";
+
+/// The kinds of names a file defines, each a namespace of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SymbolKind {
+    Label, // Labels of the global code, the labels of a function never leave it
+    Constant,
+    Function,
+}
+
+// Which file each line of a spliced source came from, and which files define and export each
+// name. A name is private to the file defining it unless that file has `.export NAME`, so two
+// files can both have a label `done`. Inside the parser, a name defined by more than one file
+// is stored as name@file for each file that does not export it.
+#[derive(Debug, Default)]
+struct Scopes {
+    symbols: SymbolTable, // Every name the tables of the parser hold
+    files: Vec<String>,
+    line_files: Vec<usize>, // Index into files of each line, empty for a single file
+    defined: HashMap<(SymbolKind, SymbolId), usize>, // The first file defining each name
+    shared: HashMap<(SymbolKind, SymbolId), Vec<usize>>, // All files of names several define
+    exports: HashMap<SymbolId, usize>, // The file exporting each name
+}
+
+impl Scopes {
+    // Collects the names of every file of tokens, a single file without a map
+    fn new(tokens: &[Vec<String>], map: Option<&SourceMap>) -> Result<Scopes, ParseError> {
+        let mut scopes = Scopes::default();
+        if let Some(map) = map.filter(|map| map.files.len() > 1) {
+            for &(file, _) in &map.lines {
+                let name = &map.files[file].0;
+                let index = match scopes.files.iter().position(|known| known == name) {
+                    Some(index) => index,
+                    None => {
+                        scopes.files.push(name.clone());
+                        scopes.files.len() - 1
+                    }
+                };
+                scopes.line_files.push(index);
+            }
+        }
+        let in_data = data_block_lines(tokens);
+        let mut in_function = false;
+        let mut exports = Vec::new();
+        for (index, line) in tokens.iter().enumerate() {
+            let file = scopes.file(index + 1);
+            let (labels, rest) = split_labels(line);
+            if !in_function {
+                for name in labels {
+                    scopes.define(SymbolKind::Label, name, file);
+                }
+            }
+            let Some(first) = rest.first() else {
+                continue;
+            };
+            if in_data[index] {
+                if first != DATA_DIRECTIVE && first != ".end" {
+                    scopes.define(SymbolKind::Constant, first, file);
+                }
+            } else if is_constant_directive(first) {
+                if let Some(name) = rest.get(1) {
+                    scopes.define(SymbolKind::Constant, name, file);
+                }
+            } else if first == EXPORT_DIRECTIVE {
+                exports.push((index + 1, rest));
+            } else if first == ".end" {
+                in_function = false;
+            } else if first.starts_with('.') && !in_function {
+                scopes.define(SymbolKind::Function, first, file);
+                in_function = true;
+            }
+        }
+        for (line_number, tokens) in exports {
+            let [_, name] = tokens else {
+                return Err(ParseError::WrongOperandCount {
+                    line: line_number,
+                    instruction: EXPORT_DIRECTIVE.to_string(),
+                    expected: 1,
+                    found: tokens.len() - 1,
+                });
+            };
+            let file = scopes.file(line_number);
+            let kinds: &[SymbolKind] = match name.starts_with('.') {
+                true => &[SymbolKind::Function],
+                false => &[SymbolKind::Label, SymbolKind::Constant],
+            };
+            let id = scopes.symbols.get(name);
+            let Some(id) = id.filter(|&id| {
+                kinds
+                    .iter()
+                    .any(|&kind| scopes.files_defining(kind, id).contains(&file))
+            }) else {
+                return Err(ParseError::UndefinedExport {
+                    line: line_number,
+                    name: name.clone(),
+                });
+            };
+            match scopes.exports.get(&id) {
+                Some(&first) if first != file => {
+                    return Err(ParseError::DuplicateExport {
+                        line: line_number,
+                        name: name.clone(),
+                        file: scopes.files[first].clone(),
+                    })
+                }
+                _ => scopes.exports.insert(id, file),
+            };
+        }
+        Ok(scopes)
+    }
+
+    fn define(&mut self, kind: SymbolKind, name: &str, file: usize) {
+        let key = (kind, self.symbols.intern(name));
+        match self.defined.get(&key) {
+            None => {
+                self.defined.insert(key, file);
+            }
+            Some(&first) if first != file => {
+                let files = self.shared.entry(key).or_insert_with(|| vec![first]);
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+            Some(_) => {}
+        }
+    }
+
+    fn files_defining(&self, kind: SymbolKind, id: SymbolId) -> &[usize] {
+        match self.shared.get(&(kind, id)) {
+            Some(files) => files,
+            None => self
+                .defined
+                .get(&(kind, id))
+                .map_or(&[], std::slice::from_ref),
+        }
+    }
+
+    // The file a line of the spliced source came from
+    fn file(&self, line_number: usize) -> usize {
+        let index = line_number.saturating_sub(1);
+        self.line_files.get(index).copied().unwrap_or(0)
+    }
+
+    // The id a definition of name on a line is stored under
+    fn key(&mut self, kind: SymbolKind, name: &str, line_number: usize) -> SymbolId {
+        let file = self.file(line_number);
+        let id = self.symbols.intern(name);
+        match self.shared.contains_key(&(kind, id)) && self.exports.get(&id) != Some(&file) {
+            true => {
+                let private = format!("{}@{}", name, self.files[file]);
+                self.symbols.intern(&private)
+            }
+            false => id,
+        }
+    }
+
+    // key as the name, for the tables that are keyed by name
+    fn key_name(&mut self, kind: SymbolKind, name: &str, line_number: usize) -> String {
+        let id = self.key(kind, name, line_number);
+        self.symbols.name(id).to_string()
+    }
+
+    // The id a use of name on a line refers to: its own file's definition, else the exported
+    // one. A name only other files define without exporting it is an error, a name nobody
+    // defines is None for the caller to report.
+    fn resolve(
+        &self,
+        kind: SymbolKind,
+        name: &str,
+        line_number: usize,
+    ) -> Result<Option<SymbolId>, ParseError> {
+        let Some(id) = self.symbols.get(name) else {
+            return Ok(None);
+        };
+        let files = self.files_defining(kind, id);
+        let file = self.file(line_number);
+        if files.is_empty() {
+            return Ok(Some(id));
+        }
+        if files.contains(&file) {
+            return Ok(match self.shared.contains_key(&(kind, id)) {
+                true if self.exports.get(&id) != Some(&file) => {
+                    self.symbols.get(&format!("{}@{}", name, self.files[file]))
+                }
+                _ => Some(id),
+            });
+        }
+        match self.exports.get(&id) {
+            Some(exporter) if files.contains(exporter) => Ok(Some(id)),
+            _ => Err(ParseError::NotExported {
+                line: line_number,
+                name: name.to_string(),
+                file: self.files[files[0]].clone(),
+            }),
+        }
+    }
+
+    // The name resolve gives the id of, name itself when nobody defines it
+    fn resolve_name(
+        &self,
+        kind: SymbolKind,
+        name: &str,
+        line_number: usize,
+    ) -> Result<String, ParseError> {
+        Ok(match self.resolve(kind, name, line_number)? {
+            Some(id) => self.symbols.name(id).to_string(),
+            None => name.to_string(),
+        })
+    }
+}
+
+/// First pass: records the instruction index each label points at, per function scope.
+/// Labels in global code live under `None`, labels inside a function under its name.
+fn collect_labels(tokens: &[Vec<String>], scopes: &mut Scopes) -> Result<Labels, ParseError> {
+    let in_data = data_block_lines(tokens);
+    let mut labels: Labels = HashMap::new();
+    let mut scope: Option<String> = None; // Function currently being defined, if any
+    let mut global_count = 0; // Instructions seen so far in global code
+    let mut function_count = 0; // Instructions seen so far in the current function
+
+    for (line_number, tokens) in tokens.iter().enumerate() {
+        if in_data[line_number] {
+            continue; // Labels in .data blocks are addresses, collected by collect_data
+        }
+        let (line_labels, tokens) = split_labels(tokens);
+        for name in line_labels {
+            check_reserved("label", name, line_number + 1)?;
+            let index = if scope.is_some() {
+                function_count
+            } else {
+                global_count
+            };
+            let key = match scope {
+                Some(_) => scopes.symbols.intern(name),
+                None => scopes.key(SymbolKind::Label, name, line_number + 1),
+            };
+            let scope_labels = labels.entry(scope.clone()).or_default();
+            if scope_labels.insert(key, index).is_some() {
+                return Err(ParseError::DuplicateLabel {
+                    line: line_number + 1,
+                    label: name.to_string(),
+                });
+            }
+        }
+        if tokens.is_empty() || is_constant_directive(&tokens[0]) || tokens[0] == EXPORT_DIRECTIVE {
+            continue;
+        }
+        if tokens[0].starts_with('.') {
+            // Malformed function blocks are reported by the second pass
+            if tokens[0] == ".end" {
+                scope = None;
+            } else if scope.is_none() {
+                scope = Some(scopes.key_name(SymbolKind::Function, &tokens[0], line_number + 1));
+                function_count = 0;
+            }
+        } else if scope.is_some() {
+            function_count += 1;
+        } else {
+            global_count += 1;
+        }
+    }
+    Ok(labels)
+}
+
+// Names given a value with .define, with the line each was defined on
+type Constants = HashMap<SymbolId, (i64, usize)>;
+
+const DEFINE_DIRECTIVE: &str = ".define";
+
+// `.ascii NAME "text"` places text in memory and makes NAME its address
+const ASCII_DIRECTIVE: &str = ".ascii";
+
+// Lines that only define a constant, they produce no instruction
+fn is_constant_directive(token: &str) -> bool {
+    token == DEFINE_DIRECTIVE || token == ASCII_DIRECTIVE
+}
+
+/// The value of the escape after a `\\` in a string or character: \n, \t, \r, \0, \\, \', \"
+/// or \xHH with two hex digits. None if it is not one of them.
+fn unescape(chars: &mut Chars) -> Option<u16> {
+    match chars.next()? {
+        'n' => Some(b'\n'.into()),
+        't' => Some(b'\t'.into()),
+        'r' => Some(b'\r'.into()),
+        '0' => Some(0),
+        c @ ('\\' | '\'' | '"') => Some(c as u16),
+        'x' => {
+            let digits = chars.as_str().get(..2)?;
+            if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            chars.nth(1);
+            u8::from_str_radix(digits, 16).ok().map(u16::from)
+        }
+        _ => None,
+    }
+}
+
+/// The value of a `'A'` or `'\n'` character literal, None if the token is not exactly one ASCII
+/// character or escape in single quotes.
+pub fn char_literal(token: &str) -> Option<u16> {
+    let text = token.strip_prefix('\'')?.strip_suffix('\'')?;
+    let mut chars = text.chars();
+    let value = match chars.next()? {
+        '\\' => unescape(&mut chars)?,
+        '\'' => return None,
+        c if c.is_ascii() => c as u16,
+        _ => return None,
+    };
+    chars.as_str().is_empty().then_some(value)
+}
+
+/// The characters of a `"..."` token, with escapes replaced as described at unescape.
+/// Only ASCII is allowed, one memory cell holds one character.
+fn parse_string(token: &str, line_number: usize) -> Result<Vec<u16>, ParseError> {
+    let invalid = || ParseError::InvalidString {
+        line: line_number,
+        string: token.to_string(),
+    };
+    let text = token
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .filter(|_| token.len() >= 2)
+        .ok_or_else(invalid)?;
+    let mut cells = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let cell = match c {
+            '\\' => unescape(&mut chars).ok_or_else(invalid)?,
+            '"' => return Err(invalid()), // An unescaped quote inside the string
+            c if c.is_ascii() => c as u16,
+            _ => return Err(invalid()),
+        };
+        cells.push(cell);
+    }
+    Ok(cells)
+}
+
+// Handles `.ascii NAME "text"`, the text goes at the data address with a NUL after it
+fn define_string(
+    tokens: &[String],
+    line_number: usize,
+    constants: &mut Constants,
+    data: &mut DataLayout,
+    scopes: &mut Scopes,
+) -> Result<(), ParseError> {
+    let [_, name, string] = tokens else {
+        return Err(ParseError::MissingOperand {
+            line: line_number,
+            instruction: ASCII_DIRECTIVE.to_string(),
+        });
+    };
+    let mut cells = parse_string(string, line_number)?;
+    cells.push(0);
+    let address = data.next.to_string();
+    define_constant(
+        &[ASCII_DIRECTIVE.to_string(), name.clone(), address],
+        line_number,
+        constants,
+        scopes,
+    )?;
+    data.place(&cells, line_number, string)?;
+    Ok(())
+}
+
+// Directives of a .data block that lay out memory: `.org ADDR` moves the data address,
+// `.align N` moves it up to the next multiple of N and `.space N` reserves N cells of 0s
+const ORG_DIRECTIVE: &str = ".org";
+const ALIGN_DIRECTIVE: &str = ".align";
+const SPACE_DIRECTIVE: &str = ".space";
+
+// Cells the data can fill, the addresses of 16 bit memory
+const DATA_CELLS: usize = 1 << 16;
+
+// Memory contents from address 0 on with the line that placed each cell, so data placed twice
+// can name both lines
+#[derive(Default)]
+struct DataLayout {
+    cells: Vec<u16>,
+    placed_by: Vec<Option<usize>>,
+    next: usize, // Address the next data goes at
+}
+
+impl DataLayout {
+    // Places cells at the data address and moves it past them
+    fn place(&mut self, cells: &[u16], line_number: usize, token: &str) -> Result<(), ParseError> {
+        let (start, end) = (self.next, self.next + cells.len());
+        if end > DATA_CELLS {
+            return Err(ParseError::OperandOutOfRange {
+                line: line_number,
+                token: token.to_string(),
+            });
+        }
+        let placed = self.placed_by.get(start..end.min(self.placed_by.len()));
+        if let Some((offset, first_line)) = placed
+            .into_iter()
+            .flatten()
+            .enumerate()
+            .find_map(|(offset, placed)| placed.map(|first_line| (offset, first_line)))
+        {
+            return Err(ParseError::DataOverlap {
+                line: line_number,
+                address: start + offset,
+                first_line,
+            });
+        }
+        if self.cells.len() < end {
+            self.cells.resize(end, 0);
+            self.placed_by.resize(end, None);
+        }
+        self.cells[start..end].copy_from_slice(cells);
+        self.placed_by[start..end].fill(Some(line_number));
+        self.next = end;
+        Ok(())
+    }
+}
+
+// The operand of .org, .align or .space, a number, constant or expression up to 65535
+fn data_operand(
+    tokens: &[String],
+    line_number: usize,
+    constants: &Constants,
+    scopes: &Scopes,
+) -> Result<usize, ParseError> {
+    if tokens.len() < 2 {
+        return Err(ParseError::MissingOperand {
+            line: line_number,
+            instruction: tokens[0].to_string(),
+        });
+    }
+    let operand = tokens[1..].join(" ");
+    let value = evaluate_constant(&operand, line_number, constants, scopes)?;
+    usize::try_from(value)
+        .ok()
+        .filter(|&value| value < DATA_CELLS)
+        .ok_or(ParseError::OperandOutOfRange {
+            line: line_number,
+            token: operand,
+        })
+}
+
+// Starts a block of `NAME VALUE` lines up to `.end`, each one a constant like .define, and of
+// .ascii, .org, .align and .space lines placing data in memory
+const DATA_DIRECTIVE: &str = ".data";
+
+// Which lines belong to a .data block, its .data and .end lines included
+fn data_block_lines(tokens: &[Vec<String>]) -> Vec<bool> {
+    let mut in_block = false;
+    tokens
+        .iter()
         .map(|line| {
-            // Clean the line by removing comments and splitting it into tokens
-            let clean_line = line.split(';').next().unwrap_or(line); // Ignore comments
-            clean_line
-                .split_whitespace() // Split line into words/tokens based on whitespace
-                .filter(|token| !token.is_empty()) // Ignore empty tokens
-                .map(|token| token.to_string()) // Convert tokens from &str to String
-                .collect() // Collect tokens into a Vec<String>
+            let (_, line) = split_labels(line);
+            match line.first().map(String::as_str) {
+                Some(DATA_DIRECTIVE) => in_block = true,
+                Some(".end") if in_block => {
+                    in_block = false;
+                    return true;
+                }
+                _ => {}
+            }
+            in_block
         })
-        .collect() // Collect all lines of tokens into a Vec<Vec<String>>
+        .collect()
+}
+
+/// Collects the constants and lays out the data of every .data block before the instructions
+/// are parsed, so they can be used above the block too. Values may refer to names from earlier
+/// .data lines. A label on a .data line is a constant holding the data address after .org or
+/// .align on the line took effect, so `buffer: .space 16` names the start of the 16 cells.
+fn collect_data(
+    tokens: &[Vec<String>],
+    in_data: &[bool],
+    scopes: &mut Scopes,
+) -> Result<(Constants, DataLayout), ParseError> {
+    let mut constants = Constants::new();
+    let mut data = DataLayout::default();
+    let mut block_line = None; // Line of the .data opening the current block
+    for (index, line) in tokens.iter().enumerate() {
+        if !in_data[index] {
+            continue;
+        }
+        let line_number = index + 1;
+        let (labels, line) = split_labels(line);
+        let directive = line.first().map(String::as_str);
+        match directive {
+            Some(ORG_DIRECTIVE) => data.next = data_operand(line, line_number, &constants, scopes)?,
+            Some(ALIGN_DIRECTIVE) => match data_operand(line, line_number, &constants, scopes)? {
+                0 => {
+                    return Err(ParseError::InvalidOperand {
+                        line: line_number,
+                        token: line[1..].join(" "),
+                    })
+                }
+                multiple => data.next = data.next.div_ceil(multiple) * multiple,
+            },
+            _ => {}
+        }
+        for label in labels {
+            let define = [DATA_DIRECTIVE, label, &data.next.to_string()].map(String::from);
+            define_constant(&define, line_number, &mut constants, scopes)?;
+        }
+        match directive {
+            None | Some(ORG_DIRECTIVE | ALIGN_DIRECTIVE) => {}
+            Some(DATA_DIRECTIVE) => block_line = Some(line_number),
+            Some(".end") => block_line = None,
+            Some(SPACE_DIRECTIVE) => {
+                let count = data_operand(line, line_number, &constants, scopes)?;
+                data.place(&vec![0; count], line_number, &line[1..].join(" "))?;
+            }
+            Some(ASCII_DIRECTIVE) => {
+                define_string(line, line_number, &mut constants, &mut data, scopes)?
+            }
+            Some(_) => {
+                // The same as `.define NAME VALUE`, with .data named in errors
+                let mut define = vec![DATA_DIRECTIVE.to_string()];
+                define.extend(line.iter().cloned());
+                define_constant(&define, line_number, &mut constants, scopes)?;
+            }
+        }
+    }
+    match block_line {
+        Some(line) => Err(ParseError::UnterminatedData { line }),
+        None => Ok((constants, data)),
+    }
+}
+
+/// What a label, function or constant name also means, "an instruction" for a mnemonic in
+/// any case and "a register" for `b`, `bx` or `r1`, None if it means nothing else.
+pub fn reserved_word(name: &str) -> Option<&'static str> {
+    if INSTRUCTION_SET
+        .iter()
+        .any(|info| info.mnemonic.eq_ignore_ascii_case(name))
+    {
+        Some("an instruction")
+    } else if !is_number_literal(name) && parse_register(name, 0).is_ok() {
+        Some("a register")
+    } else {
+        None
+    }
+}
+
+// Rejects a label, function or constant named like an instruction or register, `call add` or
+// `jmp b` would read as something else. Functions are checked without their dot.
+fn check_reserved(kind: &str, name: &str, line_number: usize) -> Result<(), ParseError> {
+    match reserved_word(name.strip_prefix('.').unwrap_or(name)) {
+        Some(reserved) => Err(ParseError::ReservedName {
+            line: line_number,
+            kind: kind.to_string(),
+            name: name.to_string(),
+            reserved: reserved.to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Renames the labels, functions and constants that check_reserved rejects by appending `_`,
+/// where they are defined and where they are used: jump targets for labels, CALL for functions,
+/// operands and expressions for constants. Returns the new source and the renamed names.
+/// Constants named like a register are left alone, a `b` operand already means the register.
+pub fn fix_reserved_names(source: &str) -> (String, Vec<String>) {
+    let lines: Vec<&str> = source.split_inclusive('\n').collect();
+    let tokens: Vec<Vec<String>> = lines.iter().map(|line| lex_line(line)).collect();
+    let in_data = data_block_lines(&tokens);
+    // The name a line defines as a constant, `.define NAME 1` or `NAME 1` in a .data block
+    let defined_constant = |index: usize, rest: &[String]| match rest.first()?.as_str() {
+        first if is_constant_directive(first) => rest.get(1).cloned(),
+        first if in_data[index] && !first.starts_with('.') => rest.first().cloned(),
+        _ => None,
+    };
+
+    let mut labels = HashSet::new();
+    let mut functions = HashSet::new(); // Without their dot
+    let mut constants = HashSet::new();
+    for (index, line) in tokens.iter().enumerate() {
+        let (line_labels, rest) = split_labels(line);
+        for name in line_labels {
+            if reserved_word(name).is_some() {
+                labels.insert(name.to_string());
+            }
+        }
+        if let Some(name) = defined_constant(index, rest) {
+            if reserved_word(&name) == Some("an instruction") {
+                constants.insert(name);
+            }
+        } else if let Some(name) = rest.first().and_then(|first| first.strip_prefix('.')) {
+            let directive = [
+                DATA_DIRECTIVE,
+                ".end",
+                INCLUDE_DIRECTIVE,
+                INCLUDE_FORCE_DIRECTIVE,
+                EXPORT_DIRECTIVE,
+            ]
+            .contains(&rest[0].as_str());
+            if !directive && !in_data[index] && reserved_word(name).is_some() {
+                functions.insert(name.to_string());
+            }
+        }
+    }
+
+    // Byte offsets the `_` goes in at, the end of each name to rename
+    let mut inserts = Vec::new();
+    let mut line_start = 0;
+    for (index, line) in lines.iter().enumerate() {
+        let spans = token_spans(line);
+        let (line_labels, rest) = split_labels(&tokens[index]);
+        for (name, span) in line_labels.iter().zip(&spans) {
+            if labels.contains(*name) {
+                inserts.push(line_start + span.start + name.len());
+            }
+        }
+        let spans = &spans[line_labels.len()..];
+        let data_line = in_data[index] && defined_constant(index, rest).is_some();
+        let mnemonic = rest.first().map(|first| first.to_uppercase());
+        for (position, (token, span)) in rest.iter().zip(spans).enumerate() {
+            let operand = token.trim_end_matches(',');
+            let end = line_start + span.start + operand.len();
+            let renamed = match (position, mnemonic.as_deref()) {
+                (0, _) if data_line => constants.contains(operand),
+                (0, _) => operand
+                    .strip_prefix('.')
+                    .is_some_and(|name| functions.contains(name)),
+                (1, _) if is_constant_directive(&rest[0]) => constants.contains(operand),
+                (_, Some("JMP" | "JE" | "JNE" | "JG" | "JL")) => labels.contains(operand),
+                (_, Some("CALL" | "CALLE" | "CALLNE")) => {
+                    functions.contains(operand.strip_prefix('.').unwrap_or(operand))
+                }
+                _ if token.starts_with(['"', '\'']) => false,
+                _ => {
+                    // Constants inside expressions, words split as in legacy_binary_literals
+                    let mut word_start = None;
+                    for (offset, c) in token.char_indices().chain([(token.len(), ' ')]) {
+                        if c.is_ascii_alphanumeric() || c == '_' {
+                            word_start.get_or_insert(offset);
+                        } else if let Some(start) = word_start.take() {
+                            if constants.contains(&token[start..offset]) {
+                                inserts.push(line_start + span.start + offset);
+                            }
+                        }
+                    }
+                    false
+                }
+            };
+            if renamed {
+                inserts.push(end);
+            }
+        }
+        line_start += line.len();
+    }
+
+    let mut fixed = String::with_capacity(source.len() + inserts.len());
+    let mut copied = 0;
+    for &offset in &inserts {
+        fixed.push_str(&source[copied..offset]);
+        fixed.push('_');
+        copied = offset;
+    }
+    fixed.push_str(&source[copied..]);
+    let functions = functions.into_iter().map(|name| format!(".{}", name));
+    let mut renamed: Vec<String> = labels
+        .into_iter()
+        .chain(functions)
+        .chain(constants)
+        .collect();
+    renamed.sort();
+    (fixed, renamed)
+}
+
+/// Whether a token has the shape of a name: a letter or `_`, then letters, digits and `_`.
+fn is_name(token: &str) -> bool {
+    token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Evaluates a constant expression, which has to fit in 16 bits signed or unsigned.
+fn evaluate_constant(
+    expression: &str,
+    line_number: usize,
+    constants: &Constants,
+    scopes: &Scopes,
+) -> Result<i64, ParseError> {
+    let lookup = |name: &str| {
+        let key = scopes
+            .resolve(SymbolKind::Constant, name, line_number)
+            .ok()??;
+        constants.get(&key).map(|&(value, _)| value)
+    };
+    let out_of_range = || ParseError::OperandOutOfRange {
+        line: line_number,
+        token: expression.to_string(),
+    };
+    let value = evaluate(expression, &lookup).map_err(|e| match e {
+        ExpressionError::Syntax => ParseError::InvalidExpression {
+            line: line_number,
+            expression: expression.to_string(),
+        },
+        ExpressionError::Undefined(name) => {
+            match scopes.resolve(SymbolKind::Constant, &name, line_number) {
+                Err(private) => private,
+                Ok(_) => ParseError::UndefinedConstant {
+                    line: line_number,
+                    name,
+                },
+            }
+        }
+        ExpressionError::DivideByZero => ParseError::ConstantDivideByZero { line: line_number },
+        ExpressionError::Overflow => out_of_range(),
+    })?;
+    if !(-32768..=65535).contains(&value) {
+        return Err(out_of_range());
+    }
+    Ok(value)
+}
+
+/// Handles `.define NAME value`, the value is a number, an earlier constant or an expression.
+/// Names that could be read as a register or a number are rejected.
+fn define_constant(
+    tokens: &[String],
+    line_number: usize,
+    constants: &mut Constants,
+    scopes: &mut Scopes,
+) -> Result<(), ParseError> {
+    if tokens.len() < 3 {
+        return Err(ParseError::MissingOperand {
+            line: line_number,
+            instruction: tokens[0].to_string(),
+        });
+    }
+    let name = &tokens[1];
+    let key = scopes.key(SymbolKind::Constant, name, line_number);
+    if let Some(&(_, first_line)) = constants.get(&key) {
+        return Err(ParseError::DuplicateConstant {
+            line: line_number,
+            name: name.to_string(),
+            first_line,
+        });
+    }
+    check_reserved("constant", name, line_number)?;
+    if !is_name(name) || is_number_literal(name) || parse_register(name, line_number).is_ok() {
+        return Err(ParseError::InvalidConstantName {
+            line: line_number,
+            name: name.to_string(),
+        });
+    }
+    let value = evaluate_constant(&tokens[2..].join(" "), line_number, constants, scopes)?;
+    constants.insert(key, (value, line_number));
+    Ok(())
+}
+
+/// Replaces constants and `(...)` expressions in the operands of an instruction with their values,
+/// `#NAME` and `#(...)` give immediates. Jump targets and CALL are left alone, they name labels
+/// and functions.
+fn substitute_constants(
+    tokens: &[String],
+    line_number: usize,
+    constants: &Constants,
+    scopes: &Scopes,
+) -> Result<Vec<String>, ParseError> {
+    let mnemonic = tokens[0].to_uppercase();
+    if matches!(
+        mnemonic.as_str(),
+        "JMP" | "JE" | "JNE" | "JG" | "JL" | "CALL" | "CALLE" | "CALLNE"
+    ) {
+        return Ok(tokens.to_vec());
+    }
+    let mut substituted = vec![tokens[0].clone()];
+    for token in &tokens[1..] {
+        let (operand, comma) = match token.strip_suffix(',') {
+            Some(operand) => (operand, ","),
+            None => (token.as_str(), ""),
+        };
+        let (hash, body) = match operand.strip_prefix('#') {
+            Some(body) => ("#", body),
+            None => ("", operand),
+        };
+        let value = if body.starts_with('(') {
+            Some(evaluate_constant(body, line_number, constants, scopes)?)
+        } else if let Some(&(value, _)) = scopes
+            .resolve(SymbolKind::Constant, body, line_number)?
+            .and_then(|key| constants.get(&key))
+        {
+            Some(value)
+        } else if !hash.is_empty() && is_name(body) && !is_number_literal(body) {
+            // `#NAME` can only mean a constant, bare names may still be registers
+            return Err(ParseError::UndefinedConstant {
+                line: line_number,
+                name: body.to_string(),
+            });
+        } else {
+            None
+        };
+        substituted.push(match value {
+            Some(value) => format!("{}{}{}", hash, value, comma),
+            None => token.clone(),
+        });
+    }
+    Ok(substituted)
+}
+
+// Replaces the label a jump of the global code goes to and the function a CALL calls with the
+// names they are stored under, see Scopes. Jumps inside a function use its own labels.
+fn resolve_targets(
+    tokens: &[String],
+    line_number: usize,
+    in_function: bool,
+    scopes: &Scopes,
+) -> Result<Vec<String>, ParseError> {
+    let kind = match tokens[0].to_uppercase().as_str() {
+        "JMP" | "JE" | "JNE" | "JG" | "JL" if !in_function => SymbolKind::Label,
+        "CALL" | "CALLE" | "CALLNE" => SymbolKind::Function,
+        _ => return Ok(tokens.to_vec()),
+    };
+    let mut tokens = tokens.to_vec();
+    if let Some(target) = tokens.get_mut(1) {
+        let name = match kind == SymbolKind::Function && !target.starts_with('.') {
+            true => format!(".{}", target),
+            false => target.clone(),
+        };
+        *target = scopes.resolve_name(kind, &name, line_number)?;
+    }
+    Ok(tokens)
+}
+
+/// Source line of every instruction, per function scope as in the labels.
+/// Walks the lines the same way the parser does, so index N is instruction N of that scope.
+pub fn instruction_lines(source: &str) -> HashMap<Option<String>, Vec<usize>> {
+    scoped_instruction_lines(&lex(source), &mut Scopes::default())
+}
+
+// instruction_lines of lexed lines, with functions under the names scopes stores them as
+fn scoped_instruction_lines(
+    tokens: &[Vec<String>],
+    scopes: &mut Scopes,
+) -> HashMap<Option<String>, Vec<usize>> {
+    let mut lines: HashMap<Option<String>, Vec<usize>> = HashMap::new();
+    let mut scope: Option<String> = None;
+    let in_data = data_block_lines(tokens);
+    for (index, tokens) in tokens.iter().enumerate() {
+        let (_, tokens) = split_labels(tokens);
+        if tokens.is_empty()
+            || is_constant_directive(&tokens[0])
+            || tokens[0] == EXPORT_DIRECTIVE
+            || in_data[index]
+        {
+            continue;
+        }
+        if tokens[0] == ".end" {
+            scope = None;
+        } else if tokens[0].starts_with('.') {
+            scope = Some(scopes.key_name(SymbolKind::Function, &tokens[0], index + 1));
+        } else {
+            lines.entry(scope.clone()).or_default().push(index + 1);
+        }
+    }
+    lines
+}
+
+/// A label, function or constant where a source file defines it, as listed by
+/// symbol_definitions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolDefinition {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub line: usize,           // Starts at 1
+    pub scope: Option<String>, // Function a label is defined in, None for the global code
+}
+
+/// Every label, function and constant a source file defines, in line order. Names are as
+/// written, without the file suffix the parser gives private names repeated across files.
+pub fn symbol_definitions(source: &str) -> Vec<SymbolDefinition> {
+    let tokens = lex(source);
+    let in_data = data_block_lines(&tokens);
+    let mut definitions = Vec::new();
+    let mut scope: Option<String> = None;
+    for (index, tokens) in tokens.iter().enumerate() {
+        let mut define = |name: &str, kind, scope: &Option<String>| {
+            definitions.push(SymbolDefinition {
+                name: name.to_string(),
+                kind,
+                line: index + 1,
+                scope: scope.clone(),
+            })
+        };
+        let (labels, tokens) = split_labels(tokens);
+        let first = tokens.first().map(String::as_str);
+        if in_data[index] {
+            // Lines of a .data block are `NAME VALUE` or directives, labels name addresses
+            for label in labels {
+                define(label, SymbolKind::Constant, &None);
+            }
+            match first {
+                Some(ASCII_DIRECTIVE) => {
+                    if let Some(name) = tokens.get(1) {
+                        define(name, SymbolKind::Constant, &None);
+                    }
+                }
+                Some(name) if !name.starts_with('.') => define(name, SymbolKind::Constant, &None),
+                _ => {}
+            }
+            continue;
+        }
+        for label in labels {
+            define(label, SymbolKind::Label, &scope);
+        }
+        match first {
+            Some(first) if is_constant_directive(first) => {
+                if let Some(name) = tokens.get(1) {
+                    define(name, SymbolKind::Constant, &None);
+                }
+            }
+            Some(EXPORT_DIRECTIVE | INCLUDE_DIRECTIVE | INCLUDE_FORCE_DIRECTIVE) => {}
+            Some(".end") => scope = None,
+            Some(name) if name.starts_with('.') => {
+                define(name, SymbolKind::Function, &None);
+                scope = Some(name.to_string());
+            }
+            _ => {}
+        }
+    }
+    definitions
 }
 
 /// Parses the tokenized lines into instructions, handling functions internally.
-pub fn parse_file(f_contents: String) -> Vec<Instruction> {
+pub fn parse_file(f_contents: String) -> Result<Program, ParseError> {
+    parse_with_config(f_contents, &declare_config())
+}
+
+/// parse_file with the configuration given instead of read from config.toml.
+pub fn parse_with_config(f_contents: String, config: &Config) -> Result<Program, ParseError> {
+    parse_source(f_contents, None, config)
+}
+
+/// parse_with_config for a source read by read_with_includes, where each file keeps the names
+/// it does not `.export` to itself. Errors are located in their file with the map.
+pub fn parse_with_includes(
+    f_contents: String,
+    map: &SourceMap,
+    config: &Config,
+) -> Result<Program, ParseError> {
+    parse_source(f_contents, Some(map), config).map_err(|e| map.locate(e))
+}
+
+fn parse_source(
+    f_contents: String,
+    map: Option<&SourceMap>,
+    config: &Config,
+) -> Result<Program, ParseError> {
     let mut instructions = Vec::new(); // Vector to store parsed instructions
     let mut functions = HashMap::new(); // Map to store functions and their instructions
-    let config = declare_config(); // Obtain configuration settings
     let tokens = lex(&f_contents); // Tokenize the input contents
+    let mut scopes = Scopes::new(&tokens, map)?; // Which file defines and exports each name
+    let labels = collect_labels(&tokens, &mut scopes)?; // Resolve label positions up front so forward jumps work
+    let no_labels = HashMap::new();
     let mut current_function: Option<String> = None; // Track the current function being defined
+    let mut current_function_line = 0; // Line the current function was defined on
     let mut current_function_instructions = Vec::new(); // Store instructions for the current function
-
-    if config.verbose_debug {
-        // If verbose debugging is enabled, print the tokenized instructions
-        println!("Tokenized instructions:\n{:?}", tokens);
-    }
+    let mut calls = Vec::new(); // Every CALL with its line, checked once all functions are known
+    let in_data = data_block_lines(&tokens);
+    // Names from .data, then .define so far, and the memory contents of the .data blocks
+    let (mut constants, mut data) = collect_data(&tokens, &in_data, &mut scopes)?;
+    let source_lines: Vec<&str> = f_contents.lines().collect();
+    let mut annotations = HashMap::new();
 
     // Iterate over the tokenized lines
-    for (line_number, tokens) in tokens.iter().enumerate() {
-        if tokens.is_empty() {
-            continue; // Skip empty lines
+    for (index, tokens) in tokens.iter().enumerate() {
+        let line_number = index + 1; // Lines are reported starting at 1
+        let (_, tokens) = split_labels(tokens); // Labels were collected in the first pass
+        if tokens.is_empty() || in_data[index] {
+            continue; // Skip empty lines, lines holding only a label and .data blocks
         }
+        let scope_labels = labels.get(&current_function).unwrap_or(&no_labels);
+
+        if tokens[0] == DEFINE_DIRECTIVE {
+            define_constant(tokens, line_number, &mut constants, &mut scopes)?;
+            continue;
+        }
+        if tokens[0] == ASCII_DIRECTIVE {
+            // Strings outside .data blocks go after all the data placed so far
+            data.next = data.cells.len();
+            define_string(tokens, line_number, &mut constants, &mut data, &mut scopes)?;
+            continue;
+        }
+        if tokens[0] == EXPORT_DIRECTIVE {
+            continue; // Checked by Scopes::new
+        }
+        let tokens = &substitute_constants(tokens, line_number, &constants, &scopes)?;
+        let tokens = &resolve_targets(tokens, line_number, current_function.is_some(), &scopes)?;
+        let mut annotate = |index: usize| {
+            if let Some(text) = annotation(source_lines[line_number - 1]) {
+                annotations.insert((current_function.clone(), index as u16), text.to_string());
+            }
+        };
 
         // Check if the first token indicates the start of a function
         if tokens[0].starts_with('.') {
@@ -69,135 +1561,1143 @@ pub fn parse_file(f_contents: String) -> Vec<Instruction> {
                 // Handle the end of a function
                 if let Some(func_name) = current_function.take() {
                     // Insert the function's instructions into the map
-                    functions.insert(func_name, current_function_instructions);
+                    functions.insert(
+                        func_name,
+                        Function {
+                            body: current_function_instructions,
+                            line: current_function_line,
+                        },
+                    );
                     current_function_instructions = Vec::new(); // Reset for the next function
                 } else {
                     // Error if .end is found without a corresponding function
-                    println!(
-                        "Error: .end without a corresponding function on line {}.",
-                        line_number
-                    );
-                    std::process::exit(0);
+                    return Err(ParseError::UnmatchedEnd { line: line_number });
                 }
             } else {
                 // Start a new function
                 if current_function.is_none() {
-                    current_function = Some(tokens[0].to_string()); // Store the function name
+                    check_reserved("function", &tokens[0], line_number)?;
+                    // Store the function name
+                    current_function =
+                        Some(scopes.key_name(SymbolKind::Function, &tokens[0], line_number));
+                    current_function_line = line_number;
                 } else {
                     // Error if nested function definitions are found
-                    println!(
-                        "Error: Nested function definitions are not allowed on line {}.",
-                        line_number
-                    );
-                    std::process::exit(0);
+                    return Err(ParseError::NestedFunction { line: line_number });
                 }
             }
         } else if let Some(ref _func_name) = current_function {
             // Collect instructions for the current function
-            if let Some(instruction) = parse_instruction(tokens, line_number as i32) {
-                current_function_instructions.push(instruction); // Add instruction to the current function
+            let instruction = parse_instruction(
+                tokens,
+                line_number,
+                scope_labels,
+                &scopes.symbols,
+                &config.operand_defaults,
+            )?;
+            if let Some(name) = instruction.called_function() {
+                calls.push((name.to_string(), line_number));
             }
-        } else if let Some(instruction) = parse_instruction(tokens, line_number as i32) {
+            annotate(current_function_instructions.len());
+            current_function_instructions.push(instruction); // Add instruction to the current function
+        } else {
             // Add instruction to the global instructions
+            let instruction = parse_instruction(
+                tokens,
+                line_number,
+                scope_labels,
+                &scopes.symbols,
+                &config.operand_defaults,
+            )?;
+            if let Some(name) = instruction.called_function() {
+                calls.push((name.to_string(), line_number));
+            }
+            annotate(instructions.len());
             instructions.push(instruction);
         }
     }
 
-    if config.verbose_debug {
-        // Print global instructions and functions if verbose debugging is enabled
-        println!("Global instructions: {:?}", instructions);
-        println!("Functions: {:?}", functions);
+    // A function still open at the end of the file never had its .end
+    if let Some(name) = current_function {
+        return Err(ParseError::UnterminatedFunction {
+            line: current_function_line,
+            name,
+        });
+    }
+
+    // Functions may be defined after they are called, so CALLs are only checked now
+    if let Some((name, line)) = calls
+        .into_iter()
+        .find(|(name, _)| !functions.contains_key(name))
+    {
+        return Err(ParseError::UndefinedFunction { line, name });
     }
 
     // Ensure HALT instruction is at the end of global instructions
     //instructions.push(Instruction::HALT);
 
-    instructions // Return the collected instructions
+    let program = Program {
+        instructions,
+        functions,
+        labels: labels
+            .get(&None)
+            .into_iter()
+            .flatten()
+            .map(|(&id, &index)| (scopes.symbols.name(id).to_string(), index))
+            .collect(),
+        data: data.cells,
+        annotations,
+        positions: HashMap::new(),
+        source_hash: Some(fnv1a(f_contents.as_bytes())),
+    };
+
+    if config.verbosity() >= 3 {
+        // At the highest verbosity, dump what the parser saw and produced
+        eprint!(
+            "{}",
+            format_parse_dump(&f_contents, &tokens, &labels, &scopes.symbols, &program)
+        );
+    }
+
+    Ok(program) // Return the collected instructions and functions
 }
 
-/// Parses a single instruction from tokens.
-fn parse_instruction(tokens: &[String], line_number: i32) -> Option<Instruction> {
-    if tokens.is_empty() {
-        return None; // Return None if no instruction is found
+/// Formats the parser's view of a file: each source line with its tokens, the labels,
+/// and the functions in the order they were defined.
+pub fn format_parse_dump(
+    source: &str,
+    tokens: &[Vec<String>],
+    labels: &Labels,
+    symbols: &SymbolTable,
+    program: &Program,
+) -> String {
+    let mut out = String::from("Tokenized lines:\n");
+    for (i, (line, line_tokens)) in source.lines().zip(tokens).enumerate() {
+        if line_tokens.is_empty() {
+            continue;
+        }
+        out.push_str(&format!(
+            "{:>4} | {:<32} | {}\n",
+            i + 1,
+            line.trim(),
+            line_tokens
+                .iter()
+                .map(|token| format!("[{}]", token))
+                .collect::<Vec<_>>()
+                .join(" ")
+        ));
     }
+
+    let mut functions: Vec<(&String, &Function)> = program.functions.iter().collect();
+    functions.sort_by_key(|(_, function)| function.line);
+
+    let mut scopes: Vec<&Option<String>> = labels.keys().collect();
+    scopes.sort_by_key(|scope| {
+        scope
+            .as_ref()
+            .and_then(|name| program.functions.get(name))
+            .map(|f| f.line)
+    });
+    out.push_str("Labels:\n");
+    for scope in scopes {
+        let mut scope_labels: Vec<(&str, u16)> = labels[scope]
+            .iter()
+            .map(|(&id, &index)| (symbols.name(id), index))
+            .collect();
+        scope_labels.sort_by_key(|&(name, index)| (index, name));
+        for (name, index) in scope_labels {
+            let owner = scope.as_deref().unwrap_or("global");
+            out.push_str(&format!("  {} -> {} ({})\n", name, index, owner));
+        }
+    }
+
+    out.push_str(&format!(
+        "Global code: {} instructions\n",
+        program.instructions.len()
+    ));
+    out.push_str("Functions:\n");
+    for (name, function) in functions {
+        out.push_str(&format!(
+            "  {} (line {}): {} instructions\n",
+            name,
+            function.line,
+            function.body.len()
+        ));
+    }
+    out
+}
+
+/// Resolves a jump operand, either a raw instruction index or a label in the current scope.
+fn parse_jump_target(
+    tokens: &[String],
+    line_number: usize,
+    labels: &HashMap<SymbolId, u16>,
+    symbols: &SymbolTable,
+) -> Result<u16, ParseError> {
+    let target = tokens.get(1).ok_or_else(|| ParseError::MissingOperand {
+        line: line_number,
+        instruction: tokens[0].to_uppercase(),
+    })?;
+    if let Ok(index) = target.parse::<u16>() {
+        return Ok(index);
+    }
+    symbols
+        .get(target)
+        .and_then(|id| labels.get(&id))
+        .copied()
+        .ok_or_else(|| ParseError::UndefinedLabel {
+            line: line_number,
+            label: target.to_string(),
+        })
+}
+
+/// Parses a single instruction from tokens.
+fn parse_instruction(
+    tokens: &[String],
+    line_number: usize,
+    labels: &HashMap<SymbolId, u16>,
+    symbols: &SymbolTable,
+    operand_defaults: &HashMap<String, u16>,
+) -> Result<Instruction, ParseError> {
     let instruc = &tokens[0]; // Get the instruction name
-    let (dest, src): (u16, u16) = parse_operands(tokens); // Parse destination and source operands
+    let mnemonic = instruc.to_uppercase();
+    // Source used when the line leaves it out, only if operand_defaults in config.toml has one
+    let default_source = operand_defaults
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(&mnemonic))
+        .map(|(_, &value)| Operand::Immediate(value));
+
+    // Operand at a position of the line, read as a register, a source operand or an immediate
+    let token = |position: usize| {
+        tokens
+            .get(position)
+            .map(String::as_str)
+            .ok_or_else(|| ParseError::MissingOperand {
+                line: line_number,
+                instruction: mnemonic.clone(),
+            })
+    };
+    let register = |position| token(position).and_then(|t| parse_register(t, line_number));
+    let operand = |position| match (tokens.get(position), default_source) {
+        (None, Some(default)) => Ok(default),
+        _ => token(position).and_then(|t| parse_operand(t, line_number)),
+    };
+    let immediate = |position| token(position).and_then(|t| parse_immediate(t, line_number));
 
     // Match the instruction name and create the appropriate Instruction variant
-    match instruc.to_uppercase().as_str() {
-        "ADD" => Some(Instruction::ADD(dest, src)),
-        "SUB" => Some(Instruction::SUB(dest, src)),
-        "MUL" => Some(Instruction::MUL(dest, src)),
+    let instruction = match mnemonic.as_str() {
+        "ADD" => Instruction::ADD(register(1)?, operand(2)?),
+        "SUB" => Instruction::SUB(register(1)?, operand(2)?),
+        "ABSDIFF" => Instruction::ABSDIFF(register(1)?, operand(2)?),
+        "MEMCLR" => Instruction::MEMCLR(immediate(1)?, immediate(2)?),
+        "ISPOW2" => Instruction::ISPOW2(register(1)?),
+        "NEXTPOW2" => Instruction::NEXTPOW2(register(1)?),
+        "AND" => Instruction::AND(register(1)?, operand(2)?),
+        "OR" => Instruction::OR(register(1)?, operand(2)?),
+        "XOR" => Instruction::XOR(register(1)?, operand(2)?),
+        "NOT" => Instruction::NOT(register(1)?),
+        "POPCNTR" => {
+            let (dest, start, count) = (register(1)?, register(2)?, immediate(3)?);
+            if start as usize + count as usize > REGISTER_COUNT {
+                return Err(ParseError::RegisterRangeOutOfBounds {
+                    line: line_number,
+                    start,
+                    count,
+                });
+            }
+            Instruction::POPCNTR(dest, start, count)
+        }
+        "SHL" => Instruction::SHL(register(1)?, operand(2)?),
+        "SHR" => Instruction::SHR(register(1)?, operand(2)?),
+        "RCL" => Instruction::RCL(register(1)?, operand(2)?),
+        "RCR" => Instruction::RCR(register(1)?, operand(2)?),
+        "MUL" => Instruction::MUL(register(1)?, operand(2)?),
         "MOV" => {
-            // Function to create a MOV instruction based on the destination and source
-            fn create_mov_instruction(dest: u16, src: Option<&str>) -> Instruction {
-                match src {
-                    Some(value) => {
-                        // Try to parse the source value as u16
-                        if let Ok(parsed_value) = value.parse::<u16>() {
-                            Instruction::MOV(dest, parsed_value) // Move immediate value
-                        } else {
-                            // If parsing fails, treat the value as a register
-                            let reg_index =
-                                letter_to_integer(value.chars().next().unwrap_or(' ')).unwrap_or(0);
-                            Instruction::MOVR(dest, reg_index.into()) // Move from register
-                        }
-                    }
-                    None => Instruction::MOV(dest, 0), // Default to moving 0 if src is None
-                }
-            }
-
-            // Convert dest and src to u16 and call create_mov_instruction
-            let instruction = create_mov_instruction(dest, Some(&tokens[2]));
-            Some(instruction)
-        }
-        "SWAP" => Some(Instruction::SWAP(dest, src)),
-        "DIV" => Some(Instruction::DIV(dest, src)),
-        "CLR" => Some(Instruction::CLR(dest)),
-        "DEC" => Some(Instruction::DEC(dest)),
-        "INC" => Some(Instruction::INC(dest)),
-        "CMP" => Some(Instruction::CMP(dest, src)),
-        "HALT" => Some(Instruction::HALT),
-        "PRINT" => Some(Instruction::PRINT(dest)),
-        "POW" => Some(Instruction::POW(dest, src)),
-        "MOVR" => Some(Instruction::MOVR(dest, src)),
-        "JMP" => Some(Instruction::JMP(dest)),
-        "NOP" => Some(Instruction::NOP),
-        _ => {
-            // Handle unknown instructions
-            println!(
-                "Error: Unknown instruction: \"{}\" on line {}.",
-                instruc, line_number
-            );
-            std::process::exit(0); // Exit if an unknown instruction is encountered
+            // MOV needs both a destination and a source, unless config.toml gives a default source
+            if tokens.len() < 3 && default_source.is_none() {
+                return Err(ParseError::WrongOperandCount {
+                    line: line_number,
+                    instruction: "MOV".to_string(),
+                    expected: 2,
+                    found: tokens.len() - 1,
+                });
+            }
+
+            // Immediates are moved as they are, registers are copied with MOVR
+            match operand(2)? {
+                Operand::Immediate(value) => Instruction::MOV(register(1)?, value),
+                Operand::Register(reg_index) => Instruction::MOVR(register(1)?, reg_index),
+            }
+        }
+        "SWAP" => Instruction::SWAP(register(1)?, register(2)?),
+        "DIV" | "MOD" => {
+            let (reg, src) = (register(1)?, operand(2)?);
+            // Dividing by a zero immediate can only ever fail, so it is caught here already
+            if src == Operand::Immediate(0) {
+                return Err(ParseError::DivisionByZero {
+                    line: line_number,
+                    instruction: mnemonic,
+                });
+            }
+            if mnemonic == "DIV" {
+                Instruction::DIV(reg, src)
+            } else {
+                Instruction::MOD(reg, src)
+            }
+        }
+        "CLR" => Instruction::CLR(register(1)?),
+        "DEC" => Instruction::DEC(register(1)?),
+        "INC" => Instruction::INC(register(1)?),
+        "CMP" => Instruction::CMP(register(1)?, operand(2)?),
+        "HALT" => Instruction::HALT,
+        // Bare PRINT prints register a, as it always has. The bare-print lint warns about it.
+        "PRINT" if tokens.len() == 1 => Instruction::PRINT(0),
+        "PRINT" => Instruction::PRINT(register(1)?),
+        "PRINTX" => Instruction::PRINTX(register(1)?),
+        "PRINTB" => Instruction::PRINTB(register(1)?),
+        "PRINTC" => Instruction::PRINTC(register(1)?),
+        "PRINTS" => Instruction::PRINTS(immediate(1)?),
+        "PRINTW" => Instruction::PRINTW(register(1)?, immediate(2)?),
+        "POW" => Instruction::POW(register(1)?, operand(2)?),
+        "MOVR" => Instruction::MOVR(register(1)?, register(2)?),
+        "JMP" => Instruction::JMP(parse_jump_target(tokens, line_number, labels, symbols)?),
+        "JE" => Instruction::JE(parse_jump_target(tokens, line_number, labels, symbols)?),
+        "JNE" => Instruction::JNE(parse_jump_target(tokens, line_number, labels, symbols)?),
+        "JG" => Instruction::JG(parse_jump_target(tokens, line_number, labels, symbols)?),
+        "JL" => Instruction::JL(parse_jump_target(tokens, line_number, labels, symbols)?),
+        "CALL" | "CALLE" | "CALLNE" => {
+            // Functions are defined as `.name`, accept the call with or without the dot
+            let name = token(1)?;
+            let name = match name.starts_with('.') {
+                true => name.to_string(),
+                false => format!(".{}", name),
+            };
+            match mnemonic.as_str() {
+                "CALLE" => Instruction::CALLE(name),
+                "CALLNE" => Instruction::CALLNE(name),
+                _ => Instruction::CALL(name),
+            }
         }
+        "RET" => Instruction::RET,
+        "LOAD" => Instruction::LOAD(register(1)?, immediate(2)?),
+        "STORE" => Instruction::STORE(register(1)?, immediate(2)?),
+        "LOADR" => Instruction::LOADR(register(1)?, register(2)?),
+        "STORER" => Instruction::STORER(register(1)?, register(2)?),
+        "LOADINC" => Instruction::LOADINC(register(1)?, register(2)?),
+        "PUSH" => Instruction::PUSH(register(1)?),
+        "POP" => Instruction::POP(register(1)?),
+        "IN" => Instruction::IN(register(1)?),
+        "KEYIN" => Instruction::KEYIN(register(1)?),
+        "GETF" => Instruction::GETF(register(1)?),
+        "SETF" => Instruction::SETF(register(1)?),
+        "FLAGSWAP" => Instruction::FLAGSWAP(register(1)?),
+        "NOP" => Instruction::NOP,
+        mnemonic => {
+            // Instructions outside the core set may have been registered by an embedder
+            let arity = custom_arity(mnemonic).ok_or_else(|| ParseError::UnknownInstruction {
+                line: line_number,
+                token: instruc.to_string(),
+            })?;
+            let operands = &tokens[1..];
+            if operands.len() != arity {
+                return Err(ParseError::WrongOperandCount {
+                    line: line_number,
+                    instruction: mnemonic.to_string(),
+                    expected: arity,
+                    found: operands.len(),
+                });
+            }
+            let operands = operands
+                .iter()
+                .map(|token| parse_value(token, line_number))
+                .collect::<Result<_, _>>()?;
+            Instruction::CUSTOM(mnemonic.to_string(), operands)
+        }
+    };
+    // Registers can only be checked once the program runs
+    if let Some((limit, Operand::Immediate(value))) = limited_operand(&instruction) {
+        if !limit.range.contains(&value) {
+            return Err(ParseError::OperandOutOfLimit {
+                line: line_number,
+                limit: limit.clone(),
+                value,
+            });
+        }
+    }
+    Ok(instruction)
+}
+
+/// Splits a number literal into its digits and radix: `0x` hex, `0b` binary, the older
+/// `b1010` binary form, or plain decimal. Returns None if the token is not a number.
+pub fn number_literal(token: &str) -> Option<(&str, u32)> {
+    let prefix = token.get(..2).map(|prefix| prefix.to_ascii_lowercase());
+    match prefix.as_deref() {
+        Some("0x") => Some((&token[2..], 16)),
+        Some("0b") => Some((&token[2..], 2)),
+        _ if token.starts_with(|c: char| c.is_ascii_digit()) => Some((token, 10)),
+        _ if token.len() > 1
+            && token.starts_with(['b', 'B'])
+            && token[1..].chars().all(|c| c == '0' || c == '1') =>
+        {
+            Some((&token[1..], 2))
+        }
+        _ => None,
     }
 }
 
-/// Parses the operands from the tokenized line.
-fn parse_operands(tokens: &[String]) -> (u16, u16) {
-    // Parse the first and second operands from the tokens, defaulting to 0 if not found
-    let dest = parse_value(tokens.get(1).unwrap_or(&"0".to_string()));
-    let src = parse_value(tokens.get(2).unwrap_or(&"0".to_string()));
-    (dest, src) // Return the parsed operands as a tuple
+/// Whether a token is written as a number or a character rather than a register.
+fn is_number_literal(token: &str) -> bool {
+    let token = token.trim_end_matches(',');
+    token.starts_with('\'') || number_literal(token.strip_prefix('-').unwrap_or(token)).is_some()
 }
 
-/// Converts a token into a u16 value, handling both numeric and register inputs.
-fn parse_value(token: &String) -> u16 {
-    // Check if the token is a binary number
-    if token.starts_with("b") && has_b_with_num(token) {
-        i32::from_str_radix(&token[2..], 2).unwrap_or_else(|_| {
-            // Handle invalid binary numbers
-            println!("Error: Not a valid binary number: {}", token);
-            std::process::exit(0);
-        }) as u16 // Cast to u16
-    } else if let Ok(value) = token.parse::<u16>() {
-        // Attempt to parse the token as a u16
-        value
+/// Converts a number literal, optionally written with a leading `#`, into its value.
+/// Negative numbers down to -32768 are stored as two's complement, values that do not fit in
+/// 16 bits are rejected.
+fn parse_number(token: &str, line_number: usize) -> Result<u16, ParseError> {
+    let invalid = || ParseError::InvalidOperand {
+        line: line_number,
+        token: token.to_string(),
+    };
+    let out_of_range = || ParseError::OperandOutOfRange {
+        line: line_number,
+        token: token.to_string(),
+    };
+    let literal = token.strip_prefix('#').unwrap_or(token);
+    if literal.starts_with('\'') {
+        return char_literal(literal).ok_or_else(|| ParseError::InvalidCharacter {
+            line: line_number,
+            token: token.to_string(),
+        });
+    }
+    let (negative, literal) = match literal.strip_prefix('-') {
+        Some(magnitude) => (true, magnitude),
+        None => (false, literal),
+    };
+    let (digits, radix) = number_literal(literal).ok_or_else(invalid)?;
+    let magnitude = u32::from_str_radix(digits, radix).map_err(|e| match e.kind() {
+        IntErrorKind::PosOverflow => out_of_range(),
+        _ => invalid(),
+    })?;
+    // Values past 16 bits are rejected instead of wrapping around
+    if negative {
+        match magnitude {
+            0..=32768 => Ok((magnitude as i32).wrapping_neg() as u16),
+            _ => Err(out_of_range()),
+        }
     } else {
-        // If parsing fails, treat the token as a register
-        letter_to_integer(token.chars().next().unwrap_or(' '))
-            .unwrap_or(0)
-            .into() // Convert to u16
+        u16::try_from(magnitude).map_err(|_| out_of_range())
+    }
+}
+
+/// Parses a register: a letter (`b` or `bx`), `rN`, or a bare register number.
+pub fn parse_register(token: &str, line_number: usize) -> Result<u16, ParseError> {
+    let token = token.trim_end_matches(','); // Operands may be followed by a comma
+    let invalid = || ParseError::InvalidRegister {
+        line: line_number,
+        token: token.to_string(),
+    };
+    let numbered = token
+        .strip_prefix(['r', 'R'])
+        .filter(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+    let index = if let Some(number) = numbered {
+        number.parse::<u16>().map_err(|_| invalid())?
+    } else if is_number_literal(token) {
+        parse_number(token, line_number)?
+    } else {
+        let mut chars = token.chars();
+        match (chars.next(), chars.as_str()) {
+            (Some(letter), "" | "x" | "X") => letter_to_integer(letter).ok_or_else(invalid)?.into(),
+            _ => return Err(invalid()),
+        }
+    };
+    if index as usize >= REGISTER_COUNT {
+        return Err(invalid());
+    }
+    Ok(index)
+}
+
+/// Parses a source operand, `#42` and bare numbers are immediates, anything else a register.
+pub fn parse_operand(token: &str, line_number: usize) -> Result<Operand, ParseError> {
+    let token = token.trim_end_matches(',');
+    if token.starts_with('#') || is_number_literal(token) {
+        Ok(Operand::Immediate(parse_number(token, line_number)?))
+    } else {
+        Ok(Operand::Register(parse_register(token, line_number)?))
+    }
+}
+
+/// Parses an operand that can only be an immediate, such as a memory address.
+fn parse_immediate(token: &str, line_number: usize) -> Result<u16, ParseError> {
+    parse_number(token.trim_end_matches(','), line_number)
+}
+
+/// Converts a token into a u16 value, an immediate's value or a register's index.
+pub fn parse_value(token: &str, line_number: usize) -> Result<u16, ParseError> {
+    match parse_operand(token, line_number)? {
+        Operand::Register(index) | Operand::Immediate(index) => Ok(index),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn quiet() -> Config {
+        Config {
+            debug: false,
+            ..Config::default()
+        }
+    }
+
+    fn parse(source: &str) -> Result<Program, ParseError> {
+        parse_with_config(source.to_string(), &quiet())
+    }
+
+    // A fresh directory with the files, for tests of .include and .export
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("alice-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        for (name, contents) in files {
+            fs::write(directory.join(name), contents).unwrap();
+        }
+        directory
+    }
+
+    fn parse_files(test: &str, files: &[(&str, &str)]) -> Result<Program, ParseError> {
+        let directory = write_files(test, files);
+        let main = directory.join(files[0].0).to_string_lossy().into_owned();
+        let (source, map) = read_with_includes(&main, SourceEncoding::Utf8)?;
+        let program = parse_with_includes(source, &map, &quiet());
+        let _ = fs::remove_dir_all(&directory);
+        program
+    }
+
+    #[test]
+    fn symbols_resolve_to_their_definitions() {
+        let program = parse(
+            ".define STEP 3\nmov ax, STEP\nloop: add ax, STEP\njmp loop\ncall .twice\nhalt\n\
+             .twice\nloop: add ax, ax\njmp loop\n.end\n",
+        )
+        .unwrap();
+        assert_eq!(program.labels, HashMap::from([("loop".to_string(), 1)]));
+        assert_eq!(
+            program.instructions,
+            vec![
+                Instruction::MOV(0, 3),
+                Instruction::ADD(0, Operand::Immediate(3)),
+                Instruction::JMP(1),
+                Instruction::CALL(".twice".to_string()),
+                Instruction::HALT,
+            ]
+        );
+        // The function's own loop label, not the global one
+        assert_eq!(program.functions[".twice"].body[1], Instruction::JMP(0));
+        assert_eq!(program.functions[".twice"].line, 7);
+    }
+
+    #[test]
+    fn symbol_errors_name_the_symbol() {
+        let message = |source: &str| parse(source).unwrap_err().to_string();
+        assert_eq!(
+            message("jmp nowhere\n"),
+            "Undefined label \"nowhere\" on line 1. [E016]"
+        );
+        assert_eq!(
+            message("here: nop\nhere: nop\n"),
+            "Duplicate label \"here\" on line 2. [E015]"
+        );
+        assert_eq!(
+            message(".define LIMIT 1\n.define LIMIT 2\n"),
+            "Constant LIMIT on line 2 is already defined on line 1. [E018]"
+        );
+        assert_eq!(
+            message("call .missing\n"),
+            "CALL to undefined function .missing on line 1. [E017]"
+        );
+    }
+
+    #[test]
+    fn symbol_definitions_list_every_name_where_it_is_defined() {
+        let source = ".define STEP 3\n.data\ncount 10\n.end\nloop: add ax, STEP\njmp loop\n\
+                      .twice\nloop: add ax, ax\njmp loop\n.end\n";
+        let definitions = symbol_definitions(source);
+        let listed: Vec<(&str, SymbolKind, usize, Option<&str>)> = definitions
+            .iter()
+            .map(|symbol| {
+                (
+                    symbol.name.as_str(),
+                    symbol.kind,
+                    symbol.line,
+                    symbol.scope.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("STEP", SymbolKind::Constant, 1, None),
+                ("count", SymbolKind::Constant, 3, None),
+                ("loop", SymbolKind::Label, 5, None),
+                (".twice", SymbolKind::Function, 7, None),
+                ("loop", SymbolKind::Label, 8, Some(".twice")),
+            ]
+        );
+    }
+
+    #[test]
+    fn private_names_stay_in_their_file() {
+        let program = parse_files(
+            "private",
+            &[
+                (
+                    "main.asm",
+                    ".include \"lib.asm\"\njmp done\nnop\ndone: call .helper\nhalt\n",
+                ),
+                (
+                    "lib.asm",
+                    ".export .helper\n.helper\njmp done\nnop\ndone: ret\n.end\n",
+                ),
+            ],
+        )
+        .unwrap();
+        assert_eq!(program.instructions[0], Instruction::JMP(2));
+        assert_eq!(program.functions[".helper"].body[0], Instruction::JMP(2));
+    }
+
+    #[test]
+    fn names_not_exported_are_reported_where_they_are_used() {
+        let error = parse_files(
+            "not-exported",
+            &[
+                ("main.asm", ".include \"lib.asm\"\nmov ax, LIMIT\n"),
+                ("lib.asm", ".define LIMIT 10\n"),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(error.code(), "E030");
+        assert!(error
+            .to_string()
+            .contains("LIMIT on line 2 exists but is not exported from"));
+    }
+
+    #[test]
+    fn parse_dump_lists_lines_labels_and_functions_in_source_order() {
+        // .zeta comes first in the source, so it is listed before .alpha
+        let source = "start: call .zeta\ncall .alpha\nhalt\n.zeta\nback: ret\n.end\n.alpha\nagain: nop\nret\n.end\n";
+        let tokens = lex(source);
+        let mut scopes = Scopes::new(&tokens, None).unwrap();
+        let labels = collect_labels(&tokens, &mut scopes).unwrap();
+        let program = parse(source).unwrap();
+        let dump = format_parse_dump(source, &tokens, &labels, &scopes.symbols, &program);
+        assert_eq!(
+            dump,
+            concat!(
+                "Tokenized lines:\n",
+                "   1 | start: call .zeta                | [start:] [call] [.zeta]\n",
+                "   2 | call .alpha                      | [call] [.alpha]\n",
+                "   3 | halt                             | [halt]\n",
+                "   4 | .zeta                            | [.zeta]\n",
+                "   5 | back: ret                        | [back:] [ret]\n",
+                "   6 | .end                             | [.end]\n",
+                "   7 | .alpha                           | [.alpha]\n",
+                "   8 | again: nop                       | [again:] [nop]\n",
+                "   9 | ret                              | [ret]\n",
+                "  10 | .end                             | [.end]\n",
+                "Labels:\n",
+                "  start -> 0 (global)\n",
+                "  back -> 0 (.zeta)\n",
+                "  again -> 0 (.alpha)\n",
+                "Global code: 3 instructions\n",
+                "Functions:\n",
+                "  .zeta (line 4): 1 instructions\n",
+                "  .alpha (line 7): 2 instructions\n",
+            )
+        );
+    }
+
+    #[test]
+    fn registers_are_letters_numbers_or_r_numbers() {
+        for (token, index) in [("a", 0), ("bx", 1), ("CX", 2), ("r3", 3), ("4", 4)] {
+            assert_eq!(parse_register(token, 1).ok(), Some(index), "{}", token);
+        }
+        let last = format!("r{}", REGISTER_COUNT - 1);
+        assert_eq!(
+            parse_register(&last, 1).ok(),
+            Some(REGISTER_COUNT as u16 - 1)
+        );
+        for token in ["r99", "ab", "a1", "r-1", "$", ""] {
+            assert!(
+                matches!(
+                    parse_register(token, 1),
+                    Err(ParseError::InvalidRegister { .. })
+                ),
+                "{}",
+                token
+            );
+        }
+    }
+
+    #[test]
+    fn a_bad_register_operand_is_an_error_not_register_0() {
+        for source in ["add $, 1\n", "movr ax, ?\n", "mul ab, bx\n"] {
+            assert_eq!(parse(source).unwrap_err().code(), "E004", "{}", source);
+        }
+    }
+
+    #[test]
+    fn tokens_are_classified_as_the_parser_reads_them() {
+        let tokens = &lex("loop: add ax, #3 ; count\njmp loop\n.ascii TEXT \"hi\"\n");
+        let classes: Vec<Vec<TokenClass>> = tokens.iter().map(|t| classify_tokens(t)).collect();
+        use TokenClass::*;
+        assert_eq!(
+            classes,
+            [
+                vec![Symbol, Mnemonic, Register, Immediate],
+                vec![Mnemonic, Symbol],
+                vec![Symbol, Symbol, Immediate],
+            ]
+        );
+    }
+
+    #[test]
+    fn a_highlighted_line_tags_every_class_and_keeps_its_spacing() {
+        let line = "loop:  add ax, #3   ; count";
+        assert_eq!(
+            highlight_source_line(line, Highlight::Tags),
+            "<symbol>loop:</symbol>  <mnemonic>add</mnemonic> <register>ax,</register> \
+             <immediate>#3</immediate>   <comment>; count</comment>"
+        );
+        assert_eq!(
+            highlight_source_line(".ascii TEXT \"a;b\" # text\n", Highlight::Tags),
+            "<symbol>.ascii</symbol> <symbol>TEXT</symbol> <immediate>\"a;b\"</immediate> \
+             <comment># text</comment>\n"
+        );
+        assert_eq!(highlight_source_line(line, Highlight::Plain), line);
+        assert_eq!(
+            highlight_line(&lex("jmp loop").concat(), Highlight::Tags),
+            "<mnemonic>jmp</mnemonic> <symbol>loop</symbol>"
+        );
+        assert_eq!(
+            highlight_line(&lex("mov   ax, 5").concat(), Highlight::Plain),
+            "mov ax, 5"
+        );
+    }
+
+    #[test]
+    fn an_unknown_mnemonic_carries_its_line() {
+        let error = parse("mov ax, 1\nprint ax\nfrob ax\nhalt\n").unwrap_err();
+        assert!(matches!(
+            error,
+            ParseError::UnknownInstruction { line: 3, ref token } if token == "frob"
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Unknown instruction \"frob\" on line 3. [E001]"
+        );
+    }
+
+    #[test]
+    fn each_error_is_reported_on_its_line() {
+        let cases = [
+            ("nop\nmov ax, 70000\n", "E003", 2),
+            ("nop\nload ax, bx\n", "E002", 2),
+            ("nop\nmovr ax, zz\n", "E004", 2),
+            ("nop\nshl ax, 40\n", "E005", 2),
+            ("nop\nadd ax\n", "E006", 2),
+            ("nop\nmov ax\n", "E007", 2),
+            ("nop\n.end\n", "E008", 2),
+            (".outer\n.inner\nret\n.end\n.end\n", "E009", 2),
+            ("nop\n.unfinished\nret\n", "E010", 2),
+            ("nop\n.data\ncount 10\n", "E011", 2),
+            ("nop\n.ascii TEXT \"open\n", "E012", 2),
+            ("nop\nmov ax, 'ab'\n", "E013", 2),
+            ("nop\npopcntr ax, r60, 10\n", "E014", 2),
+            ("nop\nloop: nop\nloop: nop\n", "E015", 3),
+            ("nop\njmp nowhere\n", "E016", 2),
+            ("nop\ncall .missing\n", "E017", 2),
+            (".define N1 1\n.define N1 2\n", "E018", 2),
+            ("nop\n.define 1N 1\n", "E019", 2),
+            ("nop\n.define ax 1\n", "E020", 2),
+            ("nop\nmov ax, #(MISSING + 1)\n", "E021", 2),
+            ("nop\nmov ax, #(1 +)\n", "E022", 2),
+            ("nop\n.define N1 (4 / 0)\n", "E023", 2),
+            ("nop\ndiv ax, #0\n", "E024", 2),
+        ];
+        for (source, code, line) in cases {
+            let mut error = parse(source).unwrap_err();
+            assert_eq!(error.code(), code, "{:?}: {}", source, error);
+            assert_eq!(error.line_mut().copied(), Some(line), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn a_file_reached_twice_is_included_once() {
+        let program = parse_files(
+            "diamond",
+            &[
+                (
+                    "main.asm",
+                    ".include \"a.asm\"\n.include \"b.asm\"\nmov ax, LIMIT\ncall .twice\nhalt\n",
+                ),
+                ("a.asm", ".include \"common.asm\"\n"),
+                ("b.asm", ".include \"./common.asm\"\n"),
+                (
+                    "common.asm",
+                    ".export LIMIT\n.define LIMIT 3\n.export .twice\n.twice\nadd ax, ax\nret\n.end\n",
+                ),
+            ],
+        )
+        .unwrap();
+        assert_eq!(program.instructions[0], Instruction::MOV(0, 3));
+        assert_eq!(program.functions.len(), 1);
+    }
+
+    #[test]
+    fn include_force_splices_a_file_in_again() {
+        let error = parse_files(
+            "force",
+            &[
+                (
+                    "main.asm",
+                    ".include \"limit.asm\"\n.include_force \"limit.asm\"\nhalt\n",
+                ),
+                ("limit.asm", ".define LIMIT 1\n"),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(error.code(), "E018");
+    }
+
+    // File names of the includes in a cycle, without the test directory
+    fn cycle(error: ParseError) -> Vec<String> {
+        let ParseError::IncludeCycle { chain } = error else {
+            panic!("not a cycle: {}", error);
+        };
+        chain
+            .iter()
+            .map(|path| {
+                Path::new(path)
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn include_cycles_show_the_chain() {
+        let error = parse_files("self", &[("self.asm", ".include \"self.asm\"\n")]).unwrap_err();
+        assert_eq!(cycle(error), ["self.asm", "self.asm"]);
+
+        let error = parse_files(
+            "cycle",
+            &[
+                ("main.asm", ".include \"a.asm\"\n"),
+                ("a.asm", ".include \"b.asm\"\n"),
+                ("b.asm", "nop\n.include \"a.asm\"\n"),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(cycle(error), ["a.asm", "b.asm", "a.asm"]);
+    }
+
+    #[test]
+    fn a_missing_include_names_the_line() {
+        let error =
+            parse_files("missing", &[("main.asm", "nop\n.include \"gone.asm\"\n")]).unwrap_err();
+        assert_eq!(error.code(), "E028");
+        assert!(
+            error.to_string().contains("gone.asm' on line 2"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn both_comment_characters_end_a_line() {
+        assert_eq!(
+            lex("mov ax, 1 ; set ax\nmov bx, #2 # and bx\n# a whole line\n; another\n"),
+            [
+                vec!["mov", "ax,", "1"],
+                vec!["mov", "bx,", "#2"],
+                vec![],
+                vec![],
+            ]
+        );
+        // `#` followed by a value is an immediate, `;` in a string or character is text
+        assert_eq!(
+            lex(".ascii SEMI \"a;b\"\nmov ax, ';'\nmov ax, #5#6\n"),
+            [
+                vec![".ascii", "SEMI", "\"a;b\""],
+                vec!["mov", "ax,", "';'"],
+                vec!["mov", "ax,", "#5#6"],
+            ]
+        );
+        assert_eq!(line_comment("nop ; lint-allow: x"), Some(" lint-allow: x"));
+        assert_eq!(annotation("nop ;@ label=setup"), Some("label=setup"));
+        assert_eq!(annotation("nop ; plain"), None);
+    }
+
+    #[test]
+    fn each_escape_stands_for_its_byte() {
+        for (literal, value) in [
+            ("'A'", 65),
+            ("'\\n'", 10),
+            ("'\\t'", 9),
+            ("'\\r'", 13),
+            ("'\\0'", 0),
+            ("'\\\\'", 92),
+            ("'\\''", 39),
+            ("'\\\"'", 34),
+            ("'\\x7f'", 127),
+        ] {
+            assert_eq!(char_literal(literal), Some(value), "{}", literal);
+        }
+        assert_eq!(char_literal("'\\q'"), None);
+        let program = parse(".ascii TEXT \"\\x41\\t\\\"\\\\\"\n").unwrap();
+        assert_eq!(program.data, [0x41, 9, 34, 92, 0]);
+    }
+
+    #[test]
+    fn numbers_can_be_hex_binary_negative_or_characters() {
+        let program = parse("mov ax, 0x2A\nmov bx, #0b101010\nmov cx, -4\nmov dx, 'A'\n").unwrap();
+        assert_eq!(
+            program.instructions,
+            [
+                Instruction::MOV(0, 42),
+                Instruction::MOV(1, 42),
+                Instruction::MOV(2, (-4i16) as u16),
+                Instruction::MOV(3, 65),
+            ]
+        );
+    }
+
+    #[test]
+    fn legacy_binary_literals_are_rewritten_once() {
+        let source = "mov ax, b101 ; b11 stays\n.define LOW 3\nadd ax, #(LOW + b1)\n";
+        assert_eq!(legacy_binary_literals(source).len(), 2);
+        let (fixed, count) = fix_binary_literals(source);
+        assert_eq!(count, 2);
+        assert_eq!(
+            fixed,
+            "mov ax, 0b101 ; b11 stays\n.define LOW 3\nadd ax, #(LOW + 0b1)\n"
+        );
+        assert_eq!(fix_binary_literals(&fixed), (fixed.clone(), 0));
+    }
+
+    #[test]
+    fn reserved_names_are_rejected_where_they_are_defined() {
+        for (source, line, kind, name) in [
+            ("halt\n.mov\nret\n.end\n", 2, "function", ".mov"),
+            ("nop\nb: nop\nhalt\n", 2, "label", "b"),
+            ("nop\n.define HALT 2\nhalt\n", 2, "constant", "HALT"),
+        ] {
+            match parse(source) {
+                Err(ParseError::ReservedName {
+                    line: at,
+                    kind: what,
+                    name: named,
+                    ..
+                }) => assert_eq!((at, what.as_str(), named.as_str()), (line, kind, name)),
+                other => panic!("expected ReservedName for {}, got {:?}", name, other.ok()),
+            }
+        }
+    }
+
+    #[test]
+    fn reserved_names_are_renamed_where_they_are_used() {
+        let source =
+            ".define HALT 2\nmov ax, #HALT\nb: call .mov\ncmp ax, 8\njl b\nprint ax\nhalt\n\
+                      .mov\nadd ax, #HALT\nret\n.end\n";
+        assert_eq!(parse(source).unwrap_err().code(), "E020");
+        let (fixed, renamed) = fix_reserved_names(source);
+        assert_eq!(
+            fixed,
+            ".define HALT_ 2\nmov ax, #HALT_\nb_: call .mov_\ncmp ax, 8\njl b_\nprint ax\nhalt\n\
+             .mov_\nadd ax, #HALT_\nret\n.end\n"
+        );
+        assert_eq!(renamed.len(), 3);
+        // The original cannot run under its own names, so it runs with names nothing reserves
+        let meant = ".define STEP 2\nmov ax, #STEP\nagain: call .grow\ncmp ax, 8\njl again\n\
+                     print ax\nhalt\n.grow\nadd ax, #STEP\nret\n.end\n";
+        let run = |source: &str| run_program(source).unwrap().output;
+        assert_eq!(run(&fixed), run(meant));
+        assert_eq!(run(&fixed), "ax: 8\n");
+    }
+
+    #[test]
+    fn operand_defaults_fill_in_a_missing_source() {
+        let mut config = quiet();
+        config.operand_defaults.insert("add".to_string(), 1);
+        let program = parse_with_config("add a\nadd b, 5\n".to_string(), &config).ok();
+        assert_eq!(
+            program.map(|program| program.instructions),
+            Some(vec![
+                Instruction::ADD(0, Operand::Immediate(1)),
+                Instruction::ADD(1, Operand::Immediate(5)),
+            ])
+        );
+        // Without a default the source is needed, and only the listed mnemonic gets one
+        assert_eq!(parse("add a\n").unwrap_err().code(), "E006");
+        assert_eq!(
+            parse_with_config("sub a\n".to_string(), &config)
+                .unwrap_err()
+                .code(),
+            "E006"
+        );
+    }
+
+    #[test]
+    fn a_latin_1_file_is_rejected_where_it_stops_being_utf_8() {
+        // "é" in Latin-1 is the single byte 0xe9, not valid UTF-8
+        let latin1 = b"mov a, 1\n; caf\xe9\nhalt\n".to_vec();
+        match decode_source("old.asm", latin1.clone(), SourceEncoding::Utf8) {
+            Err(ParseError::InvalidUtf8 {
+                path,
+                line,
+                column,
+                byte,
+            }) => assert_eq!((path.as_str(), line, column, byte), ("old.asm", 2, 6, 0xe9)),
+            other => panic!("expected InvalidUtf8, got {:?}", other.ok()),
+        }
+        assert_eq!(
+            decode_source("old.asm", latin1.clone(), SourceEncoding::Latin1).ok(),
+            Some("mov a, 1\n; café\nhalt\n".to_string())
+        );
+        assert_eq!(
+            decode_source("old.asm", latin1, SourceEncoding::LossyUtf8).ok(),
+            Some("mov a, 1\n; caf\u{fffd}\nhalt\n".to_string())
+        );
+    }
+
+    #[test]
+    fn an_included_file_is_read_with_the_same_encoding() {
+        let directory = write_files(
+            "latin1-include",
+            &[("main.asm", ".include \"lib.asm\"\nhalt\n")],
+        );
+        fs::write(directory.join("lib.asm"), b"; \xe9t\xe9\nmov a, 2\n").unwrap();
+        let main = directory.join("main.asm").to_string_lossy().into_owned();
+        assert_eq!(
+            read_with_includes(&main, SourceEncoding::Utf8)
+                .unwrap_err()
+                .code(),
+            "E027"
+        );
+        let (source, _) = read_with_includes(&main, SourceEncoding::Latin1).unwrap();
+        assert!(source.contains("; été\nmov a, 2"));
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn the_source_hash_changes_with_any_byte_of_any_included_file() {
+        let hash = |test: &str, lib: &str| {
+            parse_files(
+                test,
+                &[
+                    ("main.asm", ".include \"lib.asm\"\ncall .setup\n"),
+                    ("lib.asm", lib),
+                ],
+            )
+            .unwrap()
+            .source_hash
+        };
+        let original = hash("hash-a", ".export .setup\n.setup\nmov a, 1\nret\n.end\n");
+        assert!(original.is_some());
+        assert_eq!(
+            hash("hash-b", ".export .setup\n.setup\nmov a, 1\nret\n.end\n"),
+            original
+        );
+        assert_ne!(
+            hash("hash-c", ".export .setup\n.setup\nmov a, 2\nret\n.end\n"),
+            original
+        );
+    }
+
+    // The value a constant has in a program that moves it into ax
+    fn address_of(data: &str, name: &str) -> u16 {
+        let program = parse(&format!("{}mov ax, {}\nhalt\n", data, name)).unwrap();
+        match program.instructions[0] {
+            Instruction::MOV(0, value) => value,
+            ref other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn align_moves_up_to_the_next_multiple() {
+        for (org, multiple, aligned) in [(5, 4, 8), (8, 4, 8), (0, 16, 0), (7, 1, 7), (17, 16, 32)]
+        {
+            let data = format!(".data\n.org {}\nhere: .align {}\n.end\n", org, multiple);
+            assert_eq!(address_of(&data, "here"), aligned, "{}", data);
+        }
+        let error = parse(".data\n.align 0\n.end\n").unwrap_err();
+        assert_eq!(
+            (error.code(), error.to_string().contains("line 2")),
+            ("E002", true)
+        );
+    }
+
+    #[test]
+    fn a_label_on_space_names_its_first_cell() {
+        let data = ".data\n.org 0x10\n.ascii HI \"hi\"\nbuffer: .space 4\nafter: .space 1\n.end\n\
+                    .ascii LATE \"x\"\n";
+        assert_eq!(address_of(data, "buffer"), 0x13);
+        assert_eq!(address_of(data, "after"), 0x17);
+        assert_eq!(address_of(data, "LATE"), 0x18);
+        let program = parse(data).unwrap();
+        assert_eq!(program.data.len(), 0x1A);
+        assert!(program.data[..0x10].iter().all(|&cell| cell == 0));
+        assert_eq!(program.data[0x10..0x13], [104, 105, 0]);
+        assert_eq!(program.data[0x13..0x18], [0; 5]);
+        assert_eq!(program.data[0x18..], [120, 0]);
+    }
+
+    #[test]
+    fn overlapping_data_is_reported_with_both_lines() {
+        let error = parse(".data\n.org 0x10\n.space 8\n.org 0x14\n.space 2\n.end\n").unwrap_err();
+        assert!(matches!(
+            error,
+            ParseError::DataOverlap {
+                line: 5,
+                address: 0x14,
+                first_line: 3
+            }
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Data on line 5 overlaps the data placed on line 3 at address 20. [E033]"
+        );
+        // Going back to just after earlier data is fine
+        assert!(parse(
+            ".data\n.org 0x10\n.space 8\n.org 0\n.space 16\n.org 0x18\n.space 1\n.end\n"
+        )
+        .is_ok());
+        let error =
+            parse(".data\n.org 4\n.ascii A_ \"abc\"\n.org 6\n.ascii B_ \"d\"\n.end\n").unwrap_err();
+        assert!(matches!(
+            error,
+            ParseError::DataOverlap {
+                line: 5,
+                address: 6,
+                first_line: 3
+            }
+        ));
+    }
+
+    #[test]
+    fn data_cannot_go_past_the_last_address() {
+        assert!(parse(".data\n.org 0xFFFF\n.space 1\n.end\n").is_ok());
+        for (source, line) in [
+            (".data\n.org 0xFFFF\n.space 2\n.end\n", 3),
+            (".data\n.org 0x10000\n.end\n", 2),
+        ] {
+            let mut error = parse(source).unwrap_err();
+            assert_eq!(error.code(), "E003", "{}", source);
+            assert_eq!(error.line_mut().copied(), Some(line), "{}", source);
+        }
     }
 }
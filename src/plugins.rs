@@ -0,0 +1,87 @@
+use crate::*;
+use std::sync::Mutex;
+
+// Instructions added by embedders on top of the core set in instructions.rs.
+// Definitions must be registered before parsing, so the parser accepts their mnemonics.
+
+pub trait InstructionDef: Send {
+    /// Mnemonic used in the assembly, matched case-insensitively.
+    fn mnemonic(&self) -> &str;
+    /// Number of operands the instruction takes.
+    fn arity(&self) -> usize;
+    /// Runs the instruction, operands are parsed the same way as core operands.
+    fn execute(&self, cpu: &mut Cpu, operands: &[u16]);
+}
+
+static REGISTRY: Mutex<Vec<Box<dyn InstructionDef>>> = Mutex::new(Vec::new());
+
+/// Adds an instruction to the table, replacing any earlier definition with the same mnemonic.
+/// Mnemonics of core instructions always refer to the core instruction.
+#[allow(dead_code)]
+pub fn register_instruction(def: Box<dyn InstructionDef>) {
+    let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    registry.retain(|existing| !existing.mnemonic().eq_ignore_ascii_case(def.mnemonic()));
+    registry.push(def);
+}
+
+/// Returns the arity of a registered instruction, or None if the mnemonic is unknown.
+pub fn custom_arity(mnemonic: &str) -> Option<usize> {
+    let registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    registry
+        .iter()
+        .find(|def| def.mnemonic().eq_ignore_ascii_case(mnemonic))
+        .map(|def| def.arity())
+}
+
+/// Runs a registered instruction, returns false if the mnemonic is not registered.
+pub fn execute_custom(cpu: &mut Cpu, mnemonic: &str, operands: &[u16]) -> bool {
+    let registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    match registry
+        .iter()
+        .find(|def| def.mnemonic().eq_ignore_ascii_case(mnemonic))
+    {
+        Some(def) => {
+            def.execute(cpu, operands);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Triple;
+
+    impl InstructionDef for Triple {
+        fn mnemonic(&self) -> &str {
+            "TRIPLE"
+        }
+        fn arity(&self) -> usize {
+            1
+        }
+        fn execute(&self, cpu: &mut Cpu, operands: &[u16]) {
+            let value = cpu.register(operands[0]);
+            cpu.set_register(operands[0], value.wrapping_mul(3));
+        }
+    }
+
+    #[test]
+    fn a_registered_instruction_parses_and_runs() {
+        register_instruction(Box::new(Triple));
+        assert_eq!(custom_arity("triple"), Some(1));
+        let config = Config {
+            debug: false,
+            ..Config::default()
+        };
+        let program =
+            parse_with_config("mov b, 7\ntriple b\nTRIPLE b\nhalt\n".to_string(), &config).unwrap();
+        assert_eq!(
+            program.instructions[1],
+            Instruction::CUSTOM("TRIPLE".to_string(), vec![1])
+        );
+        let cpu = program.run_with(std::io::empty(), std::io::sink()).unwrap();
+        assert_eq!(cpu.register(1), 63);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::*;
+use std::io;
+
+// Reruns a program with a register set to each of several sizes, for --profile-growth. The
+// instructions executed in the whole program and in each function are counted in every run,
+// and classify_growth guesses the curve each part's counts follow.
+
+// The instruction counts of one part of a program at each size, with the curve they fit best
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrowthRow {
+    pub label: String, // "whole program", "global code" or the name of a function
+    pub steps: Vec<(u16, u64)>, // Size and instructions executed, one per run that finished
+    pub growth: Option<Growth>, // None when the runs cannot tell
+}
+
+#[derive(Debug)]
+pub struct GrowthProfile {
+    pub rows: Vec<GrowthRow>, // The whole program first, then global code and functions by name
+    pub failed: Vec<(u16, RuntimeError)>, // Sizes whose run stopped with an error, left out
+}
+
+/// Runs program once for each value in values, with that value in register and no output.
+/// prepare sets up each Cpu after the program is loaded, before the register is set.
+pub fn growth_profile(
+    program: &Program,
+    config: &Config,
+    register: u16,
+    values: &[u16],
+    mut prepare: impl FnMut(&mut Cpu),
+) -> GrowthProfile {
+    let mut runs = Vec::new();
+    let mut failed = Vec::new();
+    for &value in values {
+        let mut cpu = Cpu::new(config.clone());
+        cpu.set_output(Box::new(io::sink()));
+        cpu.set_input(Box::new(io::empty()));
+        let counter = StepCounter::default();
+        cpu.add_tracer(Box::new(counter.clone()));
+        cpu.load_program(program);
+        prepare(&mut cpu);
+        cpu.registers[register as usize] = value;
+        match cpu.run() {
+            Ok(()) => runs.push((value, cpu.instructions_executed, counter.counts())),
+            Err(e) => failed.push((value, e)),
+        }
+    }
+
+    let mut functions: Vec<Option<String>> = runs
+        .iter()
+        .flat_map(|(_, _, counts)| counts.keys().cloned())
+        .collect();
+    functions.sort();
+    functions.dedup();
+    let mut rows = vec![(
+        "whole program".to_string(),
+        runs.iter()
+            .map(|&(value, total, _)| (value, total))
+            .collect::<Vec<_>>(),
+    )];
+    for function in functions {
+        let steps = runs
+            .iter()
+            .map(|(value, _, counts)| (*value, counts.get(&function).copied().unwrap_or(0)))
+            .collect();
+        let label = match function {
+            Some(function) => function,
+            None => "global code".to_string(),
+        };
+        rows.push((label, steps));
+    }
+    let rows = rows
+        .into_iter()
+        .map(|(label, steps)| {
+            let points: Vec<(f64, f64)> =
+                steps.iter().map(|&(n, y)| (n as f64, y as f64)).collect();
+            GrowthRow {
+                label,
+                growth: classify_growth(&points),
+                steps,
+            }
+        })
+        .collect();
+    GrowthProfile { rows, failed }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // .pairs runs its inner loop n times for each of n rounds of the outer one
+    const QUADRATIC: &str = "call .pairs\nhalt\n.pairs\nmov i, n\nouter: mov j, n\n\
+                             inner: dec j\ncmp j, 0\njne inner\ndec i\ncmp i, 0\njne outer\n\
+                             ret\n.end\n";
+
+    // .each runs its loop n times
+    const LINEAR: &str = "call .each\nhalt\n.each\nmov i, n\nagain: dec i\ncmp i, 0\n\
+                          jne again\nret\n.end\n";
+
+    fn growth_of(source: &str) -> Vec<(String, Option<Growth>)> {
+        let config = Config {
+            debug: false,
+            ..Config::default()
+        };
+        let program = parse_with_config(source.to_string(), &config).unwrap();
+        let n = parse_register("n", 0).unwrap();
+        let profile = growth_profile(&program, &config, n, &[4, 8, 16, 32, 64], |_| {});
+        assert!(profile.failed.is_empty());
+        profile
+            .rows
+            .into_iter()
+            .map(|row| (row.label, row.growth))
+            .collect()
+    }
+
+    #[test]
+    fn nested_loops_over_the_size_are_quadratic_in_their_function() {
+        assert_eq!(
+            growth_of(QUADRATIC),
+            [
+                ("whole program".to_string(), Some(Growth::Quadratic)),
+                ("global code".to_string(), Some(Growth::Constant)),
+                (".pairs".to_string(), Some(Growth::Quadratic)),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_single_loop_over_the_size_is_linear_in_its_function() {
+        assert_eq!(
+            growth_of(LINEAR),
+            [
+                ("whole program".to_string(), Some(Growth::Linear)),
+                ("global code".to_string(), Some(Growth::Constant)),
+                (".each".to_string(), Some(Growth::Linear)),
+            ]
+        );
+    }
+
+    #[test]
+    fn each_function_is_counted_on_its_own() {
+        let config = Config {
+            debug: false,
+            ..Config::default()
+        };
+        let program = parse_with_config(LINEAR.to_string(), &config).unwrap();
+        let n = parse_register("n", 0).unwrap();
+        let profile = growth_profile(&program, &config, n, &[2, 3], |cpu| {
+            cpu.max_instructions = 20
+        });
+        let steps: Vec<Vec<(u16, u64)>> =
+            profile.rows.iter().map(|row| row.steps.clone()).collect();
+        // CALL and HALT in global code, MOV, n rounds of DEC, CMP and JNE, and RET in .each
+        assert_eq!(
+            steps,
+            [
+                vec![(2, 10), (3, 13)],
+                vec![(2, 2), (3, 2)],
+                vec![(2, 8), (3, 11)]
+            ]
+        );
+        assert_eq!(profile.rows[0].growth, None);
+
+        let limited = growth_profile(&program, &config, n, &[2, 30], |cpu| {
+            cpu.max_instructions = 20
+        });
+        assert_eq!(limited.failed.len(), 1);
+        assert_eq!(limited.failed[0].0, 30);
+        assert_eq!(limited.rows[0].steps, [(2, 10)]);
+    }
+}
@@ -0,0 +1,200 @@
+// Summary statistics over benchmark samples, such as the instructions per second of each run.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub min: f64,
+    pub median: f64,
+    pub p95: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub std_dev: f64, // Sample standard deviation, 0 for a single sample
+}
+
+/// The p-th percentile (0 to 100) of samples sorted in ascending order, interpolated linearly
+/// between the two nearest ranks, so the 50th percentile of an even count is the mean of the
+/// middle two. Panics on an empty slice.
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p.clamp(0.0, 100.0) / 100.0 * (sorted.len() - 1) as f64;
+    let (below, above) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[below] + (sorted[above] - sorted[below]) * (rank - below as f64)
+}
+
+/// Summarizes samples in any order, None when there are none.
+pub fn summarize(samples: &[f64]) -> Option<Summary> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mut sorted = samples.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let count = sorted.len() as f64;
+    let mean = sorted.iter().sum::<f64>() / count;
+    let variance = match sorted.len() {
+        1 => 0.0,
+        _ => sorted.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (count - 1.0),
+    };
+    Some(Summary {
+        min: sorted[0],
+        median: percentile(&sorted, 50.0),
+        p95: percentile(&sorted, 95.0),
+        max: sorted[sorted.len() - 1],
+        mean,
+        std_dev: variance.sqrt(),
+    })
+}
+
+/// The extra time a feature costs in percent, from the instructions per second without it and
+/// with it: half the speed is 100% more time.
+pub fn overhead_percent(without: f64, with: f64) -> f64 {
+    (without / with - 1.0) * 100.0
+}
+
+// How a count grows with the size of its input, as guessed by classify_growth
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Growth {
+    Constant,
+    Linear,
+    NLogN,
+    Quadratic,
+}
+
+impl Growth {
+    // The part of the count that depends on the size n, 0 for a constant
+    fn curve(self, n: f64) -> f64 {
+        match self {
+            Growth::Constant => 0.0,
+            Growth::Linear => n,
+            Growth::NLogN => n * n.max(1.0).ln(),
+            Growth::Quadratic => n * n,
+        }
+    }
+}
+
+impl std::fmt::Display for Growth {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Growth::Constant => write!(f, "constant"),
+            Growth::Linear => write!(f, "linear"),
+            Growth::NLogN => write!(f, "n log n"),
+            Growth::Quadratic => write!(f, "quadratic"),
+        }
+    }
+}
+
+/// Guesses how counts grow with the sizes they were measured at from (size, count) points,
+/// fitting count = a + b * f(size) by least squares for each curve and taking the one that
+/// leaves the smallest error relative to the counts, the simplest on a tie. Only a heuristic,
+/// a few sizes cannot tell every curve apart. None with fewer than three distinct sizes.
+pub fn classify_growth(points: &[(f64, f64)]) -> Option<Growth> {
+    let mut sizes: Vec<f64> = points.iter().map(|&(n, _)| n).collect();
+    sizes.sort_by(f64::total_cmp);
+    sizes.dedup();
+    if sizes.len() < 3 {
+        return None;
+    }
+    let total: f64 = points.iter().map(|&(_, y)| y * y).sum();
+    if total == 0.0 {
+        return Some(Growth::Constant);
+    }
+    let mut best: Option<(Growth, f64)> = None;
+    for growth in [
+        Growth::Constant,
+        Growth::Linear,
+        Growth::NLogN,
+        Growth::Quadratic,
+    ] {
+        let Some(error) = fit_error(points, growth) else {
+            continue;
+        };
+        let error = (error / total).sqrt();
+        // A more complex curve has to fit clearly better to win
+        if best.is_none_or(|(_, best_error)| error < best_error - 1e-9) {
+            best = Some((growth, error));
+        }
+    }
+    best.map(|(growth, _)| growth)
+}
+
+// Sum of squared residuals of the least squares fit y = a + b * growth.curve(n), None when the
+// best fit has the count shrinking as the size grows
+fn fit_error(points: &[(f64, f64)], growth: Growth) -> Option<f64> {
+    let count = points.len() as f64;
+    let xs: Vec<f64> = points.iter().map(|&(n, _)| growth.curve(n)).collect();
+    let mean_x = xs.iter().sum::<f64>() / count;
+    let mean_y = points.iter().map(|&(_, y)| y).sum::<f64>() / count;
+    let spread: f64 = xs.iter().map(|x| (x - mean_x).powi(2)).sum();
+    let slope = match spread {
+        0.0 => 0.0,
+        _ => {
+            points
+                .iter()
+                .zip(&xs)
+                .map(|(&(_, y), x)| (x - mean_x) * (y - mean_y))
+                .sum::<f64>()
+                / spread
+        }
+    };
+    if slope < 0.0 {
+        return None;
+    }
+    let intercept = mean_y - slope * mean_x;
+    Some(
+        points
+            .iter()
+            .zip(&xs)
+            .map(|(&(_, y), x)| (y - intercept - slope * x).powi(2))
+            .sum(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_interpolate_between_ranks() {
+        let sorted = [10.0, 20.0, 30.0, 40.0, 50.0];
+        assert_eq!(percentile(&sorted, 0.0), 10.0);
+        assert_eq!(percentile(&sorted, 50.0), 30.0);
+        assert_eq!(percentile(&sorted, 95.0), 48.0);
+        assert_eq!(percentile(&sorted, 100.0), 50.0);
+        assert_eq!(percentile(&[1.0, 2.0, 3.0, 4.0], 50.0), 2.5);
+        assert_eq!(percentile(&[7.0], 95.0), 7.0);
+    }
+
+    #[test]
+    fn a_summary_does_not_depend_on_the_sample_order() {
+        let summary = summarize(&[9.0, 2.0, 5.0, 4.0, 7.0, 4.0, 5.0, 4.0]).unwrap();
+        assert_eq!((summary.min, summary.median, summary.max), (2.0, 4.5, 9.0));
+        assert_eq!(summary.mean, 5.0);
+        assert!((summary.std_dev - (32.0f64 / 7.0).sqrt()).abs() < 1e-12);
+        assert_eq!(summarize(&[3.0]).unwrap().std_dev, 0.0);
+        assert_eq!(summarize(&[]), None);
+    }
+
+    #[test]
+    fn overhead_is_the_extra_time_not_the_lost_speed() {
+        assert_eq!(overhead_percent(1000.0, 500.0), 100.0);
+        assert_eq!(overhead_percent(1000.0, 800.0), 25.0);
+        assert_eq!(overhead_percent(1000.0, 1000.0), 0.0);
+    }
+
+    #[test]
+    fn growth_is_told_apart_from_the_counts() {
+        let points =
+            |count: fn(f64) -> f64| [4.0, 8.0, 16.0, 32.0, 64.0].map(|n| (n, count(n))).to_vec();
+        assert_eq!(classify_growth(&points(|_| 12.0)), Some(Growth::Constant));
+        assert_eq!(
+            classify_growth(&points(|n| 5.0 + 3.0 * n)),
+            Some(Growth::Linear)
+        );
+        assert_eq!(
+            classify_growth(&points(|n| 2.0 * n * n)),
+            Some(Growth::Quadratic)
+        );
+        assert_eq!(
+            classify_growth(&points(|n| n * n.ln())),
+            Some(Growth::NLogN)
+        );
+        assert_eq!(classify_growth(&[(1.0, 1.0), (2.0, 2.0)]), None);
+    }
+}
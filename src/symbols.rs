@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Names of labels, constants and functions stored once while a program is parsed. A name is
+// interned into a SymbolTable and the parser's tables hold its SymbolId, a u32, giving the name
+// back where it is shown. The list of names and the lookup by name share one allocation per name.
+//
+// Only the parser works with ids. Program, Instruction::CALL and ParseError keep their names as
+// Strings, since they are the public API and outlive the table that would resolve an id.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SymbolId(u32);
+
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    names: Vec<Rc<str>>,             // By id
+    ids: HashMap<Rc<str>, SymbolId>, // The same names, to find their id
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    /// The id of name, added to the table the first time it is interned.
+    pub fn intern(&mut self, name: &str) -> SymbolId {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        let id = SymbolId(u32::try_from(self.names.len()).expect("fewer than 2^32 names"));
+        let name: Rc<str> = Rc::from(name);
+        self.names.push(Rc::clone(&name));
+        self.ids.insert(name, id);
+        id
+    }
+
+    /// The id of name if it was interned, without adding it.
+    pub fn get(&self, name: &str) -> Option<SymbolId> {
+        self.ids.get(name).copied()
+    }
+
+    /// The name an id stands for. Panics for an id from another table.
+    pub fn name(&self, id: SymbolId) -> &str {
+        &self.names[id.0 as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interning_a_name_again_gives_the_same_id() {
+        let mut symbols = SymbolTable::new();
+        let done = symbols.intern("done");
+        let start = symbols.intern("start");
+        assert_ne!(done, start);
+        assert_eq!(symbols.intern("done"), done);
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols.name(done), "done");
+        assert_eq!(symbols.name(start), "start");
+    }
+
+    #[test]
+    fn get_does_not_add_names() {
+        let mut symbols = SymbolTable::new();
+        assert_eq!(symbols.get("loop"), None);
+        assert!(symbols.is_empty());
+        let id = symbols.intern("loop");
+        assert_eq!(symbols.get("loop"), Some(id));
+        assert_eq!(symbols.get("Loop"), None);
+    }
+}
@@ -0,0 +1,94 @@
+use std::io::{self, IsTerminal, Read};
+use std::process::{self, Command, Stdio};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+// The keyboard of --interactive. The terminal is switched to reading key by key without echo,
+// and a thread passes every key to KEYIN through a bounded queue. The terminal is put back on
+// every way out: the end of the run, a runtime error, a panic and Ctrl-C.
+
+// Keys that can wait for KEYIN, further keys are dropped until the program reads some
+const KEY_QUEUE: usize = 16;
+
+const CTRL_C: u8 = 3;
+
+// Terminal settings from before --interactive changed them, in the form `stty -g` prints
+static SAVED_SETTINGS: Mutex<Option<String>> = Mutex::new(None);
+
+// Runs stty on the terminal stdin is connected to
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "stty exited with {}",
+            output.status
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Puts the terminal back the way it was, does nothing if it was not changed or already restored
+pub fn restore_terminal() {
+    let saved = SAVED_SETTINGS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take();
+    if let Some(saved) = saved {
+        let _ = stty(&[&saved]);
+    }
+}
+
+// Restores the terminal when it goes out of scope
+pub struct RawMode;
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+// Switches the terminal to key by key input and starts the thread reading it
+pub fn start_keyboard() -> Result<(RawMode, mpsc::Receiver<u8>), String> {
+    if !io::stdin().is_terminal() {
+        return Err("--interactive needs a terminal to read keys from.".to_string());
+    }
+    let saved =
+        stty(&["-g"]).map_err(|e| format!("Could not read the terminal settings: {}.", e))?;
+    *SAVED_SETTINGS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(saved);
+    // Without isig Ctrl-C arrives as a key, so the reader can restore the terminal before exiting
+    if let Err(e) = stty(&["-icanon", "-echo", "-isig", "min", "1"]) {
+        restore_terminal();
+        return Err(format!("Could not switch the terminal to raw mode: {}.", e));
+    }
+    let previous_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        previous_hook(info);
+    }));
+
+    let (keys, receiver) = mpsc::sync_channel(KEY_QUEUE);
+    thread::spawn(move || {
+        let mut key = [0];
+        loop {
+            match io::stdin().read(&mut key).map(|read| (read, key[0])) {
+                Ok((0, _)) | Err(_) => break,
+                Ok((_, CTRL_C)) => {
+                    restore_terminal();
+                    eprintln!("Interrupted.");
+                    process::exit(130);
+                }
+                // A full queue drops the key, a finished program leaves nobody to send to
+                Ok((_, key)) => {
+                    let _ = keys.try_send(key);
+                }
+            }
+        }
+    });
+    Ok((RawMode, receiver))
+}
@@ -0,0 +1,359 @@
+use crate::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+
+// Execution traces. A Cpu with tracers hands every executed instruction to each of them, together
+// with the registers it changed. Without tracers nothing is recorded or formatted.
+
+// A register an instruction wrote a different value to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegisterChange {
+    pub register: u16,
+    pub old: u16,
+    pub new: u16,
+}
+
+// One executed instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEvent {
+    pub function: Option<String>, // None for global code
+    pub index: u16,
+    pub instruction: Instruction,
+    pub changes: Vec<RegisterChange>,
+    pub annotation: Option<String>, // Text of the `;@` comment on the instruction's line
+    pub position: Option<(String, usize)>, // File and line of the instruction, see Program::positions
+}
+
+pub trait Tracer {
+    /// Called after each instruction that ran without an error, in execution order.
+    fn trace(&mut self, event: &TraceEvent);
+}
+
+// Registers that differ between two snapshots of the register bank
+pub fn register_changes(before: &[u16], after: &[u16]) -> Vec<RegisterChange> {
+    before
+        .iter()
+        .zip(after)
+        .enumerate()
+        .filter(|(_, (old, new))| old != new)
+        .map(|(register, (&old, &new))| RegisterChange {
+            register: register as u16,
+            old,
+            new,
+        })
+        .collect()
+}
+
+// Whether an instruction is one of the mnemonics of a trace_filter, any case, every instruction
+// is when the filter is empty
+pub fn passes_trace_filter(filter: &[String], instruction: &Instruction) -> bool {
+    filter.is_empty()
+        || filter
+            .iter()
+            .any(|mnemonic| mnemonic.eq_ignore_ascii_case(instruction.to_mnemonic()))
+}
+
+// Hands a tracer only the instructions that pass a trace_filter, the trace of --trace and
+// --trace-file is limited this way while counting tracers still see every instruction
+pub struct FilteredTracer<T: Tracer> {
+    pub filter: Vec<String>,
+    pub tracer: T,
+}
+
+impl<T: Tracer> Tracer for FilteredTracer<T> {
+    fn trace(&mut self, event: &TraceEvent) {
+        if passes_trace_filter(&self.filter, &event.instruction) {
+            self.tracer.trace(event);
+        }
+    }
+}
+
+// Prints each instruction and the registers it changed to stdout, where PRINT writes too, so the
+// output of a PRINT shows up just above its trace line
+pub struct ConsoleTracer {
+    pub signed: bool, // Show values as two's complement, as PRINT does in signed mode
+    pub color: bool,  // Plain text without it, for a trace piped into a file
+}
+
+impl ConsoleTracer {
+    // The line printed for an event
+    fn format(&self, event: &TraceEvent) -> String {
+        let paint = |text: String, color| match self.color {
+            true => text.color(color),
+            false => text,
+        };
+        let changes: Vec<String> = event
+            .changes
+            .iter()
+            .map(|change| {
+                format!(
+                    "{}: {} -> {}",
+                    register_name(change.register as usize),
+                    format_value(change.old, PrintFormat::Decimal, self.signed),
+                    format_value(change.new, PrintFormat::Decimal, self.signed)
+                )
+            })
+            .collect();
+        let location = format!(
+            "[{} {}]",
+            event.function.as_deref().unwrap_or("global"),
+            event.index
+        );
+        let instruction = highlight_line(
+            &lex(&event.instruction.to_string()).concat(),
+            Highlight::colored(self.color),
+        );
+        let mut line = format!("{} {}", paint(location, Colors::BlueFg), instruction);
+        if !changes.is_empty() {
+            line = format!("{} {}", line, paint(changes.join(", "), Colors::YellowFg));
+        }
+        if let Some(annotation) = &event.annotation {
+            line = format!(
+                "{} {}",
+                line,
+                paint(format!("@ {}", annotation), Colors::BrightBlackFg)
+            );
+        }
+        line
+    }
+}
+
+impl Tracer for ConsoleTracer {
+    fn trace(&mut self, event: &TraceEvent) {
+        println!("{}", self.format(event));
+    }
+}
+
+// Writes one JSON object per instruction and line, for other tools to read:
+// {"function":null,"index":0,"instruction":"MOV ax, #5","changes":[{"register":"ax","old":0,"new":5}],"annotation":null,"file":"a.asm","line":1}
+pub struct JsonTracer<W: Write> {
+    pub out: W,
+}
+
+// A JSON string literal
+pub(crate) fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl<W: Write> Tracer for JsonTracer<W> {
+    fn trace(&mut self, event: &TraceEvent) {
+        let changes: Vec<String> = event
+            .changes
+            .iter()
+            .map(|change| {
+                format!(
+                    "{{\"register\":{},\"old\":{},\"new\":{}}}",
+                    json_string(&register_name(change.register as usize)),
+                    change.old,
+                    change.new
+                )
+            })
+            .collect();
+        let optional = |text: &Option<String>| match text {
+            Some(text) => json_string(text),
+            None => "null".to_string(),
+        };
+        let (file, line) = match &event.position {
+            Some((file, line)) => (json_string(file), line.to_string()),
+            None => ("null".to_string(), "null".to_string()),
+        };
+        // A trace that can no longer be written is not worth stopping the program for
+        let _ = writeln!(
+            self.out,
+            "{{\"function\":{},\"index\":{},\"instruction\":{},\"changes\":[{}],\"annotation\":{},\"file\":{},\"line\":{}}}",
+            optional(&event.function),
+            event.index,
+            json_string(&event.instruction.to_string()),
+            changes.join(","),
+            optional(&event.annotation),
+            file,
+            line
+        );
+    }
+}
+
+// Counts the instructions executed in each function, keyed None for global code. Clones
+// share the counts, so one can go to add_tracer and the other be read after the run.
+#[derive(Clone, Default)]
+pub struct StepCounter(Rc<RefCell<HashMap<Option<String>, u64>>>);
+
+impl StepCounter {
+    pub fn counts(&self) -> HashMap<Option<String>, u64> {
+        self.0.borrow().clone()
+    }
+}
+
+impl Tracer for StepCounter {
+    fn trace(&mut self, event: &TraceEvent) {
+        *self
+            .0
+            .borrow_mut()
+            .entry(event.function.clone())
+            .or_default() += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keeps every event, clones share them like StepCounter
+    #[derive(Clone, Default)]
+    struct Collector(Rc<RefCell<Vec<TraceEvent>>>);
+
+    impl Tracer for Collector {
+        fn trace(&mut self, event: &TraceEvent) {
+            self.0.borrow_mut().push(event.clone());
+        }
+    }
+
+    const LOOP: &str = "mov cx, 2\nagain: dec cx\ncall .visit\ncmp cx, 0\njne again\nhalt\n\
+                        .visit\nret\n.end\n";
+
+    fn run_traced(source: &str, tracer: Box<dyn Tracer>) {
+        let config = Config {
+            debug: false,
+            ..Config::default()
+        };
+        let program = parse_with_config(source.to_string(), &config).unwrap();
+        let mut cpu = Cpu::new(config);
+        cpu.set_output(Box::new(std::io::sink()));
+        cpu.add_tracer(tracer);
+        cpu.load_program(&program);
+        cpu.run().unwrap();
+    }
+
+    fn collect(source: &str) -> Vec<TraceEvent> {
+        let collector = Collector::default();
+        run_traced(source, Box::new(collector.clone()));
+        let events = collector.0.borrow().clone();
+        events
+    }
+
+    #[test]
+    fn events_follow_execution_order() {
+        let executed: Vec<(Option<String>, u16)> = collect(LOOP)
+            .into_iter()
+            .map(|event| (event.function, event.index))
+            .collect();
+        let visit = Some(".visit".to_string());
+        assert_eq!(
+            executed,
+            vec![
+                (None, 0),
+                (None, 1),
+                (None, 2),
+                (visit.clone(), 0),
+                (None, 3),
+                (None, 4),
+                (None, 1),
+                (None, 2),
+                (visit, 0),
+                (None, 3),
+                (None, 4),
+                (None, 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn events_carry_the_registers_that_changed() {
+        let events = collect(LOOP);
+        assert_eq!(
+            events[0].changes,
+            vec![RegisterChange {
+                register: 2,
+                old: 0,
+                new: 2
+            }]
+        );
+        assert_eq!(events[0].instruction, Instruction::MOV(2, 2));
+        // CMP only sets flags
+        assert!(events[4].changes.is_empty());
+    }
+
+    #[test]
+    fn a_jump_filter_leaves_only_jumps() {
+        let collector = Collector::default();
+        let filter = ["jmp", "je", "JNE", "jg", "jl", "call", "ret"].map(String::from);
+        run_traced(
+            LOOP,
+            Box::new(FilteredTracer {
+                filter: filter.to_vec(),
+                tracer: collector.clone(),
+            }),
+        );
+        let events = collector.0.borrow();
+        let mnemonics: Vec<&str> = events
+            .iter()
+            .map(|event| event.instruction.to_mnemonic())
+            .collect();
+        assert_eq!(
+            mnemonics,
+            ["CALL", "RET", "JNE", "CALL", "RET", "JNE"].to_vec()
+        );
+    }
+
+    #[test]
+    fn an_annotation_follows_its_instruction_into_the_trace() {
+        let events = collect("mov a, 1 ;@ label=setup\ninc a\nhalt\n");
+        let annotations: Vec<Option<&str>> = events
+            .iter()
+            .map(|event| event.annotation.as_deref())
+            .collect();
+        assert_eq!(annotations, [Some("label=setup"), None, None]);
+    }
+
+    #[test]
+    fn json_lines_hold_one_event_each() {
+        let event = TraceEvent {
+            function: None,
+            index: 0,
+            instruction: Instruction::MOV(0, 5),
+            changes: vec![RegisterChange {
+                register: 0,
+                old: 0,
+                new: 5,
+            }],
+            annotation: Some("set \"a\"".to_string()),
+            position: Some(("a.asm".to_string(), 1)),
+        };
+        let mut tracer = JsonTracer { out: Vec::new() };
+        tracer.trace(&event);
+        assert_eq!(
+            String::from_utf8(tracer.out).unwrap(),
+            "{\"function\":null,\"index\":0,\"instruction\":\"MOV ax, #5\",\"changes\":[{\"register\":\"ax\",\"old\":0,\"new\":5}],\"annotation\":\"set \\\"a\\\"\",\"file\":\"a.asm\",\"line\":1}\n"
+        );
+    }
+
+    #[test]
+    fn a_console_trace_without_color_is_plain_text() {
+        let events = collect("mov ax, 5 ;@ start\nhalt\n");
+        let plain = ConsoleTracer {
+            signed: false,
+            color: false,
+        };
+        assert_eq!(
+            plain.format(&events[0]),
+            "[global 0] MOV ax, #5 ax: 0 -> 5 @ start"
+        );
+        let colored = ConsoleTracer {
+            signed: false,
+            color: true,
+        };
+        assert!(colored.format(&events[0]).contains('\x1b'));
+    }
+}